@@ -26,7 +26,8 @@ pub async fn track_airtime(
     };
 
     let mut stream = tuner::start_streaming(
-        channel.channel_type, channel.channel.clone(), user).await?;
+        channel.channel_type, channel.channel.clone(), user, None,
+        None).await?;
 
     let template = mustache::compile_str(command)?;
     let data = mustache::MapBuilder::new()
@@ -36,7 +37,7 @@ pub async fn track_airtime(
     let cmd = template.render_data_to_string(&data)?;
 
     let (input, output) = command_util::spawn_pipeline(
-        vec![cmd], stream.id())?;
+        vec![cmd], stream.id(), &Default::default())?;
 
     let stop_trigger = stream.take_stop_trigger();
 
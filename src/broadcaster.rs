@@ -1,74 +1,654 @@
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
 use std::io;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix::dev::{MessageResponse, ResponseChannel};
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
+use chrono::DateTime;
 use log;
 use tokio::io::AsyncRead;
 use tokio::sync::mpsc;
 
-use crate::chunk_stream::ChunkStream;
+use crate::chunk_stream::MpegTsChunkStream;
+use crate::config::{
+    PrimingConfig, SlowSubscriberConfig, SlowSubscriberPolicy,
+    StreamingConfig};
+use crate::datetime_ext::Jst;
+use crate::mpeg_ts_packet::*;
+use crate::command_util::CommandPipelineInput;
 use crate::mpeg_ts_stream::MpegTsStream;
+use crate::splice::{self, SpliceSink, SpliceTarget};
+use crate::time_shift_buffer::{Chunk, LoadFuture, TimeShiftBuffer};
 use crate::tuner::TunerSessionId as BroadcasterId;
 use crate::tuner::TunerSubscriptionId as SubscriberId;
 
 struct Subscriber {
     id: SubscriberId,
     sender: mpsc::Sender<Bytes>,
+    config: SlowSubscriberConfig,
+    // Chunks which cannot be queued, used only in the block policy.
+    pending_chunks: VecDeque<Bytes>,
+    pending_size: usize,
+    num_drops: usize,
+    // The sequence number of the next chunk in the time-shift buffer, used
+    // only while the subscriber is behind the live position.
+    cursor: Option<u64>,
+    // True while the chunk at the cursor is being loaded from a segment file.
+    loading: bool,
+}
+
+enum Feed {
+    Done,
+    // The chunk at the sequence number has to be loaded from a segment file.
+    // Call `Subscriber::loaded()` with the result.
+    Load(u64, LoadFuture),
+    Disconnect,
+}
+
+impl Subscriber {
+    fn new(
+        id: SubscriberId,
+        sender: mpsc::Sender<Bytes>,
+        config: SlowSubscriberConfig,
+        cursor: Option<u64>,
+    ) -> Self {
+        Subscriber {
+            id, sender, config,
+            pending_chunks: VecDeque::new(),
+            pending_size: 0,
+            num_drops: 0,
+            cursor,
+            loading: false,
+        }
+    }
+
+    // Sends chunks in the time-shift buffer from the cursor as many as
+    // possible.  The subscriber switches to the live position once it catches
+    // up.
+    fn feed(
+        &mut self,
+        broadcaster_id: BroadcasterId,
+        buffer: &TimeShiftBuffer,
+    ) -> Feed {
+        let mut cursor = match self.cursor {
+            Some(cursor) if !self.loading => cursor,
+            _ => return Feed::Done,
+        };
+
+        if cursor < buffer.first_seq() {
+            log::warn!("{}: {} fell behind the time-shift buffer, skip {} \
+                        chunks",
+                       broadcaster_id, self.id, buffer.first_seq() - cursor);
+            cursor = buffer.first_seq();
+        }
+
+        while cursor < buffer.next_seq() {
+            let chunk = match buffer.get(cursor) {
+                Some(Chunk::Ready(chunk)) => chunk,
+                Some(Chunk::Loading(future)) => {
+                    self.cursor = Some(cursor);
+                    self.loading = true;
+                    return Feed::Load(cursor, future);
+                }
+                None => break,
+            };
+            if !self.try_send_buffered(broadcaster_id, chunk) {
+                break;
+            }
+            cursor += 1;
+        }
+
+        self.update_cursor(broadcaster_id, cursor, buffer);
+        Feed::Done
+    }
+
+    // Called when a chunk requested by `Feed::Load` has been loaded.
+    fn loaded(
+        &mut self,
+        broadcaster_id: BroadcasterId,
+        seq: u64,
+        result: io::Result<Bytes>,
+        buffer: &TimeShiftBuffer,
+    ) -> Feed {
+        self.loading = false;
+        if self.cursor != Some(seq) {
+            return Feed::Done;
+        }
+        let chunk = match result {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                log::error!("{}: Failed to read the time-shift buffer for {}, \
+                             disconnect: {}", broadcaster_id, self.id, err);
+                return Feed::Disconnect;
+            }
+        };
+        if !self.try_send_buffered(broadcaster_id, chunk) {
+            // Load it again in the next feed.
+            return Feed::Done;
+        }
+        self.update_cursor(broadcaster_id, seq + 1, buffer);
+        self.feed(broadcaster_id, buffer)
+    }
+
+    fn try_send_buffered(
+        &mut self,
+        broadcaster_id: BroadcasterId,
+        chunk: Bytes,
+    ) -> bool {
+        match self.sender.try_send(chunk) {
+            Ok(_) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => false,
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                log::debug!("{}: Closed by {}, wait for unsubscribe",
+                            broadcaster_id, self.id);
+                false
+            }
+        }
+    }
+
+    fn update_cursor(
+        &mut self,
+        broadcaster_id: BroadcasterId,
+        cursor: u64,
+        buffer: &TimeShiftBuffer,
+    ) {
+        if cursor < buffer.next_seq() {
+            self.cursor = Some(cursor);
+        } else {
+            log::debug!("{}: {} caught up with the live position",
+                        broadcaster_id, self.id);
+            self.cursor = None;
+        }
+    }
+
+    // Returns `false` if the subscriber has to be disconnected.
+    fn send(&mut self, broadcaster_id: BroadcasterId, chunk: Bytes) -> bool {
+        if !self.flush_pending_chunks() {
+            // Closed by the subscriber, wait for unsubscribe.
+            return true;
+        }
+
+        if !self.pending_chunks.is_empty() {
+            // Keep the order of chunks.
+            return self.push_pending_chunk(broadcaster_id, chunk);
+        }
+
+        match self.sender.try_send(chunk) {
+            Ok(_) => true,
+            Err(mpsc::error::TrySendError::Full(chunk)) => {
+                match self.config.policy {
+                    SlowSubscriberPolicy::Drop => {
+                        self.num_drops += 1;
+                        log::warn!("{}: No space for {}, drop TS packets in \
+                                    the chunk ({} drops)",
+                                   broadcaster_id, self.id, self.num_drops);
+                        true
+                    }
+                    SlowSubscriberPolicy::Disconnect => {
+                        self.num_drops += 1;
+                        if self.num_drops > self.config.max_drops {
+                            log::error!("{}: No space for {}, disconnect \
+                                         ({} drops)",
+                                        broadcaster_id, self.id,
+                                        self.num_drops);
+                            return false;
+                        }
+                        log::warn!("{}: No space for {}, drop TS packets in \
+                                    the chunk ({} drops)",
+                                   broadcaster_id, self.id, self.num_drops);
+                        true
+                    }
+                    SlowSubscriberPolicy::Block =>
+                        self.push_pending_chunk(broadcaster_id, chunk),
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                log::debug!("{}: Closed by {}, wait for unsubscribe",
+                            broadcaster_id, self.id);
+                true
+            }
+        }
+    }
+
+    // Returns `false` if closed by the subscriber.
+    fn flush_pending_chunks(&mut self) -> bool {
+        while let Some(chunk) = self.pending_chunks.pop_front() {
+            let len = chunk.len();
+            match self.sender.try_send(chunk) {
+                Ok(_) => self.pending_size -= len,
+                Err(mpsc::error::TrySendError::Full(chunk)) => {
+                    self.pending_chunks.push_front(chunk);
+                    break;
+                }
+                Err(mpsc::error::TrySendError::Closed(_)) => {
+                    self.pending_chunks.clear();
+                    self.pending_size = 0;
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn push_pending_chunk(
+        &mut self,
+        broadcaster_id: BroadcasterId,
+        chunk: Bytes,
+    ) -> bool {
+        self.pending_size += chunk.len();
+        self.pending_chunks.push_back(chunk);
+        if self.pending_size > self.config.max_buffer_size {
+            log::error!("{}: Buffer for {} exceeds {} bytes, disconnect",
+                        broadcaster_id, self.id, self.config.max_buffer_size);
+            return false;
+        }
+        true
+    }
+}
+
+// Keeps the latest PAT and PMTs.
+#[derive(Default)]
+struct PsiCache {
+    pat_collector: PsiSectionCollector,
+    pat: Option<Bytes>,
+    // PMT PIDs in the order listed in the PAT.
+    pmt_pids: Vec<u16>,
+    pmt_collectors: HashMap<u16, PsiSectionCollector>,
+    pmts: HashMap<u16, Bytes>,
+}
+
+impl PsiCache {
+    fn update(&mut self, chunk: &[u8]) {
+        for packet in ts_packets(chunk) {
+            let pid = packet.pid();
+            if pid == PAT_PID {
+                if let Some((packets, section)) =
+                    self.pat_collector.collect(&packet) {
+                    self.update_pat(packets, pmt_pids_in_pat(&section));
+                }
+            } else if let Some(collector) = self.pmt_collectors.get_mut(&pid) {
+                if let Some((packets, _)) = collector.collect(&packet) {
+                    self.pmts.insert(pid, packets);
+                }
+            }
+        }
+    }
+
+    fn update_pat(&mut self, packets: Bytes, pmt_pids: Vec<u16>) {
+        if pmt_pids != self.pmt_pids {
+            // Discard PMTs which are no longer listed in the PAT.
+            self.pmt_collectors.retain(|pid, _| pmt_pids.contains(pid));
+            self.pmts.retain(|pid, _| pmt_pids.contains(pid));
+            for &pid in pmt_pids.iter() {
+                self.pmt_collectors.entry(pid).or_default();
+            }
+            self.pmt_pids = pmt_pids;
+        }
+        self.pat = Some(packets);
+    }
+
+    // Returns TS packets of the PAT followed by the PMTs, or `None` until all
+    // of them are collected.
+    fn packets(&self) -> Option<Bytes> {
+        let pat = self.pat.as_ref()?;
+        let mut packets = BytesMut::from(&pat[..]);
+        for pid in self.pmt_pids.iter() {
+            packets.extend_from_slice(self.pmts.get(pid)?);
+        }
+        Some(packets.freeze())
+    }
+}
+
+// Keeps data sent to a new subscriber before chunks from the source, so that
+// the subscriber can start playback without waiting for the next PAT/PMT.
+struct Primer {
+    config: PrimingConfig,
+    psi_cache: PsiCache,
+    recent_chunks: VecDeque<(Instant, Bytes)>,
+}
+
+impl Primer {
+    fn new(config: PrimingConfig) -> Self {
+        Primer {
+            config,
+            psi_cache: Default::default(),
+            recent_chunks: VecDeque::new(),
+        }
+    }
+
+    fn update(&mut self, chunk: &Bytes) {
+        if self.config.psi {
+            self.psi_cache.update(chunk);
+        }
+
+        if self.config.buffer_secs > 0 {
+            let now = Instant::now();
+            let duration = Duration::from_secs(self.config.buffer_secs);
+            while let Some((time, _)) = self.recent_chunks.front() {
+                if now.duration_since(*time) <= duration {
+                    break;
+                }
+                self.recent_chunks.pop_front();
+            }
+            self.recent_chunks.push_back((now, chunk.clone()));
+        }
+    }
+
+    fn psi_chunk(&self) -> Option<Bytes> {
+        if self.config.psi {
+            self.psi_cache.packets()
+        } else {
+            None
+        }
+    }
+
+    // Returns chunks to be sent to a new subscriber, at most `max_chunks`.
+    fn chunks(&self, max_chunks: usize) -> Vec<Bytes> {
+        let mut chunks: Vec<Bytes> = self.psi_chunk().into_iter().collect();
+        let n = max_chunks.saturating_sub(chunks.len())
+            .min(self.recent_chunks.len());
+        let skip = self.recent_chunks.len() - n;
+        chunks.extend(self.recent_chunks
+                      .iter()
+                      .skip(skip)
+                      .map(|(_, chunk)| chunk.clone()));
+        chunks.truncate(max_chunks);
+        chunks
+    }
 }
 
 pub struct Broadcaster {
     id: BroadcasterId,
     subscribers: Vec<Subscriber>,
+    primer: Primer,
+    time_shift_buffer: Option<TimeShiftBuffer>,
+    // Set only when the source supports splicing.
+    splice_sink: Option<SpliceSink>,
+    // The stream of the subscriber whose filter pipeline is spliced to the
+    // source.  No chunk comes from the source while splicing.
+    spliced_stream: Option<MpegTsStream>,
+    // Called when a stream returned from `subscribe()` is closed.
+    stop_fn: fn(SubscriberId),
 }
 
 impl Broadcaster {
-    // 100 chunks, large enough for 5 sec buffering.
-    const MAX_CHUNKS: usize = 500;
-
-    // 32 KiB, large enough for 10 ms buffering.
-    const CHUNK_SIZE: usize = 4096 * 8;
+    // 174 TS packets (about 32 KiB), large enough for 10 ms buffering.
+    //
+    // Each chunk contains only whole TS packets.  So, dropping a chunk never
+    // tears TS packets.
+    const CHUNK_SIZE: usize = TS_PACKET_SIZE * 174;
 
     pub fn new<R>(
         id: BroadcasterId,
         source: R,
+        config: &StreamingConfig,
+        ctx: &mut Context<Self>
+    ) -> Self
+    where
+        R: AsyncRead + Unpin + 'static,
+    {
+        Self::init(id, source, None, config, ctx)
+    }
+
+    // `sink` must be shared with the source which supports splicing.
+    pub fn with_splice_sink<R>(
+        id: BroadcasterId,
+        source: R,
+        sink: SpliceSink,
+        config: &StreamingConfig,
         ctx: &mut Context<Self>
     ) -> Self
     where
         R: AsyncRead + Unpin + 'static,
     {
-        let stream = ChunkStream::new(source, Self::CHUNK_SIZE);
+        Self::init(id, source, Some(sink), config, ctx)
+    }
+
+    fn init<R>(
+        id: BroadcasterId,
+        source: R,
+        splice_sink: Option<SpliceSink>,
+        config: &StreamingConfig,
+        ctx: &mut Context<Self>
+    ) -> Self
+    where
+        R: AsyncRead + Unpin + 'static,
+    {
+        let mut stream = MpegTsChunkStream::new(source, Self::CHUNK_SIZE);
+        if let Some(ref sink) = splice_sink {
+            stream.set_splice_sink(sink.clone());
+        }
         let _ = Self::add_stream(stream, ctx);
-        Self { id, subscribers: Vec::new() }
+        let time_shift_buffer = if config.time_shift.buffer_minutes > 0 {
+            let name = format!("mirakc-{}", id).replace('#', "");
+            Some(TimeShiftBuffer::new(&config.time_shift, &name))
+        } else {
+            None
+        };
+        Self {
+            id,
+            subscribers: Vec::new(),
+            primer: Primer::new(config.priming.clone()),
+            time_shift_buffer,
+            splice_sink,
+            spliced_stream: None,
+            stop_fn: crate::tuner::stop_streaming,
+        }
     }
 
-    fn subscribe(&mut self, id: SubscriberId) -> MpegTsStream {
-        let (sender, receiver) = mpsc::channel(Self::MAX_CHUNKS);
-        self.subscribers.push(Subscriber { id, sender });
-        MpegTsStream::new(id, receiver)
+    pub fn set_stop_fn(&mut self, stop_fn: fn(SubscriberId)) {
+        self.stop_fn = stop_fn;
+    }
+
+    // Splices the source to `input` if possible.  Otherwise, chunks from
+    // `stream` are piped to `input` in the normal way.
+    fn splice(&mut self, mut stream: MpegTsStream, input: CommandPipelineInput) {
+        let spliceable = splice::is_supported() &&
+            self.time_shift_buffer.is_none() &&
+            self.spliced_stream.is_none() &&
+            self.subscribers.len() == 1 &&
+            self.subscribers[0].id == stream.id() &&
+            self.subscribers[0].pending_chunks.is_empty();
+        let sink = match self.splice_sink {
+            Some(ref sink) if spliceable => sink,
+            _ => {
+                actix::spawn(stream.pipe(input));
+                return;
+            }
+        };
+        log::info!("{}: Start splicing to {}", self.id, stream.id());
+        let pending = stream.take_queued_chunks();
+        sink.set(SpliceTarget::new(stream.id(), input, pending));
+        self.spliced_stream = Some(stream);
+    }
+
+    fn stop_splicing(&mut self) {
+        let stream = match self.spliced_stream.take() {
+            Some(stream) => stream,
+            None => return,
+        };
+        // The sink may have already been cleared by the source.
+        let released =
+            self.splice_sink.as_ref().and_then(|sink| sink.release());
+        match released {
+            Some(released) => {
+                log::info!("{}: Stop splicing to {}", self.id, stream.id());
+                // The source hands back the target at the end of a TS packet
+                // so that the filter pipeline never receives torn packets.
+                actix::spawn(async move {
+                    if let Ok(target) = released.await {
+                        target.fallback(stream).await;
+                    }
+                });
+            }
+            None => drop(stream),
+        }
+        // Chunks in the primer are stale.
+        self.primer = Primer::new(self.primer.config.clone());
+    }
+
+    fn subscribe(
+        &mut self,
+        id: SubscriberId,
+        config: SlowSubscriberConfig,
+        start_time: Option<DateTime<Jst>>,
+        ctx: &mut Context<Self>,
+    ) -> MpegTsStream {
+        self.stop_splicing();
+
+        let cursor = match (start_time, self.time_shift_buffer.as_ref()) {
+            (Some(time), Some(buffer)) => {
+                let seq = buffer.seq_at(time);
+                if seq < buffer.next_seq() {
+                    Some(seq)
+                } else {
+                    None
+                }
+            }
+            (Some(_), None) => {
+                log::warn!("{}: No time-shift buffer, {} starts from the \
+                            live position", self.id, id);
+                None
+            }
+            (None, _) => None,
+        };
+
+        let queue_size = config.queue_size.max(1);
+        let (mut sender, receiver) = mpsc::channel(queue_size);
+        // Recent chunks are useless for a time-shifted subscriber.
+        let chunks = if cursor.is_some() {
+            self.primer.psi_chunk().into_iter().collect()
+        } else {
+            self.primer.chunks(queue_size)
+        };
+        for chunk in chunks {
+            if sender.try_send(chunk).is_err() {
+                break;
+            }
+        }
+
+        let mut subscriber = Subscriber::new(id, sender, config, cursor);
+        let feed = match self.time_shift_buffer {
+            Some(ref buffer) => subscriber.feed(self.id, buffer),
+            None => Feed::Done,
+        };
+        self.subscribers.push(subscriber);
+        self.handle_feed(id, feed, ctx);
+        MpegTsStream::with_stop_fn(id, receiver, self.stop_fn)
     }
 
     fn unsubscribe(&mut self, id: SubscriberId) {
+        let found = self.subscribers
+            .iter()
+            .find(|subscriber| subscriber.id == id);
+        if let Some(subscriber) = found {
+            if subscriber.num_drops > 0 {
+                log::warn!("{}: {} dropped {} chunks in total",
+                           self.id, id, subscriber.num_drops);
+            }
+        }
         // Log warning message if the user haven't subscribed.
         self.subscribers.retain(|subscriber| subscriber.id != id);
+
+        let spliced = self.spliced_stream
+            .as_ref()
+            .is_some_and(|stream| stream.id() == id);
+        if spliced {
+            if let Some(ref sink) = self.splice_sink {
+                // Dropping the target closes the filter pipeline.
+                drop(sink.take());
+            }
+            self.spliced_stream = None;
+        }
     }
 
-    fn broadcast(&mut self, chunk: Bytes) {
+    fn broadcast(&mut self, chunk: Bytes, ctx: &mut Context<Self>) {
+        if self.spliced_stream.is_some() {
+            // The source stopped splicing due to an error on the filter
+            // pipeline.  Close the stream, and wait for unsubscribe.
+            log::debug!("{}: Splicing stopped by the source", self.id);
+            self.spliced_stream = None;
+        }
+
+        self.primer.update(&chunk);
+
+        if let Some(ref mut buffer) = self.time_shift_buffer {
+            if let Err(err) = buffer.push(chunk.clone()) {
+                log::error!("{}: Failed to push a chunk to the time-shift \
+                             buffer, disable it: {}", self.id, err);
+                self.time_shift_buffer = None;
+            }
+        }
+
+        let mut feeds = Vec::new();
         for subscriber in self.subscribers.iter_mut() {
-            match subscriber.sender.try_send(chunk.clone()) {
-                Ok(_) => {},
-                Err(mpsc::error::TrySendError::Full(_)) => {
-                    log::warn!("{}: No space for {}, drop the chunk",
-                               self.id, subscriber.id);
-                }
-                Err(mpsc::error::TrySendError::Closed(_)) => {
-                    log::debug!("{}: Closed by {}, wait for unsubscribe",
-                                self.id, subscriber.id);
+            let feed = match (subscriber.cursor, &self.time_shift_buffer) {
+                (Some(_), Some(buffer)) => subscriber.feed(self.id, buffer),
+                _ => {
+                    subscriber.cursor = None;
+                    if subscriber.send(self.id, chunk.clone()) {
+                        Feed::Done
+                    } else {
+                        Feed::Disconnect
+                    }
                 }
+            };
+            feeds.push((subscriber.id, feed));
+        }
+        for (id, feed) in feeds.into_iter() {
+            self.handle_feed(id, feed, ctx);
+        }
+    }
+
+    fn handle_feed(
+        &mut self,
+        id: SubscriberId,
+        feed: Feed,
+        ctx: &mut Context<Self>,
+    ) {
+        match feed {
+            Feed::Done => (),
+            Feed::Load(seq, future) => {
+                // File I/O is performed outside the actor's thread.
+                ctx.spawn(actix::fut::wrap_future::<_, Self>(future)
+                          .map(move |result, act, ctx| {
+                              act.handle_loaded(id, seq, result, ctx)
+                          }));
+            }
+            Feed::Disconnect => {
+                // Dropping the sender closes the stream.
+                self.subscribers.retain(|subscriber| subscriber.id != id);
             }
         }
     }
+
+    fn handle_loaded(
+        &mut self,
+        id: SubscriberId,
+        seq: u64,
+        result: io::Result<Bytes>,
+        ctx: &mut Context<Self>,
+    ) {
+        let subscriber = match self.subscribers
+            .iter_mut()
+            .find(|subscriber| subscriber.id == id) {
+                Some(subscriber) => subscriber,
+                None => return,  // already unsubscribed
+            };
+        let feed = match self.time_shift_buffer {
+            Some(ref buffer) =>
+                subscriber.loaded(self.id, seq, result, buffer),
+            None => {
+                // Disabled while loading.
+                subscriber.loading = false;
+                subscriber.cursor = None;
+                Feed::Done
+            }
+        };
+        self.handle_feed(id, feed, ctx);
+    }
 }
 
 impl Actor for Broadcaster {
@@ -86,7 +666,10 @@ impl Actor for Broadcaster {
 // subscribe
 
 pub struct SubscribeMessage {
-    pub id: SubscriberId
+    pub id: SubscriberId,
+    pub config: SlowSubscriberConfig,
+    // Start streaming from the time-shift buffer if specified.
+    pub start_time: Option<DateTime<Jst>>,
 }
 
 impl fmt::Display for SubscribeMessage {
@@ -117,10 +700,10 @@ impl Handler<SubscribeMessage> for Broadcaster {
     fn handle(
         &mut self,
         msg: SubscribeMessage,
-        _: &mut Self::Context
+        ctx: &mut Self::Context
     ) -> Self::Result {
         log::debug!("{}", msg);
-        self.subscribe(msg.id)
+        self.subscribe(msg.id, msg.config, msg.start_time, ctx)
     }
 }
 
@@ -153,12 +736,42 @@ impl Handler<UnsubscribeMessage> for Broadcaster {
     }
 }
 
+// splice
+
+pub struct SpliceMessage {
+    pub stream: MpegTsStream,
+    pub input: CommandPipelineInput,
+}
+
+impl fmt::Display for SpliceMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Splice to {}", self.stream.id())
+    }
+}
+
+impl Message for SpliceMessage {
+    type Result = ();
+}
+
+impl Handler<SpliceMessage> for Broadcaster {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SpliceMessage,
+        _: &mut Self::Context
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.splice(msg.stream, msg.input)
+    }
+}
+
 // stream handler
 
 impl StreamHandler<io::Result<Bytes>> for Broadcaster {
     fn handle(&mut self, chunk: io::Result<Bytes>, ctx: &mut Context<Self>) {
         match chunk {
-            Ok(chunk) => self.broadcast(chunk),
+            Ok(chunk) => self.broadcast(chunk, ctx),
             Err(err) => {
                 log::error!("{}: Error, stop: {}", self.id, err);
                 ctx.stop();
@@ -171,3 +784,210 @@ impl StreamHandler<io::Result<Bytes>> for Broadcaster {
         ctx.stop();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpeg_ts_packet::test_helper::*;
+
+    fn create_subscriber(
+        policy: SlowSubscriberPolicy,
+    ) -> (Subscriber, mpsc::Receiver<Bytes>) {
+        let (sender, receiver) = mpsc::channel(1);
+        let config = SlowSubscriberConfig {
+            policy,
+            queue_size: 1,
+            max_drops: 1,
+            max_buffer_size: 4,
+        };
+        (Subscriber::new(Default::default(), sender, config, None), receiver)
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_drop() {
+        let (mut subscriber, mut receiver) =
+            create_subscriber(SlowSubscriberPolicy::Drop);
+        let id = Default::default();
+
+        assert!(subscriber.send(id, Bytes::from("1")));
+        assert!(subscriber.send(id, Bytes::from("2")));
+        assert!(subscriber.send(id, Bytes::from("3")));
+        assert_eq!(subscriber.num_drops, 2);
+
+        assert_eq!(receiver.recv().await, Some(Bytes::from("1")));
+        assert!(subscriber.send(id, Bytes::from("4")));
+        assert_eq!(receiver.recv().await, Some(Bytes::from("4")));
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_disconnect() {
+        let (mut subscriber, _receiver) =
+            create_subscriber(SlowSubscriberPolicy::Disconnect);
+        let id = Default::default();
+
+        assert!(subscriber.send(id, Bytes::from("1")));
+        assert!(subscriber.send(id, Bytes::from("2")));
+        assert_eq!(subscriber.num_drops, 1);
+        assert!(!subscriber.send(id, Bytes::from("3")));
+        assert_eq!(subscriber.num_drops, 2);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_block() {
+        let (mut subscriber, mut receiver) =
+            create_subscriber(SlowSubscriberPolicy::Block);
+        let id = Default::default();
+
+        assert!(subscriber.send(id, Bytes::from("1")));
+        assert!(subscriber.send(id, Bytes::from("22")));
+        assert!(subscriber.send(id, Bytes::from("33")));
+        assert_eq!(subscriber.num_drops, 0);
+        assert_eq!(subscriber.pending_size, 4);
+
+        assert_eq!(receiver.recv().await, Some(Bytes::from("1")));
+        assert!(subscriber.send(id, Bytes::from("4")));
+        assert_eq!(subscriber.pending_size, 3);
+        assert_eq!(receiver.recv().await, Some(Bytes::from("22")));
+
+        assert!(subscriber.send(id, Bytes::from("55")));
+        assert_eq!(subscriber.pending_size, 3);
+        assert!(!subscriber.send(id, Bytes::from("66")));
+    }
+
+    #[test]
+    fn test_psi_cache() {
+        let mut cache = PsiCache::default();
+        let pat = create_pat_packet(&[0x0101, 0x0102]);
+        let pmt1 = create_psi_packet(0x0101, 0x02, &[0x01; 9]);
+        let pmt2 = create_psi_packet(0x0102, 0x02, &[0x02; 9]);
+        let video = create_packet(0x0111, true, &[0x00; 4]);
+
+        // PMTs before the PAT are ignored.
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&pmt1);
+        chunk.extend_from_slice(&pat);
+        chunk.extend_from_slice(&video);
+        cache.update(&chunk);
+        assert!(cache.packets().is_none());
+
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&pmt2);
+        chunk.extend_from_slice(&pmt1);
+        chunk.extend_from_slice(&video);
+        cache.update(&chunk);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&pat);
+        expected.extend_from_slice(&pmt1);
+        expected.extend_from_slice(&pmt2);
+        assert_eq!(cache.packets(), Some(Bytes::from(expected)));
+
+        // A PMT no longer listed in the PAT is discarded.
+        let pat = create_pat_packet(&[0x0102]);
+        cache.update(&pat);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&pat);
+        expected.extend_from_slice(&pmt2);
+        assert_eq!(cache.packets(), Some(Bytes::from(expected)));
+    }
+
+    #[test]
+    fn test_primer() {
+        let chunk = Bytes::from(create_packet(0x0111, true, &[0x00; 4]));
+
+        let mut primer = Primer::new(Default::default());
+        primer.update(&chunk);
+        assert!(primer.chunks(10).is_empty());
+
+        let mut primer = Primer::new(PrimingConfig {
+            psi: true,
+            buffer_secs: 10,
+        });
+        let pat = Bytes::from(create_pat_packet(&[0x0101]));
+        let pmt = Bytes::from(create_psi_packet(0x0101, 0x02, &[0x01; 9]));
+        primer.update(&pat);
+        primer.update(&pmt);
+        primer.update(&chunk);
+
+        let chunks = primer.chunks(10);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[0].len(), TS_PACKET_SIZE * 2);
+        assert_eq!(chunks[3], chunk);
+
+        let chunks = primer.chunks(2);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), TS_PACKET_SIZE * 2);
+        assert_eq!(chunks[1], chunk);
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_feed() {
+        let (mut subscriber, mut receiver) =
+            create_subscriber(SlowSubscriberPolicy::Drop);
+        let id = Default::default();
+        let config = crate::config::TimeShiftConfig {
+            buffer_minutes: 1,
+            dir: None,
+        };
+        let mut buffer = TimeShiftBuffer::new(&config, "test");
+        buffer.push(Bytes::from("1")).unwrap();
+        buffer.push(Bytes::from("2")).unwrap();
+
+        subscriber.cursor = Some(0);
+        assert!(matches!(subscriber.feed(id, &buffer), Feed::Done));
+        assert_eq!(subscriber.cursor, Some(1));
+        assert_eq!(receiver.recv().await, Some(Bytes::from("1")));
+
+        buffer.push(Bytes::from("3")).unwrap();
+        assert!(matches!(subscriber.feed(id, &buffer), Feed::Done));
+        assert_eq!(subscriber.cursor, Some(2));
+        assert_eq!(receiver.recv().await, Some(Bytes::from("2")));
+
+        assert!(matches!(subscriber.feed(id, &buffer), Feed::Done));
+        assert_eq!(subscriber.cursor, None);
+        assert_eq!(receiver.recv().await, Some(Bytes::from("3")));
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_feed_file() {
+        let (mut subscriber, mut receiver) =
+            create_subscriber(SlowSubscriberPolicy::Drop);
+        let id = Default::default();
+        let config = crate::config::TimeShiftConfig {
+            buffer_minutes: 1,
+            dir: Some(std::env::temp_dir().to_str().unwrap().to_string()),
+        };
+        let name = format!("mirakc-test-feed-{}", std::process::id());
+        let mut buffer = TimeShiftBuffer::new(&config, &name);
+        buffer.push(Bytes::from("1")).unwrap();
+        buffer.push(Bytes::from("2")).unwrap();
+
+        subscriber.cursor = Some(0);
+        let (seq, future) = match subscriber.feed(id, &buffer) {
+            Feed::Load(seq, future) => (seq, future),
+            _ => panic!("Must be loaded"),
+        };
+        assert_eq!(seq, 0);
+        // No more load while loading.
+        assert!(matches!(subscriber.feed(id, &buffer), Feed::Done));
+
+        let result = future.await;
+        let (seq, future) = match subscriber.loaded(id, 0, result, &buffer) {
+            Feed::Load(seq, future) => (seq, future),
+            _ => panic!("Must be loaded"),
+        };
+        assert_eq!(seq, 1);
+        assert_eq!(receiver.recv().await, Some(Bytes::from("1")));
+
+        let result = future.await;
+        assert!(matches!(subscriber.loaded(id, 1, result, &buffer),
+                         Feed::Done));
+        assert_eq!(subscriber.cursor, None);
+        assert_eq!(receiver.recv().await, Some(Bytes::from("2")));
+
+        subscriber.cursor = Some(0);
+        let result = Err(io::Error::from(io::ErrorKind::NotFound));
+        subscriber.loading = true;
+        assert!(matches!(subscriber.loaded(id, 0, result, &buffer),
+                         Feed::Disconnect));
+    }
+}
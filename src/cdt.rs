@@ -0,0 +1,400 @@
+use std::collections::HashMap;
+
+use crate::models::*;
+use crate::mpeg_ts_packet::{self, PsiSectionCollector};
+
+// Collects logo data transmitted in the CDT (common data table) defined in
+// ARIB STD-B21.
+//
+// A logo is identified by the network ID and the logo ID.  Each logo is
+// transmitted in several types for different screen sizes, and the most
+// preferred type received is kept.
+#[derive(Default)]
+pub struct CdtCollector {
+    collector: PsiSectionCollector,
+    logos: HashMap<(NetworkId, u16), Logo>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Logo {
+    pub nid: NetworkId,
+    pub logo_id: u16,
+    pub logo_type: u8,
+    pub version: u16,
+    // A PNG image including the palette.
+    pub data: Vec<u8>,
+}
+
+impl Logo {
+    // Types in the order of preference.  HD types come first.
+    //
+    //   5: 64x36 (HD, large)
+    //   2: 48x27 (HD, small)
+    //   3: 72x36 (SD 4:3, large)
+    //   4: 54x36 (SD 16:9, large)
+    //   0: 48x24 (SD 4:3, small)
+    //   1: 36x24 (SD 16:9, small)
+    const TYPES: [u8; 6] = [5, 2, 3, 4, 0, 1];
+
+    fn rank(&self) -> usize {
+        Self::TYPES
+            .iter()
+            .position(|&logo_type| logo_type == self.logo_type)
+            .unwrap_or(Self::TYPES.len())
+    }
+
+    fn is_preferred_to(&self, other: &Logo) -> bool {
+        self.version != other.version || self.rank() < other.rank()
+    }
+}
+
+impl CdtCollector {
+    // Feeds a chunk which contains only whole TS packets.
+    pub fn feed(&mut self, chunk: &[u8]) {
+        for packet in mpeg_ts_packet::ts_packets(chunk) {
+            if packet.pid() != mpeg_ts_packet::CDT_PID {
+                continue;
+            }
+            if let Some((_, section)) = self.collector.collect(&packet) {
+                if let Some(logo) = CdtSection::parse(&section) {
+                    self.update(logo);
+                }
+            }
+        }
+    }
+
+    // Returns true if all logos have been received in the most preferred
+    // type.
+    pub fn is_complete(&self, logos: &[(NetworkId, u16)]) -> bool {
+        logos.iter().all(|key| match self.logos.get(key) {
+            Some(logo) => logo.rank() == 0,
+            None => false,
+        })
+    }
+
+    pub fn into_logos(mut self) -> Vec<Logo> {
+        self.logos.drain().map(|(_, logo)| logo).collect()
+    }
+
+    fn update(&mut self, logo: Logo) {
+        let key = (logo.nid, logo.logo_id);
+        let replace = match self.logos.get(&key) {
+            Some(current) => logo.is_preferred_to(current),
+            None => true,
+        };
+        if replace {
+            self.logos.insert(key, logo);
+        }
+    }
+}
+
+struct CdtSection;
+
+impl CdtSection {
+    // common_data_table
+    const TABLE_ID: u8 = 0xC8;
+    // data_type for logo data.
+    const LOGO_DATA_TYPE: u8 = 0x01;
+
+    fn parse(section: &[u8]) -> Option<Logo> {
+        // 13 bytes header and 4 bytes CRC32.
+        if section.len() < 17 || section[0] != Self::TABLE_ID {
+            return None;
+        }
+        let end = section.len() - 4;
+        if mpeg_ts_packet::crc32(section) != 0 {
+            return None;
+        }
+
+        let nid = (section[8] as u16) << 8 | section[9] as u16;
+        let data_type = section[10];
+        if data_type != Self::LOGO_DATA_TYPE {
+            return None;
+        }
+        let descriptors_loop_length =
+            ((section[11] & 0x0F) as usize) << 8 | section[12] as usize;
+
+        // data_module_byte for logo data.
+        let pos = 13 + descriptors_loop_length;
+        if pos + 7 > end {
+            return None;
+        }
+        let logo_type = section[pos];
+        let logo_id =
+            ((section[pos + 1] & 0x01) as u16) << 8 | section[pos + 2] as u16;
+        let version =
+            ((section[pos + 3] & 0x0F) as u16) << 8 | section[pos + 4] as u16;
+        let data_size =
+            (section[pos + 5] as usize) << 8 | section[pos + 6] as usize;
+        let data = &section[pos + 7..];
+        if data_size > end - (pos + 7) {
+            return None;
+        }
+
+        Some(Logo {
+            nid: nid.into(),
+            logo_id,
+            logo_type,
+            version,
+            data: add_palette(&data[..data_size])?,
+        })
+    }
+}
+
+// PNG images in the CDT have no palette.  Receivers use the common fixed
+// colors defined in ARIB STD-B24 instead.  This function inserts PLTE and tRNS
+// chunks so that the image can be displayed in generic image viewers.
+//
+// Returns `None` if the data is not a PNG image.
+fn add_palette(png: &[u8]) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    // The signature and the IHDR chunk.
+    const HEADER_SIZE: usize = 8 + 4 + 4 + 13 + 4;
+    const INDEXED_COLOR: u8 = 3;
+
+    if png.len() < HEADER_SIZE || !png.starts_with(SIGNATURE) ||
+        &png[12..16] != b"IHDR" {
+        return None;
+    }
+    if png[25] != INDEXED_COLOR || has_png_chunk(png, b"PLTE") {
+        return Some(png.to_vec());
+    }
+
+    let colors = common_fixed_colors();
+    let plte: Vec<u8> = colors
+        .iter()
+        .flat_map(|color| color[..3].to_vec())
+        .collect();
+    let trns: Vec<u8> = colors.iter().map(|color| color[3]).collect();
+
+    let mut data = png[..HEADER_SIZE].to_vec();
+    write_png_chunk(&mut data, b"PLTE", &plte);
+    write_png_chunk(&mut data, b"tRNS", &trns);
+    data.extend_from_slice(&png[HEADER_SIZE..]);
+    Some(data)
+}
+
+fn has_png_chunk(png: &[u8], chunk_type: &[u8]) -> bool {
+    let mut pos = 8;
+    while pos + 8 <= png.len() {
+        if &png[pos + 4..pos + 8] == chunk_type {
+            return true;
+        }
+        let len = u32::from_be_bytes(
+            [png[pos], png[pos + 1], png[pos + 2], png[pos + 3]]) as usize;
+        pos += 12 + len;
+    }
+    false
+}
+
+fn write_png_chunk(data: &mut Vec<u8>, chunk_type: &[u8], body: &[u8]) {
+    data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(chunk_type);
+    data.extend_from_slice(body);
+    let crc = png_crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+// The CRC32 used in PNG, which differs from the one used in PSI sections.
+fn png_crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data.iter() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// Returns the 128 common fixed colors in RGBA.
+//
+//   0..=7:    Primary colors with the full intensity
+//   8:        Transparent
+//   9..=15:   Primary colors with the half intensity
+//   16..=64:  Other colors made of 4 levels of RGB components
+//   65..=127: Semi-transparent versions of the colors above
+fn common_fixed_colors() -> Vec<[u8; 4]> {
+    const LEVELS: [u8; 4] = [0, 85, 170, 255];
+
+    let mut colors = Vec::with_capacity(128);
+    for i in 0..8u8 {
+        colors.push(primary_color(i, 255));
+    }
+    colors.push([0, 0, 0, 0]);
+    for i in 1..8u8 {
+        colors.push(primary_color(i, 170));
+    }
+    for &r in LEVELS.iter() {
+        for &g in LEVELS.iter() {
+            for &b in LEVELS.iter() {
+                let rgb = [r, g, b];
+                let full = rgb.iter().all(|&c| c == 0 || c == 255);
+                let half = rgb.iter().all(|&c| c == 0 || c == 170);
+                if !full && !half {
+                    colors.push([r, g, b, 255]);
+                }
+            }
+        }
+    }
+    let semi_transparent: Vec<[u8; 4]> = colors[1..65]
+        .iter()
+        .filter(|color| color[3] == 255)
+        .map(|color| [color[0], color[1], color[2], 128])
+        .collect();
+    colors.extend(semi_transparent);
+    colors
+}
+
+fn primary_color(index: u8, level: u8) -> [u8; 4] {
+    let component = |bit: u8| if index & bit != 0 { level } else { 0 };
+    [component(1), component(2), component(4), 255]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpeg_ts_packet::test_helper::create_packet;
+
+    #[test]
+    fn test_cdt_collector() {
+        let mut collector = CdtCollector::default();
+        let wanted = [(4.into(), 1)];
+        assert!(!collector.is_complete(&wanted));
+
+        collector.feed(&create_cdt_packet(4, 1, 0, 0));
+        assert!(!collector.is_complete(&wanted));
+
+        collector.feed(&create_cdt_packet(4, 1, 5, 0));
+        assert!(collector.is_complete(&wanted));
+
+        // A less preferred type is ignored.
+        collector.feed(&create_cdt_packet(4, 1, 2, 0));
+        assert!(collector.is_complete(&wanted));
+
+        // Another network.
+        collector.feed(&create_cdt_packet(6, 1, 5, 0));
+        assert!(!collector.is_complete(&[(4.into(), 1), (6.into(), 2)]));
+
+        let mut logos = collector.into_logos();
+        logos.sort_by_key(|logo| logo.nid.value());
+        assert_eq!(logos.len(), 2);
+        assert_eq!(logos[0].nid, 4.into());
+        assert_eq!(logos[0].logo_type, 5);
+        assert!(has_png_chunk(&logos[0].data, b"PLTE"));
+        assert!(has_png_chunk(&logos[0].data, b"tRNS"));
+        assert_eq!(logos[1].nid, 6.into());
+    }
+
+    #[test]
+    fn test_cdt_collector_new_version() {
+        let mut collector = CdtCollector::default();
+        collector.feed(&create_cdt_packet(4, 1, 5, 0));
+        collector.feed(&create_cdt_packet(4, 1, 1, 1));
+        let logos = collector.into_logos();
+        assert_eq!(logos.len(), 1);
+        assert_eq!(logos[0].logo_type, 1);
+        assert_eq!(logos[0].version, 1);
+    }
+
+    #[test]
+    fn test_cdt_section_with_broken_crc() {
+        let mut packet = create_cdt_packet(4, 1, 5, 0);
+        packet[20] ^= 0xFF;
+        let mut collector = CdtCollector::default();
+        collector.feed(&packet);
+        assert!(collector.into_logos().is_empty());
+    }
+
+    #[test]
+    fn test_add_palette() {
+        let png = create_png();
+        let data = add_palette(&png).unwrap();
+        assert_eq!(data.len(), png.len() + (12 + 128 * 3) + (12 + 128));
+        assert!(has_png_chunk(&data, b"IHDR"));
+        assert!(has_png_chunk(&data, b"PLTE"));
+        assert!(has_png_chunk(&data, b"IEND"));
+
+        // Not modified if the palette exists.
+        assert_eq!(add_palette(&data).unwrap(), data);
+
+        assert!(add_palette(b"not a png").is_none());
+    }
+
+    #[test]
+    fn test_png_crc32() {
+        assert_eq!(png_crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(png_crc32(b"IEND"), 0xAE42_6082);
+    }
+
+    #[test]
+    fn test_common_fixed_colors() {
+        let colors = common_fixed_colors();
+        assert_eq!(colors.len(), 128);
+        assert_eq!(colors[0], [0, 0, 0, 255]);
+        assert_eq!(colors[7], [255, 255, 255, 255]);
+        assert_eq!(colors[8], [0, 0, 0, 0]);
+        assert_eq!(colors[9], [170, 0, 0, 255]);
+        assert_eq!(colors[15], [170, 170, 170, 255]);
+        assert_eq!(colors[16], [0, 0, 85, 255]);
+        assert_eq!(colors[64], [255, 255, 170, 255]);
+        assert_eq!(colors[65], [255, 0, 0, 128]);
+        assert_eq!(colors[127], [255, 255, 170, 128]);
+    }
+
+    // A minimal indexed-color PNG image without the palette.
+    fn create_png() -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        write_png_chunk(&mut png, b"IHDR", &[
+            0, 0, 0, 1,  // width
+            0, 0, 0, 1,  // height
+            8,  // bit depth
+            3,  // color type
+            0, 0, 0,
+        ]);
+        write_png_chunk(&mut png, b"IDAT", &[0x78, 0x9C, 0x63, 0x60, 0x00,
+                                             0x00, 0x00, 0x02, 0x00, 0x01]);
+        write_png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+
+    // Creates a TS packet containing a CDT section with a valid CRC32.
+    fn create_cdt_packet(
+        nid: u16,
+        logo_id: u16,
+        logo_type: u8,
+        version: u16,
+    ) -> Vec<u8> {
+        let png = create_png();
+        let mut section = vec![
+            CdtSection::TABLE_ID,
+            0x00, 0x00,  // section_length
+            0x00, 0x01,  // download_data_id
+            0xC1,
+            0x00,  // section_number
+            0x00,  // last_section_number
+            (nid >> 8) as u8, nid as u8,
+            CdtSection::LOGO_DATA_TYPE,
+            0xF0, 0x00,  // descriptors_loop_length
+            logo_type,
+            0xFE | (logo_id >> 8) as u8, logo_id as u8,
+            0xF0 | (version >> 8) as u8, version as u8,
+            (png.len() >> 8) as u8, png.len() as u8,
+        ];
+        section.extend_from_slice(&png);
+        let section_length = section.len() - 3 + 4;
+        section[1] = 0xF0 | (section_length >> 8) as u8;
+        section[2] = section_length as u8;
+        let crc = mpeg_ts_packet::crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let mut payload = vec![0x00];  // pointer_field
+        payload.extend_from_slice(&section);
+        create_packet(mpeg_ts_packet::CDT_PID, true, &payload)
+    }
+}
@@ -0,0 +1,307 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use tokio::io::AsyncReadExt;
+
+use crate::command_util;
+use crate::config::{Config, ProcessConfig};
+use crate::epg::*;
+use crate::error::Error;
+use crate::models::*;
+use crate::tuner;
+
+// Scans physical channels and finds channels which have services.
+//
+// Each candidate channel is scanned by the same command as the scan-services
+// job.  A channel is skipped if no service is found within the timeout.
+
+pub struct ChannelScanner {
+    command: String,
+    process: ProcessConfig,
+    channel_types: Vec<ChannelType>,
+    timeout: Duration,
+}
+
+pub struct ScannedChannel {
+    pub channel_type: ChannelType,
+    pub channel: String,
+    pub services: Vec<EpgService>,
+}
+
+// TODO: The following implementation has code clones similar to
+//       ServiceScanner.
+
+impl ChannelScanner {
+    const LABEL: &'static str = "channel-scanner";
+
+    // Large values overflow when added to the current time.
+    pub const MAX_TIMEOUT_SECS: u64 = 3600;
+
+    pub fn new(
+        command: String,
+        process: ProcessConfig,
+        channel_types: Vec<ChannelType>,
+        timeout: Duration,
+    ) -> Self {
+        ChannelScanner { command, process, channel_types, timeout }
+    }
+
+    pub async fn scan_channels(self) -> Result<Vec<ScannedChannel>, Error> {
+        log::debug!("Scanning channels...");
+
+        let mut channels = Vec::new();
+        for channel_type in self.channel_types.iter() {
+            for channel in Self::candidates(*channel_type).into_iter() {
+                let services = match self.scan_channel(
+                    *channel_type, &channel).await {
+                    Ok(services) => services,
+                    // No tuner can be used for the remaining channels.
+                    Err(Error::TunerUnavailable) =>
+                        return Err(Error::TunerUnavailable),
+                    Err(err) => {
+                        log::debug!("{} {}: Skipped: {}",
+                                    channel_type, channel, err);
+                        continue;
+                    }
+                };
+                if services.is_empty() {
+                    continue;
+                }
+                log::info!("{} {}: Found {} services",
+                           channel_type, channel, services.len());
+                channels.push(ScannedChannel {
+                    channel_type: *channel_type,
+                    channel,
+                    services,
+                });
+            }
+        }
+
+        log::debug!("Found {} channels", channels.len());
+
+        Ok(channels)
+    }
+
+    // Physical channels in the format used by recpt1.
+    fn candidates(channel_type: ChannelType) -> Vec<String> {
+        match channel_type {
+            ChannelType::GR => (13..=62)
+                .map(|ch| ch.to_string())
+                .collect(),
+            ChannelType::BS => (1..=23)
+                .step_by(2)
+                .flat_map(|tp| (0..3).map(move |slot| {
+                    format!("BS{:02}_{}", tp, slot)
+                }))
+                .collect(),
+            ChannelType::CS => (2..=24)
+                .step_by(2)
+                .map(|tp| format!("CS{}", tp))
+                .collect(),
+            ChannelType::SKY => vec![],
+        }
+    }
+
+    async fn scan_channel(
+        &self,
+        channel_type: ChannelType,
+        channel: &str,
+    ) -> Result<Vec<EpgService>, Error> {
+        log::debug!("Scanning {} {}...", channel_type, channel);
+
+        let user = TunerUser {
+            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
+            priority: (-1).into(),
+        };
+
+        let stream = tuner::start_streaming(
+            channel_type, channel.to_string(), user, None, None).await?;
+
+        let epg_channel = EpgChannel {
+            name: format!("{}{}", channel_type, channel),
+            channel_type,
+            channel: channel.to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+
+        let template = mustache::compile_str(&self.command)?;
+        let data = mustache::MapBuilder::new()
+            .insert("sids", &epg_channel.services)?
+            .insert("xsids", &epg_channel.excluded_services)?
+            .build();
+        let cmd = template.render_data_to_string(&data)?;
+
+        let (input, mut output) = command_util::spawn_pipeline(
+            vec![cmd], stream.id(), &self.process)?;
+
+        // Unlike ServiceScanner, the stream is not spawned as a task.  No TS
+        // packet may come from the tuner, and the stream has to be dropped
+        // together with the pipeline when it times out.
+        let mut buf = Vec::new();
+        let result = {
+            let read = Box::pin(output.read_to_end(&mut buf));
+            let pipe = Box::pin(stream.pipe(input));
+            tokio::time::timeout(self.timeout, async move {
+                match future::select(read, pipe).await {
+                    Either::Left((result, _)) => result,
+                    // The tuner stopped streaming.  Read the rest.
+                    Either::Right((_, read)) => read.await,
+                }
+            }).await
+        };
+
+        // Explicitly dropping the output of the pipeline is needed.  The output
+        // holds the child processes and it kills them when dropped.
+        drop(output);
+
+        match result {
+            Ok(result) => result?,
+            Err(_) => {
+                log::debug!("{} {}: Timed out", channel_type, channel);
+                return Ok(vec![]);
+            }
+        };
+
+        let services: Vec<TsService> = serde_json::from_slice(&buf)?;
+        Ok(services
+           .into_iter()
+           .map(|sv| EpgService::from((&epg_channel, &sv)))
+           .collect())
+    }
+}
+
+// Channel types which can be scanned with the enabled tuners.
+pub fn supported_channel_types(config: &Config) -> Vec<ChannelType> {
+    [ChannelType::GR, ChannelType::BS, ChannelType::CS]
+        .iter()
+        .filter(|channel_type| config.tuners
+                .iter()
+                .filter(|tuner| !tuner.disabled)
+                .any(|tuner| tuner.channel_types.contains(channel_type)))
+        .cloned()
+        .collect()
+}
+
+// Renders a `channels` section in the config YAML.
+//
+// Each channel is named after its first service.  Services found are listed
+// in comments.
+pub fn render_channels_yaml(channels: &[ScannedChannel]) -> String {
+    let mut yaml = String::from("channels:\n");
+    let mut names = HashSet::new();
+    for channel in channels.iter() {
+        let mut name = channel.services
+            .first()
+            .map(|sv| sv.name.clone())
+            .unwrap_or_default();
+        if name.is_empty() || !names.insert(name.clone()) {
+            name = format!("{} ({}{})",
+                           name, channel.channel_type, channel.channel)
+                .trim_start()
+                .to_string();
+            names.insert(name.clone());
+        }
+        yaml.push_str(&format!("  - name: {}\n", quote(&name)));
+        yaml.push_str(&format!("    type: {}\n", channel.channel_type));
+        yaml.push_str(&format!("    channel: {}\n", quote(&channel.channel)));
+        for sv in channel.services.iter() {
+            yaml.push_str(&format!("    # {}: {}\n", sv.sid.value(), sv.name));
+        }
+    }
+    yaml
+}
+
+// A JSON string is a valid double-quoted scalar in YAML.
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_candidates() {
+        let channels = ChannelScanner::candidates(ChannelType::GR);
+        assert_eq!(channels.len(), 50);
+        assert_eq!(channels[0], "13");
+        assert_eq!(channels[49], "62");
+
+        let channels = ChannelScanner::candidates(ChannelType::BS);
+        assert_eq!(channels.len(), 36);
+        assert_eq!(channels[0], "BS01_0");
+        assert_eq!(channels[35], "BS23_2");
+
+        let channels = ChannelScanner::candidates(ChannelType::CS);
+        assert_eq!(channels.len(), 12);
+        assert_eq!(channels[0], "CS2");
+        assert_eq!(channels[11], "CS24");
+
+        assert!(ChannelScanner::candidates(ChannelType::SKY).is_empty());
+    }
+
+    #[test]
+    fn test_render_channels_yaml() {
+        let channels = vec![
+            scanned(ChannelType::GR, "27", &[(1024, "NHK"), (1025, "NHK2")]),
+            scanned(ChannelType::GR, "28", &[(1024, "NHK")]),
+            scanned(ChannelType::BS, "BS01_0", &[(101, "BS \"1\"")]),
+        ];
+        assert_eq!(render_channels_yaml(&channels), "\
+channels:
+  - name: \"NHK\"
+    type: GR
+    channel: \"27\"
+    # 1024: NHK
+    # 1025: NHK2
+  - name: \"NHK (GR28)\"
+    type: GR
+    channel: \"28\"
+    # 1024: NHK
+  - name: \"BS \\\"1\\\"\"
+    type: BS
+    channel: \"BS01_0\"
+    # 101: BS \"1\"
+");
+
+        let yaml = render_channels_yaml(&channels);
+        let config: crate::config::Config =
+            serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(config.channels.len(), 3);
+        assert_eq!(config.channels[2].name, "BS \"1\"");
+    }
+
+    fn scanned(
+        channel_type: ChannelType,
+        channel: &str,
+        services: &[(u16, &str)],
+    ) -> ScannedChannel {
+        let epg_channel = EpgChannel {
+            name: "".to_string(),
+            channel_type,
+            channel: channel.to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        };
+        ScannedChannel {
+            channel_type,
+            channel: channel.to_string(),
+            services: services
+                .iter()
+                .map(|(sid, name)| EpgService {
+                    nid: 1.into(),
+                    tsid: 2.into(),
+                    sid: (*sid).into(),
+                    service_type: 1,
+                    logo_id: 0,
+                    remote_control_key_id: 0,
+                    name: name.to_string(),
+                    channel: epg_channel.clone(),
+                    has_logo_data: false,
+                })
+                .collect(),
+        }
+    }
+}
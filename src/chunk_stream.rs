@@ -2,10 +2,13 @@ use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
-use bytes::{Bytes, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 use tokio::io::AsyncRead;
 use tokio::stream::Stream;
 
+use crate::mpeg_ts_packet::{TS_PACKET_SIZE, TS_SYNC_BYTE};
+use crate::splice::SpliceSink;
+
 // ChunkStream provides a stream of data chunks with a specific maximum size.
 //
 // There is a similar types in tokio like tokio_codec::FramedRead, but these
@@ -41,3 +44,211 @@ where
         }
     }
 }
+
+// MpegTsChunkStream provides a stream of chunks each of which contains only
+// whole TS packets.
+//
+// Bytes read from the reader are synchronized to the sync byte (0x47) of a TS
+// packet.  A position is regarded as the beginning of a TS packet when the sync
+// byte is found at the position and also at the position of the next packet.
+// Bytes outside TS packets are dropped.
+//
+// Subscribers of a broadcaster can safely drop any chunk emitted from this
+// stream without tearing TS packets.
+pub struct MpegTsChunkStream<R> {
+    reader: R,
+    chunk_size: usize,
+    buf: BytesMut,
+    synced: bool,
+    // Set only when the reader supports splicing.
+    splice_sink: Option<SpliceSink>,
+}
+
+impl<R> MpegTsChunkStream<R> {
+    // `chunk_size` is rounded down to a multiple of the TS packet size.
+    pub fn new(reader: R, chunk_size: usize) -> Self {
+        let chunk_size =
+            (chunk_size / TS_PACKET_SIZE).max(1) * TS_PACKET_SIZE;
+        MpegTsChunkStream {
+            reader,
+            chunk_size,
+            buf: BytesMut::with_capacity(chunk_size),
+            synced: false,
+            splice_sink: None,
+        }
+    }
+
+    pub fn set_splice_sink(&mut self, sink: SpliceSink) {
+        self.splice_sink = Some(sink);
+    }
+
+    fn take_chunk(&mut self) -> Option<Bytes> {
+        loop {
+            if !self.synced {
+                self.synced = self.resync();
+                if !self.synced {
+                    return None;
+                }
+            }
+
+            let num_packets = (self.buf.len() / TS_PACKET_SIZE)
+                .min(self.chunk_size / TS_PACKET_SIZE);
+            let buf = &self.buf;
+            let num_aligned = (0..num_packets)
+                .take_while(|i| buf[i * TS_PACKET_SIZE] == TS_SYNC_BYTE)
+                .count();
+            if num_aligned < num_packets {
+                log::warn!("Lost synchronization with TS packets");
+                self.synced = false;
+            }
+
+            if num_aligned > 0 {
+                let chunk = self.buf.split_to(num_aligned * TS_PACKET_SIZE);
+                return Some(chunk.freeze());
+            }
+
+            if self.synced {
+                return None;  // need more data
+            }
+        }
+    }
+
+    // Drops bytes until the beginning of a TS packet is found.
+    fn resync(&mut self) -> bool {
+        let buf = &self.buf;
+        let pos = (0..buf.len().saturating_sub(TS_PACKET_SIZE))
+            .find(|&i| {
+                buf[i] == TS_SYNC_BYTE &&
+                    buf[i + TS_PACKET_SIZE] == TS_SYNC_BYTE
+            });
+        match pos {
+            Some(pos) => {
+                if pos > 0 {
+                    log::warn!("Dropped {} bytes for synchronization", pos);
+                }
+                self.buf.advance(pos);
+                true
+            }
+            None => {
+                // Keep the last bytes which may contain the beginning of a TS
+                // packet.
+                let n = buf.len().saturating_sub(TS_PACKET_SIZE);
+                if n > 0 {
+                    log::warn!("Dropped {} bytes for synchronization", n);
+                    self.buf.advance(n);
+                }
+                false
+            }
+        }
+    }
+}
+
+impl<R> Stream for MpegTsChunkStream<R>
+where
+    R: AsyncRead + Unpin
+{
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context
+    ) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunk) = this.take_chunk() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+
+            if let Some(ref sink) = this.splice_sink {
+                // Bytes of a partial TS packet are handed over to the filter
+                // pipeline spliced to the reader.  Data from the reader is
+                // resynchronized after splicing.
+                if sink.hand_over(&mut this.buf, this.synced) {
+                    this.synced = false;
+                }
+            }
+
+            let additional = this.chunk_size
+                .saturating_sub(this.buf.len())
+                .max(TS_PACKET_SIZE);
+            this.buf.reserve(additional);
+
+            match Pin::new(&mut this.reader).poll_read_buf(cx, &mut this.buf) {
+                Poll::Ready(Ok(0)) => {
+                    if !this.buf.is_empty() {
+                        log::debug!("Dropped {} bytes at EOF", this.buf.len());
+                        this.buf.clear();
+                    }
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::stream::StreamExt;
+    use tokio_test::io::Builder;
+
+    fn packet(id: u8) -> Vec<u8> {
+        let mut packet = vec![id; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_mpeg_ts_chunk_stream() {
+        let data = [packet(1), packet(2), packet(3)].concat();
+        let reader = Builder::new()
+            .read(&data[..100])
+            .read(&data[100..400])
+            .read(&data[400..])
+            .build();
+        let mut stream = MpegTsChunkStream::new(reader, TS_PACKET_SIZE * 8);
+
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], &data[..TS_PACKET_SIZE * 2]);
+
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], &data[TS_PACKET_SIZE * 2..]);
+
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mpeg_ts_chunk_stream_sync() {
+        let data = [
+            vec![0x47, 0, 0], packet(1), packet(2), vec![0], packet(3),
+            packet(4), vec![0; 10],
+        ].concat();
+        let reader = Builder::new().read(&data).build();
+        let mut stream = MpegTsChunkStream::new(reader, TS_PACKET_SIZE * 8);
+
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], &[packet(1), packet(2)].concat()[..]);
+
+        let chunk = stream.next().await.unwrap().unwrap();
+        assert_eq!(&chunk[..], &[packet(3), packet(4)].concat()[..]);
+
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_mpeg_ts_chunk_stream_chunk_size() {
+        let data = [packet(1), packet(2), packet(3)].concat();
+        let reader = Builder::new().read(&data).build();
+        let mut stream = MpegTsChunkStream::new(reader, TS_PACKET_SIZE + 1);
+
+        for i in 1..=3 {
+            let chunk = stream.next().await.unwrap().unwrap();
+            assert_eq!(&chunk[..], &packet(i)[..]);
+        }
+
+        assert!(stream.next().await.is_none());
+    }
+}
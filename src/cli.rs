@@ -0,0 +1,462 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::AsyncWriteExt;
+
+use crate::channel_scanner::{self, ChannelScanner};
+use crate::command_util;
+use crate::config::{self, Config};
+use crate::eit_feeder;
+use crate::epg::{self, EpgChannel, EpgProgram};
+use crate::error::Error;
+use crate::models::*;
+use crate::service_scanner::ServiceScanner;
+use crate::tuner;
+use crate::web;
+
+// Subcommands for offline operations.  They reuse the components of the
+// server, but don't start the web server.
+
+pub fn subcommands() -> Vec<clap::App<'static, 'static>> {
+    vec![
+        clap::SubCommand::with_name("check-config")
+            .about("Check a configuration file and exit")
+            .long_about(
+                "Check a configuration file and exit.\n\
+                 \n\
+                 All errors and warnings found are printed.  The exit status \
+                 is 1 if there are errors."),
+        clap::SubCommand::with_name("scan-channels")
+            .about("Scan channels and output a channels section")
+            .long_about(
+                "Scan channels and output a channels section.\n\
+                 \n\
+                 Physical channels of the specified types are scanned with \
+                 the tuners and the command of the scan-services job defined \
+                 in the configuration file.  Channels having services are \
+                 output in the YAML format.")
+            .arg(clap::Arg::with_name("types")
+                 .short("t")
+                 .long("types")
+                 .takes_value(true)
+                 .multiple(true)
+                 .use_delimiter(true)
+                 .possible_values(&["GR", "BS", "CS"])
+                 .value_name("TYPE")
+                 .help("Channel types to scan [default: all types supported \
+                        by the tuners]"))
+            .arg(clap::Arg::with_name("timeout")
+                 .long("timeout")
+                 .takes_value(true)
+                 .default_value("10")
+                 .value_name("SECS")
+                 .validator(validate_scan_timeout)
+                 .help("Timeout for scanning a channel"))
+            .arg(clap::Arg::with_name("output")
+                 .short("o")
+                 .long("output")
+                 .takes_value(true)
+                 .value_name("FILE")
+                 .help("Write the result to a file instead of stdout")),
+        clap::SubCommand::with_name("scan-services")
+            .about("Scan services in the channels and output them in JSON"),
+        clap::SubCommand::with_name("collect-eits")
+            .about("Collect EIT sections and update the EPG cache once")
+            .long_about(
+                "Collect EIT sections and update the EPG cache once.\n\
+                 \n\
+                 Services are scanned before collecting EIT sections if no \
+                 service is cached.  epg.cache-dir must be specified in the \
+                 configuration file."),
+        clap::SubCommand::with_name("epg")
+            .about("Operations for the EPG cache")
+            .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(clap::SubCommand::with_name("dump")
+                        .about("Output programs in schedules.json")
+                        .arg(clap::Arg::with_name("format")
+                             .long("format")
+                             .takes_value(true)
+                             .possible_values(&["json", "csv"])
+                             .default_value("json")
+                             .value_name("FORMAT")
+                             .help("Output format"))),
+        clap::SubCommand::with_name("stream")
+            .about("Output a TS stream from a tuner to stdout")
+            .long_about(
+                "Output a TS stream from a tuner to stdout.\n\
+                 \n\
+                 The channel has to be defined in the configuration file.  \
+                 Filters are applied in the same way as the web API.")
+            .arg(clap::Arg::with_name("channel")
+                 .long("channel")
+                 .takes_value(true)
+                 .required(true)
+                 .value_name("TYPE/CHANNEL")
+                 .validator(validate_channel)
+                 .help("Channel to stream like GR/27"))
+            .arg(clap::Arg::with_name("sid")
+                 .long("sid")
+                 .takes_value(true)
+                 .value_name("SID")
+                 .validator(validate_sid)
+                 .help("Service ID to be extracted with the service filter"))
+            .arg(clap::Arg::with_name("duration")
+                 .long("duration")
+                 .takes_value(true)
+                 .value_name("DURATION")
+                 .validator(validate_duration)
+                 .help("Duration like 30s or 1h [default: until the stream \
+                        ends]"))
+            .arg(clap::Arg::with_name("pre-filter")
+                 .long("pre-filter")
+                 .help("Apply filters.pre-filter"))
+            .arg(clap::Arg::with_name("post-filter")
+                 .long("post-filter")
+                 .help("Apply filters.post-filter")),
+    ]
+}
+
+// Returns `None` if no subcommand is specified.
+pub async fn run(
+    config_path: &str,
+    args: &clap::ArgMatches<'_>,
+) -> Option<Result<(), Error>> {
+    let result = match args.subcommand() {
+        ("check-config", _) => check_config(config_path),
+        ("scan-channels", Some(args)) =>
+            scan_channels(config::load(config_path), args).await,
+        ("scan-services", _) => scan_services(config::load(config_path)).await,
+        ("collect-eits", _) => collect_eits(config::load(config_path)).await,
+        ("epg", Some(args)) => match args.subcommand() {
+            ("dump", Some(args)) => dump_epg(config::load(config_path), args),
+            _ => unreachable!(),
+        },
+        ("stream", Some(args)) =>
+            stream(config::load(config_path), args).await,
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn check_config(config_path: &str) -> ! {
+    let result = match config::check(config_path) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}: {}", config_path, err);
+            std::process::exit(1);
+        }
+    };
+    for warning in result.warnings.iter() {
+        eprintln!("{}: warning: {}", config_path, warning);
+    }
+    for error in result.errors.iter() {
+        eprintln!("{}: error: {}", config_path, error);
+    }
+    if !result.errors.is_empty() {
+        std::process::exit(1);
+    }
+    println!("{}: OK", config_path);
+    std::process::exit(0);
+}
+
+async fn scan_channels(
+    config: Arc<Config>,
+    args: &clap::ArgMatches<'_>,
+) -> Result<(), Error> {
+    let channel_types = match args.values_of("types") {
+        Some(types) => types
+            .map(|channel_type| parse_channel_type(channel_type).unwrap())
+            .collect(),
+        None => channel_scanner::supported_channel_types(&config),
+    };
+    // Values have been validated by clap.
+    let timeout = args.value_of("timeout").unwrap().parse::<u64>().unwrap();
+
+    tuner::start(config.clone());
+
+    let scanner = ChannelScanner::new(
+        config.jobs.scan_services.command.clone(),
+        config.jobs.scan_services.process.clone(),
+        channel_types,
+        Duration::from_secs(timeout));
+    let channels = scanner.scan_channels().await?;
+    let yaml = channel_scanner::render_channels_yaml(&channels);
+
+    match args.value_of("output") {
+        Some(path) => std::fs::write(path, yaml)?,
+        None => print!("{}", yaml),
+    }
+    Ok(())
+}
+
+async fn scan_services(config: Arc<Config>) -> Result<(), Error> {
+    tuner::start(config.clone());
+    // Cached services are used for detecting relocations.
+    epg::start_offline(config.clone());
+
+    let (services, _) = new_service_scanner(&config).scan_services().await?;
+    println!("{}", serde_json::to_string_pretty(&services)?);
+    Ok(())
+}
+
+async fn collect_eits(config: Arc<Config>) -> Result<(), Error> {
+    if config.epg.cache_dir.is_none() {
+        return Err(Error::InvalidConfig(
+            "epg.cache-dir must be specified".to_string()));
+    }
+
+    tuner::start(config.clone());
+    epg::start_offline(config.clone());
+    eit_feeder::start(config.clone());
+
+    if epg::query_services().await?.is_empty() {
+        log::info!("No service cached, scan services");
+        let (services, _) =
+            new_service_scanner(&config).scan_services().await?;
+        epg::update_services(services);
+    }
+
+    eit_feeder::feed_eit_sections().await?;
+    epg::save().await
+}
+
+fn new_service_scanner(config: &Config) -> ServiceScanner {
+    let channels = config.channels
+        .iter()
+        .filter(|channel| !channel.disabled)
+        .cloned()
+        .map(EpgChannel::from)
+        .collect();
+    ServiceScanner::new(
+        config.jobs.scan_services.command.clone(),
+        config.jobs.scan_services.process.clone(),
+        channels,
+        config.epg.follow_relocations)
+}
+
+fn dump_epg(
+    config: Arc<Config>,
+    args: &clap::ArgMatches<'_>,
+) -> Result<(), Error> {
+    let programs = epg::load_programs(config)?;
+    match args.value_of("format") {
+        Some("csv") => print!("{}", render_programs_csv(&programs)),
+        _ => {
+            let programs: Vec<MirakurunProgram> = programs
+                .into_iter()
+                .map(MirakurunProgram::from)
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&programs)?);
+        }
+    }
+    Ok(())
+}
+
+fn render_programs_csv(programs: &[EpgProgram]) -> String {
+    let mut csv = String::from(
+        "networkId,serviceId,eventId,startAt,duration,name\n");
+    for program in programs.iter() {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            program.quad.nid().value(),
+            program.quad.sid().value(),
+            program.quad.eid().value(),
+            program.start_at.to_rfc3339(),
+            program.duration.num_seconds(),
+            escape_csv(program.name.as_deref().unwrap_or(""))));
+    }
+    csv
+}
+
+fn escape_csv(s: &str) -> String {
+    if s.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+async fn stream(
+    config: Arc<Config>,
+    args: &clap::ArgMatches<'_>,
+) -> Result<(), Error> {
+    // Values have been validated by clap.
+    let (channel_type, channel) =
+        parse_channel(args.value_of("channel").unwrap()).unwrap();
+    let sid = args.value_of("sid")
+        .map(|sid| ServiceId::from(sid.parse::<u16>().unwrap()));
+    let duration = args.value_of("duration")
+        .map(|duration| humantime::parse_duration(duration).unwrap());
+
+    let channel: EpgChannel = config.channels
+        .iter()
+        .find(|config| {
+            !config.disabled && config.channel_type == channel_type &&
+                config.channel == channel
+        })
+        .cloned()
+        .map(EpgChannel::from)
+        .ok_or(Error::ChannelNotFound)?;
+
+    let mut filters = Vec::new();
+    let pre_filter = &config.filters.pre_filter;
+    if args.is_present("pre-filter") && !pre_filter.is_empty() {
+        filters.push(web::make_filter_command(
+            pre_filter, &channel, sid, None)?);
+    }
+    if let Some(sid) = sid {
+        filters.push(web::make_service_filter_command(
+            &config.filters.service_filter, sid)?);
+    }
+    let post_filter = &config.filters.post_filter;
+    if args.is_present("post-filter") && !post_filter.is_empty() {
+        filters.push(web::make_filter_command(
+            post_filter, &channel, sid, None)?);
+    }
+    filters.retain(|filter| !filter.is_empty());
+
+    tuner::start(config.clone());
+
+    let user = TunerUser {
+        info: TunerUserInfo::Job { name: "stream".to_string() },
+        priority: 0.into(),
+    };
+    let stream = tuner::start_streaming(
+        channel.channel_type, channel.channel.clone(), user, None,
+        None).await?;
+
+    let mut stdout = tokio::io::stdout();
+    let copy = async {
+        if filters.is_empty() {
+            stream.pipe(&mut stdout).await;
+        } else {
+            let (input, mut output) = command_util::spawn_pipeline(
+                filters, stream.id(), &config.filters.process)?;
+            tokio::spawn(stream.pipe(input));
+            tokio::io::copy(&mut output, &mut stdout).await?;
+        }
+        Ok::<(), Error>(())
+    };
+    match duration {
+        Some(duration) => match tokio::time::timeout(duration, copy).await {
+            Ok(result) => result?,
+            Err(_) => log::info!("Stopped after {}",
+                                 humantime::format_duration(duration)),
+        },
+        None => copy.await?,
+    }
+    stdout.flush().await?;
+    Ok(())
+}
+
+fn parse_channel_type(s: &str) -> Option<ChannelType> {
+    match s {
+        "GR" => Some(ChannelType::GR),
+        "BS" => Some(ChannelType::BS),
+        "CS" => Some(ChannelType::CS),
+        "SKY" => Some(ChannelType::SKY),
+        _ => None,
+    }
+}
+
+// Parses a channel like `GR/27`.
+fn parse_channel(s: &str) -> Option<(ChannelType, String)> {
+    let pos = s.find('/')?;
+    let channel_type = parse_channel_type(&s[..pos])?;
+    let channel = &s[pos + 1..];
+    if channel.is_empty() {
+        return None;
+    }
+    Some((channel_type, channel.to_string()))
+}
+
+// Validators for command-line arguments.  clap prints the error message and
+// exits the process if validation fails.
+
+fn validate_scan_timeout(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(timeout) if timeout > 0 &&
+            timeout <= ChannelScanner::MAX_TIMEOUT_SECS => Ok(()),
+        _ => Err(format!("must be a number of seconds in 1..={}",
+                         ChannelScanner::MAX_TIMEOUT_SECS)),
+    }
+}
+
+fn validate_channel(s: String) -> Result<(), String> {
+    match parse_channel(&s) {
+        Some(_) => Ok(()),
+        None => Err("must be TYPE/CHANNEL like GR/27".to_string()),
+    }
+}
+
+fn validate_sid(s: String) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err("must be a number in 0..=65535".to_string()),
+    }
+}
+
+fn validate_duration(s: String) -> Result<(), String> {
+    let duration = humantime::parse_duration(&s)
+        .map_err(|err| format!("must be a duration like 30s: {}", err))?;
+    // Large values overflow when added to the current time.
+    if std::time::Instant::now().checked_add(duration).is_none() {
+        return Err("too large".to_string());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::datetime_ext::Jst;
+
+    #[test]
+    fn test_parse_channel() {
+        assert_eq!(parse_channel("GR/27"),
+                   Some((ChannelType::GR, "27".to_string())));
+        assert_eq!(parse_channel("BS/BS01_0"),
+                   Some((ChannelType::BS, "BS01_0".to_string())));
+        assert_eq!(parse_channel("GR/"), None);
+        assert_eq!(parse_channel("GR27"), None);
+        assert_eq!(parse_channel("XX/27"), None);
+    }
+
+    #[test]
+    fn test_validators() {
+        assert!(validate_scan_timeout("10".to_string()).is_ok());
+        assert!(validate_scan_timeout("0".to_string()).is_err());
+        assert!(validate_scan_timeout("3601".to_string()).is_err());
+        assert!(validate_scan_timeout("x".to_string()).is_err());
+        assert!(validate_channel("GR/27".to_string()).is_ok());
+        assert!(validate_channel("GR27".to_string()).is_err());
+        assert!(validate_sid("1024".to_string()).is_ok());
+        assert!(validate_sid("65536".to_string()).is_err());
+        assert!(validate_duration("30s".to_string()).is_ok());
+        assert!(validate_duration("30".to_string()).is_err());
+        assert!(validate_duration(
+            "18446744073709551615s".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_render_programs_csv() {
+        let mut program = EpgProgram::new(
+            (1, 2, 3, 4).into());
+        program.start_at = Jst.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        program.duration = chrono::Duration::minutes(30);
+        program.name = Some("a, \"b\"".to_string());
+        assert_eq!(render_programs_csv(&[program]), "\
+networkId,serviceId,eventId,startAt,duration,name
+1,3,4,2020-01-01T00:00:00+09:00,1800,\"a, \"\"b\"\"\"
+");
+    }
+
+    #[test]
+    fn test_escape_csv() {
+        assert_eq!(escape_csv("abc"), "abc");
+        assert_eq!(escape_csv("a,b"), "\"a,b\"");
+        assert_eq!(escape_csv("a\"b"), "\"a\"\"b\"");
+        assert_eq!(escape_csv("a\nb"), "\"a\nb\"");
+    }
+}
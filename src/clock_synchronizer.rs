@@ -6,6 +6,7 @@ use serde_json;
 use tokio::io::AsyncReadExt;
 
 use crate::command_util;
+use crate::config::ProcessConfig;
 use crate::epg::*;
 use crate::error::Error;
 use crate::models::*;
@@ -13,6 +14,7 @@ use crate::tuner;
 
 pub struct ClockSynchronizer {
     command: String,
+    process: ProcessConfig,
     channels: Vec<EpgChannel>,
 }
 
@@ -24,9 +26,10 @@ impl ClockSynchronizer {
 
     pub fn new(
         command: String,
-        channels: Vec<EpgChannel>
+        process: ProcessConfig,
+        channels: Vec<EpgChannel>,
     ) -> Self {
-        ClockSynchronizer { command, channels }
+        ClockSynchronizer { command, process, channels }
     }
 
     pub async fn sync_clocks(
@@ -37,7 +40,7 @@ impl ClockSynchronizer {
         let mut clocks = Vec::new();
         for channel in self.channels.iter() {
             clocks.append(&mut Self::sync_clocks_in_channel(
-                &channel, &self.command).await?);
+                &channel, &self.command, &self.process).await?);
         }
 
         let mut map = HashMap::new();
@@ -55,6 +58,7 @@ impl ClockSynchronizer {
     async fn sync_clocks_in_channel(
         channel: &EpgChannel,
         command: &str,
+        process: &ProcessConfig,
     ) -> Result<Vec<SyncClock>, Error> {
         log::debug!("Synchronizing clocks in {}...", channel.name);
 
@@ -64,7 +68,8 @@ impl ClockSynchronizer {
         };
 
         let stream = tuner::start_streaming(
-            channel.channel_type, channel.channel.clone(), user).await?;
+            channel.channel_type, channel.channel.clone(), user, None,
+            None).await?;
 
         let template = mustache::compile_str(command)?;
         let data = mustache::MapBuilder::new()
@@ -74,7 +79,7 @@ impl ClockSynchronizer {
         let cmd = template.render_data_to_string(&data)?;
 
         let (input, mut output) = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+            vec![cmd], stream.id(), process)?;
 
         let handle = tokio::spawn(stream.pipe(input));
 
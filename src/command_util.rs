@@ -1,21 +1,30 @@
-use std::env;
+use std::collections::VecDeque;
+use std::fmt;
 use std::io;
+use std::io::Read;
 use std::future::Future;
-use std::os::unix::io::AsRawFd;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::CommandExt;
 use std::pin::Pin;
-use std::process::{Command, Child, ChildStdin, ChildStdout, Stdio};
+use std::process::{
+    Command, Child, ChildStderr, ChildStdin, ChildStdout, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
 use std::task::{Poll, Context};
+use std::thread;
 
 use failure::Fail;
 use tokio::prelude::*;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::config::ProcessConfig;
 use crate::tokio_snippet;
 use crate::tuner::TunerSubscriptionId as CommandPipelineId;
 
 pub fn spawn_process(
     command: &str,
     input: Stdio,
+    config: &ProcessConfig,
 ) -> Result<Child, Error> {
     let words = match shell_words::split(command) {
         Ok(words) => words,
@@ -23,26 +32,326 @@ pub fn spawn_process(
     };
     let words: Vec<&str> = words.iter().map(|word| &word[..]).collect();
     let (prog, args) = words.split_first().unwrap();
-    let stderr = match env::var_os("MIRAKC_DEBUG_CHILD_PROCESS") {
-        Some(_) => Stdio::inherit(),
-        None => Stdio::null(),
-    };
-    Command::new(prog)
-        .args(args)
+    // The stderr must be read by `capture_stderr()`.  Otherwise, the child
+    // process will be blocked when the pipe becomes full.
+    let mut cmd = Command::new(prog);
+    cmd.args(args)
         .stdin(input)
         .stdout(Stdio::piped())
-        .stderr(stderr)
-        .spawn()
+        .stderr(Stdio::piped())
+        .envs(config.env.iter());
+    apply_process_config(&mut cmd, config);
+    cmd.spawn()
         .map_err(|err| Error::UnableToSpawn(command.to_string(), err))
 }
 
+fn apply_process_config(cmd: &mut Command, config: &ProcessConfig) {
+    if let Some(ref dir) = config.working_dir {
+        cmd.current_dir(dir);
+    }
+    // Supplementary groups are cleared by `Command` when dropping the root
+    // privileges.
+    if let Some(gid) = config.gid {
+        cmd.gid(gid);
+    }
+    if let Some(uid) = config.uid {
+        cmd.uid(uid);
+    }
+
+    let nice = config.nice;
+    let cpu_limit = config.cpu_limit;
+    let memory_limit = config.memory_limit;
+    if nice.is_none() && cpu_limit.is_none() && memory_limit.is_none() {
+        return;
+    }
+    // The following closure is called in the child process after changing
+    // the user and the group.  So, only an unprivileged operation like
+    // increasing the niceness or lowering limits is allowed unless the child
+    // process runs as root.
+    //
+    // Only async-signal-safe functions can be called in the closure.
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(nice) = nice {
+                // The niceness is relative to the current one.
+                let current = get_priority()?;
+                if libc::setpriority(
+                    libc::PRIO_PROCESS as _, 0, current + nice) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(secs) = cpu_limit {
+                let rlim = make_rlimit(secs);
+                if libc::setrlimit(libc::RLIMIT_CPU, &rlim) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(bytes) = memory_limit {
+                let rlim = make_rlimit(bytes);
+                if libc::setrlimit(libc::RLIMIT_AS, &rlim) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+// getpriority(2) can legitimately return -1.  So, errno has to be cleared
+// before calling it in order to detect an error.
+unsafe fn get_priority() -> io::Result<libc::c_int> {
+    set_errno(0);
+    let priority = libc::getpriority(libc::PRIO_PROCESS as _, 0);
+    if priority == -1 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(0) {
+            return Err(err);
+        }
+    }
+    Ok(priority)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn set_errno(value: libc::c_int) {
+    *libc::__errno_location() = value;
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn set_errno(value: libc::c_int) {
+    *libc::__error() = value;
+}
+
+fn make_rlimit(limit: u64) -> libc::rlimit {
+    libc::rlimit {
+        rlim_cur: limit as libc::rlim_t,
+        rlim_max: limit as libc::rlim_t,
+    }
+}
+
+// Reads lines from the stderr of a child process in a dedicated thread, and
+// logs them at the info level with `context` and the name of the command.
+// Child processes write to stderr only for errors and important messages in
+// most cases, so the lines are visible with the default log level.
+//
+// Lines are also kept in `stderr_log` if specified.
+pub fn capture_stderr<T>(
+    process: &mut Child,
+    context: T,
+    command: &str,
+    stderr_log: Option<StderrLog>,
+)
+where
+    T: fmt::Display,
+{
+    let stderr = match process.stderr.take() {
+        Some(stderr) => stderr,
+        None => return,
+    };
+    let prog = command.split_whitespace().next().unwrap_or_default();
+    let label = format!("{}: {}[{}]", context, prog, process.id());
+    let result = thread::Builder::new()
+        .name(format!("stderr-{}", process.id()))
+        .spawn(move || read_stderr(stderr, label, stderr_log));
+    if let Err(err) = result {
+        log::error!("{}: Failed to spawn a thread for reading stderr: {}",
+                    context, err);
+    }
+}
+
+fn read_stderr(
+    mut stderr: ChildStderr,
+    label: String,
+    stderr_log: Option<StderrLog>,
+) {
+    // Lines longer than this are split.
+    const MAX_LINE_SIZE: usize = 1024;
+
+    let emit = |line: &mut Vec<u8>| {
+        if line.is_empty() {
+            return;
+        }
+        let text = String::from_utf8_lossy(line).into_owned();
+        log::info!("{}: {}", label, text);
+        if let Some(ref stderr_log) = stderr_log {
+            stderr_log.push(text);
+        }
+        line.clear();
+    };
+
+    let mut buf = [0; 4096];
+    let mut line = Vec::with_capacity(MAX_LINE_SIZE);
+    loop {
+        let n = match stderr.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted =>
+                continue,
+            Err(_) => break,
+        };
+        // Progress messages are often terminated by CR.
+        for &byte in buf[..n].iter() {
+            match byte {
+                b'\n' | b'\r' => emit(&mut line),
+                _ => {
+                    line.push(byte);
+                    if line.len() >= MAX_LINE_SIZE {
+                        emit(&mut line);
+                    }
+                }
+            }
+        }
+    }
+    emit(&mut line);
+}
+
+// Keeps the last lines written to stderr by child processes.
+#[derive(Clone, Default)]
+pub struct StderrLog(Arc<Mutex<VecDeque<String>>>);
+
+impl StderrLog {
+    const MAX_LINES: usize = 100;
+
+    pub fn lines(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().unwrap();
+        if lines.len() >= Self::MAX_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+// A child process whose exit is monitored by a dedicated thread.
+//
+// `Child::wait()` blocks the thread until the process exits, and
+// `Child::kill()` cannot be called while another thread is waiting for the
+// exit.  So, the monitoring thread waits for the exit without reaping the
+// process by using `waitid(WNOWAIT)`, and the process is killed with its PID
+// before it's reaped.  This guarantees that the PID is never reused while we
+// can kill the process.
+pub struct MonitoredProcess {
+    pid: u32,
+    state: Arc<Mutex<ProcessState>>,
+}
+
+#[derive(Default)]
+struct ProcessState {
+    exited: bool,
+    killed: bool,
+}
+
+// Information about the exit of a process.
+#[derive(Clone, Debug)]
+pub struct ProcessExit {
+    pub pid: u32,
+    pub command: String,
+    pub status: ExitStatus,
+    // `true` if the process was killed by `MonitoredProcess::kill()`.
+    pub killed: bool,
+}
+
+impl ProcessExit {
+    pub fn is_expected(&self) -> bool {
+        self.killed || self.status.success()
+    }
+}
+
+impl MonitoredProcess {
+    // `on_exit` is called on the monitoring thread when the process exits.
+    pub fn new<T, F>(
+        process: Child,
+        context: T,
+        command: &str,
+        on_exit: F,
+    ) -> Self
+    where
+        T: fmt::Display,
+        F: FnOnce(ProcessExit) + Send + 'static,
+    {
+        let pid = process.id();
+        let state: Arc<Mutex<ProcessState>> = Default::default();
+        let prog = command.split_whitespace().next().unwrap_or_default();
+        let label = format!("{}: {}[{}]", context, prog, pid);
+        let command = command.to_string();
+        let thread_state = state.clone();
+        let result = thread::Builder::new()
+            .name(format!("wait-{}", pid))
+            .spawn(move || {
+                let status = match Self::wait(process, &thread_state) {
+                    Ok(status) => status,
+                    Err(err) => {
+                        log::error!("{}: Failed to wait: {}", label, err);
+                        return;
+                    }
+                };
+                let killed = thread_state.lock().unwrap().killed;
+                if killed {
+                    log::debug!("{}: Killed", label);
+                } else {
+                    log::debug!("{}: Exited with {}", label, status);
+                }
+                on_exit(ProcessExit { pid, command, status, killed });
+            });
+        if let Err(err) = result {
+            log::error!("{}: Failed to spawn a thread for waiting: {}",
+                        context, err);
+        }
+        MonitoredProcess { pid, state }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn kill(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.exited {
+            return;
+        }
+        state.killed = true;
+        unsafe {
+            libc::kill(self.pid as libc::pid_t, libc::SIGKILL);
+        }
+    }
+
+    fn wait(
+        mut process: Child,
+        state: &Mutex<ProcessState>,
+    ) -> io::Result<ExitStatus> {
+        loop {
+            let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+            let ret = unsafe {
+                libc::waitid(libc::P_PID, process.id() as libc::id_t,
+                             &mut info, libc::WEXITED | libc::WNOWAIT)
+            };
+            if ret == 0 {
+                break;
+            }
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        // The process is a zombie at this point.  Mark it as exited before
+        // reaping it so that `kill()` never sends a signal to a reused PID.
+        state.lock().unwrap().exited = true;
+        process.wait()
+    }
+}
+
 // Spawn processes for input commands and build a pipeline, then returns
 // endpoints of the pipeline.
+//
+// `config` is applied to all processes in the pipeline.
 pub fn spawn_pipeline(
     commands: Vec<String>,
     id: CommandPipelineId,
+    config: &ProcessConfig,
 ) -> Result<CommandPipelineIoPair, Error> {
-    let mut builder = CommandPipelineBuilder::new(id);
+    let mut builder = CommandPipelineBuilder::new(id, config);
     for command in commands.into_iter() {
         builder.spawn(command)?;
     }
@@ -66,20 +375,25 @@ type CommandPipelineIoPair = (CommandPipelineInput, CommandPipelineOutput);
 
 // pipeline builder
 
-struct CommandPipelineBuilder {
+struct CommandPipelineBuilder<'a> {
     id: CommandPipelineId,
+    config: &'a ProcessConfig,
     stdin: Option<ChildStdin>,
     stdout: Option<ChildStdout>,
     commands: Vec<CommandData>,
+    exits: (mpsc::UnboundedSender<ProcessExit>,
+            mpsc::UnboundedReceiver<ProcessExit>),
 }
 
-impl CommandPipelineBuilder {
-    pub fn new(id: CommandPipelineId) -> Self {
+impl<'a> CommandPipelineBuilder<'a> {
+    pub fn new(id: CommandPipelineId, config: &'a ProcessConfig) -> Self {
         CommandPipelineBuilder {
             id,
+            config,
             stdin: None,
             stdout: None,
             commands: Vec::new(),
+            exits: mpsc::unbounded_channel(),
         }
     }
 
@@ -90,14 +404,26 @@ impl CommandPipelineBuilder {
             Stdio::from(self.stdout.take().unwrap())
         };
 
-        let mut process = spawn_process(&command, input)?;
+        let mut process = spawn_process(&command, input, self.config)?;
         log::debug!("{}: Spawned {}: `{}`",
                     self.id, process.id(), command);
+        capture_stderr(&mut process, self.id, &command, None);
 
         if self.stdin.is_none() {
             self.stdin = process.stdin.take();
         }
         self.stdout = process.stdout.take();
+
+        let id = self.id;
+        let exits = self.exits.0.clone();
+        let process = MonitoredProcess::new(
+            process, id, &command, move |exit| {
+                if !exit.is_expected() {
+                    log::error!("{}: `{}` exited with {}",
+                                id, exit.command, exit.status);
+                }
+                let _ = exits.send(exit);
+            });
         self.commands.push(CommandData{ command, process });
 
         Ok(())
@@ -109,7 +435,8 @@ impl CommandPipelineBuilder {
         let input = CommandPipelineInput::new(
             Self::make_childio_async(self.stdin.take())?, pipeline.id, broken);
         let output = CommandPipelineOutput::new(
-            Self::make_childio_async(self.stdout.take())?, pipeline);
+            Self::make_childio_async(self.stdout.take())?, pipeline,
+            self.exits.1);
         Ok((input, output))
     }
 
@@ -133,7 +460,7 @@ struct CommandPipeline {
 
 struct CommandData {
     command: String,
-    process: Child,
+    process: MonitoredProcess,
 }
 
 impl CommandPipeline {
@@ -150,10 +477,10 @@ impl CommandPipeline {
 
 impl Drop for CommandPipeline {
     fn drop(&mut self) {
-        for data in self.commands.iter_mut() {
-            let _ = data.process.kill();
-            let _ = data.process.wait();
-            log::debug!("{}: Killed {}: `{}`",
+        // The processes are reaped by the monitoring threads.
+        for data in self.commands.iter() {
+            data.process.kill();
+            log::debug!("{}: Kill {}: `{}`",
                        self.id, data.process.id(), data.command);
         }
 
@@ -184,6 +511,21 @@ impl CommandPipelineInput {
     ) -> Self {
         Self { inner, pipeline_id, pipeline_broken }
     }
+
+    // Used for writing data to the pipe without `poll_write()`.
+    pub fn poll_write_ready(&self, cx: &mut Context) -> Poll<io::Result<()>> {
+        self.inner.poll_write_ready(cx).map_ok(|_| ())
+    }
+
+    pub fn clear_write_ready(&self, cx: &mut Context) -> io::Result<()> {
+        self.inner.clear_write_ready(cx)
+    }
+}
+
+impl AsRawFd for CommandPipelineInput {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.get_ref().as_raw_fd()
+    }
 }
 
 impl AsyncWrite for CommandPipelineInput {
@@ -238,15 +580,29 @@ impl AsyncWrite for CommandPipelineInput {
 
 pub struct CommandPipelineOutput {
     inner: tokio_snippet::ChildIo<ChildStdout>,
-    _pipeline: CommandPipeline,
+    pipeline: CommandPipeline,
+    exits: mpsc::UnboundedReceiver<ProcessExit>,
 }
 
 impl CommandPipelineOutput {
     fn new(
         inner: tokio_snippet::ChildIo<ChildStdout>,
         pipeline: CommandPipeline,
+        exits: mpsc::UnboundedReceiver<ProcessExit>,
     ) -> Self {
-        Self { inner, _pipeline: pipeline }
+        Self { inner, pipeline, exits }
+    }
+
+    // Returns an error if a command in the pipeline exited unexpectedly.
+    fn poll_exits(&mut self, cx: &mut Context) -> io::Result<()> {
+        while let Poll::Ready(Some(exit)) = self.exits.poll_recv(cx) {
+            if !exit.is_expected() {
+                return Err(io::Error::other(format!(
+                    "{}: `{}` exited with {}",
+                    self.pipeline.id, exit.command, exit.status)));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -256,7 +612,16 @@ impl AsyncRead for CommandPipelineOutput {
         cx: &mut Context,
         buf: &mut [u8]
     ) -> Poll<io::Result<usize>> {
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        // Data remaining in the pipe is read before reporting the failure of
+        // a command.
+        match Pin::new(&mut self.inner).poll_read(cx, buf) {
+            Poll::Ready(Ok(n)) if n > 0 => Poll::Ready(Ok(n)),
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            poll => match self.poll_exits(cx) {
+                Ok(_) => poll,
+                Err(err) => Poll::Ready(Err(err)),
+            },
+        }
     }
 }
 
@@ -267,27 +632,123 @@ mod tests {
 
     #[test]
     fn test_spawn_process() {
-        let result = spawn_process("sh -c 'exit 0;'", Stdio::null());
+        let result = spawn_process(
+            "sh -c 'exit 0;'", Stdio::null(), &Default::default());
         assert!(result.is_ok());
         let _ = result.unwrap().wait();
 
-        let result = spawn_process("'", Stdio::null());
+        let result = spawn_process(
+            "'", Stdio::null(), &Default::default());
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().to_string(), r#"Unable to parse: '"#);
 
-        let result = spawn_process("command-not-found", Stdio::null());
+        let result = spawn_process(
+            "command-not-found", Stdio::null(), &Default::default());
         assert!(result.is_err());
         assert_matches!(result.unwrap_err(),
                         Error::UnableToSpawn(_, io::Error {..}));
     }
 
+    #[test]
+    fn test_spawn_process_with_config() {
+        let mut env = std::collections::HashMap::new();
+        env.insert("MIRAKC_TEST".to_string(), "env".to_string());
+        let config = ProcessConfig {
+            nice: Some(1),
+            working_dir: Some("/".to_string()),
+            env,
+            ..Default::default()
+        };
+        let mut process = spawn_process(
+            r#"sh -c 'echo $MIRAKC_TEST $(pwd) $(nice)'"#,
+            Stdio::null(), &config).unwrap();
+        let mut output = String::new();
+        process.stdout.take().unwrap().read_to_string(&mut output).unwrap();
+        let _ = process.wait();
+        let nice = unsafe { get_priority() }.unwrap();
+        assert_eq!(output.trim(), format!("env / {}", nice + 1));
+    }
+
+    #[test]
+    fn test_capture_stderr() {
+        let command = r#"sh -c 'echo 1 >&2; printf "2\r3" >&2'"#;
+        let mut process = spawn_process(
+            command, Stdio::null(), &Default::default()).unwrap();
+        let stderr_log = StderrLog::default();
+        capture_stderr(&mut process, "test", command, Some(stderr_log.clone()));
+        let _ = process.wait();
+
+        // Wait for the thread reading the stderr.
+        for _ in 0..100 {
+            if stderr_log.lines().len() == 3 {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(stderr_log.lines(), vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_stderr_log() {
+        let stderr_log = StderrLog::default();
+        for i in 0..(StderrLog::MAX_LINES + 1) {
+            stderr_log.push(i.to_string());
+        }
+        let lines = stderr_log.lines();
+        assert_eq!(lines.len(), StderrLog::MAX_LINES);
+        assert_eq!(lines[0], "1");
+    }
+
+    #[test]
+    fn test_monitored_process() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let process = spawn_process(
+            "sh -c 'exit 1'", Stdio::null(), &Default::default()).unwrap();
+        let tx2 = tx.clone();
+        let _process = MonitoredProcess::new(
+            process, "test", "sh", move |exit| tx2.send(exit).unwrap());
+        let exit = rx.recv().unwrap();
+        assert_eq!(exit.status.code(), Some(1));
+        assert!(!exit.killed);
+        assert!(!exit.is_expected());
+
+        let process = spawn_process(
+            "sleep 10", Stdio::null(), &Default::default()).unwrap();
+        let process = MonitoredProcess::new(
+            process, "test", "sleep 10", move |exit| tx.send(exit).unwrap());
+        process.kill();
+        let exit = rx.recv().unwrap();
+        assert_eq!(exit.command, "sleep 10");
+        assert!(exit.killed);
+        assert!(exit.is_expected());
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_command_failed() {
+        let (_input, mut output) = spawn_pipeline(vec![
+            "sh -c 'exit 1'".to_string(),
+        ], Default::default(), &Default::default()).unwrap();
+
+        // EOF may be detected before the exit of the process.
+        let mut buf = [0; 1];
+        let mut result = output.read(&mut buf).await;
+        for _ in 0..100 {
+            if result.is_err() {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+            result = output.read(&mut buf).await;
+        }
+        assert!(result.is_err());
+    }
+
     #[tokio::test]
     async fn test_pipeline() {
         use futures::task::noop_waker;
 
         let (mut input, mut output) = spawn_pipeline(vec![
             "cat".to_string()
-        ], Default::default()).unwrap();
+        ], Default::default(), &Default::default()).unwrap();
 
         let result = input.write_all(b"hello").await;
         assert!(result.is_ok());
@@ -322,7 +783,7 @@ mod tests {
     async fn test_pipeline_input_dropped() {
         let (mut input, mut output) = spawn_pipeline(vec![
             "cat".to_string(),
-        ], Default::default()).unwrap();
+        ], Default::default(), &Default::default()).unwrap();
 
         let _ = input.write_all(b"hello").await;
 
@@ -341,7 +802,7 @@ mod tests {
         let (mut input, output) = spawn_pipeline(vec![
             "cat".to_string(),
             "sleep 5".to_string(),
-        ], Default::default()).unwrap();
+        ], Default::default(), &Default::default()).unwrap();
 
         drop(output);
 
@@ -397,7 +858,7 @@ mod tests {
 
         let (input, output) = spawn_pipeline(vec![
             "sh -c 'exec 0<&-;'".to_string(),
-        ], Default::default()).unwrap();
+        ], Default::default(), &Default::default()).unwrap();
 
         let (tx, rx) = oneshot::channel();
         let mut wrapper = Wrapper::new(input, Some(tx));
@@ -1,25 +1,138 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use std::time::SystemTime;
 
 use num_cpus;
 use serde::Deserialize;
-use serde_yaml;
+use serde_yaml::{self, Value};
 
+use crate::error::Error;
 use crate::models::{ChannelType, ServiceId};
 
+// Exits the process after printing issues if the config file is invalid.
 pub fn load(config_path: &str) -> Arc<Config> {
-    let reader = File::open(config_path)
-        .unwrap_or_else(|err| {
-            panic!("Failed to open {}: {}", config_path, err);
-        });
-    let mut config: Config = serde_yaml::from_reader(reader)
-        .unwrap_or_else(|err| {
-            panic!("Failed to paser {}: {}", config_path, err);
-        });
-    config.last_modified = std::fs::metadata(config_path)
-        .map(|metadata| metadata.modified().ok()).ok().flatten();
-    Arc::new(config)
+    let result = check(config_path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", config_path, err);
+        std::process::exit(1);
+    });
+    for warning in result.warnings.iter() {
+        log::warn!("{}: {}", config_path, warning);
+    }
+    if !result.errors.is_empty() {
+        for error in result.errors.iter() {
+            eprintln!("{}: error: {}", config_path, error);
+        }
+        std::process::exit(1);
+    }
+    Arc::new(result.config)
+}
+
+// Warnings are logged.  All errors found are contained in the error returned.
+pub fn try_load(config_path: &str) -> Result<Arc<Config>, Error> {
+    let result = check(config_path)?;
+    for warning in result.warnings.iter() {
+        log::warn!("{}: {}", config_path, warning);
+    }
+    if !result.errors.is_empty() {
+        let errors: Vec<String> = result.errors
+            .iter()
+            .map(|error| error.to_string())
+            .collect();
+        return Err(Error::InvalidConfig(errors.join(", ")));
+    }
+    Ok(Arc::new(result.config))
+}
+
+// Loads a config file and validates it.  An error is returned only when the
+// file cannot be parsed.
+pub fn check(config_path: &str) -> Result<CheckResult, Error> {
+    let mut files = Vec::new();
+    let value = load_yaml(Path::new(config_path), &mut Vec::new(), &mut files)?;
+    let value = expand_env_vars(value, &mut Vec::new(), &|name| {
+        std::env::var(name).ok()
+    })?;
+    let mut config = deserialize_config(value.clone())?;
+    let warnings = find_unknown_keys(&value, &config);
+    let errors = config.validate();
+    // The latest modification time in the files loaded.
+    config.last_modified = files
+        .iter()
+        .map(|file| std::fs::metadata(file)
+             .map(|metadata| metadata.modified().ok()).ok().flatten())
+        .fold(None, std::cmp::max);
+    Ok(CheckResult { config, errors, warnings })
+}
+
+// Line numbers are lost when merging included files.  So, the YAML path of
+// the value is contained in the error instead.
+fn deserialize_config(value: Value) -> Result<Config, Error> {
+    serde_path_to_error::deserialize(value)
+        .map_err(|err| Error::InvalidConfig(
+            format!("{}: {}", err.path(), err.inner())))
+}
+
+pub struct CheckResult {
+    pub config: Config,
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+}
+
+// An issue found in a config, with the YAML path of the problematic value like
+// `tuners[0].command`.
+#[derive(Debug, PartialEq)]
+pub struct ConfigIssue {
+    pub path: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new<P, M>(path: P, message: M) -> Self
+    where
+        P: Into<String>,
+        M: Into<String>,
+    {
+        ConfigIssue { path: path.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+// The current config shared among components which need to see a new config
+// after reloading.
+#[derive(Clone)]
+pub struct SharedConfig {
+    path: String,
+    config: Arc<RwLock<Arc<Config>>>,
+}
+
+impl SharedConfig {
+    pub fn new(path: &str, config: Arc<Config>) -> Self {
+        SharedConfig {
+            path: path.to_string(),
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn set(&self, config: Arc<Config>) {
+        *self.config.write().unwrap() = config;
+    }
 }
 
 // result
@@ -38,8 +151,12 @@ pub struct Config {
     #[serde(default)]
     pub tuners: Vec<TunerConfig>,
     #[serde(default)]
+    pub quotas: Vec<QuotaConfig>,
+    #[serde(default)]
     pub filters: FiltersConfig,
     #[serde(default)]
+    pub streaming: StreamingConfig,
+    #[serde(default)]
     pub jobs: JobsConfig,
     #[serde(default)]
     pub recorder: RecorderConfig,
@@ -52,15 +169,41 @@ pub struct Config {
 pub struct EpgConfig {
     #[serde(default)]
     pub cache_dir: Option<String>,
+    // Scan services which have moved to other transponders or slots.
+    #[serde(default)]
+    pub follow_relocations: bool,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct ServerConfig {
     #[serde(default = "ServerConfig::default_addrs")]
-    pub addrs: Vec<ServerAddr>,
+    pub addrs: Vec<ServerAddrConfig>,
     #[serde(default = "ServerConfig::default_workers")]
     pub workers: usize,
+    // The deadline of the graceful shutdown in seconds.
+    #[serde(default = "ServerConfig::default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default)]
+    pub auth: AuthConfig,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ServerAddrConfig {
+    #[serde(flatten)]
+    pub addr: ServerAddr,
+    // IP addresses or CIDR blocks of clients allowed to connect to the
+    // address.  All clients are allowed if empty.  Not supported for UNIX
+    // domain sockets.
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+}
+
+impl From<ServerAddr> for ServerAddrConfig {
+    fn from(addr: ServerAddr) -> Self {
+        ServerAddrConfig { addr, allowed_clients: vec![] }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -70,14 +213,65 @@ pub enum ServerAddr {
     Unix(String),
 }
 
+// Credentials for the web API.  Authentication is disabled if no credential
+// is defined.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    #[serde(default)]
+    pub users: Vec<UserConfig>,
+}
+
+impl AuthConfig {
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty() || !self.users.is_empty()
+    }
+}
+
+// A static API token given in the `Authorization: Bearer` header or in the
+// `token` query parameter.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TokenConfig {
+    pub token: String,
+    pub groups: Vec<ApiGroup>,
+}
+
+// A user for HTTP basic authentication.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct UserConfig {
+    pub name: String,
+    pub password: String,
+    pub groups: Vec<ApiGroup>,
+}
+
+// Groups of endpoints which a credential is allowed to access.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiGroup {
+    // Read-only endpoints for channels, services, programs and tuners.
+    Epg,
+    // Endpoints for streaming TS packets.
+    Streaming,
+    // Endpoints changing the state of the server.
+    Admin,
+}
+
 impl ServerConfig {
-    fn default_addrs() -> Vec<ServerAddr> {
-        vec![ServerAddr::Http("localhost:40772".to_string())]
+    fn default_addrs() -> Vec<ServerAddrConfig> {
+        vec![ServerAddr::Http("localhost:40772".to_string()).into()]
     }
 
     fn default_workers() -> usize {
         num_cpus::get()
     }
+
+    fn default_shutdown_timeout() -> u64 {
+        30
+    }
 }
 
 impl Default for ServerConfig {
@@ -85,6 +279,8 @@ impl Default for ServerConfig {
         ServerConfig {
             addrs: Self::default_addrs(),
             workers: Self::default_workers(),
+            shutdown_timeout: Self::default_shutdown_timeout(),
+            auth: Default::default(),
         }
     }
 }
@@ -113,6 +309,86 @@ pub struct TunerConfig {
     pub command: String,
     #[serde(default)]
     pub disabled: bool,
+    #[serde(default)]
+    pub process: ProcessConfig,
+}
+
+// Limits applied to web clients.  The first quota matching a client is
+// applied.  A quota matches a client if all of the specified conditions are
+// satisfied.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct QuotaConfig {
+    pub name: String,
+    // IP addresses or CIDR blocks of the peer.
+    #[serde(default)]
+    pub clients: Vec<String>,
+    // Tokens in `server.auth.tokens`.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    // Names of users in `server.auth.users`.
+    #[serde(default)]
+    pub users: Vec<String>,
+    // A regular expression matching with the User-Agent header.
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    // Compiled from `user_agent` in the validation.
+    #[serde(skip)]
+    pub user_agent_regex: Option<CompiledRegex>,
+    // Priorities in the X-Mirakurun-Priority header are limited to this value.
+    #[serde(default)]
+    pub max_priority: Option<i32>,
+    // Used if the X-Mirakurun-Priority header is not specified.
+    #[serde(default)]
+    pub default_priority: i32,
+    // The maximum number of concurrent streams shared by all clients matching
+    // the quota.
+    #[serde(default)]
+    pub max_streams: Option<usize>,
+}
+
+// Compared by the pattern so that configs can be compared.
+#[derive(Clone, Debug)]
+pub struct CompiledRegex(regex::Regex);
+
+impl CompiledRegex {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        Ok(CompiledRegex(regex::Regex::new(pattern)?))
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+}
+
+impl PartialEq for CompiledRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+// Settings applied to child processes spawned for commands.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct ProcessConfig {
+    // Niceness added to the default one.
+    #[serde(default)]
+    pub nice: Option<i32>,
+    // RLIMIT_CPU in seconds.
+    #[serde(default)]
+    pub cpu_limit: Option<u64>,
+    // RLIMIT_AS in bytes.
+    #[serde(default)]
+    pub memory_limit: Option<u64>,
+    #[serde(default)]
+    pub working_dir: Option<String>,
+    // Environment variables added to the inherited ones.
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    #[serde(default)]
+    pub uid: Option<u32>,
+    #[serde(default)]
+    pub gid: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -126,6 +402,12 @@ pub struct FiltersConfig {
     pub program_filter: String,
     #[serde(default)]
     pub post_filter: String,
+    // Named filter chains selectable with the `filters` query parameter.
+    #[serde(default)]
+    pub chains: HashMap<String, FilterChainConfig>,
+    // Applied to all filter commands.
+    #[serde(default)]
+    pub process: ProcessConfig,
 }
 
 impl FiltersConfig {
@@ -152,10 +434,145 @@ impl Default for FiltersConfig {
             service_filter: Self::default_service_filter(),
             program_filter: Self::default_program_filter(),
             post_filter: String::new(),
+            chains: HashMap::new(),
+            process: Default::default(),
         }
     }
 }
 
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct FilterChainConfig {
+    pub commands: Vec<String>,
+    #[serde(default)]
+    pub stage: FilterStage,
+    // IP addresses or CIDR blocks of clients allowed to use the chain.  All
+    // clients are allowed if empty.
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilterStage {
+    // Placed before the service/program filter.
+    Pre,
+    // Placed after the service/program filter.
+    #[default]
+    Post,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct StreamingConfig {
+    #[serde(default)]
+    pub slow_subscriber: SlowSubscriberConfig,
+    #[serde(default)]
+    pub priming: PrimingConfig,
+    #[serde(default)]
+    pub time_shift: TimeShiftConfig,
+    // Move TS packets from a tuner process to a filter pipeline by using
+    // splice(2) while the tuner has only one subscriber.  Linux only.
+    #[serde(default)]
+    pub splice: bool,
+    // Share a filter pipeline among streams which have the same filters on
+    // the same tuner session.
+    #[serde(default = "StreamingConfig::default_share_filter_pipelines")]
+    pub share_filter_pipelines: bool,
+}
+
+impl StreamingConfig {
+    fn default_share_filter_pipelines() -> bool {
+        true
+    }
+}
+
+impl Default for StreamingConfig {
+    fn default() -> Self {
+        StreamingConfig {
+            slow_subscriber: Default::default(),
+            priming: Default::default(),
+            time_shift: Default::default(),
+            splice: false,
+            share_filter_pipelines:
+                StreamingConfig::default_share_filter_pipelines(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct SlowSubscriberConfig {
+    #[serde(default)]
+    pub policy: SlowSubscriberPolicy,
+    #[serde(default = "SlowSubscriberConfig::default_queue_size")]
+    pub queue_size: usize,
+    #[serde(default)]
+    pub max_drops: usize,
+    #[serde(default = "SlowSubscriberConfig::default_max_buffer_size")]
+    pub max_buffer_size: usize,
+}
+
+impl SlowSubscriberConfig {
+    fn default_queue_size() -> usize {
+        // 500 chunks, large enough for 5 sec buffering.
+        500
+    }
+
+    fn default_max_buffer_size() -> usize {
+        32 * 1024 * 1024  // 32 MiB
+    }
+}
+
+impl Default for SlowSubscriberConfig {
+    fn default() -> Self {
+        SlowSubscriberConfig {
+            policy: Default::default(),
+            queue_size: Self::default_queue_size(),
+            max_drops: 0,
+            max_buffer_size: Self::default_max_buffer_size(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowSubscriberPolicy {
+    // Drop chunks which cannot be queued.
+    #[default]
+    Drop,
+    // Drop chunks which cannot be queued, and disconnect the subscriber when
+    // the number of dropped chunks exceeds `max-drops`.
+    Disconnect,
+    // Buffer chunks which cannot be queued, and disconnect the subscriber
+    // when the size of buffered chunks exceeds `max-buffer-size`.
+    Block,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PrimingConfig {
+    // Send the latest PAT/PMT to a new subscriber before any other chunks.
+    #[serde(default)]
+    pub psi: bool,
+    // Send chunks received in the last N seconds to a new subscriber.
+    #[serde(default)]
+    pub buffer_secs: u64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct TimeShiftConfig {
+    // Keep chunks received in the last N minutes.  0 disables the time-shift
+    // buffer.
+    #[serde(default)]
+    pub buffer_minutes: u64,
+    // Chunks are stored in files in this directory if specified, otherwise in
+    // memory.
+    #[serde(default)]
+    pub dir: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub struct JobsConfig {
@@ -165,6 +582,8 @@ pub struct JobsConfig {
     pub sync_clocks: JobConfig,
     #[serde(default = "JobsConfig::default_update_schedules")]
     pub update_schedules: JobConfig,
+    #[serde(default = "JobsConfig::default_collect_logos")]
+    pub collect_logos: CollectLogosJobConfig,
 }
 
 impl JobsConfig {
@@ -174,6 +593,7 @@ impl JobsConfig {
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 31 5 * * * *".to_string(),
+            process: Default::default(),
         }
     }
 
@@ -183,6 +603,7 @@ impl JobsConfig {
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 3 12 * * * *".to_string(),
+            process: Default::default(),
         }
     }
 
@@ -192,6 +613,15 @@ impl JobsConfig {
                       {{#sids}} --sids={{.}}{{/sids}}\
                       {{#xsids}} --xsids={{.}}{{/xsids}}".to_string(),
             schedule: "0 7,37 * * * * *".to_string(),
+            process: Default::default(),
+        }
+    }
+
+    fn default_collect_logos() -> CollectLogosJobConfig {
+        CollectLogosJobConfig {
+            schedule: "0 41 5 * * * *".to_string(),
+            timeout: CollectLogosJobConfig::default_timeout(),
+            disabled: false,
         }
     }
 }
@@ -202,6 +632,7 @@ impl Default for JobsConfig {
             scan_services: Self::default_scan_services(),
             sync_clocks: Self::default_sync_clocks(),
             update_schedules: Self::default_update_schedules(),
+            collect_logos: Self::default_collect_logos(),
         }
     }
 }
@@ -211,6 +642,26 @@ impl Default for JobsConfig {
 pub struct JobConfig {
     pub command: String,
     pub schedule: String,
+    #[serde(default)]
+    pub process: ProcessConfig,
+}
+
+// Logos are collected in this process.  So, no command is specified.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct CollectLogosJobConfig {
+    pub schedule: String,
+    // Timeout in seconds for collecting logos in each network.
+    #[serde(default = "CollectLogosJobConfig::default_timeout")]
+    pub timeout: u64,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl CollectLogosJobConfig {
+    fn default_timeout() -> u64 {
+        300
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq)]
@@ -249,10 +700,776 @@ impl Default for MirakurunConfig {
     }
 }
 
+// validation
+
+impl Config {
+    // Regular expressions are compiled here so that they're not compiled on
+    // every request.
+    fn validate(&mut self) -> Vec<ConfigIssue> {
+        let mut errors = Vec::new();
+
+        let mut names = HashSet::new();
+        for (i, channel) in self.channels.iter().enumerate() {
+            if !names.insert(&channel.name) {
+                errors.push(ConfigIssue::new(
+                    format!("channels[{}].name", i),
+                    format!("Duplicate channel name: {}", channel.name)));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (i, tuner) in self.tuners.iter().enumerate() {
+            if !names.insert(&tuner.name) {
+                errors.push(ConfigIssue::new(
+                    format!("tuners[{}].name", i),
+                    format!("Duplicate tuner name: {}", tuner.name)));
+            }
+            validate_template(
+                &mut errors, format!("tuners[{}].command", i), &tuner.command);
+        }
+
+        let supported_types: Vec<ChannelType> = self.tuners
+            .iter()
+            .filter(|tuner| !tuner.disabled)
+            .flat_map(|tuner| tuner.channel_types.iter().cloned())
+            .collect();
+        for (i, channel) in self.channels.iter().enumerate() {
+            if !channel.disabled &&
+                !supported_types.contains(&channel.channel_type) {
+                errors.push(ConfigIssue::new(
+                    format!("channels[{}].type", i),
+                    format!("No tuner supports {}", channel.channel_type)));
+            }
+        }
+
+        let filters = [
+            ("filters.pre-filter", &self.filters.pre_filter),
+            ("filters.service-filter", &self.filters.service_filter),
+            ("filters.program-filter", &self.filters.program_filter),
+            ("filters.post-filter", &self.filters.post_filter),
+            ("recorder.track-airtime-command",
+             &self.recorder.track_airtime_command),
+        ];
+        for (path, template) in filters.iter() {
+            validate_template(&mut errors, *path, template);
+        }
+
+        for (name, chain) in self.filters.chains.iter() {
+            for (i, command) in chain.commands.iter().enumerate() {
+                validate_template(
+                    &mut errors,
+                    format!("filters.chains.{}.commands[{}]", name, i),
+                    command);
+            }
+            for (i, client) in chain.allowed_clients.iter().enumerate() {
+                if !is_valid_client_pattern(client) {
+                    errors.push(ConfigIssue::new(
+                        format!("filters.chains.{}.allowed-clients[{}]",
+                                name, i),
+                        format!("Invalid IP address or CIDR block: {}",
+                                client)));
+                }
+            }
+        }
+
+        for (i, addr) in self.server.addrs.iter().enumerate() {
+            if let ServerAddr::Unix(_) = addr.addr {
+                if !addr.allowed_clients.is_empty() {
+                    errors.push(ConfigIssue::new(
+                        format!("server.addrs[{}].allowed-clients", i),
+                        "Not supported for a UNIX domain socket"));
+                }
+            }
+            for (j, client) in addr.allowed_clients.iter().enumerate() {
+                if !is_valid_client_pattern(client) {
+                    errors.push(ConfigIssue::new(
+                        format!("server.addrs[{}].allowed-clients[{}]", i, j),
+                        format!("Invalid IP address or CIDR block: {}",
+                                client)));
+                }
+            }
+        }
+
+        for (i, token) in self.server.auth.tokens.iter().enumerate() {
+            if token.token.is_empty() {
+                errors.push(ConfigIssue::new(
+                    format!("server.auth.tokens[{}].token", i),
+                    "Empty token"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (i, user) in self.server.auth.users.iter().enumerate() {
+            if user.name.is_empty() || user.name.contains(':') {
+                errors.push(ConfigIssue::new(
+                    format!("server.auth.users[{}].name", i),
+                    format!("Invalid user name: {}", user.name)));
+            }
+            if !names.insert(&user.name) {
+                errors.push(ConfigIssue::new(
+                    format!("server.auth.users[{}].name", i),
+                    format!("Duplicate user name: {}", user.name)));
+            }
+            if user.password.is_empty() {
+                errors.push(ConfigIssue::new(
+                    format!("server.auth.users[{}].password", i),
+                    "Empty password"));
+            }
+        }
+
+        let mut names = HashSet::new();
+        for (i, quota) in self.quotas.iter_mut().enumerate() {
+            if !names.insert(quota.name.clone()) {
+                errors.push(ConfigIssue::new(
+                    format!("quotas[{}].name", i),
+                    format!("Duplicate quota name: {}", quota.name)));
+            }
+            for (j, client) in quota.clients.iter().enumerate() {
+                if !is_valid_client_pattern(client) {
+                    errors.push(ConfigIssue::new(
+                        format!("quotas[{}].clients[{}]", i, j),
+                        format!("Invalid IP address or CIDR block: {}",
+                                client)));
+                }
+            }
+            if let Some(ref pattern) = quota.user_agent {
+                match CompiledRegex::new(pattern) {
+                    Ok(regex) => quota.user_agent_regex = Some(regex),
+                    Err(err) => errors.push(ConfigIssue::new(
+                        format!("quotas[{}].user-agent", i),
+                        format!("Invalid regular expression: {}", err))),
+                }
+            }
+            if let Some(max_priority) = quota.max_priority {
+                if quota.default_priority > max_priority {
+                    errors.push(ConfigIssue::new(
+                        format!("quotas[{}].default-priority", i),
+                        "Greater than max-priority"));
+                }
+            }
+        }
+
+        let jobs = [
+            ("jobs.scan-services", &self.jobs.scan_services),
+            ("jobs.sync-clocks", &self.jobs.sync_clocks),
+            ("jobs.update-schedules", &self.jobs.update_schedules),
+        ];
+        for (path, job) in jobs.iter() {
+            validate_template(
+                &mut errors, format!("{}.command", path), &job.command);
+            if let Err(err) = cron::Schedule::from_str(&job.schedule) {
+                errors.push(ConfigIssue::new(
+                    format!("{}.schedule", path),
+                    format!("Invalid cron expression: {}", err)));
+            }
+        }
+        if let Err(err) =
+            cron::Schedule::from_str(&self.jobs.collect_logos.schedule) {
+            errors.push(ConfigIssue::new(
+                "jobs.collect-logos.schedule",
+                format!("Invalid cron expression: {}", err)));
+        }
+        if self.jobs.collect_logos.timeout == 0 {
+            errors.push(ConfigIssue::new(
+                "jobs.collect-logos.timeout", "Zero timeout"));
+        }
+
+        errors
+    }
+}
+
+fn validate_template<P>(errors: &mut Vec<ConfigIssue>, path: P, template: &str)
+where
+    P: Into<String>,
+{
+    if let Err(err) = mustache::compile_str(template) {
+        errors.push(ConfigIssue::new(
+            path, format!("Invalid Mustache template: {}", err)));
+    }
+}
+
+fn is_valid_client_pattern(pattern: &str) -> bool {
+    let (network, prefix_len) = match pattern.find('/') {
+        Some(pos) => (&pattern[..pos], Some(&pattern[pos + 1..])),
+        None => (pattern, None),
+    };
+    let max_len = match network.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) => 32,
+        Ok(IpAddr::V6(_)) => 128,
+        Err(_) => return false,
+    };
+    match prefix_len.map(|len| len.parse::<u32>()) {
+        Some(Ok(len)) => len <= max_len,
+        Some(Err(_)) => false,
+        None => true,
+    }
+}
+
+// serde silently ignores unknown keys.  Whether a key is used is determined by
+// replacing its value with a value which no property in the config accepts.
+// The key is unknown if the config is still deserialized into the same one.
+fn find_unknown_keys(value: &Value, config: &Config) -> Vec<ConfigIssue> {
+    let mut paths = Vec::new();
+    collect_key_paths(value, &mut Vec::new(), &mut paths);
+
+    let mut unknowns: Vec<Vec<PathElem>> = Vec::new();
+    for path in paths.into_iter() {
+        // Keys under an unknown key are not checked.
+        if unknowns.iter().any(|unknown| path.starts_with(unknown)) {
+            continue;
+        }
+        let mut probe = value.clone();
+        *lookup_mut(&mut probe, &path) = Value::Sequence(vec![
+            Value::Sequence(vec![Value::Sequence(vec![])])]);
+        match serde_yaml::from_value::<Config>(probe) {
+            Ok(ref probed) if probed == config => unknowns.push(path),
+            _ => (),
+        }
+    }
+
+    unknowns
+        .iter()
+        .map(|path| ConfigIssue::new(format_path(path), "Unknown key"))
+        .collect()
+}
+
+#[derive(Clone, PartialEq)]
+enum PathElem {
+    Key(Value),
+    Index(usize),
+}
+
+fn collect_key_paths(
+    value: &Value,
+    path: &mut Vec<PathElem>,
+    paths: &mut Vec<Vec<PathElem>>,
+) {
+    match value {
+        Value::Mapping(mapping) => {
+            for (key, value) in mapping.iter() {
+                path.push(PathElem::Key(key.clone()));
+                paths.push(path.clone());
+                collect_key_paths(value, path, paths);
+                path.pop();
+            }
+        }
+        Value::Sequence(sequence) => {
+            for (i, value) in sequence.iter().enumerate() {
+                path.push(PathElem::Index(i));
+                collect_key_paths(value, path, paths);
+                path.pop();
+            }
+        }
+        _ => (),
+    }
+}
+
+fn lookup_mut<'a>(value: &'a mut Value, path: &[PathElem]) -> &'a mut Value {
+    path.iter().fold(value, |value, elem| match (value, elem) {
+        (Value::Mapping(mapping), PathElem::Key(key)) =>
+            mapping.get_mut(key).unwrap(),
+        (Value::Sequence(sequence), PathElem::Index(i)) => &mut sequence[*i],
+        _ => unreachable!(),
+    })
+}
+
+fn format_path(path: &[PathElem]) -> String {
+    let mut s = String::new();
+    for elem in path.iter() {
+        match elem {
+            PathElem::Key(Value::String(key)) => {
+                if !s.is_empty() {
+                    s.push('.');
+                }
+                s.push_str(key);
+            }
+            PathElem::Key(key) => {
+                let key = serde_yaml::to_string(key).unwrap_or_default();
+                s.push_str(&format!("[{}]", key.trim_start_matches("---")
+                                    .trim()));
+            }
+            PathElem::Index(i) => s.push_str(&format!("[{}]", i)),
+        }
+    }
+    s
+}
+
+// includes
+
+// Loads a YAML file and the files included from it.
+//
+// Included files are merged in the order listed, and then the including file
+// is merged on top of them:
+//
+//   * Mappings are merged recursively
+//   * Sequences are concatenated
+//   * Other values are overridden
+//
+// Relative paths are resolved from the directory of the including file.
+fn load_yaml(
+    path: &Path,
+    stack: &mut Vec<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<Value, Error> {
+    let canonical = path.canonicalize()?;
+    if stack.contains(&canonical) {
+        return Err(Error::InvalidConfig(
+            format!("{}: Circular include", path.display())));
+    }
+
+    let reader = File::open(path)?;
+    let mut value: Value = serde_yaml::from_reader(reader)
+        .map_err(|err| Error::InvalidConfig(
+            format!("{}: {}", path.display(), err)))?;
+    files.push(path.to_path_buf());
+
+    let includes = match value {
+        Value::Mapping(ref mut mapping) =>
+            mapping.remove(&Value::String("include".to_string())),
+        _ => None,
+    };
+    let includes = match includes {
+        None => vec![],
+        Some(Value::String(include)) => vec![include],
+        Some(Value::Sequence(includes)) => includes
+            .into_iter()
+            .map(|include| match include {
+                Value::String(include) => Ok(include),
+                _ => Err(Error::InvalidConfig(format!(
+                    "{}: include: Must be a string or a list of strings",
+                    path.display()))),
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        Some(_) => return Err(Error::InvalidConfig(format!(
+            "{}: include: Must be a string or a list of strings",
+            path.display()))),
+    };
+
+    let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+    stack.push(canonical);
+    let mut merged = Value::Null;
+    for include in includes.iter() {
+        let included = load_yaml(&base_dir.join(include), stack, files)?;
+        merged = merge_yaml(merged, included);
+    }
+    stack.pop();
+
+    Ok(merge_yaml(merged, value))
+}
+
+fn merge_yaml(base: Value, value: Value) -> Value {
+    match (base, value) {
+        (Value::Mapping(mut base), Value::Mapping(mapping)) => {
+            for (key, value) in mapping.into_iter() {
+                let merged = match base.remove(&key) {
+                    Some(base_value) => merge_yaml(base_value, value),
+                    None => value,
+                };
+                base.insert(key, merged);
+            }
+            Value::Mapping(base)
+        }
+        (Value::Sequence(mut base), Value::Sequence(sequence)) => {
+            base.extend(sequence);
+            Value::Sequence(base)
+        }
+        (base, Value::Null) => base,
+        (_, value) => value,
+    }
+}
+
+// environment variables
+
+// Expands `${VAR}` and `${VAR:-default}` in string values.  `$${` is replaced
+// with a literal `${`.
+fn expand_env_vars<F>(
+    value: Value,
+    path: &mut Vec<PathElem>,
+    getenv: &F,
+) -> Result<Value, Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(s) => expand_str(&s, getenv)
+            .map(Value::String)
+            .map_err(|msg| Error::InvalidConfig(
+                format!("{}: {}", format_path(path), msg))),
+        Value::Mapping(mapping) => {
+            let mut expanded = serde_yaml::Mapping::new();
+            for (key, value) in mapping.into_iter() {
+                path.push(PathElem::Key(key.clone()));
+                let value = expand_env_vars(value, path, getenv)?;
+                path.pop();
+                expanded.insert(key, value);
+            }
+            Ok(Value::Mapping(expanded))
+        }
+        Value::Sequence(sequence) => {
+            let mut expanded = Vec::with_capacity(sequence.len());
+            for (i, value) in sequence.into_iter().enumerate() {
+                path.push(PathElem::Index(i));
+                expanded.push(expand_env_vars(value, path, getenv)?);
+                path.pop();
+            }
+            Ok(Value::Sequence(expanded))
+        }
+        value => Ok(value),
+    }
+}
+
+fn expand_str<F>(s: &str, getenv: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find("${") {
+        if rest[..pos].ends_with('$') {
+            result.push_str(&rest[..pos - 1]);
+            result.push_str("${");
+            rest = &rest[pos + 2..];
+            continue;
+        }
+        result.push_str(&rest[..pos]);
+        let end = match rest[pos..].find('}') {
+            Some(end) => pos + end,
+            None => return Err(format!("Unclosed variable reference: {}", s)),
+        };
+        let (name, default) = match rest[pos + 2..end].find(":-") {
+            Some(i) => (&rest[pos + 2..pos + 2 + i],
+                        Some(&rest[pos + 2 + i + 2..end])),
+            None => (&rest[pos + 2..end], None),
+        };
+        match (getenv(name), default) {
+            (Some(value), _) => result.push_str(&value),
+            (None, Some(default)) => result.push_str(default),
+            (None, None) =>
+                return Err(format!("Undefined environment variable: {}", name)),
+        }
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_try_load() {
+        let path = std::env::temp_dir().join(
+            format!("mirakc-test-config-{}.yml", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        std::fs::write(path, "channels: []").unwrap();
+        let config = try_load(path).unwrap();
+        assert!(config.last_modified.is_some());
+
+        std::fs::write(path, "channels: {}").unwrap();
+        assert!(try_load(path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+        assert!(try_load(path).is_err());
+    }
+
+    #[test]
+    fn test_shared_config() {
+        let shared = SharedConfig::new("/path/to/config.yml", Default::default());
+        assert_eq!(shared.path(), "/path/to/config.yml");
+        assert!(shared.get().channels.is_empty());
+
+        let mut config = Config::default();
+        config.epg.cache_dir = Some("/tmp".to_string());
+        shared.clone().set(Arc::new(config));
+        assert_eq!(shared.get().epg.cache_dir, Some("/tmp".to_string()));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config: Config = serde_yaml::from_str(r#"
+            channels:
+              - name: ch
+                type: GR
+                channel: '0'
+              - name: ch
+                type: BS
+                channel: '1'
+            tuners:
+              - name: tuner
+                types: [GR]
+                command: '{{#channel}}'
+            jobs:
+              sync-clocks:
+                command: '{{/channel}}'
+                schedule: '0 0 * * * * *'
+              collect-logos:
+                schedule: '0 0 * * * * *'
+                timeout: 0
+        "#).unwrap();
+        let errors: Vec<String> = config.validate()
+            .iter()
+            .map(|error| error.path.clone())
+            .collect();
+        assert_eq!(errors, [
+            "channels[1].name",
+            "tuners[0].command",
+            "channels[1].type",
+            "jobs.sync-clocks.command",
+            "jobs.collect-logos.timeout",
+        ]);
+
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn test_validate_quotas() {
+        let mut config: Config = serde_yaml::from_str(r#"
+            quotas:
+              - name: a
+                clients: [192.168.0.0/16, localhost]
+                user-agent: '^EPGStation/'
+              - name: a
+                user-agent: '('
+                max-priority: 0
+                default-priority: 1
+        "#).unwrap();
+        let errors: Vec<String> = config.validate()
+            .iter()
+            .map(|error| error.path.clone())
+            .collect();
+        assert_eq!(errors, [
+            "quotas[0].clients[1]",
+            "quotas[1].name",
+            "quotas[1].user-agent",
+            "quotas[1].default-priority",
+        ]);
+        assert_eq!(config.quotas[0].user_agent_regex,
+                   CompiledRegex::new("^EPGStation/").ok());
+        assert!(config.quotas[1].user_agent_regex.is_none());
+    }
+
+    #[test]
+    fn test_validate_server() {
+        let mut config: Config = serde_yaml::from_str(r#"
+            server:
+              addrs:
+                - http: '0.0.0.0:40772'
+                  allowed-clients: [192.168.0.0/16, localhost]
+                - unix: /path/to/sock
+                  allowed-clients: [127.0.0.1]
+              auth:
+                tokens:
+                  - token: ''
+                    groups: [epg]
+                users:
+                  - name: user
+                    password: pass
+                    groups: [epg]
+                  - name: user
+                    password: ''
+                    groups: [admin]
+                  - name: 'a:b'
+                    password: pass
+                    groups: [admin]
+        "#).unwrap();
+        let errors: Vec<String> = config.validate()
+            .iter()
+            .map(|error| error.path.clone())
+            .collect();
+        assert_eq!(errors, [
+            "server.addrs[0].allowed-clients[1]",
+            "server.addrs[1].allowed-clients",
+            "server.auth.tokens[0].token",
+            "server.auth.users[1].name",
+            "server.auth.users[1].password",
+            "server.auth.users[2].name",
+        ]);
+    }
+
+    #[test]
+    fn test_is_valid_client_pattern() {
+        assert!(is_valid_client_pattern("127.0.0.1"));
+        assert!(is_valid_client_pattern("192.168.0.0/16"));
+        assert!(is_valid_client_pattern("::1"));
+        assert!(is_valid_client_pattern("fe80::/10"));
+        assert!(!is_valid_client_pattern("192.168.0.0/33"));
+        assert!(!is_valid_client_pattern("192.168.0.0/"));
+        assert!(!is_valid_client_pattern("localhost"));
+    }
+
+    #[test]
+    fn test_find_unknown_keys() {
+        let yaml = r#"
+            channels:
+              - name: ch
+                type: GR
+                channel: '0'
+                unknown: 1
+            server:
+              addrs:
+                - http: '0.0.0.0:40772'
+                  unknown: 1
+              workers: 2
+              unknown:
+                nested: 1
+            unknown: 1
+        "#;
+        let value: Value = serde_yaml::from_str(yaml).unwrap();
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        let warnings: Vec<String> = find_unknown_keys(&value, &config)
+            .iter()
+            .map(|warning| warning.path.clone())
+            .collect();
+        assert_eq!(warnings, [
+            "channels[0].unknown",
+            "server.addrs[0].unknown",
+            "server.unknown",
+            "unknown",
+        ]);
+    }
+
+    #[test]
+    fn test_format_path() {
+        assert_eq!(format_path(&[]), "");
+        assert_eq!(format_path(&[
+            PathElem::Key(Value::String("tuners".to_string())),
+            PathElem::Index(1),
+            PathElem::Key(Value::String("command".to_string())),
+        ]), "tuners[1].command");
+        assert_eq!(format_path(&[
+            PathElem::Key(Value::String("a".to_string())),
+            PathElem::Key(Value::Number(1.into())),
+        ]), "a[1]");
+    }
+
+    #[test]
+    fn test_load_yaml() {
+        let dir = std::env::temp_dir().join(
+            format!("mirakc-test-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("hosts")).unwrap();
+        std::fs::write(dir.join("channels.yml"), r#"
+            channels:
+              - name: ch1
+                type: GR
+                channel: '1'
+            server:
+              workers: 2
+        "#).unwrap();
+        std::fs::write(dir.join("hosts/tuners.yml"), r#"
+            tuners:
+              - name: tuner
+                types: [GR]
+                command: cmd
+        "#).unwrap();
+        std::fs::write(dir.join("config.yml"), r#"
+            include:
+              - channels.yml
+              - hosts/tuners.yml
+            channels:
+              - name: ch2
+                type: GR
+                channel: '2'
+            server:
+              workers: 4
+        "#).unwrap();
+
+        let mut files = Vec::new();
+        let value = load_yaml(
+            &dir.join("config.yml"), &mut Vec::new(), &mut files).unwrap();
+        assert_eq!(files.len(), 3);
+        let config: Config = serde_yaml::from_value(value).unwrap();
+        let names: Vec<&str> = config.channels
+            .iter()
+            .map(|channel| channel.name.as_str())
+            .collect();
+        assert_eq!(names, ["ch1", "ch2"]);
+        assert_eq!(config.tuners.len(), 1);
+        assert_eq!(config.server.workers, 4);
+
+        std::fs::write(dir.join("channels.yml"), "include: config.yml")
+            .unwrap();
+        assert!(load_yaml(
+            &dir.join("config.yml"), &mut Vec::new(), &mut Vec::new())
+                .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_deserialize_config() {
+        let deserialize = |s: &str| deserialize_config(
+            serde_yaml::from_str(s).unwrap()).map_err(|err| err.to_string());
+
+        assert!(deserialize("server: {workers: 2}").is_ok());
+        assert_eq!(
+            deserialize("server: {workers: abc}").unwrap_err(),
+            "Invalid config: server.workers: invalid type: string \"abc\", \
+             expected usize");
+        assert_eq!(
+            deserialize("tuners: [{name: tuner, types: [GR]}]").unwrap_err(),
+            "Invalid config: tuners[0]: missing field `command`");
+    }
+
+    #[test]
+    fn test_merge_yaml() {
+        let merge = |base: &str, value: &str| merge_yaml(
+            serde_yaml::from_str(base).unwrap(),
+            serde_yaml::from_str(value).unwrap());
+        let parse = |s: &str| serde_yaml::from_str::<Value>(s).unwrap();
+
+        assert_eq!(merge("{a: 1, b: 2}", "{b: 3, c: 4}"),
+                   parse("{a: 1, b: 3, c: 4}"));
+        assert_eq!(merge("{a: {b: 1}}", "{a: {c: 2}}"),
+                   parse("{a: {b: 1, c: 2}}"));
+        assert_eq!(merge("[1, 2]", "[3]"), parse("[1, 2, 3]"));
+        assert_eq!(merge("{a: 1}", "{a: [1]}"), parse("{a: [1]}"));
+        assert_eq!(merge("{a: 1}", "~"), parse("{a: 1}"));
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        let getenv = |name: &str| match name {
+            "DEV" => Some("/dev/px4video0".to_string()),
+            "EMPTY" => Some("".to_string()),
+            _ => None,
+        };
+
+        assert_eq!(expand_str("recpt1 --device ${DEV}", &getenv).unwrap(),
+                   "recpt1 --device /dev/px4video0");
+        assert_eq!(expand_str("${HOST:-localhost}:40772", &getenv).unwrap(),
+                   "localhost:40772");
+        assert_eq!(expand_str("${EMPTY:-default}", &getenv).unwrap(), "");
+        assert_eq!(expand_str("$${DEV}", &getenv).unwrap(), "${DEV}");
+        assert_eq!(expand_str("$DEV {{channel}}", &getenv).unwrap(),
+                   "$DEV {{channel}}");
+        assert!(expand_str("${HOST}", &getenv).is_err());
+        assert!(expand_str("${DEV", &getenv).is_err());
+
+        let value: Value = serde_yaml::from_str(r#"
+            tuners:
+              - command: ${DEV}
+                types: [GR]
+        "#).unwrap();
+        assert_eq!(expand_env_vars(value, &mut Vec::new(), &getenv).unwrap(),
+                   serde_yaml::from_str::<Value>(r#"
+            tuners:
+              - command: /dev/px4video0
+                types: [GR]
+        "#).unwrap());
+
+        let value: Value = serde_yaml::from_str(r#"
+            tuners:
+              - command: ${HOST}
+        "#).unwrap();
+        match expand_env_vars(value, &mut Vec::new(), &getenv) {
+            Err(Error::InvalidConfig(msg)) => assert_eq!(
+                msg, "tuners[0].command: Undefined environment variable: HOST"),
+            _ => panic!(),
+        }
+    }
+
     #[test]
     fn test_config() {
         let result = serde_yaml::from_str::<Config>("{}");
@@ -268,12 +1485,15 @@ mod tests {
             last_modified: Default::default(),
             epg: EpgConfig {
                 cache_dir: Some("/path/to/epg".to_string()),
+                follow_relocations: false,
             },
             server: Default::default(),
             channels: vec![],
             tuners: vec![],
+            quotas: vec![],
             jobs: Default::default(),
             filters: Default::default(),
+            streaming: Default::default(),
             recorder: Default::default(),
             mirakurun: Default::default(),
         });
@@ -288,6 +1508,19 @@ mod tests {
 
     #[test]
     fn test_epg_config() {
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<EpgConfig>(r#"
+                cache-dir: /path/to/epg
+                follow-relocations: true
+            "#).unwrap(),
+            EpgConfig {
+                cache_dir: Some("/path/to/epg".to_string()),
+                follow_relocations: true,
+            });
     }
 
     #[test]
@@ -303,9 +1536,11 @@ mod tests {
             "#).unwrap(),
             ServerConfig {
                 addrs: vec![
-                    ServerAddr::Http("0.0.0.0:40772".to_string()),
+                    ServerAddr::Http("0.0.0.0:40772".to_string()).into(),
                 ],
                 workers: ServerConfig::default_workers(),
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: Default::default(),
             });
 
         assert_eq!(
@@ -315,9 +1550,11 @@ mod tests {
             "#).unwrap(),
             ServerConfig {
                 addrs: vec![
-                    ServerAddr::Unix("/path/to/sock".to_string()),
+                    ServerAddr::Unix("/path/to/sock".to_string()).into(),
                 ],
                 workers: ServerConfig::default_workers(),
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: Default::default(),
             });
 
         assert_eq!(
@@ -328,12 +1565,67 @@ mod tests {
             "#).unwrap(),
             ServerConfig {
                 addrs: vec![
-                    ServerAddr::Http("0.0.0.0:40772".to_string()),
-                    ServerAddr::Unix("/path/to/sock".to_string()),
+                    ServerAddr::Http("0.0.0.0:40772".to_string()).into(),
+                    ServerAddr::Unix("/path/to/sock".to_string()).into(),
+                ],
+                workers: ServerConfig::default_workers(),
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                addrs:
+                  - http: '0.0.0.0:40772'
+                    allowed-clients: [192.168.0.0/16]
+            "#).unwrap(),
+            ServerConfig {
+                addrs: vec![
+                    ServerAddrConfig {
+                        addr: ServerAddr::Http("0.0.0.0:40772".to_string()),
+                        allowed_clients: vec!["192.168.0.0/16".to_string()],
+                    },
                 ],
                 workers: ServerConfig::default_workers(),
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                auth:
+                  tokens:
+                    - token: secret
+                      groups: [epg, streaming]
+                  users:
+                    - name: admin
+                      password: pass
+                      groups: [admin]
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: AuthConfig {
+                    tokens: vec![TokenConfig {
+                        token: "secret".to_string(),
+                        groups: vec![ApiGroup::Epg, ApiGroup::Streaming],
+                    }],
+                    users: vec![UserConfig {
+                        name: "admin".to_string(),
+                        password: "pass".to_string(),
+                        groups: vec![ApiGroup::Admin],
+                    }],
+                },
             });
 
+        assert!(serde_yaml::from_str::<ServerConfig>(r#"
+            auth:
+              tokens:
+                - token: secret
+                  groups: [unknown]
+        "#).is_err());
+
         assert_eq!(
             serde_yaml::from_str::<ServerConfig>(r#"
                 workers: 2
@@ -341,6 +1633,56 @@ mod tests {
             ServerConfig {
                 addrs: ServerConfig::default_addrs(),
                 workers: 2,
+                shutdown_timeout: ServerConfig::default_shutdown_timeout(),
+                auth: Default::default(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<ServerConfig>(r#"
+                shutdown-timeout: 10
+            "#).unwrap(),
+            ServerConfig {
+                addrs: ServerConfig::default_addrs(),
+                workers: ServerConfig::default_workers(),
+                shutdown_timeout: 10,
+                auth: Default::default(),
+            });
+    }
+
+    #[test]
+    fn test_quota_config() {
+        assert!(serde_yaml::from_str::<QuotaConfig>("{}").is_err());
+
+        assert_eq!(
+            serde_yaml::from_str::<QuotaConfig>(r#"
+                name: x
+            "#).unwrap(),
+            QuotaConfig {
+                name: "x".to_string(),
+                ..Default::default()
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<QuotaConfig>(r#"
+                name: x
+                clients: [192.168.0.0/16]
+                tokens: [secret]
+                users: [user]
+                user-agent: '^EPGStation/'
+                max-priority: 1
+                default-priority: -1
+                max-streams: 2
+            "#).unwrap(),
+            QuotaConfig {
+                name: "x".to_string(),
+                clients: vec!["192.168.0.0/16".to_string()],
+                tokens: vec!["secret".to_string()],
+                users: vec!["user".to_string()],
+                user_agent: Some("^EPGStation/".to_string()),
+                user_agent_regex: None,
+                max_priority: Some(1),
+                default_priority: -1,
+                max_streams: Some(2),
             });
     }
 
@@ -421,6 +1763,7 @@ mod tests {
                                     ChannelType::SKY],
                 command: "open tuner".to_string(),
                 disabled: false,
+                process: Default::default(),
             });
 
         assert_eq!(
@@ -438,6 +1781,40 @@ mod tests {
                                     ChannelType::SKY],
                 command: "open tuner".to_string(),
                 disabled: true,
+                process: Default::default(),
+            });
+
+        let mut env = HashMap::new();
+        env.insert("LANG".to_string(), "C".to_string());
+        assert_eq!(
+            serde_yaml::from_str::<TunerConfig>(r#"
+                name: x
+                types: [GR]
+                command: open tuner
+                process:
+                  nice: 10
+                  cpu-limit: 60
+                  memory-limit: 1048576
+                  working-dir: /tmp
+                  env:
+                    LANG: C
+                  uid: 1000
+                  gid: 1000
+            "#).unwrap(),
+            TunerConfig {
+                name: "x".to_string(),
+                channel_types: vec![ChannelType::GR],
+                command: "open tuner".to_string(),
+                disabled: false,
+                process: ProcessConfig {
+                    nice: Some(10),
+                    cpu_limit: Some(60),
+                    memory_limit: Some(1048576),
+                    working_dir: Some("/tmp".to_string()),
+                    env,
+                    uid: Some(1000),
+                    gid: Some(1000),
+                },
             });
 
         assert!(
@@ -463,6 +1840,8 @@ mod tests {
                 service_filter: FiltersConfig::default_service_filter(),
                 program_filter: FiltersConfig::default_program_filter(),
                 post_filter: String::new(),
+                chains: HashMap::new(),
+                process: Default::default(),
             });
 
         assert_eq!(
@@ -474,6 +1853,8 @@ mod tests {
                 service_filter: "filter".to_string(),
                 program_filter: FiltersConfig::default_program_filter(),
                 post_filter: String::new(),
+                chains: HashMap::new(),
+                process: Default::default(),
             });
 
         assert_eq!(
@@ -485,6 +1866,8 @@ mod tests {
                 service_filter: FiltersConfig::default_service_filter(),
                 program_filter: "filter".to_string(),
                 post_filter: String::new(),
+                chains: HashMap::new(),
+                process: Default::default(),
             });
 
         assert_eq!(
@@ -496,7 +1879,150 @@ mod tests {
                 service_filter: FiltersConfig::default_service_filter(),
                 program_filter: FiltersConfig::default_program_filter(),
                 post_filter: "filter".to_string(),
+                chains: HashMap::new(),
+                process: Default::default(),
+            });
+
+        let mut chains = HashMap::new();
+        chains.insert("descramble".to_string(), FilterChainConfig {
+            commands: vec!["descramble".to_string()],
+            stage: FilterStage::Pre,
+            allowed_clients: vec![],
+        });
+        chains.insert("transcode".to_string(), FilterChainConfig {
+            commands: vec!["decode".to_string(), "encode".to_string()],
+            stage: FilterStage::Post,
+            allowed_clients: vec!["192.168.0.0/16".to_string()],
+        });
+        assert_eq!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                chains:
+                  descramble:
+                    commands: [descramble]
+                    stage: pre
+                  transcode:
+                    commands: [decode, encode]
+                    allowed-clients: [192.168.0.0/16]
+            "#).unwrap(),
+            FiltersConfig {
+                chains,
+                ..Default::default()
+            });
+
+        assert!(
+            serde_yaml::from_str::<FiltersConfig>(r#"
+                chains:
+                  invalid:
+                    stage: pre
+            "#).is_err());
+    }
+
+    #[test]
+    fn test_streaming_config() {
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>("{}").unwrap(),
+            Default::default());
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                slow-subscriber:
+                  policy: disconnect
+                  max-drops: 10
+            "#).unwrap(),
+            StreamingConfig {
+                slow_subscriber: SlowSubscriberConfig {
+                    policy: SlowSubscriberPolicy::Disconnect,
+                    queue_size: SlowSubscriberConfig::default_queue_size(),
+                    max_drops: 10,
+                    max_buffer_size:
+                        SlowSubscriberConfig::default_max_buffer_size(),
+                },
+                priming: Default::default(),
+                time_shift: Default::default(),
+                splice: false,
+                share_filter_pipelines: true,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                slow-subscriber:
+                  policy: block
+                  queue-size: 1000
+                  max-buffer-size: 1024
+            "#).unwrap(),
+            StreamingConfig {
+                slow_subscriber: SlowSubscriberConfig {
+                    policy: SlowSubscriberPolicy::Block,
+                    queue_size: 1000,
+                    max_drops: 0,
+                    max_buffer_size: 1024,
+                },
+                priming: Default::default(),
+                time_shift: Default::default(),
+                splice: false,
+                share_filter_pipelines: true,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                priming:
+                  psi: true
+                  buffer-secs: 2
+            "#).unwrap(),
+            StreamingConfig {
+                slow_subscriber: Default::default(),
+                priming: PrimingConfig {
+                    psi: true,
+                    buffer_secs: 2,
+                },
+                time_shift: Default::default(),
+                splice: false,
+                share_filter_pipelines: true,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                time-shift:
+                  buffer-minutes: 30
+                  dir: /tmp
+            "#).unwrap(),
+            StreamingConfig {
+                slow_subscriber: Default::default(),
+                priming: Default::default(),
+                time_shift: TimeShiftConfig {
+                    buffer_minutes: 30,
+                    dir: Some("/tmp".to_string()),
+                },
+                splice: false,
+                share_filter_pipelines: true,
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                splice: true
+            "#).unwrap(),
+            StreamingConfig {
+                slow_subscriber: Default::default(),
+                priming: Default::default(),
+                time_shift: Default::default(),
+                splice: true,
+                share_filter_pipelines: true,
             });
+
+        assert_eq!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                share-filter-pipelines: false
+            "#).unwrap(),
+            StreamingConfig {
+                share_filter_pipelines: false,
+                ..Default::default()
+            });
+
+        assert!(
+            serde_yaml::from_str::<StreamingConfig>(r#"
+                slow-subscriber:
+                  policy: wait
+            "#).is_err());
     }
 
     #[test]
@@ -515,9 +2041,11 @@ mod tests {
                 scan_services: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    process: Default::default(),
                 },
                 sync_clocks: JobsConfig::default_sync_clocks(),
                 update_schedules: JobsConfig::default_update_schedules(),
+                collect_logos: JobsConfig::default_collect_logos(),
             });
 
         assert_eq!(
@@ -531,8 +2059,10 @@ mod tests {
                 sync_clocks: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    process: Default::default(),
                 },
                 update_schedules: JobsConfig::default_update_schedules(),
+                collect_logos: JobsConfig::default_collect_logos(),
             });
 
         assert_eq!(
@@ -547,6 +2077,26 @@ mod tests {
                 update_schedules: JobConfig {
                     command: "job".to_string(),
                     schedule: "*".to_string(),
+                    process: Default::default(),
+                },
+                collect_logos: JobsConfig::default_collect_logos(),
+            });
+
+        assert_eq!(
+            serde_yaml::from_str::<JobsConfig>(r#"
+                collect-logos:
+                  schedule: '*'
+                  timeout: 60
+                  disabled: true
+            "#).unwrap(),
+            JobsConfig {
+                scan_services: JobsConfig::default_scan_services(),
+                sync_clocks: JobsConfig::default_sync_clocks(),
+                update_schedules: JobsConfig::default_update_schedules(),
+                collect_logos: CollectLogosJobConfig {
+                    schedule: "*".to_string(),
+                    timeout: 60,
+                    disabled: true,
                 },
             });
     }
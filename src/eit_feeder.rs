@@ -10,7 +10,7 @@ use serde_json;
 use tokio::prelude::*;
 use tokio::io::BufReader;
 
-use crate::config::Config;
+use crate::config::{Config, JobConfig, ProcessConfig};
 use crate::datetime_ext::*;
 use crate::error::Error;
 use crate::epg::{self, *};
@@ -23,6 +23,18 @@ pub fn start(config: Arc<Config>) {
     actix::registry::SystemRegistry::set(addr);
 }
 
+pub async fn reload(config: Arc<Config>) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = config;
+            Ok(())
+        } else {
+            Ok(EitFeeder::from_registry()
+               .send(ReloadMessage { config }).await?)
+        }
+    }
+}
+
 pub async fn feed_eit_sections() -> Result<(), Error> {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
@@ -43,7 +55,7 @@ impl EitFeeder {
     }
 
     async fn feed_eit_sections(
-        command: String
+        config: JobConfig
     ) -> Result<(), Error> {
         let services = epg::query_services().await?;
 
@@ -61,7 +73,7 @@ impl EitFeeder {
         }
         let channels = map.values().cloned().collect();
 
-        EitCollector::new(command, channels)
+        EitCollector::new(config.command, config.process, channels)
             .collect_schedules().await
     }
 }
@@ -87,6 +99,35 @@ impl Default for EitFeeder {
     }
 }
 
+// reload
+
+struct ReloadMessage {
+    config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reload")
+    }
+}
+
+impl Message for ReloadMessage {
+    type Result = ();
+}
+
+impl Handler<ReloadMessage> for EitFeeder {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.config = msg.config;
+    }
+}
+
 // feed eit sections
 
 struct FeedEitSectionsMessage;
@@ -111,7 +152,7 @@ impl Handler<FeedEitSectionsMessage> for EitFeeder {
     ) -> Self::Result {
         log::debug!("{}", msg);
         let fut = Box::pin(Self::feed_eit_sections(
-            self.config.jobs.update_schedules.command.clone()));
+            self.config.jobs.update_schedules.clone()));
         Response::fut(fut)
     }
 }
@@ -120,6 +161,7 @@ impl Handler<FeedEitSectionsMessage> for EitFeeder {
 
 pub struct EitCollector {
     command: String,
+    process: ProcessConfig,
     channels: Vec<EpgChannel>,
 }
 
@@ -132,9 +174,10 @@ impl EitCollector {
 
     pub fn new(
         command: String,
-        channels: Vec<EpgChannel>
+        process: ProcessConfig,
+        channels: Vec<EpgChannel>,
     ) -> Self {
-        EitCollector { command, channels }
+        EitCollector { command, process, channels }
     }
 
     pub async fn collect_schedules(
@@ -143,8 +186,8 @@ impl EitCollector {
         log::info!("Collecting EIT sections...");
         let mut num_sections = 0;
         for channel in self.channels.iter() {
-            num_sections +=
-                Self::collect_eits_in_channel(&channel, &self.command).await?;
+            num_sections += Self::collect_eits_in_channel(
+                &channel, &self.command, &self.process).await?;
         }
         log::info!("Collected {} EIT sections", num_sections);
         Ok(())
@@ -153,6 +196,7 @@ impl EitCollector {
     async fn collect_eits_in_channel(
         channel: &EpgChannel,
         command: &str,
+        process: &ProcessConfig,
     ) -> Result<usize, Error> {
         log::debug!("Collecting EIT sections in {}...", channel.name);
 
@@ -162,7 +206,8 @@ impl EitCollector {
         };
 
         let stream = tuner::start_streaming(
-            channel.channel_type, channel.channel.clone(), user).await?;
+            channel.channel_type, channel.channel.clone(), user, None,
+            None).await?;
 
         let template = mustache::compile_str(command)?;
         let data = mustache::MapBuilder::new()
@@ -172,7 +217,7 @@ impl EitCollector {
         let cmd = template.render_data_to_string(&data)?;
 
         let (input, output) = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+            vec![cmd], stream.id(), process)?;
 
         let handle = tokio::spawn(stream.pipe(input));
 
@@ -1,9 +1,9 @@
 use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::iter::FromIterator;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use actix::prelude::*;
@@ -12,6 +12,7 @@ use indexmap::IndexMap;
 use log;
 use serde::{Deserialize, Serialize};
 
+use crate::cdt::Logo;
 use crate::config::{Config, ChannelConfig};
 use crate::datetime_ext::*;
 use crate::eit_feeder::*;
@@ -32,6 +33,32 @@ pub fn start(config: Arc<Config>) {
     actix::registry::SystemRegistry::set(addr);
 }
 
+// Starts without invoking jobs.  Used in subcommands which don't start the
+// server.
+pub fn start_offline(config: Arc<Config>) {
+    let addr = Epg::start_in_arbiter(&Arbiter::new(), |_| Epg {
+        offline: true,
+        ..Epg::new(config)
+    });
+    actix::registry::SystemRegistry::set(addr);
+}
+
+// Loads programs from schedules.json in `epg.cache-dir` without starting Epg.
+pub fn load_programs(config: Arc<Config>) -> Result<Vec<EpgProgram>, Error> {
+    let mut epg = Epg::new(config);
+    epg.load_schedules()?;
+    epg.collect_programs();
+    let mut programs: Vec<EpgProgram> = epg.schedules
+        .values()
+        .flat_map(|schedule| schedule.programs.values().cloned())
+        .collect();
+    programs.sort_by_key(|program| {
+        (program.quad.nid().value(), program.quad.sid().value(),
+         program.start_at)
+    });
+    Ok(programs)
+}
+
 pub async fn query_channels() -> Result<Vec<MirakurunChannel>, Error> {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
@@ -103,6 +130,7 @@ pub async fn query_service_by_nid_sid(
                         services: Vec::new(),
                         excluded_services: Vec::new(),
                     },
+                    has_logo_data: false,
                 }),
             }
         } else {
@@ -169,6 +197,61 @@ pub fn update_services(services: Vec<EpgService>) {
     }
 }
 
+// Saves logos into `epg.cache-dir`.  Logos not included are kept.
+pub fn update_logos(logos: Vec<Logo>) {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = logos;
+        } else {
+            Epg::from_registry().do_send(UpdateLogosMessage { logos });
+        }
+    }
+}
+
+// Returns the path to a PNG file of the logo of the service.
+pub async fn query_logo_path(
+    nid: NetworkId,
+    sid: ServiceId,
+) -> Result<PathBuf, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            // for avoiding warn(dead_code)
+            let _ = QueryLogoPathMessage { nid, sid };
+            match sid.value() {
+                0 => Err(Error::ServiceNotFound),
+                1 => Err(Error::LogoNotFound),
+                _ => Ok(PathBuf::from("/dev/null")),
+            }
+        } else {
+            Epg::from_registry().send(QueryLogoPathMessage {
+                nid, sid
+            }).await?
+        }
+    }
+}
+
+pub async fn query_relocations() -> Result<Vec<ServiceRelocation>, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(Vec::new())
+        } else {
+            Epg::from_registry().send(QueryRelocationsMessage).await?
+        }
+    }
+}
+
+pub fn update_relocations(relocations: Vec<ServiceRelocation>) {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = relocations;
+        } else {
+            Epg::from_registry().do_send(UpdateRelocationsMessage {
+                relocations
+            });
+        }
+    }
+}
+
 pub fn update_clocks(clocks: HashMap<ServiceTriple, Clock>) {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
@@ -210,6 +293,30 @@ pub fn save_schedules() {
     }
 }
 
+// Services are rescanned if channels are changed.
+pub async fn reload(config: Arc<Config>) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = config;
+            Ok(())
+        } else {
+            Ok(Epg::from_registry()
+               .send(ReloadMessage { config }).await?)
+        }
+    }
+}
+
+// Saves services, clocks and schedules, and waits for the completion.
+pub async fn save() -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(())
+        } else {
+            Ok(Epg::from_registry().send(SaveMessage).await?)
+        }
+    }
+}
+
 pub fn update_airtime(
     quad: EventQuad,
     start_time: DateTime<Jst>,
@@ -241,10 +348,17 @@ pub fn remove_airtime(quad: EventQuad) {
 
 struct Epg {
     config: Arc<Config>,
+    // Jobs are not invoked if true.
+    offline: bool,
     services: Vec<EpgService>,
+    // Services found on transponders other than the ones in the config in
+    // the last scan.
+    relocations: Vec<ServiceRelocation>,
     clocks: HashMap<ServiceTriple, Clock>,
     schedules: HashMap<ServiceTriple, EpgSchedule>,
     airtimes: HashMap<EventQuad, Airtime>,
+    // Logos saved in `epg.cache-dir`.
+    logos: HashSet<(NetworkId, u16)>,
 }
 
 struct Airtime {
@@ -256,10 +370,13 @@ impl Epg {
     fn new(config: Arc<Config>) -> Self {
         Epg {
             config,
+            offline: false,
             services: Vec::new(),
+            relocations: Vec::new(),
             clocks: HashMap::new(),
             schedules: HashMap::new(),
             airtimes: HashMap::new(),
+            logos: HashSet::new(),
         }
     }
 
@@ -272,6 +389,7 @@ impl Epg {
 
     fn update_services(&mut self, services: Vec<EpgService>) {
         self.services = services;
+        self.update_logo_flags();
         match self.save_services() {
             Ok(_) => (),
             Err(err) => log::error!("Failed to save services: {}", err),
@@ -316,6 +434,40 @@ impl Epg {
         }
     }
 
+    fn update_logos(&mut self, logos: Vec<Logo>) {
+        match self.save_logos(logos) {
+            Ok(_) => (),
+            Err(err) => log::error!("Failed to save logos: {}", err),
+        }
+        self.update_logo_flags();
+    }
+
+    fn update_logo_flags(&mut self) {
+        for sv in self.services.iter_mut() {
+            sv.has_logo_data = sv.logo_id >= 0 &&
+                self.logos.contains(&(sv.nid, sv.logo_id as u16));
+        }
+    }
+
+    fn find_logo_path(
+        &self,
+        nid: NetworkId,
+        sid: ServiceId,
+    ) -> Result<PathBuf, Error> {
+        let service = self.services
+            .iter()
+            .find(|sv| sv.nid == nid && sv.sid == sid)
+            .ok_or(Error::ServiceNotFound)?;
+        if !service.has_logo_data {
+            return Err(Error::LogoNotFound);
+        }
+        match self.config.epg.cache_dir {
+            Some(ref cache_dir) => Ok(PathBuf::from(cache_dir).join("logos")
+                .join(logo_filename(nid, service.logo_id as u16))),
+            None => Err(Error::LogoNotFound),
+        }
+    }
+
     fn prepare_schedules(&mut self, timestamp: DateTime<Jst>) {
         let mut unused_ids: HashSet<_> =
             HashSet::from_iter(self.schedules.keys().cloned());
@@ -394,6 +546,38 @@ impl Epg {
         Ok(())
     }
 
+    fn load_logos(&mut self) -> Result<(), Error> {
+        match self.config.epg.cache_dir {
+            Some(ref cache_dir) => {
+                let dir = PathBuf::from(cache_dir).join("logos");
+                log::debug!("Loading logos from {}...", dir.display());
+                self.logos.clear();
+                let entries = match fs::read_dir(&dir) {
+                    Ok(entries) => entries,
+                    // The directory is created when the first logo is saved.
+                    Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                        log::info!("No logos collected yet");
+                        self.update_logo_flags();
+                        return Ok(());
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+                for entry in entries {
+                    let path = entry?.path();
+                    if let Some(key) = parse_logo_filename(&path) {
+                        self.logos.insert(key);
+                    }
+                }
+                self.update_logo_flags();
+                log::info!("Loaded {} logos", self.logos.len());
+            }
+            None => {
+                log::warn!("No epg.cache-dir specified, skip to load logos");
+            }
+        }
+        Ok(())
+    }
+
     fn need_scaning_services(&self) -> bool {
         match (self.services.is_empty(), &self.config.epg.cache_dir) {
             (false, Some(ref cache_dir)) => {
@@ -419,8 +603,7 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("services.json");
                 log::debug!("Saving services into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.services)?;
+                save_json(&json_path, &self.services)?;
                 log::info!("Saved {} services", self.services.len());
             }
             None => {
@@ -435,8 +618,7 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("clocks.json");
                 log::debug!("Saving clocks into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.clocks)?;
+                save_json(&json_path, &self.clocks)?;
                 log::info!("Saved {} clocks", self.clocks.len());
             }
             None => {
@@ -451,8 +633,7 @@ impl Epg {
             Some(ref cache_dir) => {
                 let json_path = PathBuf::from(cache_dir).join("schedules.json");
                 log::debug!("Saving schedules into {}...", json_path.display());
-                let writer = BufWriter::new(File::create(&json_path)?);
-                serde_json::to_writer(writer, &self.schedules)?;
+                save_json(&json_path, &self.schedules)?;
                 log::info!("Saved schedules for {} services",
                            self.schedules.len());
             }
@@ -464,11 +645,95 @@ impl Epg {
         Ok(())
     }
 
+    fn save_logos(&mut self, logos: Vec<Logo>) -> Result<(), Error> {
+        match self.config.epg.cache_dir {
+            Some(ref cache_dir) => {
+                let dir = PathBuf::from(cache_dir).join("logos");
+                log::debug!("Saving logos into {}...", dir.display());
+                fs::create_dir_all(&dir)?;
+                let num_logos = logos.len();
+                for logo in logos.into_iter() {
+                    let path = dir.join(logo_filename(logo.nid, logo.logo_id));
+                    save_file(&path, &logo.data)?;
+                    self.logos.insert((logo.nid, logo.logo_id));
+                }
+                log::info!("Saved {} logos", num_logos);
+            }
+            None => {
+                log::warn!("No epg.cache-dir specified, skip to save logos");
+            }
+        }
+        Ok(())
+    }
+
     fn collect_programs(&mut self) {
         for schedule in self.schedules.values_mut() {
             schedule.collect_programs();
         }
     }
+
+    fn reload(&mut self, config: Arc<Config>) {
+        let channels_changed = config.channels != self.config.channels;
+        self.config = config;
+        if channels_changed {
+            log::info!("Channels changed, scan services");
+            job::invoke_scan_services();
+        }
+    }
+
+    fn save(&self) {
+        if let Err(err) = self.save_services() {
+            log::error!("Failed to save services: {}", err);
+        }
+        if let Err(err) = self.save_clocks() {
+            log::error!("Failed to save clocks: {}", err);
+        }
+        if let Err(err) = self.save_schedules() {
+            log::error!("Failed to save schedules: {}", err);
+        }
+    }
+}
+
+// Writes into a temporary file, then renames it so that a cache file is never
+// left half-written when mirakc is killed while saving.
+fn save_json<T>(json_path: &Path, value: &T) -> Result<(), Error>
+where
+    T: Serialize,
+{
+    let tmp_path = json_path.with_extension("json.tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, json_path)?;
+    Ok(())
+}
+
+// Same as `save_json()`, but writes raw data.
+fn save_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+// Logos are saved as `{nid}-{logo_id}.png`.  A logo ID is unique only in
+// a network.
+fn logo_filename(nid: NetworkId, logo_id: u16) -> String {
+    format!("{}-{}.png", nid.value(), logo_id)
+}
+
+fn parse_logo_filename(path: &Path) -> Option<(NetworkId, u16)> {
+    if path.extension()? != "png" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let pos = stem.find('-')?;
+    let nid = stem[..pos].parse::<u16>().ok()?;
+    let logo_id = stem[pos + 1..].parse::<u16>().ok()?;
+    Some((nid.into(), logo_id))
 }
 
 impl Actor for Epg {
@@ -479,23 +744,33 @@ impl Actor for Epg {
         if let Err(err) = self.load_services() {
             log::error!("Failed to load services: {}", err);
         }
-        if self.need_scaning_services() {
+        if !self.offline && self.need_scaning_services() {
             log::info!("Scan services immediately");
             job::invoke_scan_services();
         }
         if let Err(err) = self.load_clocks() {
             log::error!("Failed to load clocks: {}", err);
         }
-        if self.need_synchronizing_clocks() {
+        if !self.offline && self.need_synchronizing_clocks() {
             log::info!("Synchronize clocks immediately");
             job::invoke_sync_clocks();
         }
+        if let Err(err) = self.load_logos() {
+            log::error!("Failed to load logos: {}", err);
+        }
+        if !self.offline && self.config.epg.cache_dir.is_some() &&
+            self.logos.is_empty() {
+            log::info!("Collect logos immediately");
+            job::invoke_collect_logos();
+        }
         if let Err(err) = self.load_schedules() {
             log::error!("Failed to load schedules: {}", err);
         }
         self.collect_programs();
-        log::info!("Always update schedules at startup");
-        job::invoke_update_schedules();
+        if !self.offline {
+            log::info!("Always update schedules at startup");
+            job::invoke_update_schedules();
+        }
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -807,6 +1082,122 @@ impl Handler<UpdateServicesMessage> for Epg {
     }
 }
 
+// query relocations
+
+struct QueryRelocationsMessage;
+
+impl fmt::Display for QueryRelocationsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryRelocations")
+    }
+}
+
+impl Message for QueryRelocationsMessage {
+    type Result = Result<Vec<ServiceRelocation>, Error>;
+}
+
+impl Handler<QueryRelocationsMessage> for Epg {
+    type Result = Result<Vec<ServiceRelocation>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryRelocationsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        Ok(self.relocations.clone())
+    }
+}
+
+// update relocations
+
+struct UpdateRelocationsMessage {
+    relocations: Vec<ServiceRelocation>,
+}
+
+impl fmt::Display for UpdateRelocationsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateRelocations with {} relocations",
+               self.relocations.len())
+    }
+}
+
+impl Message for UpdateRelocationsMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateRelocationsMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateRelocationsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.relocations = msg.relocations;
+    }
+}
+
+// update logos
+
+struct UpdateLogosMessage {
+    logos: Vec<Logo>,
+}
+
+impl fmt::Display for UpdateLogosMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UpdateLogos with {} logos", self.logos.len())
+    }
+}
+
+impl Message for UpdateLogosMessage {
+    type Result = ();
+}
+
+impl Handler<UpdateLogosMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: UpdateLogosMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.update_logos(msg.logos);
+    }
+}
+
+// query logo path
+
+struct QueryLogoPathMessage {
+    nid: NetworkId,
+    sid: ServiceId,
+}
+
+impl fmt::Display for QueryLogoPathMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryLogoPath by ({}, {})", self.nid, self.sid)
+    }
+}
+
+impl Message for QueryLogoPathMessage {
+    type Result = Result<PathBuf, Error>;
+}
+
+impl Handler<QueryLogoPathMessage> for Epg {
+    type Result = Result<PathBuf, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryLogoPathMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.find_logo_path(msg.nid, msg.sid)
+    }
+}
+
 // update clocks
 
 struct UpdateClocksMessage {
@@ -928,6 +1319,62 @@ impl Handler<SaveSchedulesMessage> for Epg {
     }
 }
 
+// reload
+
+struct ReloadMessage {
+    config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reload")
+    }
+}
+
+impl Message for ReloadMessage {
+    type Result = ();
+}
+
+impl Handler<ReloadMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.reload(msg.config);
+    }
+}
+
+// save
+
+struct SaveMessage;
+
+impl fmt::Display for SaveMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Save")
+    }
+}
+
+impl Message for SaveMessage {
+    type Result = ();
+}
+
+impl Handler<SaveMessage> for Epg {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SaveMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.save();
+    }
+}
+
 // update airtime
 
 struct UpdateAirtimeMessage {
@@ -1237,6 +1684,9 @@ pub struct EpgService {
     pub remote_control_key_id: u16,
     pub name: String,
     pub channel: EpgChannel,
+    // Not saved in services.json.  Updated with logos in `epg.cache-dir`.
+    #[serde(skip)]
+    pub has_logo_data: bool,
 }
 
 impl EpgService {
@@ -1268,10 +1718,36 @@ impl From<(&EpgChannel, &TsService)> for EpgService {
             remote_control_key_id: sv.remote_control_key_id,
             name: sv.name.clone(),
             channel: ch.clone(),
+            has_logo_data: false,
         }
     }
 }
 
+// A service which has moved to a transponder or a slot other than the one
+// defined in the config.  Detected by using the NIT.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceRelocation {
+    pub nid: NetworkId,
+    pub sid: ServiceId,
+    pub name: String,
+    #[serde(rename = "type")]
+    pub channel_type: ChannelType,
+    // The name of the channel in the config.
+    pub channel_name: String,
+    pub from: String,
+    pub to: String,
+    pub tsid: TransportStreamId,
+}
+
+impl fmt::Display for ServiceRelocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}) in {} has moved from {} {} to {} {}",
+               self.name, self.sid, self.channel_name,
+               self.channel_type, self.from, self.channel_type, self.to)
+    }
+}
+
 impl Into<MirakurunChannelService> for EpgService {
     fn into(self) -> MirakurunChannelService {
         MirakurunChannelService {
@@ -1298,7 +1774,7 @@ pub struct EpgProgram {
 }
 
 impl EpgProgram {
-    fn new(quad: EventQuad) -> Self {
+    pub fn new(quad: EventQuad) -> Self {
         Self {
             quad: quad,
             start_at: Jst.timestamp(0, 0),
@@ -1355,6 +1831,7 @@ impl EpgProgram {
 mod tests {
     use super::*;
     use chrono::{Date, TimeZone};
+    use matches::assert_matches;
 
     #[test]
     fn test_epg_service_is_exportable() {
@@ -1614,6 +2091,74 @@ mod tests {
             Jst.ymd(2019, 10, 15).and_hms(0, 0, 0)));
     }
 
+    #[test]
+    fn test_save_json() {
+        let json_path = std::env::temp_dir().join(
+            format!("mirakc-test-save-json-{}.json", std::process::id()));
+        save_json(&json_path, &vec![1, 2]).unwrap();
+        assert_eq!(fs::read_to_string(&json_path).unwrap(), "[1,2]");
+        assert!(!json_path.with_extension("json.tmp").exists());
+        fs::remove_file(&json_path).unwrap();
+    }
+
+    #[test]
+    fn test_update_logos() {
+        let cache_dir = std::env::temp_dir().join(
+            format!("mirakc-test-update-logos-{}", std::process::id()));
+        let mut config = Config::default();
+        config.epg.cache_dir = Some(cache_dir.to_str().unwrap().to_string());
+        let config = Arc::new(config);
+
+        let services = vec![
+            create_epg_service((1, 2, 3).into(), ChannelType::GR),
+            create_epg_service((1, 2, 4).into(), ChannelType::GR),
+        ];
+
+        let mut epg = Epg::new(config.clone());
+        epg.services = services.clone();
+        epg.services[1].logo_id = -1;
+        epg.update_logos(vec![Logo {
+            nid: 1.into(),
+            logo_id: 0,
+            logo_type: 5,
+            version: 0,
+            data: b"png".to_vec(),
+        }]);
+        assert!(epg.services[0].has_logo_data);
+        assert!(!epg.services[1].has_logo_data);
+        let path = epg.find_logo_path(1.into(), 3.into()).unwrap();
+        assert_eq!(path, cache_dir.join("logos").join("1-0.png"));
+        assert_eq!(fs::read(&path).unwrap(), b"png");
+        assert!(!path.with_extension("tmp").exists());
+        assert_matches!(epg.find_logo_path(1.into(), 4.into()),
+                        Err(Error::LogoNotFound));
+        assert_matches!(epg.find_logo_path(1.into(), 5.into()),
+                        Err(Error::ServiceNotFound));
+
+        let mut epg = Epg::new(config.clone());
+        epg.services = services.clone();
+        epg.load_logos().unwrap();
+        assert!(epg.services[0].has_logo_data);
+
+        // No logos have been collected.
+        fs::remove_dir_all(cache_dir.join("logos")).unwrap();
+        let mut epg = Epg::new(config.clone());
+        epg.services = services;
+        epg.load_logos().unwrap();
+        assert!(!epg.services[0].has_logo_data);
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_logo_filename() {
+        assert_eq!(parse_logo_filename(Path::new("/logos/1-2.png")),
+                   Some((1.into(), 2)));
+        assert_eq!(parse_logo_filename(Path::new("/logos/1-2.tmp")), None);
+        assert_eq!(parse_logo_filename(Path::new("/logos/1.png")), None);
+        assert_eq!(parse_logo_filename(Path::new("/logos/x-2.png")), None);
+    }
+
     fn create_epg_service(
         triple: ServiceTriple,
         channel_type: ChannelType
@@ -1632,7 +2177,8 @@ mod tests {
                 channel: "ch".to_string(),
                 services: Vec::new(),
                 excluded_services: Vec::new(),
-            }
+            },
+            has_logo_data: false,
         }
     }
 
@@ -13,16 +13,38 @@ use crate::command_util;
 pub enum Error {
     #[fail(display = "Tuner unavailable")]
     TunerUnavailable,
+    #[fail(display = "Tuner not found")]
+    TunerNotFound,
+    #[fail(display = "Shutting down")]
+    ShuttingDown,
+    #[fail(display = "Job already running")]
+    JobAlreadyRunning,
     #[fail(display = "Channel not found")]
     ChannelNotFound,
     #[fail(display = "Service not found")]
     ServiceNotFound,
+    #[fail(display = "Logo not found")]
+    LogoNotFound,
     #[fail(display = "Clock not synced")]
     ClockNotSynced,
     #[fail(display = "Program not found")]
     ProgramNotFound,
     #[fail(display = "Session not found")]
     SessionNotFound,
+    #[fail(display = "Stream not found")]
+    StreamNotFound,
+    #[fail(display = "Stream in use")]
+    StreamInUse,
+    #[fail(display = "Filter chain not found: {}", 0)]
+    FilterChainNotFound(String),
+    #[fail(display = "Filter chain not allowed: {}", 0)]
+    FilterChainNotAllowed(String),
+    #[fail(display = "Unauthorized")]
+    Unauthorized,
+    #[fail(display = "Access denied")]
+    AccessDenied,
+    #[fail(display = "Quota exceeded: {}", 0)]
+    QuotaExceeded(String),
     #[fail(display = "Command failed: {}", 0)]
     CommandFailed(command_util::Error),
     #[fail(display = "std::io::error: {}", 0)]
@@ -31,6 +53,8 @@ pub enum Error {
     JsonError(serde_json::Error),
     #[fail(display = "YAML error: {}", 0)]
     YamlError(serde_yaml::Error),
+    #[fail(display = "Invalid config: {}", 0)]
+    InvalidConfig(String),
     #[fail(display = "Mailbox error: {}", 0)]
     MailboxError(actix::MailboxError),
     #[fail(display = "Mustache error: {}", 0)]
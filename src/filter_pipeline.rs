@@ -0,0 +1,567 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use actix::prelude::*;
+use futures::future::{self, AbortHandle};
+use futures::stream::StreamExt;
+
+use crate::broadcaster::*;
+use crate::command_util;
+use crate::config::{Config, SlowSubscriberConfig, StreamingConfig};
+use crate::error::Error;
+use crate::mpeg_ts_stream::*;
+use crate::tuner::{self, TunerSessionId};
+
+// Filter pipelines are shared among streams which have the same filter
+// commands on the same tuner session.
+//
+// The output of a shared filter pipeline is broadcasted to the streams.  So,
+// N streams of a service cost only one set of filter processes.
+//
+// The tuner subscription of the stream which created the shared pipeline is
+// used as the input of the pipeline, and kept until all streams using the
+// pipeline are closed.  Each of other streams keeps its own tuner
+// subscription so that it's counted in quotas and listed in /api/streams.
+// TS packets from the subscription are discarded.
+
+pub fn start(config: Arc<Config>) {
+    let addr = FilterPipelineManager::new(config).start();
+    actix::registry::SystemRegistry::set(addr);
+}
+
+// Existing pipelines are kept.  New settings are applied to pipelines created
+// after reloading.
+pub async fn reload(config: Arc<Config>) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = config;
+            Ok(())
+        } else {
+            Ok(FilterPipelineManager::from_registry()
+               .send(ReloadMessage { config }).await?)
+        }
+    }
+}
+
+// Returns a stream which outputs TS packets processed by `filters`.
+//
+// The output of `filters` must be TS packets.
+pub async fn start_streaming(
+    stream: MpegTsStream,
+    filters: Vec<String>,
+    slow_subscriber: SlowSubscriberConfig,
+) -> Result<MpegTsStream, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = (filters, slow_subscriber);
+            Ok(stream)
+        } else {
+            FilterPipelineManager::from_registry()
+                .send(StartStreamingMessage {
+                    stream, filters, slow_subscriber
+                }).await?
+        }
+    }
+}
+
+// Ends a stream using a shared pipeline.  Returns `Error::StreamNotFound` if
+// the stream doesn't use any shared pipeline, or `Error::StreamInUse` if the
+// stream has already ended but its tuner subscription still feeds a shared
+// pipeline.
+pub async fn kill_stream(id: MpegTsStreamId) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = id;
+            Err(Error::StreamNotFound)
+        } else {
+            FilterPipelineManager::from_registry()
+                .send(KillStreamMessage { id }).await?
+        }
+    }
+}
+
+pub fn stop_streaming(id: MpegTsStreamId) {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = id;
+        } else {
+            FilterPipelineManager::from_registry().do_send(
+                StopStreamingMessage { id });
+        }
+    }
+}
+
+// filter pipeline manager
+
+type SharedPipelineKey = (TunerSessionId, Vec<String>);
+
+struct SharedPipeline {
+    broadcaster: Addr<Broadcaster>,
+    streams: Vec<MpegTsStreamId>,
+    // Tasks keeping tuner subscriptions of streams which joined the pipeline.
+    joiners: Vec<(MpegTsStreamId, AbortHandle)>,
+    // The stream which created the pipeline.  Its tuner subscription is kept
+    // for the input of the pipeline until the pipeline is dropped, even after
+    // the stream ends.
+    creator: MpegTsStreamId,
+    // Closes the tuner subscription used for the input of the pipeline when
+    // dropped.
+    _stop_trigger: Option<MpegTsStreamStopTrigger>,
+}
+
+impl SharedPipeline {
+    fn remove_joiner(&mut self, id: MpegTsStreamId) {
+        if let Some(pos) = self.joiners.iter().position(|(i, _)| *i == id) {
+            // Dropping the stream closes the tuner subscription.
+            let (_, handle) = self.joiners.remove(pos);
+            handle.abort();
+        }
+    }
+}
+
+impl Drop for SharedPipeline {
+    fn drop(&mut self) {
+        for (_, handle) in self.joiners.iter() {
+            handle.abort();
+        }
+    }
+}
+
+struct FilterPipelineManager {
+    config: Arc<Config>,
+    pipelines: HashMap<SharedPipelineKey, SharedPipeline>,
+}
+
+impl FilterPipelineManager {
+    fn new(config: Arc<Config>) -> Self {
+        FilterPipelineManager { config, pipelines: HashMap::new() }
+    }
+
+    fn create_pipeline(
+        &mut self,
+        key: SharedPipelineKey,
+        mut stream: MpegTsStream,
+    ) -> Result<Addr<Broadcaster>, Error> {
+        let id = stream.id();
+        let stop_trigger = stream.take_stop_trigger();
+        let (input, output) = command_util::spawn_pipeline(
+            key.1.clone(), id, &self.config.filters.process)?;
+        if self.config.streaming.splice {
+            tuner::splice_streaming(stream, input);
+        } else {
+            actix::spawn(stream.pipe(input));
+        }
+
+        // The time-shift buffer is kept in the broadcaster of the tuner
+        // session.
+        let config = StreamingConfig {
+            time_shift: Default::default(),
+            ..self.config.streaming.clone()
+        };
+        let session_id = key.0;
+        let broadcaster = Broadcaster::create(move |ctx| {
+            let mut broadcaster =
+                Broadcaster::new(session_id, output, &config, ctx);
+            broadcaster.set_stop_fn(stop_streaming);
+            broadcaster
+        });
+
+        log::info!("{}: Created a shared filter pipeline", id);
+
+        self.pipelines.insert(key, SharedPipeline {
+            broadcaster: broadcaster.clone(),
+            streams: vec![id],
+            joiners: Vec::new(),
+            creator: id,
+            _stop_trigger: stop_trigger,
+        });
+
+        Ok(broadcaster)
+    }
+
+    fn find_pipeline(
+        &mut self,
+        key: &SharedPipelineKey,
+    ) -> Option<&mut SharedPipeline> {
+        let connected = match self.pipelines.get(key) {
+            Some(pipeline) => pipeline.broadcaster.connected(),
+            None => return None,
+        };
+        if !connected {
+            // The pipeline has been broken.
+            self.pipelines.remove(key);
+            return None;
+        }
+        self.pipelines.get_mut(key)
+    }
+
+    fn stop_streaming(&mut self, id: MpegTsStreamId) {
+        let found = self.pipelines
+            .iter_mut()
+            .find(|(_, pipeline)| pipeline.streams.contains(&id));
+        let key = match found {
+            Some((key, pipeline)) => {
+                pipeline.broadcaster.do_send(UnsubscribeMessage { id });
+                pipeline.streams.retain(|stream_id| *stream_id != id);
+                pipeline.remove_joiner(id);
+                if !pipeline.streams.is_empty() {
+                    return;
+                }
+                key.clone()
+            }
+            None => {
+                // Already stopped by `kill_stream()`.
+                log::debug!("{}: No shared filter pipeline found", id);
+                return;
+            }
+        };
+        log::info!("{}: Removed a shared filter pipeline", id);
+        self.pipelines.remove(&key);
+    }
+
+    fn kill_stream(&mut self, id: MpegTsStreamId) -> Result<(), Error> {
+        let found = self.pipelines
+            .values()
+            .any(|pipeline| pipeline.streams.contains(&id));
+        if !found {
+            let feeding = self.pipelines
+                .values()
+                .any(|pipeline| pipeline.creator == id);
+            if feeding {
+                // Killing the tuner subscription breaks the pipeline.
+                log::warn!("{}: Feeding a shared filter pipeline", id);
+                return Err(Error::StreamInUse);
+            }
+            return Err(Error::StreamNotFound);
+        }
+        log::info!("{}: Kill the stream", id);
+        // The broadcaster closes the stream of the subscriber.
+        self.stop_streaming(id);
+        Ok(())
+    }
+}
+
+// Reads and discards TS packets in order to keep the tuner subscription of
+// a stream joining a shared pipeline.  The stream is stopped when the tuner
+// subscription ends.
+fn keep_subscription(
+    stream: MpegTsStream,
+    manager: Addr<FilterPipelineManager>,
+) -> AbortHandle {
+    let id = stream.id();
+    let (fut, handle) = future::abortable(async move {
+        stream.for_each(|_| future::ready(())).await;
+        log::debug!("{}: Tuner subscription ended", id);
+        manager.do_send(StopStreamingMessage { id });
+    });
+    actix::spawn(async move {
+        let _ = fut.await;
+    });
+    handle
+}
+
+impl Actor for FilterPipelineManager {
+    type Context = actix::Context<Self>;
+
+    fn started(&mut self, _: &mut Self::Context) {
+        log::debug!("Started");
+    }
+
+    fn stopped(&mut self, _: &mut Self::Context) {
+        log::debug!("Stopped");
+    }
+}
+
+impl Supervised for FilterPipelineManager {}
+impl SystemService for FilterPipelineManager {}
+
+impl Default for FilterPipelineManager {
+    fn default() -> Self {
+        unreachable!();
+    }
+}
+
+// reload
+
+struct ReloadMessage {
+    config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reload")
+    }
+}
+
+impl Message for ReloadMessage {
+    type Result = ();
+}
+
+impl Handler<ReloadMessage> for FilterPipelineManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.config = msg.config;
+    }
+}
+
+// start streaming
+
+pub struct StartStreamingMessage {
+    pub stream: MpegTsStream,
+    pub filters: Vec<String>,
+    pub slow_subscriber: SlowSubscriberConfig,
+}
+
+impl fmt::Display for StartStreamingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StartStreaming {} with {:?}",
+               self.stream.id(), self.filters)
+    }
+}
+
+impl Message for StartStreamingMessage {
+    type Result = Result<MpegTsStream, Error>;
+}
+
+impl Handler<StartStreamingMessage> for FilterPipelineManager {
+    type Result = ActorResponse<Self, MpegTsStream, Error>;
+
+    fn handle(
+        &mut self,
+        msg: StartStreamingMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+
+        let id = msg.stream.id();
+        let key = (id.session_id(), msg.filters);
+
+        let broadcaster = match self.find_pipeline(&key) {
+            Some(pipeline) => {
+                log::info!("{}: Share the filter pipeline with {}",
+                           id, pipeline.streams[0]);
+                pipeline.streams.push(id);
+                pipeline.joiners.push(
+                    (id, keep_subscription(msg.stream, ctx.address())));
+                pipeline.broadcaster.clone()
+            }
+            None => match self.create_pipeline(key, msg.stream) {
+                Ok(broadcaster) => broadcaster,
+                Err(err) => return ActorResponse::reply(Err(err)),
+            },
+        };
+
+        let fut = actix::fut::wrap_future::<_, Self>(
+            broadcaster.send(SubscribeMessage {
+                id,
+                config: msg.slow_subscriber,
+                start_time: None,
+            }))
+            .map(move |result, act, _| {
+                if result.is_err() {
+                    log::error!("{}: Shared filter pipeline may have stopped",
+                                id);
+                    act.stop_streaming(id);
+                }
+                result.map_err(Error::from)
+            });
+
+        ActorResponse::r#async(fut)
+    }
+}
+
+// stop streaming
+
+pub struct StopStreamingMessage {
+    pub id: MpegTsStreamId,
+}
+
+impl fmt::Display for StopStreamingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StopStreaming {}", self.id)
+    }
+}
+
+impl Message for StopStreamingMessage {
+    type Result = ();
+}
+
+impl Handler<StopStreamingMessage> for FilterPipelineManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: StopStreamingMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.stop_streaming(msg.id)
+    }
+}
+
+// kill stream
+
+pub struct KillStreamMessage {
+    pub id: MpegTsStreamId,
+}
+
+impl fmt::Display for KillStreamMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KillStream {}", self.id)
+    }
+}
+
+impl Message for KillStreamMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<KillStreamMessage> for FilterPipelineManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: KillStreamMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.kill_stream(msg.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use matches::assert_matches;
+    use tokio::sync::mpsc;
+    use crate::mpeg_ts_packet::TS_PACKET_SIZE;
+    use crate::mpeg_ts_packet::test_helper::create_packet;
+
+    #[actix_rt::test]
+    async fn test_shared_pipeline() {
+        let manager =
+            FilterPipelineManager::new(Arc::new(Default::default())).start();
+        let filters = vec!["cat".to_string()];
+
+        let (mut tx1, stream1) = create_stream("tuner#0.1.1");
+        let (mut tx2, stream2) = create_stream("tuner#0.1.2");
+        let (mut tx3, stream3) = create_stream("tuner#0.1.3");
+
+        let mut output1 = start(&manager, stream1, &filters).await;
+        let mut output2 = start(&manager, stream2, &filters).await;
+        let mut output3 = start(&manager, stream3, &filters).await;
+        assert_eq!(manager.send(QueryNumPipelines).await.unwrap(), 1);
+
+        // Only the first stream feeds the pipeline.
+        let chunk = create_chunk();
+        tx1.send(chunk.clone()).await.unwrap();
+        for output in [&mut output1, &mut output2, &mut output3].iter_mut() {
+            let mut received = 0;
+            while received < chunk.len() {
+                received += output.next().await.unwrap().unwrap().len();
+            }
+            assert_eq!(received, chunk.len());
+        }
+
+        // Tuner subscriptions of joiners are kept.
+        assert!(tx2.send(chunk.clone()).await.is_ok());
+        assert!(tx3.send(chunk.clone()).await.is_ok());
+
+        // Killing a joiner closes its tuner subscription and its stream.
+        assert!(manager.send(KillStreamMessage {
+            id: "tuner#0.1.2".parse().unwrap(),
+        }).await.unwrap().is_ok());
+        assert!(output2.next().await.is_none());
+        wait_closed(&mut tx2).await;
+
+        // The stream of a joiner ends when its tuner subscription ends.
+        drop(tx3);
+        assert!(output3.next().await.is_none());
+
+        assert!(manager.send(KillStreamMessage {
+            id: "tuner#0.1.4".parse().unwrap(),
+        }).await.unwrap().is_err());
+
+        // The first stream still works.
+        tx1.send(chunk.clone()).await.unwrap();
+        assert!(output1.next().await.unwrap().is_ok());
+        assert_eq!(manager.send(QueryNumPipelines).await.unwrap(), 1);
+
+        let (_tx5, stream5) = create_stream("tuner#0.1.5");
+        let mut output5 = start(&manager, stream5, &filters).await;
+
+        // The tuner subscription of the first stream is kept for the input
+        // of the pipeline after the stream ends.
+        assert!(manager.send(KillStreamMessage {
+            id: "tuner#0.1.1".parse().unwrap(),
+        }).await.unwrap().is_ok());
+        assert!(output1.next().await.is_none());
+        assert_matches!(manager.send(KillStreamMessage {
+            id: "tuner#0.1.1".parse().unwrap(),
+        }).await.unwrap(), Err(Error::StreamInUse));
+        tx1.send(chunk.clone()).await.unwrap();
+        assert!(output5.next().await.unwrap().is_ok());
+    }
+
+    fn create_stream(id: &str) -> (mpsc::Sender<Bytes>, MpegTsStream) {
+        let (tx, rx) = mpsc::channel(10);
+        (tx, MpegTsStream::new(id.parse().unwrap(), rx))
+    }
+
+    async fn start(
+        manager: &Addr<FilterPipelineManager>,
+        stream: MpegTsStream,
+        filters: &[String],
+    ) -> MpegTsStream {
+        manager.send(StartStreamingMessage {
+            stream,
+            filters: filters.to_vec(),
+            slow_subscriber: Default::default(),
+        }).await.unwrap().unwrap()
+    }
+
+    // A chunk of TS packets large enough to be emitted from the broadcaster.
+    fn create_chunk() -> Bytes {
+        let packet = create_packet(0x0100, true, &[]);
+        let mut chunk = Vec::with_capacity(TS_PACKET_SIZE * 174);
+        for _ in 0..174 {
+            chunk.extend_from_slice(&packet);
+        }
+        Bytes::from(chunk)
+    }
+
+    async fn wait_closed(tx: &mut mpsc::Sender<Bytes>) {
+        for _ in 0..100 {
+            if tx.send(Bytes::new()).await.is_err() {
+                return;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("The tuner subscription must be closed");
+    }
+
+    struct QueryNumPipelines;
+
+    impl Message for QueryNumPipelines {
+        type Result = usize;
+    }
+
+    impl Handler<QueryNumPipelines> for FilterPipelineManager {
+        type Result = usize;
+
+        fn handle(
+            &mut self,
+            _: QueryNumPipelines,
+            _: &mut Self::Context,
+        ) -> Self::Result {
+            self.pipelines.len()
+        }
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use crate::epg::{EpgProgram, EpgService};
+use crate::models::*;
+
+// Renders an M3U playlist for IPTV players.
+//
+// `query` is added to each URL so that query parameters like `token` and
+// `decode` given to the playlist are also used for streaming.
+pub fn render_playlist(
+    base_url: &str,
+    query: &str,
+    services: &[EpgService],
+) -> String {
+    let mut m3u = String::new();
+    m3u.push_str("#EXTM3U\n");
+    for sv in services.iter() {
+        let id = MirakurunServiceId::from((sv.nid, sv.sid)).value();
+        let _ = write!(m3u, "#EXTINF:-1 tvg-id=\"{}\"", id);
+        if sv.has_logo_data {
+            let _ = write!(m3u, " tvg-logo=\"{}\"", make_url(
+                base_url, &format!("/api/services/{}/logo", id), query));
+        }
+        let _ = writeln!(m3u, " group-title=\"{}\",{}",
+                         sv.channel.channel_type, sv.name);
+        let _ = writeln!(m3u, "{}", make_url(
+            base_url, &format!("/api/services/{}/stream", id), query));
+    }
+    m3u
+}
+
+// Renders programs of services in the XMLTV format.
+//
+// Channel IDs are the same as `tvg-id` in the playlist.
+pub fn render_xmltv(
+    base_url: &str,
+    query: &str,
+    services: &[EpgService],
+    programs: &[EpgProgram],
+) -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml.push_str("<!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n");
+    let _ = writeln!(xml, "<tv generator-info-name=\"{}\">",
+                     env!("CARGO_PKG_NAME"));
+
+    for sv in services.iter() {
+        let id = MirakurunServiceId::from((sv.nid, sv.sid)).value();
+        let _ = writeln!(xml, "<channel id=\"{}\">", id);
+        let _ = writeln!(xml, "<display-name lang=\"ja\">{}</display-name>",
+                         escape(&sv.name));
+        if sv.has_logo_data {
+            let _ = writeln!(xml, "<icon src=\"{}\" />", escape(&make_url(
+                base_url, &format!("/api/services/{}/logo", id), query)));
+        }
+        xml.push_str("</channel>\n");
+    }
+
+    let exported: HashSet<_> =
+        services.iter().map(|sv| (sv.nid, sv.sid)).collect();
+    let mut programs: Vec<&EpgProgram> = programs
+        .iter()
+        .filter(|pg| exported.contains(&(pg.quad.nid(), pg.quad.sid())))
+        .filter(|pg| pg.name.is_some())  // <title> is required
+        .collect();
+    programs.sort_by_key(|pg| {
+        (pg.quad.nid().value(), pg.quad.sid().value(), pg.start_at)
+    });
+
+    for pg in programs.into_iter() {
+        let id = MirakurunServiceId::from(pg.quad).value();
+        let _ = writeln!(
+            xml, "<programme start=\"{}\" stop=\"{}\" channel=\"{}\">",
+            pg.start_at.format("%Y%m%d%H%M%S %z"),
+            (pg.start_at + pg.duration).format("%Y%m%d%H%M%S %z"),
+            id);
+        if let Some(ref name) = pg.name {
+            let _ = writeln!(xml, "<title lang=\"ja\">{}</title>",
+                             escape(name));
+        }
+        let desc = make_desc(pg);
+        if !desc.is_empty() {
+            let _ = writeln!(xml, "<desc lang=\"ja\">{}</desc>",
+                             escape(&desc));
+        }
+        let mut categories = Vec::new();
+        for genre in pg.genres.iter().flatten() {
+            if let Some(category) = genre_to_category(genre) {
+                if !categories.contains(&category) {
+                    categories.push(category);
+                }
+            }
+        }
+        for category in categories.into_iter() {
+            let _ = writeln!(xml, "<category lang=\"en\">{}</category>",
+                             category);
+        }
+        xml.push_str("</programme>\n");
+    }
+
+    xml.push_str("</tv>\n");
+    xml
+}
+
+fn make_url(base_url: &str, path: &str, query: &str) -> String {
+    if query.is_empty() {
+        format!("{}{}", base_url, path)
+    } else {
+        format!("{}{}?{}", base_url, path, query)
+    }
+}
+
+// XMLTV has no element for extended items.  They're appended to the
+// description in the same way as EPG viewers show them.
+fn make_desc(pg: &EpgProgram) -> String {
+    let mut desc = pg.description.clone().unwrap_or_default();
+    for (item, value) in pg.extended.iter().flatten() {
+        if !desc.is_empty() {
+            desc.push_str("\n\n");
+        }
+        let _ = write!(desc, "{}\n{}", item, value);
+    }
+    desc
+}
+
+// Maps content_nibble_level_1 defined in ARIB STD-B10 to a category.
+//
+// Short English words are used because players like Kodi and Jellyfin
+// classify programs by keywords like "Movie" and "Sports" in categories.
+fn genre_to_category(genre: &EpgGenre) -> Option<&'static str> {
+    match genre.lv1 {
+        0x0 => Some("News"),
+        0x1 => Some("Sports"),
+        0x2 => Some("Information"),
+        0x3 => Some("Drama"),
+        0x4 => Some("Music"),
+        0x5 => Some("Variety"),
+        0x6 => Some("Movie"),
+        0x7 => Some("Animation"),
+        0x8 => Some("Documentary"),
+        0x9 => Some("Theater"),
+        0xA => Some("Hobby"),
+        0xB => Some("Welfare"),
+        0xF => Some("Others"),
+        _ => None,  // reserved or extension
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+    use indexmap::IndexMap;
+    use crate::datetime_ext::Jst;
+    use crate::epg::EpgChannel;
+
+    #[test]
+    fn test_render_playlist() {
+        let services = vec![
+            create_service(1, 2, "GR <1>", true),
+            create_service(4, 101, "BS1", false),
+        ];
+
+        let m3u = render_playlist("http://host:40772", "", &services);
+        assert_eq!(m3u, "#EXTM3U\n\
+            #EXTINF:-1 tvg-id=\"100002\" \
+            tvg-logo=\"http://host:40772/api/services/100002/logo\" \
+            group-title=\"GR\",GR <1>\n\
+            http://host:40772/api/services/100002/stream\n\
+            #EXTINF:-1 tvg-id=\"400101\" group-title=\"GR\",BS1\n\
+            http://host:40772/api/services/400101/stream\n");
+
+        let m3u = render_playlist("http://host", "token=x", &services[1..]);
+        assert_eq!(m3u, "#EXTM3U\n\
+            #EXTINF:-1 tvg-id=\"400101\" group-title=\"GR\",BS1\n\
+            http://host/api/services/400101/stream?token=x\n");
+    }
+
+    #[test]
+    fn test_render_xmltv() {
+        let services = vec![create_service(1, 2, "GR <1>", true)];
+
+        let mut extended = IndexMap::new();
+        extended.insert("item".to_string(), "value".to_string());
+        let programs = vec![
+            EpgProgram {
+                start_at: Jst.ymd(2020, 1, 1).and_hms(1, 0, 0),
+                duration: Duration::minutes(30),
+                name: Some("B & C".to_string()),
+                description: Some("desc".to_string()),
+                extended: Some(extended),
+                genres: Some(vec![
+                    EpgGenre::new((0x6, 0, 0, 0)),
+                    EpgGenre::new((0x6, 1, 0, 0)),
+                    EpgGenre::new((0xE, 0, 0, 0)),
+                ]),
+                ..EpgProgram::new((1, 0, 2, 2).into())
+            },
+            EpgProgram {
+                start_at: Jst.ymd(2020, 1, 1).and_hms(0, 0, 0),
+                duration: Duration::minutes(60),
+                name: Some("A".to_string()),
+                ..EpgProgram::new((1, 0, 2, 1).into())
+            },
+            // No title.
+            EpgProgram::new((1, 0, 2, 3).into()),
+            // Not exported.
+            EpgProgram {
+                name: Some("X".to_string()),
+                ..EpgProgram::new((1, 0, 3, 1).into())
+            },
+        ];
+
+        let xml = render_xmltv("http://host", "", &services, &programs);
+        assert_eq!(xml, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <!DOCTYPE tv SYSTEM \"xmltv.dtd\">\n\
+            <tv generator-info-name=\"mirakc\">\n\
+            <channel id=\"100002\">\n\
+            <display-name lang=\"ja\">GR &lt;1&gt;</display-name>\n\
+            <icon src=\"http://host/api/services/100002/logo\" />\n\
+            </channel>\n\
+            <programme start=\"20200101000000 +0900\" \
+            stop=\"20200101010000 +0900\" channel=\"100002\">\n\
+            <title lang=\"ja\">A</title>\n\
+            </programme>\n\
+            <programme start=\"20200101010000 +0900\" \
+            stop=\"20200101013000 +0900\" channel=\"100002\">\n\
+            <title lang=\"ja\">B &amp; C</title>\n\
+            <desc lang=\"ja\">desc\n\nitem\nvalue</desc>\n\
+            <category lang=\"en\">Movie</category>\n\
+            </programme>\n\
+            </tv>\n");
+    }
+
+    #[test]
+    fn test_make_desc() {
+        let mut pg = EpgProgram::new((0, 0, 0, 0).into());
+        assert_eq!(make_desc(&pg), "");
+
+        let mut extended = IndexMap::new();
+        extended.insert("a".to_string(), "1".to_string());
+        extended.insert("b".to_string(), "2".to_string());
+        pg.extended = Some(extended);
+        assert_eq!(make_desc(&pg), "a\n1\n\nb\n2");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("<a href=\"x\">'&'</a>"),
+                   "&lt;a href=&quot;x&quot;&gt;&apos;&amp;&apos;&lt;/a&gt;");
+    }
+
+    fn create_service(
+        nid: u16,
+        sid: u16,
+        name: &str,
+        has_logo_data: bool,
+    ) -> EpgService {
+        EpgService {
+            nid: nid.into(),
+            tsid: 0.into(),
+            sid: sid.into(),
+            service_type: 1,
+            logo_id: 0,
+            remote_control_key_id: 0,
+            name: name.to_string(),
+            channel: EpgChannel {
+                name: "ch".to_string(),
+                channel_type: ChannelType::GR,
+                channel: "ch".to_string(),
+                services: Vec::new(),
+                excluded_services: Vec::new(),
+            },
+            has_logo_data,
+        }
+    }
+}
@@ -1,21 +1,24 @@
 use std::fmt;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use chrono::DateTime;
 use cron;
 use humantime;
 use log;
-use tokio::sync::Semaphore;
+use tokio::sync::{oneshot, Semaphore};
 
+use crate::channel_scanner::{self, ChannelScanner};
 use crate::clock_synchronizer::ClockSynchronizer;
 use crate::config::Config;
 use crate::datetime_ext::*;
 use crate::eit_feeder;
 use crate::epg::{self, *};
 use crate::error::Error;
+use crate::logo_collector::LogoCollector;
+use crate::models::ChannelType;
 use crate::service_scanner::ServiceScanner;
 
 // TODO: Refactoring
@@ -47,6 +50,70 @@ pub fn invoke_sync_clocks() {
     }
 }
 
+pub fn invoke_collect_logos() {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+        } else {
+            JobManager::from_registry().do_send(InvokeCollectLogosMessage);
+        }
+    }
+}
+
+pub async fn invoke_scan_channels(
+    channel_types: Vec<ChannelType>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = timeout;
+            if channel_types.contains(&ChannelType::CS) {
+                Err(Error::JobAlreadyRunning)
+            } else {
+                Ok(())
+            }
+        } else {
+            JobManager::from_registry().send(InvokeScanChannelsMessage {
+                channel_types, timeout
+            }).await?
+        }
+    }
+}
+
+pub async fn query_channel_scan() -> Result<Option<ChannelScanStatus>, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(Some(ChannelScanStatus::Done("channels:\n".to_string())))
+        } else {
+            Ok(JobManager::from_registry()
+               .send(QueryChannelScanMessage).await?)
+        }
+    }
+}
+
+// Jobs are rescheduled with the new config.  Running jobs are not affected.
+pub async fn reload(config: Arc<Config>) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = config;
+            Ok(())
+        } else {
+            Ok(JobManager::from_registry()
+               .send(ReloadMessage { config }).await?)
+        }
+    }
+}
+
+// Stops invoking jobs, and waits for running jobs to finish.
+pub async fn shutdown() -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(())
+        } else {
+            JobManager::from_registry().send(ShutdownMessage).await?
+        }
+    }
+}
+
 pub fn invoke_update_schedules() {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
@@ -56,6 +123,13 @@ pub fn invoke_update_schedules() {
     }
 }
 
+#[derive(Clone)]
+pub enum ChannelScanStatus {
+    Running,
+    Done(String),  // `channels` section in YAML
+    Failed,
+}
+
 struct Job {
     kind: JobKind,
     semaphore: Arc<Semaphore>,
@@ -89,6 +163,8 @@ enum JobKind {
     ScanServices,
     SyncClocks,
     UpdateSchedules,
+    CollectLogos,
+    ScanChannels,
 }
 
 impl JobKind {
@@ -104,6 +180,8 @@ impl fmt::Display for JobKind {
             ScanServices => write!(f, "scan-services"),
             SyncClocks => write!(f, "sync-clocks"),
             UpdateSchedules => write!(f, "update-schedules"),
+            CollectLogos => write!(f, "collect-logos"),
+            ScanChannels => write!(f, "scan-channels"),
         }
     }
 }
@@ -114,6 +192,17 @@ struct JobManager {
     scanning_services: bool,
     synchronizing_clocks: bool,
     updating_schedules: bool,
+    collecting_logos: bool,
+    // The status of the last channel scan invoked via the web API.
+    channel_scan: Option<ChannelScanStatus>,
+    shutting_down: bool,
+    // Used for canceling scheduled jobs when reloaded.
+    scan_services_handle: Option<SpawnHandle>,
+    sync_clocks_handle: Option<SpawnHandle>,
+    update_schedules_handle: Option<SpawnHandle>,
+    collect_logos_handle: Option<SpawnHandle>,
+    // Notified when all running jobs finish.
+    idle_waiters: Vec<oneshot::Sender<()>>,
 }
 
 impl JobManager {
@@ -124,9 +213,55 @@ impl JobManager {
             scanning_services: false,
             synchronizing_clocks: false,
             updating_schedules: false,
+            collecting_logos: false,
+            channel_scan: None,
+            shutting_down: false,
+            scan_services_handle: None,
+            sync_clocks_handle: None,
+            update_schedules_handle: None,
+            collect_logos_handle: None,
+            idle_waiters: Vec::new(),
         }
     }
 
+    fn is_idle(&self) -> bool {
+        !self.scanning_services && !self.synchronizing_clocks &&
+            !self.updating_schedules && !self.collecting_logos
+    }
+
+    fn notify_if_idle(&mut self) {
+        if self.is_idle() {
+            for waiter in self.idle_waiters.drain(..) {
+                let _ = waiter.send(());
+            }
+        }
+    }
+
+    fn reload(&mut self, config: Arc<Config>, ctx: &mut Context<Self>) {
+        self.config = config;
+        let handles = vec![
+            self.scan_services_handle.take(),
+            self.sync_clocks_handle.take(),
+            self.update_schedules_handle.take(),
+            self.collect_logos_handle.take(),
+        ];
+        for handle in handles.into_iter().flatten() {
+            ctx.cancel_future(handle);
+        }
+        self.schedule_scan_services(ctx);
+        self.schedule_sync_clocks(ctx);
+        self.schedule_update_schedules(ctx);
+        self.schedule_collect_logos(ctx);
+    }
+
+    fn shutdown(&mut self) -> oneshot::Receiver<()> {
+        self.shutting_down = true;
+        let (sender, receiver) = oneshot::channel();
+        self.idle_waiters.push(sender);
+        self.notify_if_idle();
+        receiver
+    }
+
     fn calc_next_scheduled_datetime(&self, schedule: &str) -> DateTime<Jst> {
         cron::Schedule::from_str(schedule)
             .unwrap()
@@ -142,6 +277,11 @@ impl JobManager {
     }
 
     fn invoke_scan_services(&mut self, ctx: &mut Context<Self>) {
+        if self.shutting_down {
+            log::warn!("scan-services: Shutting down, skip");
+            return;
+        }
+
         if self.scanning_services {
             log::warn!("scan-services: Already running, skip");
             return;
@@ -151,27 +291,32 @@ impl JobManager {
 
         let scanner = ServiceScanner::new(
             self.config.jobs.scan_services.command.clone(),
-            self.collect_enabled_channels());
+            self.config.jobs.scan_services.process.clone(),
+            self.collect_enabled_channels(),
+            self.config.epg.follow_relocations);
 
         let job = JobKind::ScanServices.create(self.semaphore.clone())
             .perform(scanner.scan_services());
 
         actix::fut::wrap_future::<_, Self>(job)
             .map(|result, act, _| {
-                if let Ok(services) = result {
+                if let Ok((services, relocations)) = result {
                     epg::update_services(services);
+                    epg::update_relocations(relocations);
                 }
                 act.scanning_services = false;
+                act.notify_if_idle();
             })
             .spawn(ctx);
     }
 
-    fn schedule_scan_services(&self, ctx: &mut Context<Self>) {
+    fn schedule_scan_services(&mut self, ctx: &mut Context<Self>) {
         let datetime = self.calc_next_scheduled_datetime(
             &self.config.jobs.scan_services.schedule);
         log::info!("scan-services: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::scan_services);
+        self.scan_services_handle =
+            Some(ctx.run_later(interval, Self::scan_services));
     }
 
     fn sync_clocks(&mut self, ctx: &mut Context<Self>) {
@@ -180,6 +325,11 @@ impl JobManager {
     }
 
     fn invoke_sync_clocks(&mut self, ctx: &mut Context<Self>) {
+        if self.shutting_down {
+            log::warn!("sync-clocks: Shutting down, skip");
+            return;
+        }
+
         if self.synchronizing_clocks {
             log::warn!("sync-clocks: Already running, skip");
             return;
@@ -189,6 +339,7 @@ impl JobManager {
 
         let sync = ClockSynchronizer::new(
             self.config.jobs.sync_clocks.command.clone(),
+            self.config.jobs.sync_clocks.process.clone(),
             self.collect_enabled_channels());
 
         let job = JobKind::SyncClocks.create(self.semaphore.clone())
@@ -200,16 +351,18 @@ impl JobManager {
                     epg::update_clocks(clocks);
                 }
                 act.synchronizing_clocks = false;
+                act.notify_if_idle();
             })
             .spawn(ctx);
     }
 
-    fn schedule_sync_clocks(&self, ctx: &mut Context<Self>) {
+    fn schedule_sync_clocks(&mut self, ctx: &mut Context<Self>) {
         let datetime = self.calc_next_scheduled_datetime(
             &self.config.jobs.sync_clocks.schedule);
         log::info!("sync-clocks: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::sync_clocks);
+        self.sync_clocks_handle =
+            Some(ctx.run_later(interval, Self::sync_clocks));
     }
 
     fn update_schedules(&mut self, ctx: &mut Context<Self>) {
@@ -218,6 +371,11 @@ impl JobManager {
     }
 
     fn invoke_update_schedules(&mut self, ctx: &mut Context<Self>) {
+        if self.shutting_down {
+            log::warn!("update-schedules: Shutting down, skip");
+            return;
+        }
+
         if self.updating_schedules {
             log::warn!("update-schedules: Already running, skip");
             return;
@@ -232,6 +390,7 @@ impl JobManager {
             .map(|_, act, _| {
                 epg::save_schedules();
                 act.updating_schedules = false;
+                act.notify_if_idle();
             })
             .spawn(ctx);
     }
@@ -241,7 +400,102 @@ impl JobManager {
             &self.config.jobs.update_schedules.schedule);
         log::info!("update-schedules: Scheduled for {}", datetime);
         let interval = (datetime - Jst::now()).to_std().unwrap();
-        ctx.run_later(interval, Self::update_schedules);
+        self.update_schedules_handle =
+            Some(ctx.run_later(interval, Self::update_schedules));
+    }
+
+    fn collect_logos(&mut self, ctx: &mut Context<Self>) {
+        self.invoke_collect_logos(ctx);
+        self.schedule_collect_logos(ctx);
+    }
+
+    fn invoke_collect_logos(&mut self, ctx: &mut Context<Self>) {
+        if self.config.jobs.collect_logos.disabled {
+            log::info!("collect-logos: Disabled, skip");
+            return;
+        }
+
+        if self.shutting_down {
+            log::warn!("collect-logos: Shutting down, skip");
+            return;
+        }
+
+        if self.collecting_logos {
+            log::warn!("collect-logos: Already running, skip");
+            return;
+        }
+
+        self.collecting_logos = true;
+
+        let collector = LogoCollector::new(
+            Duration::from_secs(self.config.jobs.collect_logos.timeout));
+
+        let job = JobKind::CollectLogos.create(self.semaphore.clone())
+            .perform(collector.collect_logos());
+
+        actix::fut::wrap_future::<_, Self>(job)
+            .map(|result, act, _| {
+                if let Ok(logos) = result {
+                    epg::update_logos(logos);
+                }
+                act.collecting_logos = false;
+                act.notify_if_idle();
+            })
+            .spawn(ctx);
+    }
+
+    fn schedule_collect_logos(&mut self, ctx: &mut Context<Self>) {
+        if self.config.jobs.collect_logos.disabled {
+            return;
+        }
+        let datetime = self.calc_next_scheduled_datetime(
+            &self.config.jobs.collect_logos.schedule);
+        log::info!("collect-logos: Scheduled for {}", datetime);
+        let interval = (datetime - Jst::now()).to_std().unwrap();
+        self.collect_logos_handle =
+            Some(ctx.run_later(interval, Self::collect_logos));
+    }
+
+    // The scan-channels job is not taken into account in is_idle() so that it
+    // doesn't delay the shutdown for several minutes.
+    fn invoke_scan_channels(
+        &mut self,
+        channel_types: Vec<ChannelType>,
+        timeout: Duration,
+        ctx: &mut Context<Self>,
+    ) -> Result<(), Error> {
+        if self.shutting_down {
+            log::warn!("scan-channels: Shutting down, skip");
+            return Err(Error::ShuttingDown);
+        }
+
+        if let Some(ChannelScanStatus::Running) = self.channel_scan {
+            log::warn!("scan-channels: Already running, skip");
+            return Err(Error::JobAlreadyRunning);
+        }
+
+        self.channel_scan = Some(ChannelScanStatus::Running);
+
+        let scanner = ChannelScanner::new(
+            self.config.jobs.scan_services.command.clone(),
+            self.config.jobs.scan_services.process.clone(),
+            channel_types,
+            timeout);
+
+        let job = JobKind::ScanChannels.create(self.semaphore.clone())
+            .perform(scanner.scan_channels());
+
+        actix::fut::wrap_future::<_, Self>(job)
+            .map(|result, act, _| {
+                act.channel_scan = Some(match result {
+                    Ok(channels) => ChannelScanStatus::Done(
+                        channel_scanner::render_channels_yaml(&channels)),
+                    Err(_) => ChannelScanStatus::Failed,
+                });
+            })
+            .spawn(ctx);
+
+        Ok(())
     }
 
     fn collect_enabled_channels(&self) -> Vec<EpgChannel> {
@@ -263,6 +517,7 @@ impl Actor for JobManager {
         self.schedule_scan_services(ctx);
         self.schedule_sync_clocks(ctx);
         self.schedule_update_schedules(ctx);
+        self.schedule_collect_logos(ctx);
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -279,6 +534,35 @@ impl Default for JobManager {
     }
 }
 
+// reload
+
+struct ReloadMessage {
+    config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reload")
+    }
+}
+
+impl Message for ReloadMessage {
+    type Result = ();
+}
+
+impl Handler<ReloadMessage> for JobManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.reload(msg.config, ctx);
+    }
+}
+
 // invoke scan services
 
 struct InvokeScanServicesMessage;
@@ -359,3 +643,118 @@ impl Handler<InvokeUpdateSchedulesMessage> for JobManager {
         self.invoke_update_schedules(ctx);
     }
 }
+
+// invoke collect logos
+
+struct InvokeCollectLogosMessage;
+
+impl fmt::Display for InvokeCollectLogosMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InvokeCollectLogos")
+    }
+}
+
+impl Message for InvokeCollectLogosMessage {
+    type Result = ();
+}
+
+impl Handler<InvokeCollectLogosMessage> for JobManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: InvokeCollectLogosMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.invoke_collect_logos(ctx);
+    }
+}
+
+// invoke scan channels
+
+struct InvokeScanChannelsMessage {
+    channel_types: Vec<ChannelType>,
+    timeout: Duration,
+}
+
+impl fmt::Display for InvokeScanChannelsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "InvokeScanChannels")
+    }
+}
+
+impl Message for InvokeScanChannelsMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<InvokeScanChannelsMessage> for JobManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: InvokeScanChannelsMessage,
+        ctx: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.invoke_scan_channels(msg.channel_types, msg.timeout, ctx)
+    }
+}
+
+// query channel scan
+
+struct QueryChannelScanMessage;
+
+impl fmt::Display for QueryChannelScanMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryChannelScan")
+    }
+}
+
+impl Message for QueryChannelScanMessage {
+    type Result = Option<ChannelScanStatus>;
+}
+
+impl Handler<QueryChannelScanMessage> for JobManager {
+    type Result = Option<ChannelScanStatus>;
+
+    fn handle(
+        &mut self,
+        msg: QueryChannelScanMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.channel_scan.clone()
+    }
+}
+
+// shutdown
+
+struct ShutdownMessage;
+
+impl fmt::Display for ShutdownMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Shutdown")
+    }
+}
+
+impl Message for ShutdownMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<ShutdownMessage> for JobManager {
+    type Result = Response<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: ShutdownMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let receiver = self.shutdown();
+        Response::fut(Box::pin(async move {
+            let _ = receiver.await;
+            Ok(())
+        }))
+    }
+}
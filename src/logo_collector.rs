@@ -0,0 +1,155 @@
+use std::time::Duration;
+
+use tokio::stream::StreamExt;
+
+use crate::cdt::{CdtCollector, Logo};
+use crate::epg::*;
+use crate::error::Error;
+use crate::models::*;
+use crate::tuner;
+
+// Collects logos of services from the CDT.
+//
+// Unlike other jobs, no external command is used.  TS packets are parsed in
+// this process in the same way as the NIT.
+pub struct LogoCollector {
+    timeout: Duration,
+}
+
+impl LogoCollector {
+    const LABEL: &'static str = "logo-collector";
+
+    pub fn new(timeout: Duration) -> Self {
+        LogoCollector { timeout }
+    }
+
+    pub async fn collect_logos(self) -> Result<Vec<Logo>, Error> {
+        log::debug!("Collecting logos...");
+
+        // Services are queried at this point so that services found in
+        // a scan-services job which has been invoked before this job are
+        // used.
+        let services = query_services().await?;
+
+        let mut logos = Vec::new();
+        for (channel, wanted) in find_targets(&services).into_iter() {
+            logos.append(&mut Self::collect_logos_in_channel(
+                &channel, &wanted, self.timeout).await?);
+        }
+
+        log::debug!("Collected {} logos", logos.len());
+
+        Ok(logos)
+    }
+
+    async fn collect_logos_in_channel(
+        channel: &EpgChannel,
+        wanted: &[(NetworkId, u16)],
+        timeout: Duration,
+    ) -> Result<Vec<Logo>, Error> {
+        log::debug!("Collecting logos in {}...", channel.name);
+
+        let user = TunerUser {
+            info: TunerUserInfo::Job { name: Self::LABEL.to_string() },
+            priority: (-1).into(),
+        };
+
+        let mut stream = tuner::start_streaming(
+            channel.channel_type, channel.channel.clone(), user, None,
+            None).await?;
+
+        // The CDT may be transmitted at long intervals.  Logos received
+        // before the timeout are used even if some of them are missing.
+        let mut collector = CdtCollector::default();
+        let result = tokio::time::timeout(timeout, async {
+            while let Some(Ok(chunk)) = stream.next().await {
+                collector.feed(&chunk);
+                if collector.is_complete(wanted) {
+                    break;
+                }
+            }
+        }).await;
+        if result.is_err() {
+            log::warn!("Timed out collecting logos in {}", channel.name);
+        }
+
+        // Release the tuner before a request for streaming in the next
+        // iteration.
+        drop(stream);
+
+        let logos = collector.into_logos();
+        log::debug!("Collected {} logos in {}", logos.len(), channel.name);
+
+        Ok(logos)
+    }
+}
+
+// Returns a channel to be tuned for each network and logos wanted in the
+// network.  Logos of services in a network are transmitted in every transport
+// stream of the network.
+fn find_targets(
+    services: &[EpgService],
+) -> Vec<(EpgChannel, Vec<(NetworkId, u16)>)> {
+    let mut targets: Vec<(EpgChannel, Vec<(NetworkId, u16)>)> = Vec::new();
+    for sv in services.iter().filter(|sv| sv.logo_id >= 0) {
+        let key = (sv.nid, sv.logo_id as u16);
+        match targets.iter_mut().find(|(_, wanted)| wanted[0].0 == sv.nid) {
+            Some((_, wanted)) => {
+                if !wanted.contains(&key) {
+                    wanted.push(key);
+                }
+            }
+            None => targets.push((sv.channel.clone(), vec![key])),
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_targets() {
+        let services = vec![
+            create_service(1, 1, 0, "1"),
+            create_service(1, 2, 0, "1"),
+            create_service(4, 101, 1, "BS01_0"),
+            create_service(4, 102, -1, "BS01_0"),
+            create_service(4, 103, 2, "BS01_1"),
+            create_service(4, 104, 2, "BS01_1"),
+            create_service(6, 201, -1, "CS2"),
+        ];
+        let targets = find_targets(&services);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].0.channel, "1");
+        assert_eq!(targets[0].1, vec![(1.into(), 0)]);
+        assert_eq!(targets[1].0.channel, "BS01_0");
+        assert_eq!(targets[1].1, vec![(4.into(), 1), (4.into(), 2)]);
+    }
+
+    fn create_service(
+        nid: u16,
+        sid: u16,
+        logo_id: i16,
+        channel: &str,
+    ) -> EpgService {
+        EpgService {
+            nid: nid.into(),
+            tsid: 0.into(),
+            sid: sid.into(),
+            service_type: 1,
+            logo_id,
+            remote_control_key_id: 0,
+            name: "test".to_string(),
+            channel: EpgChannel {
+                name: channel.to_string(),
+                channel_type: ChannelType::GR,
+                channel: channel.to_string(),
+                services: Vec::new(),
+                excluded_services: Vec::new(),
+            },
+            has_logo_data: false,
+        }
+    }
+}
@@ -1,5 +1,8 @@
 mod airtime_tracker;
 mod broadcaster;
+mod cdt;
+mod channel_scanner;
+mod cli;
 mod chunk_stream;
 mod clock_synchronizer;
 mod command_util;
@@ -8,11 +11,21 @@ mod datetime_ext;
 mod eit_feeder;
 mod epg;
 mod error;
+mod filter_pipeline;
 mod fs_util;
+mod iptv;
 mod job;
+mod logo_collector;
 mod models;
+mod mpeg_ts_packet;
 mod mpeg_ts_stream;
+mod nit;
+mod pid_filter;
+mod reload;
 mod service_scanner;
+mod shutdown;
+mod splice;
+mod time_shift_buffer;
 mod tokio_snippet;
 mod tuner;
 mod web;
@@ -32,6 +45,7 @@ async fn main() -> Result<(), Error> {
         .arg(clap::Arg::with_name("config")
              .short("c")
              .long("config")
+             .global(true)
              .takes_value(true)
              .value_name("FILE")
              .env("MIRAKC_CONFIG")
@@ -44,6 +58,7 @@ async fn main() -> Result<(), Error> {
                   path.\n\
                   \n\
                   See README.md for details of the YAML format."))
+        .subcommands(cli::subcommands())
         .get_matches();
 
     pretty_env_logger::init_timed();
@@ -51,13 +66,28 @@ async fn main() -> Result<(), Error> {
     let config_path = args.value_of("config").expect(
         "--config option or MIRAKC_CONFIG environment must be specified");
 
+    if let Some(result) = cli::run(config_path, &args).await {
+        return result;
+    }
+
     let config = config::load(config_path);
+    let shared_config = config::SharedConfig::new(config_path, config.clone());
 
     tuner::start(config.clone());
+    filter_pipeline::start(config.clone());
     eit_feeder::start(config.clone());
     job::start(config.clone());
     epg::start(config.clone());
-    web::serve(config.clone()).await?;
+
+    let server = web::serve(shared_config.clone())?;
+    tokio::select! {
+        result = server.clone() => result?,
+        result = shutdown::wait_for_signal() => {
+            result?;
+            shutdown::shutdown(&shared_config.get(), server).await;
+        }
+        result = reload::reload_on_sighup(shared_config.clone()) => result?,
+    }
 
     Ok(())
 }
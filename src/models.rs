@@ -315,7 +315,12 @@ impl EpgGenre {
 pub enum TunerUserInfo {
     Job { name: String },
     Tracker { stream_id: MpegTsStreamId },
-    Web { remote: Option<String>, agent: Option<String> },
+    // `quota` is the name of a quota applied to the user.
+    Web {
+        remote: Option<String>,
+        agent: Option<String>,
+        quota: Option<String>,
+    },
 }
 
 impl TunerUserInfo {
@@ -324,7 +329,8 @@ impl TunerUserInfo {
             Self::Job { name } => (name, None),
             Self::Tracker { stream_id } =>
                 (format!("Tracker({})", stream_id), None),
-            Self::Web { remote, agent } => (remote.unwrap_or_default(), agent),
+            Self::Web { remote, agent, .. } =>
+                (remote.unwrap_or_default(), agent),
         }
     }
 }
@@ -335,13 +341,13 @@ impl fmt::Display for TunerUserInfo {
             Self::Job { name } => write!(f, "Job({})", name),
             Self::Tracker { stream_id } =>
                 write!(f, "Tracker({})", stream_id),
-            Self::Web { remote: None, agent: None } =>
+            Self::Web { remote: None, agent: None, .. } =>
                 write!(f, r#"Web"#),
-            Self::Web { remote: Some(remote), agent: None } =>
+            Self::Web { remote: Some(remote), agent: None, .. } =>
                 write!(f, r#"Web(remote="{}")"#, remote),
-            Self::Web { remote: None, agent: Some(agent) } =>
+            Self::Web { remote: None, agent: Some(agent), .. } =>
                 write!(f, r#"Web(agent="{}")"#, agent),
-            Self::Web { remote: Some(remote), agent: Some(agent) } =>
+            Self::Web { remote: Some(remote), agent: Some(agent), .. } =>
                 write!(f, r#"Web(remote="{}" agent="{}")"#, remote, agent),
         }
     }
@@ -379,6 +385,13 @@ pub struct TunerUser {
 }
 
 impl TunerUser {
+    pub fn quota(&self) -> Option<&str> {
+        match self.info {
+            TunerUserInfo::Web { ref quota, .. } => quota.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn get_model(&self) -> MirakurunTunerUser {
         let (id, agent) = self.info.get_model();
         MirakurunTunerUser { id, agent, priority: self.priority.0 }
@@ -407,6 +420,10 @@ impl MirakurunServiceId {
             nid.value() as u64 * Self::MAGIC_NUMBER + sid.value() as u64)
     }
 
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+
     pub fn nid(&self) -> NetworkId {
         NetworkId::from((self.0 / Self::MAGIC_NUMBER) as u16)
     }
@@ -488,7 +505,7 @@ impl From<EventQuad> for MirakurunProgramId {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MirakurunTuner {
@@ -504,10 +521,75 @@ pub struct MirakurunTuner {
     pub is_free: bool,
     pub is_using: bool,
     pub is_fault: bool,
+    // mirakc-specific
+    pub exit_history: Vec<MirakurunTunerExit>,
+    pub preemption_history: Vec<MirakurunTunerPreemption>,
+}
+
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunTunerExit {
+    #[serde(with = "serde_jst")]
+    pub time: DateTime<Jst>,
+    pub pid: u32,
+    pub command: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signal: Option<i32>,
+    // `false` if the process exited unexpectedly.
+    pub expected: bool,
 }
 
 #[derive(Debug)]
 #[derive(Serialize)]
+pub struct MirakurunTunerProcess {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid: Option<u32>,
+}
+
+// A subscription is preempted when a user having a higher priority grabs the
+// tuner.
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunTunerPreemption {
+    #[serde(with = "serde_jst")]
+    pub time: DateTime<Jst>,
+    pub tuner_index: usize,
+    // The same as the X-Mirakurun-Tuner-User-ID header in the response of the
+    // preempted stream.
+    pub subscription_id: String,
+    #[serde(rename = "channelType")]
+    pub channel_type: ChannelType,
+    pub channel: String,
+    pub user: MirakurunTunerUser,
+    pub preempted_by: MirakurunTunerUser,
+}
+
+// A stream which a user is receiving from a tuner.
+#[derive(Debug)]
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MirakurunStream {
+    // The same as the X-Mirakurun-Tuner-User-ID header in the response of the
+    // stream.
+    pub id: String,
+    pub tuner_index: usize,
+    #[serde(rename = "channelType")]
+    pub channel_type: ChannelType,
+    pub channel: String,
+    pub user: MirakurunTunerUser,
+    pub filters: Vec<String>,
+    #[serde(with = "serde_jst")]
+    pub start_time: DateTime<Jst>,
+    // The number of bytes sent to the client.
+    pub num_bytes: u64,
+}
+
+#[derive(Clone, Debug)]
+#[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct MirakurunTunerUser {
     pub id: String,
@@ -565,8 +647,8 @@ impl From<EpgService> for MirakurunService {
             logo_id: sv.logo_id,
             remote_control_key_id: sv.remote_control_key_id,
             name: sv.name,
+            has_logo_data: sv.has_logo_data,
             channel: sv.channel.into(),
-            has_logo_data: false,
         }
     }
 }
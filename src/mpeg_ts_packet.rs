@@ -0,0 +1,322 @@
+use bytes::{Bytes, BytesMut};
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_SYNC_BYTE: u8 = 0x47;
+
+pub const PAT_PID: u16 = 0x0000;
+pub const CAT_PID: u16 = 0x0001;
+pub const NIT_PID: u16 = 0x0010;
+pub const CDT_PID: u16 = 0x0029;
+
+// A read-only view of a TS packet.
+//
+// The data must contain a whole TS packet starting with the sync byte.
+pub struct TsPacket<'a>(&'a [u8]);
+
+impl<'a> TsPacket<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        debug_assert_eq!(data.len(), TS_PACKET_SIZE);
+        debug_assert_eq!(data[0], TS_SYNC_BYTE);
+        TsPacket(data)
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.0
+    }
+
+    pub fn pid(&self) -> u16 {
+        ((self.0[1] & 0x1F) as u16) << 8 | self.0[2] as u16
+    }
+
+    pub fn is_unit_start(&self) -> bool {
+        self.0[1] & 0x40 != 0
+    }
+
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        match (self.0[3] >> 4) & 0x03 {
+            0x01 => Some(&self.0[4..]),
+            0x03 => {
+                let start = 5 + self.0[4] as usize;
+                if start < TS_PACKET_SIZE {
+                    Some(&self.0[start..])
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+// Returns an iterator over TS packets in a chunk which contains only whole TS
+// packets.  Broken packets without the sync byte are skipped.
+pub fn ts_packets(chunk: &[u8]) -> impl Iterator<Item = TsPacket<'_>> {
+    chunk
+        .chunks_exact(TS_PACKET_SIZE)
+        .filter(|data| data[0] == TS_SYNC_BYTE)
+        .map(TsPacket::new)
+}
+
+// Collects a PSI section carried in TS packets on a PID.
+//
+// Raw TS packets carrying the section are also kept so that they can be sent
+// to a subscriber as is.
+#[derive(Default)]
+pub struct PsiSectionCollector {
+    packets: BytesMut,
+    section: Vec<u8>,
+}
+
+impl PsiSectionCollector {
+    // PSI sections defined in ISO/IEC 13818-1 are limited to 1024 bytes, but
+    // private sections like the CDT are limited to 4096 bytes.
+    const MAX_PACKETS: usize = 24;
+
+    // Returns raw TS packets and the section when the section is completed.
+    pub fn collect(&mut self, packet: &TsPacket) -> Option<(Bytes, Vec<u8>)> {
+        let payload = packet.payload()?;
+
+        if packet.is_unit_start() {
+            self.reset();
+            let pointer = payload[0] as usize;
+            if 1 + pointer >= payload.len() {
+                return None;
+            }
+            self.section.extend_from_slice(&payload[1 + pointer..]);
+        } else if self.packets.is_empty() {
+            return None;  // wait for the beginning of a section
+        } else {
+            self.section.extend_from_slice(payload);
+        }
+        self.packets.extend_from_slice(packet.data());
+
+        if self.section.len() >= 3 {
+            let section_length =
+                ((self.section[1] & 0x0F) as usize) << 8 |
+                self.section[2] as usize;
+            if self.section.len() >= 3 + section_length {
+                self.section.truncate(3 + section_length);
+                let packets = self.packets.split().freeze();
+                let section = std::mem::take(&mut self.section);
+                return Some((packets, section));
+            }
+        }
+
+        if self.packets.len() >= TS_PACKET_SIZE * Self::MAX_PACKETS {
+            self.reset();
+        }
+
+        None
+    }
+
+    fn reset(&mut self) {
+        self.packets.clear();
+        self.section.clear();
+    }
+}
+
+// Returns a list of PMT PIDs defined in a PAT section.
+pub fn pmt_pids_in_pat(section: &[u8]) -> Vec<u16> {
+    // 8 bytes header and 4 bytes CRC32.
+    if section.len() < 12 || section[0] != 0x00 {
+        return Vec::new();
+    }
+    section[8..section.len() - 4]
+        .chunks_exact(4)
+        .filter(|entry| entry[0] != 0 || entry[1] != 0)  // skip the NIT PID
+        .map(|entry| ((entry[2] & 0x1F) as u16) << 8 | entry[3] as u16)
+        .collect()
+}
+
+// CRC32 used in PSI sections (ISO/IEC 13818-1 Annex A).
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data.iter() {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            if crc & 0x8000_0000 != 0 {
+                crc = (crc << 1) ^ 0x04C1_1DB7;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+// Splits a PSI section into TS packets.
+//
+// `cc` is the continuity counter of the last packet on the PID, and updated
+// with the one of the last packet created.
+pub fn psi_packets(pid: u16, section: &[u8], cc: &mut u8) -> Vec<u8> {
+    const PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+
+    let mut payload = Vec::with_capacity(section.len() + 1);
+    payload.push(0x00);  // pointer_field
+    payload.extend_from_slice(section);
+
+    let mut packets = Vec::new();
+    for (i, data) in payload.chunks(PAYLOAD_SIZE).enumerate() {
+        *cc = (*cc + 1) & 0x0F;
+        let mut packet = [0xFF; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        if i == 0 {
+            packet[1] |= 0x40;
+        }
+        packet[2] = (pid & 0xFF) as u8;
+        packet[3] = 0x10 | *cc;
+        packet[4..4 + data.len()].copy_from_slice(data);
+        packets.extend_from_slice(&packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+pub mod test_helper {
+    use super::*;
+
+    // Creates a TS packet containing a payload without an adaptation field.
+    pub fn create_packet(
+        pid: u16,
+        unit_start: bool,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut packet = vec![0xFF; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet[1] = (pid >> 8) as u8 & 0x1F;
+        if unit_start {
+            packet[1] |= 0x40;
+        }
+        packet[2] = (pid & 0xFF) as u8;
+        packet[3] = 0x10;
+        packet[4..4 + payload.len()].copy_from_slice(payload);
+        packet
+    }
+
+    // Creates a TS packet containing a PSI section with a dummy CRC32.
+    pub fn create_psi_packet(pid: u16, table_id: u8, body: &[u8]) -> Vec<u8> {
+        let section_length = body.len() + 4;
+        let mut payload = vec![
+            0x00,  // pointer_field
+            table_id,
+            0xB0 | (section_length >> 8) as u8,
+            (section_length & 0xFF) as u8,
+        ];
+        payload.extend_from_slice(body);
+        payload.extend_from_slice(&[0; 4]);  // CRC32
+        create_packet(pid, true, &payload)
+    }
+
+    pub fn create_pat_packet(pmt_pids: &[u16]) -> Vec<u8> {
+        let mut body = vec![0x00, 0x01, 0xC1, 0x00, 0x00];
+        body.extend_from_slice(&[0x00, 0x00, 0xE0, 0x10]);  // NIT
+        for (i, pid) in pmt_pids.iter().enumerate() {
+            body.extend_from_slice(&[
+                0x00, (i + 1) as u8, 0xE0 | (pid >> 8) as u8, *pid as u8,
+            ]);
+        }
+        create_psi_packet(PAT_PID, 0x00, &body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_helper::*;
+
+    #[test]
+    fn test_ts_packet() {
+        let data = create_packet(0x1FFF, true, &[1, 2, 3]);
+        let packet = TsPacket::new(&data);
+        assert_eq!(packet.pid(), 0x1FFF);
+        assert!(packet.is_unit_start());
+        assert_eq!(&packet.payload().unwrap()[..3], &[1, 2, 3]);
+
+        let mut data = create_packet(0x0100, false, &[1, 2, 3]);
+        data[3] = 0x30;  // adaptation field followed by payload
+        data[4] = 1;
+        let packet = TsPacket::new(&data);
+        assert_eq!(packet.pid(), 0x0100);
+        assert!(!packet.is_unit_start());
+        assert_eq!(&packet.payload().unwrap()[..1], &[3]);
+
+        data[3] = 0x20;  // adaptation field only
+        let packet = TsPacket::new(&data);
+        assert!(packet.payload().is_none());
+    }
+
+    #[test]
+    fn test_psi_section_collector() {
+        let mut collector = PsiSectionCollector::default();
+
+        let data = create_pat_packet(&[0x0101]);
+        let (packets, section) =
+            collector.collect(&TsPacket::new(&data)).unwrap();
+        assert_eq!(&packets[..], &data[..]);
+        assert_eq!(section.len(), 3 + 5 + 8 + 4);
+
+        // A section spanning two packets.
+        let section_length = 200 + 4;
+        let mut payload = vec![
+            0x00, 0x02, 0xB0 | (section_length >> 8) as u8,
+            (section_length & 0xFF) as u8,
+        ];
+        payload.extend_from_slice(&[0xAA; 180]);
+        let first = create_packet(0x0101, true, &payload);
+        let second = create_packet(0x0101, false, &[0xAA; 24]);
+        assert!(collector.collect(&TsPacket::new(&first)).is_none());
+        let (packets, section) =
+            collector.collect(&TsPacket::new(&second)).unwrap();
+        assert_eq!(packets.len(), TS_PACKET_SIZE * 2);
+        assert_eq!(section.len(), 3 + section_length);
+
+        // A packet without the beginning of a section is ignored.
+        assert!(collector.collect(&TsPacket::new(&second)).is_none());
+    }
+
+    #[test]
+    fn test_pmt_pids_in_pat() {
+        let data = create_pat_packet(&[0x0101, 0x0102]);
+        let mut collector = PsiSectionCollector::default();
+        let (_, section) = collector.collect(&TsPacket::new(&data)).unwrap();
+        assert_eq!(pmt_pids_in_pat(&section), vec![0x0101, 0x0102]);
+
+        assert!(pmt_pids_in_pat(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0x0376_E6E7);
+        // The CRC32 of a section including its CRC32 field is zero.
+        let section = [0x00, 0xB0, 0x05, 0x00, 0x01, 0xC1, 0x00, 0x00];
+        let crc = crc32(&section);
+        let mut data = section.to_vec();
+        data.extend_from_slice(&crc.to_be_bytes());
+        assert_eq!(crc32(&data), 0);
+    }
+
+    #[test]
+    fn test_psi_packets() {
+        let mut section = vec![0x02, 0xB0, 197];
+        section.extend_from_slice(&[0x00; 197]);
+        let mut cc = 0x0F;
+        let packets = psi_packets(0x0101, &section, &mut cc);
+        assert_eq!(packets.len(), TS_PACKET_SIZE * 2);
+        assert_eq!(cc, 0x01);
+
+        let first = TsPacket::new(&packets[..TS_PACKET_SIZE]);
+        assert_eq!(first.pid(), 0x0101);
+        assert!(first.is_unit_start());
+        assert_eq!(packets[3] & 0x0F, 0x00);
+
+        let second = TsPacket::new(&packets[TS_PACKET_SIZE..]);
+        assert!(!second.is_unit_start());
+        assert_eq!(packets[TS_PACKET_SIZE + 3] & 0x0F, 0x01);
+
+        let mut collector = PsiSectionCollector::default();
+        assert!(collector.collect(&first).is_none());
+        let (_, collected) = collector.collect(&second).unwrap();
+        assert_eq!(collected, section);
+    }
+}
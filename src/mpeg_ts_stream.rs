@@ -7,23 +7,39 @@ use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio::stream::{Stream, StreamExt};
 use tokio::sync::mpsc::Receiver;
 
-use crate::tuner;
+use crate::pid_filter::PidFilter;
 pub use crate::tuner::TunerSubscriptionId as MpegTsStreamId;
 
 pub struct MpegTsStream {
     id: MpegTsStreamId,
     receiver: Receiver<Bytes>,
     stop_trigger: Option<MpegTsStreamStopTrigger>,
+    pid_filter: Option<PidFilter>,
 }
 
 impl MpegTsStream {
+    #[cfg(test)]
     pub fn new(id: MpegTsStreamId, receiver: Receiver<Bytes>) -> Self {
+        Self::with_stop_fn(id, receiver, crate::tuner::stop_streaming)
+    }
+
+    // `stop_fn` is called when the stop trigger is dropped.
+    pub fn with_stop_fn(
+        id: MpegTsStreamId,
+        receiver: Receiver<Bytes>,
+        stop_fn: fn(MpegTsStreamId),
+    ) -> Self {
         MpegTsStream {
             id, receiver,
-            stop_trigger: Some(MpegTsStreamStopTrigger(id))
+            stop_trigger: Some(MpegTsStreamStopTrigger { id, stop_fn }),
+            pid_filter: None,
         }
     }
 
+    pub fn set_pid_filter(&mut self, pid_filter: PidFilter) {
+        self.pid_filter = Some(pid_filter);
+    }
+
     pub fn id(&self) -> MpegTsStreamId {
         self.id
     }
@@ -32,6 +48,15 @@ impl MpegTsStream {
         self.stop_trigger.take()
     }
 
+    // Takes chunks which have been queued but not read yet.
+    pub fn take_queued_chunks(&mut self) -> Vec<Bytes> {
+        let mut chunks = Vec::new();
+        while let Ok(chunk) = self.receiver.try_recv() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
     pub async fn pipe<W>(self, writer: W)
     where
         W: AsyncWrite + Unpin,
@@ -47,18 +72,33 @@ impl Stream for MpegTsStream {
         mut self: Pin<&mut Self>,
         cx: &mut Context
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver)
-            .poll_next(cx)
-            .map(|opt| opt.map(|chunk| Ok(chunk)))
+        loop {
+            let chunk = match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => chunk,
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let chunk = match self.pid_filter {
+                Some(ref mut pid_filter) => pid_filter.filter(&chunk),
+                None => chunk,
+            };
+            // Skip a chunk which contains no TS packet after filtering.
+            if !chunk.is_empty() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+        }
     }
 }
 
-pub struct MpegTsStreamStopTrigger(MpegTsStreamId);
+pub struct MpegTsStreamStopTrigger {
+    id: MpegTsStreamId,
+    stop_fn: fn(MpegTsStreamId),
+}
 
 impl Drop for MpegTsStreamStopTrigger {
     fn drop(&mut self) {
-        log::debug!("{}: Closing...", self.0);
-        tuner::stop_streaming(self.0);
+        log::debug!("{}: Closing...", self.id);
+        (self.stop_fn)(self.id);
     }
 }
 
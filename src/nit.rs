@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::models::*;
+use crate::mpeg_ts_packet::{self, PsiSectionCollector};
+
+// Collects NIT sections for the actual network, and builds a table of
+// transport streams carrying services.
+//
+// A NIT consists of multiple sections.  Sections are collected until all of
+// them have been received.
+#[derive(Default)]
+pub struct NitCollector {
+    collector: PsiSectionCollector,
+    // Keyed by the network ID.
+    networks: HashMap<NetworkId, NitSections>,
+}
+
+#[derive(Default)]
+struct NitSections {
+    version: u8,
+    last_section_number: u8,
+    received: HashSet<u8>,
+}
+
+impl NitSections {
+    fn is_complete(&self) -> bool {
+        (0..=self.last_section_number).all(|n| self.received.contains(&n))
+    }
+}
+
+// Transport streams carrying services, obtained from the NIT.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NitTable {
+    pub locations: HashMap<(NetworkId, ServiceId), TransportStreamId>,
+    // All transport streams in the NIT.
+    pub transport_streams: HashSet<(NetworkId, TransportStreamId)>,
+}
+
+impl NitTable {
+    pub fn merge(&mut self, other: NitTable) {
+        self.locations.extend(other.locations);
+        self.transport_streams.extend(other.transport_streams);
+    }
+
+    pub fn find(
+        &self,
+        nid: NetworkId,
+        sid: ServiceId,
+    ) -> Option<TransportStreamId> {
+        self.locations.get(&(nid, sid)).cloned()
+    }
+
+    // Returns a channel in the format used by recpt1 for a transport stream.
+    //
+    // The transponder number is encoded in the TSID of a transport stream in
+    // BS and CS.  The slot number `N` in `BSxx_N` is the position of the
+    // transport stream on the transponder, which is not the relative number
+    // encoded in the TSID.  For example, the TSID of NHK BS1 is 0x40F1, but
+    // it's carried on `BS15_0`.  So, the slot number is the index of the TSID
+    // in TSIDs sharing the transponder in the NIT.
+    //
+    // GR is not supported because the TSID has no information about the
+    // physical channel.
+    pub fn channel_for_tsid(
+        &self,
+        channel_type: ChannelType,
+        nid: NetworkId,
+        tsid: TransportStreamId,
+    ) -> Option<String> {
+        let transponder = |tsid: TransportStreamId| (tsid.value() >> 4) & 0x1F;
+        match channel_type {
+            ChannelType::BS => {
+                if !self.transport_streams.contains(&(nid, tsid)) {
+                    return None;
+                }
+                let slot = self.transport_streams
+                    .iter()
+                    .filter(|(other_nid, other)| {
+                        *other_nid == nid &&
+                            transponder(*other) == transponder(tsid) &&
+                            other.value() < tsid.value()
+                    })
+                    .count();
+                Some(format!("BS{:02}_{}", transponder(tsid), slot))
+            }
+            ChannelType::CS => Some(format!("CS{}", transponder(tsid))),
+            _ => None,
+        }
+    }
+}
+
+impl NitCollector {
+    // Feeds a chunk which contains only whole TS packets.
+    pub fn feed(&mut self, chunk: &[u8], table: &mut NitTable) {
+        for packet in mpeg_ts_packet::ts_packets(chunk) {
+            if packet.pid() != mpeg_ts_packet::NIT_PID {
+                continue;
+            }
+            if let Some((_, section)) = self.collector.collect(&packet) {
+                self.update(&section, table);
+            }
+        }
+    }
+
+    // Returns true if all sections of at least one network have been received.
+    pub fn is_complete(&self) -> bool {
+        self.networks.values().any(NitSections::is_complete)
+    }
+
+    fn update(&mut self, section: &[u8], table: &mut NitTable) {
+        let nit = match NitSection::parse(section) {
+            Some(nit) => nit,
+            None => return,
+        };
+        let sections = self.networks.entry(nit.nid).or_default();
+        if sections.version != nit.version {
+            *sections = NitSections {
+                version: nit.version,
+                ..Default::default()
+            };
+            table.transport_streams.retain(|(nid, _)| *nid != nit.nid);
+        }
+        sections.last_section_number = nit.last_section_number;
+        sections.received.insert(nit.section_number);
+        for (tsid, sids) in nit.transport_streams.into_iter() {
+            table.transport_streams.insert((nit.nid, tsid));
+            for sid in sids.into_iter() {
+                table.locations.insert((nit.nid, sid), tsid);
+            }
+        }
+    }
+}
+
+struct NitSection {
+    nid: NetworkId,
+    version: u8,
+    section_number: u8,
+    last_section_number: u8,
+    transport_streams: Vec<(TransportStreamId, Vec<ServiceId>)>,
+}
+
+impl NitSection {
+    // network_information_section for the actual network.
+    const TABLE_ID: u8 = 0x40;
+    // service_list_descriptor
+    const SERVICE_LIST_TAG: u8 = 0x41;
+
+    fn parse(section: &[u8]) -> Option<Self> {
+        // 10 bytes header and 4 bytes CRC32.
+        if section.len() < 16 || section[0] != Self::TABLE_ID {
+            return None;
+        }
+        let end = section.len() - 4;
+        if mpeg_ts_packet::crc32(section) != 0 {
+            return None;
+        }
+
+        let nid = (section[3] as u16) << 8 | section[4] as u16;
+        let version = (section[5] >> 1) & 0x1F;
+        let section_number = section[6];
+        let last_section_number = section[7];
+        let network_descriptors_length =
+            ((section[8] & 0x0F) as usize) << 8 | section[9] as usize;
+
+        let mut pos = 10 + network_descriptors_length + 2;  // skip the length
+        if pos > end {
+            return None;
+        }
+
+        let mut transport_streams = Vec::new();
+        while pos + 6 <= end {
+            let tsid = (section[pos] as u16) << 8 | section[pos + 1] as u16;
+            let descriptors_length =
+                ((section[pos + 4] & 0x0F) as usize) << 8 |
+                section[pos + 5] as usize;
+            let next = pos + 6 + descriptors_length;
+            if next > end {
+                return None;
+            }
+            let sids = Self::find_services(&section[pos + 6..next]);
+            transport_streams.push((tsid.into(), sids));
+            pos = next;
+        }
+
+        Some(NitSection {
+            nid: nid.into(),
+            version,
+            section_number,
+            last_section_number,
+            transport_streams,
+        })
+    }
+
+    fn find_services(mut descriptors: &[u8]) -> Vec<ServiceId> {
+        let mut sids = Vec::new();
+        while descriptors.len() >= 2 {
+            let len = descriptors[1] as usize;
+            if descriptors.len() < 2 + len {
+                break;
+            }
+            if descriptors[0] == Self::SERVICE_LIST_TAG {
+                for entry in descriptors[2..2 + len].chunks_exact(3) {
+                    let sid = (entry[0] as u16) << 8 | entry[1] as u16;
+                    sids.push(sid.into());
+                }
+            }
+            descriptors = &descriptors[2 + len..];
+        }
+        sids
+    }
+}
+
+#[cfg(test)]
+pub mod test_helper {
+    use super::*;
+    use crate::mpeg_ts_packet::test_helper::create_packet;
+
+    // Creates a TS packet containing a NIT section with a valid CRC32.
+    pub fn create_nit_packet(
+        nid: u16,
+        version: u8,
+        section_number: u8,
+        last_section_number: u8,
+        transport_streams: &[(u16, &[u16])],
+    ) -> Vec<u8> {
+        let mut ts_loop = Vec::new();
+        for (tsid, sids) in transport_streams.iter() {
+            let descriptor_length = 3 * sids.len();
+            let descriptors_length = 2 + descriptor_length;
+            ts_loop.extend_from_slice(&[
+                (tsid >> 8) as u8, *tsid as u8,
+                (nid >> 8) as u8, nid as u8,  // original_network_id
+                0xF0 | (descriptors_length >> 8) as u8,
+                descriptors_length as u8,
+                NitSection::SERVICE_LIST_TAG,
+                descriptor_length as u8,
+            ]);
+            for sid in sids.iter() {
+                ts_loop.extend_from_slice(&[(sid >> 8) as u8, *sid as u8, 0x01]);
+            }
+        }
+
+        let mut section = vec![
+            NitSection::TABLE_ID,
+            0x00, 0x00,  // section_length
+            (nid >> 8) as u8, nid as u8,
+            0xC1 | (version << 1),
+            section_number,
+            last_section_number,
+            0xF0, 0x00,  // network_descriptors_length
+            0xF0 | (ts_loop.len() >> 8) as u8, ts_loop.len() as u8,
+        ];
+        section.extend_from_slice(&ts_loop);
+        let section_length = section.len() - 3 + 4;
+        section[1] = 0xF0 | (section_length >> 8) as u8;
+        section[2] = section_length as u8;
+        let crc = mpeg_ts_packet::crc32(&section);
+        section.extend_from_slice(&crc.to_be_bytes());
+
+        let mut payload = vec![0x00];  // pointer_field
+        payload.extend_from_slice(&section);
+        create_packet(mpeg_ts_packet::NIT_PID, true, &payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_helper::*;
+
+    #[test]
+    fn test_nit_collector() {
+        let mut collector = NitCollector::default();
+        let mut table = NitTable::default();
+        assert!(!collector.is_complete());
+
+        collector.feed(
+            &create_nit_packet(4, 0, 0, 1, &[(0x4010, &[101, 102])]),
+            &mut table);
+        assert!(!collector.is_complete());
+        assert_eq!(table.find(4.into(), 101.into()), Some(0x4010.into()));
+        assert_eq!(table.find(4.into(), 102.into()), Some(0x4010.into()));
+
+        collector.feed(
+            &create_nit_packet(4, 0, 1, 1, &[(0x4031, &[161])]),
+            &mut table);
+        assert!(collector.is_complete());
+        assert_eq!(table.find(4.into(), 161.into()), Some(0x4031.into()));
+        assert_eq!(table.find(4.into(), 999.into()), None);
+        assert_eq!(table.transport_streams.len(), 2);
+
+        // A new version.
+        collector.feed(
+            &create_nit_packet(4, 1, 0, 1, &[(0x4011, &[101])]),
+            &mut table);
+        assert!(!collector.is_complete());
+        assert_eq!(table.find(4.into(), 101.into()), Some(0x4011.into()));
+        assert_eq!(table.transport_streams.len(), 1);
+        assert!(table.transport_streams.contains(&(4.into(), 0x4011.into())));
+    }
+
+    #[test]
+    fn test_nit_section_with_broken_crc() {
+        let mut packet = create_nit_packet(4, 0, 0, 0, &[(0x4010, &[101])]);
+        packet[20] ^= 0xFF;
+        let mut collector = NitCollector::default();
+        let mut table = NitTable::default();
+        collector.feed(&packet, &mut table);
+        assert!(!collector.is_complete());
+        assert!(table.locations.is_empty());
+    }
+
+    #[test]
+    fn test_nit_table_merge() {
+        let mut table = NitTable::default();
+        table.locations.insert((4.into(), 101.into()), 0x4010.into());
+        let mut other = NitTable::default();
+        other.locations.insert((4.into(), 101.into()), 0x4011.into());
+        other.locations.insert((6.into(), 200.into()), 0x6020.into());
+        table.merge(other);
+        assert_eq!(table.find(4.into(), 101.into()), Some(0x4011.into()));
+        assert_eq!(table.find(6.into(), 200.into()), Some(0x6020.into()));
+    }
+
+    #[test]
+    fn test_channel_for_tsid() {
+        let mut table = NitTable::default();
+        for tsid in [0x4010, 0x4011, 0x40F1, 0x40F2, 0x6020, 0x7040].iter() {
+            table.transport_streams.insert((4.into(), (*tsid).into()));
+        }
+        table.transport_streams.insert((6.into(), 0x40F0.into()));
+
+        let channel_for_tsid = |channel_type, tsid: u16| {
+            table.channel_for_tsid(channel_type, 4.into(), tsid.into())
+        };
+        assert_eq!(channel_for_tsid(ChannelType::BS, 0x4010),
+                   Some("BS01_0".to_string()));
+        assert_eq!(channel_for_tsid(ChannelType::BS, 0x4011),
+                   Some("BS01_1".to_string()));
+        // NHK BS1 and BS Premium.
+        assert_eq!(channel_for_tsid(ChannelType::BS, 0x40F1),
+                   Some("BS15_0".to_string()));
+        assert_eq!(channel_for_tsid(ChannelType::BS, 0x40F2),
+                   Some("BS15_1".to_string()));
+        // Not in the NIT.
+        assert_eq!(channel_for_tsid(ChannelType::BS, 0x4031), None);
+        assert_eq!(channel_for_tsid(ChannelType::CS, 0x6020),
+                   Some("CS2".to_string()));
+        assert_eq!(channel_for_tsid(ChannelType::CS, 0x7040),
+                   Some("CS4".to_string()));
+        assert_eq!(channel_for_tsid(ChannelType::GR, 0x7FE0), None);
+    }
+}
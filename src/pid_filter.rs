@@ -0,0 +1,429 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::mpeg_ts_packet::*;
+
+// PIDs lower than this are reserved for PSI/SI tables, and always passed.
+const MAX_SI_PID: u16 = 0x001F;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamComponent {
+    Video,
+    Audio,
+    Caption,
+    Superimpose,
+    Data,
+}
+
+impl StreamComponent {
+    fn matches(&self, stream_type: u8, component_tag: Option<u8>) -> bool {
+        match self {
+            Self::Video =>
+                [0x01, 0x02, 0x10, 0x1B, 0x24].contains(&stream_type),
+            Self::Audio =>
+                [0x03, 0x04, 0x0F, 0x11].contains(&stream_type),
+            Self::Caption => stream_type == 0x06 && match component_tag {
+                Some(tag) => (0x30..=0x37).contains(&tag),
+                None => false,
+            },
+            Self::Superimpose => stream_type == 0x06 && match component_tag {
+                Some(tag) => (0x38..=0x3F).contains(&tag),
+                None => false,
+            },
+            Self::Data => stream_type == 0x0D,
+        }
+    }
+}
+
+impl FromStr for StreamComponent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "video" => Ok(Self::Video),
+            "audio" => Ok(Self::Audio),
+            "caption" => Ok(Self::Caption),
+            "superimpose" => Ok(Self::Superimpose),
+            "data" => Ok(Self::Data),
+            _ => Err(format!("Unknown component: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PidFilterConfig {
+    pub components: Vec<StreamComponent>,
+    pub pids: Vec<u16>,
+}
+
+impl PidFilterConfig {
+    fn matches(&self, stream: &EsInfo) -> bool {
+        self.pids.contains(&stream.pid) ||
+            self.components
+            .iter()
+            .any(|c| c.matches(stream.stream_type, stream.component_tag))
+    }
+}
+
+impl fmt::Display for PidFilterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "components: {:?}, pids: {:?}", self.components, self.pids)
+    }
+}
+
+// Drops TS packets of elementary streams which are not selected, and rewrites
+// PMTs so that they contain only the selected elementary streams.
+//
+// PSI/SI tables and PCR packets are always passed.  ECM and EMM packets are
+// also passed because the filter runs before a decoder in filters.  Packets
+// of elementary streams are dropped until the PMT is received.
+pub struct PidFilter {
+    config: PidFilterConfig,
+    pat_collector: PsiSectionCollector,
+    cat_collector: PsiSectionCollector,
+    // EMM PIDs in the CAT.
+    emm_pids: Vec<u16>,
+    pmt_collectors: HashMap<u16, PsiSectionCollector>,
+    // Continuity counters of rewritten PMTs.
+    pmt_ccs: HashMap<u16, u8>,
+    // Selected PIDs for each PMT.
+    selected_pids: HashMap<u16, Vec<u16>>,
+    allowed_pids: HashSet<u16>,
+}
+
+impl PidFilter {
+    pub fn new(config: PidFilterConfig) -> Self {
+        PidFilter {
+            config,
+            pat_collector: Default::default(),
+            cat_collector: Default::default(),
+            emm_pids: Vec::new(),
+            pmt_collectors: HashMap::new(),
+            pmt_ccs: HashMap::new(),
+            selected_pids: HashMap::new(),
+            allowed_pids: HashSet::new(),
+        }
+    }
+
+    // The chunk must contain only whole TS packets.
+    pub fn filter(&mut self, chunk: &[u8]) -> Bytes {
+        let mut filtered = BytesMut::with_capacity(chunk.len());
+        for packet in ts_packets(chunk) {
+            let pid = packet.pid();
+            if pid == PAT_PID {
+                let result = self.pat_collector.collect(&packet);
+                if let Some((_, section)) = result {
+                    self.update_pat(&section);
+                }
+                filtered.extend_from_slice(packet.data());
+            } else if pid == CAT_PID {
+                let result = self.cat_collector.collect(&packet);
+                if let Some((_, section)) = result {
+                    self.update_cat(&section);
+                }
+                filtered.extend_from_slice(packet.data());
+            } else if self.pmt_collectors.contains_key(&pid) {
+                let result = self.pmt_collectors
+                    .get_mut(&pid)
+                    .and_then(|collector| collector.collect(&packet));
+                if let Some((_, section)) = result {
+                    if let Some(packets) = self.rewrite_pmt(pid, &section) {
+                        filtered.extend_from_slice(&packets);
+                    }
+                }
+            } else if pid <= MAX_SI_PID || self.allowed_pids.contains(&pid) {
+                filtered.extend_from_slice(packet.data());
+            }
+        }
+        filtered.freeze()
+    }
+
+    fn update_pat(&mut self, section: &[u8]) {
+        let pmt_pids = pmt_pids_in_pat(section);
+        self.pmt_collectors.retain(|pid, _| pmt_pids.contains(pid));
+        self.selected_pids.retain(|pid, _| pmt_pids.contains(pid));
+        for pid in pmt_pids.into_iter() {
+            self.pmt_collectors.entry(pid).or_default();
+        }
+        self.update_allowed_pids();
+    }
+
+    fn update_cat(&mut self, section: &[u8]) {
+        // 8 bytes header and 4 bytes CRC32.
+        if section.len() < 12 || section[0] != 0x01 {
+            return;
+        }
+        self.emm_pids = ca_pids(&section[8..section.len() - 4]);
+        self.update_allowed_pids();
+    }
+
+    // Returns TS packets of the rewritten PMT.
+    fn rewrite_pmt(&mut self, pid: u16, section: &[u8]) -> Option<Vec<u8>> {
+        let pmt = PmtSection::parse(section)?;
+
+        let mut selected = vec![pmt.pcr_pid];
+        // ECM PIDs for the whole program.
+        selected.extend(ca_pids(&section[12..pmt.es_info_offset]));
+        let mut rewritten = section[..pmt.es_info_offset].to_vec();
+        for stream in pmt.streams.iter() {
+            if self.config.matches(stream) {
+                selected.push(stream.pid);
+                selected.extend(ca_pids(stream.descriptors()));
+                rewritten.extend_from_slice(stream.data);
+            }
+        }
+        let section_length = rewritten.len() + 4 - 3;
+        rewritten[1] = (rewritten[1] & 0xF0) | (section_length >> 8) as u8;
+        rewritten[2] = (section_length & 0xFF) as u8;
+        let crc = crc32(&rewritten);
+        rewritten.extend_from_slice(&crc.to_be_bytes());
+
+        self.selected_pids.insert(pid, selected);
+        self.update_allowed_pids();
+
+        let cc = self.pmt_ccs.entry(pid).or_insert(0x0F);
+        Some(psi_packets(pid, &rewritten, cc))
+    }
+
+    fn update_allowed_pids(&mut self) {
+        self.allowed_pids = self.selected_pids
+            .values()
+            .flatten()
+            .chain(self.emm_pids.iter())
+            .cloned()
+            .collect();
+    }
+}
+
+// Returns CA PIDs in CA descriptors.
+fn ca_pids(mut descriptors: &[u8]) -> Vec<u16> {
+    // CA_descriptor
+    const TAG: u8 = 0x09;
+    let mut pids = Vec::new();
+    while descriptors.len() >= 2 {
+        let len = descriptors[1] as usize;
+        if descriptors.len() < 2 + len {
+            break;
+        }
+        if descriptors[0] == TAG && len >= 4 {
+            pids.push(((descriptors[4] & 0x1F) as u16) << 8 |
+                      descriptors[5] as u16);
+        }
+        descriptors = &descriptors[2 + len..];
+    }
+    pids
+}
+
+struct PmtSection<'a> {
+    pcr_pid: u16,
+    // The offset of the elementary stream loop.
+    es_info_offset: usize,
+    streams: Vec<EsInfo<'a>>,
+}
+
+struct EsInfo<'a> {
+    stream_type: u8,
+    pid: u16,
+    // The component tag in the stream identifier descriptor.
+    component_tag: Option<u8>,
+    // Raw data of the entry in the elementary stream loop.
+    data: &'a [u8],
+}
+
+impl<'a> EsInfo<'a> {
+    fn descriptors(&self) -> &'a [u8] {
+        &self.data[5..]
+    }
+}
+
+impl<'a> PmtSection<'a> {
+    fn parse(section: &'a [u8]) -> Option<Self> {
+        // 12 bytes header and 4 bytes CRC32.
+        if section.len() < 16 || section[0] != 0x02 {
+            return None;
+        }
+        let end = section.len() - 4;
+        let pcr_pid = ((section[8] & 0x1F) as u16) << 8 | section[9] as u16;
+        let program_info_length =
+            ((section[10] & 0x0F) as usize) << 8 | section[11] as usize;
+        let es_info_offset = 12 + program_info_length;
+        if es_info_offset > end {
+            return None;
+        }
+
+        let mut streams = Vec::new();
+        let mut pos = es_info_offset;
+        while pos + 5 <= end {
+            let stream_type = section[pos];
+            let pid = ((section[pos + 1] & 0x1F) as u16) << 8 |
+                section[pos + 2] as u16;
+            let es_info_length =
+                ((section[pos + 3] & 0x0F) as usize) << 8 |
+                section[pos + 4] as usize;
+            let next = pos + 5 + es_info_length;
+            if next > end {
+                return None;
+            }
+            let component_tag = Self::find_component_tag(
+                &section[pos + 5..next]);
+            streams.push(EsInfo {
+                stream_type, pid, component_tag, data: &section[pos..next],
+            });
+            pos = next;
+        }
+
+        Some(PmtSection { pcr_pid, es_info_offset, streams })
+    }
+
+    fn find_component_tag(mut descriptors: &[u8]) -> Option<u8> {
+        // stream_identifier_descriptor
+        const TAG: u8 = 0x52;
+        while descriptors.len() >= 2 {
+            let len = descriptors[1] as usize;
+            if descriptors.len() < 2 + len {
+                break;
+            }
+            if descriptors[0] == TAG && len >= 1 {
+                return Some(descriptors[2]);
+            }
+            descriptors = &descriptors[2 + len..];
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpeg_ts_packet::test_helper::*;
+
+    fn create_pmt_packet(pid: u16) -> Vec<u8> {
+        let mut body = vec![
+            0x00, 0x01, 0xC1, 0x00, 0x00,  // program_number, version, etc.
+            0xE1, 0xFF,  // PCR_PID
+            0xF0, 0x00,  // program_info_length
+        ];
+        body.extend_from_slice(&[  // video
+            0x02, 0xE1, 0x11, 0xF0, 0x03, 0x52, 0x01, 0x00,
+        ]);
+        body.extend_from_slice(&[  // audio
+            0x0F, 0xE1, 0x12, 0xF0, 0x03, 0x52, 0x01, 0x10,
+        ]);
+        body.extend_from_slice(&[  // caption
+            0x06, 0xE1, 0x30, 0xF0, 0x03, 0x52, 0x01, 0x30,
+        ]);
+        body.extend_from_slice(&[  // data
+            0x0D, 0xE1, 0x40, 0xF0, 0x03, 0x52, 0x01, 0x40,
+        ]);
+        create_psi_packet(pid, 0x02, &body)
+    }
+
+    fn create_scrambled_pmt_packet(pid: u16) -> Vec<u8> {
+        let body = vec![
+            0x00, 0x01, 0xC1, 0x00, 0x00,  // program_number, version, etc.
+            0xE1, 0xFF,  // PCR_PID
+            0xF0, 0x06,  // program_info_length
+            0x09, 0x04, 0x00, 0x05, 0xE1, 0x50,  // CA_descriptor (ECM)
+            // video
+            0x02, 0xE1, 0x11, 0xF0, 0x03, 0x52, 0x01, 0x00,
+            // audio with its own ECM
+            0x0F, 0xE1, 0x12, 0xF0, 0x09, 0x52, 0x01, 0x10,
+            0x09, 0x04, 0x00, 0x05, 0xE1, 0x51,
+        ];
+        create_psi_packet(pid, 0x02, &body)
+    }
+
+    fn pids(chunk: &[u8]) -> Vec<u16> {
+        ts_packets(chunk).map(|packet| packet.pid()).collect()
+    }
+
+    #[test]
+    fn test_pid_filter() {
+        let mut filter = PidFilter::new(PidFilterConfig {
+            components: vec![StreamComponent::Video],
+            pids: vec![0x0112],
+        });
+
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&create_packet(0x0111, true, &[]));
+        chunk.extend_from_slice(&create_pat_packet(&[0x0101]));
+        chunk.extend_from_slice(&create_packet(0x0111, true, &[]));
+        chunk.extend_from_slice(&create_pmt_packet(0x0101));
+        chunk.extend_from_slice(&create_packet(0x0111, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0112, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0130, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0140, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x01FF, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0012, true, &[]));
+
+        let filtered = filter.filter(&chunk);
+        assert_eq!(
+            pids(&filtered),
+            vec![0x0000, 0x0101, 0x0111, 0x0112, 0x01FF, 0x0012]);
+
+        let pmt = TsPacket::new(&filtered[TS_PACKET_SIZE..TS_PACKET_SIZE * 2]);
+        let mut collector = PsiSectionCollector::default();
+        let (_, section) = collector.collect(&pmt).unwrap();
+        assert_eq!(crc32(&section), 0);
+        let pmt = PmtSection::parse(&section).unwrap();
+        assert_eq!(pmt.pcr_pid, 0x01FF);
+        let pids: Vec<u16> = pmt.streams.iter().map(|es| es.pid).collect();
+        assert_eq!(pids, vec![0x0111, 0x0112]);
+    }
+
+    #[test]
+    fn test_pid_filter_ca_pids() {
+        let mut filter = PidFilter::new(PidFilterConfig {
+            components: vec![StreamComponent::Video],
+            pids: vec![],
+        });
+
+        let cat_body = [
+            0xFF, 0xFF, 0xC1, 0x00, 0x00,  // reserved, version, etc.
+            0x09, 0x04, 0x00, 0x05, 0xE1, 0x60,  // CA_descriptor (EMM)
+        ];
+
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&create_pat_packet(&[0x0101]));
+        chunk.extend_from_slice(&create_psi_packet(CAT_PID, 0x01, &cat_body));
+        chunk.extend_from_slice(&create_scrambled_pmt_packet(0x0101));
+        chunk.extend_from_slice(&create_packet(0x0111, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0112, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0150, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0151, true, &[]));
+        chunk.extend_from_slice(&create_packet(0x0160, true, &[]));
+
+        let filtered = filter.filter(&chunk);
+        assert_eq!(
+            pids(&filtered),
+            vec![0x0000, 0x0001, 0x0101, 0x0111, 0x0150, 0x0160]);
+    }
+
+    #[test]
+    fn test_pmt_section() {
+        let data = create_pmt_packet(0x0101);
+        let mut collector = PsiSectionCollector::default();
+        let (_, section) = collector.collect(&TsPacket::new(&data)).unwrap();
+        let pmt = PmtSection::parse(&section).unwrap();
+        assert_eq!(pmt.pcr_pid, 0x01FF);
+        assert_eq!(pmt.streams.len(), 4);
+        assert_eq!(pmt.streams[2].stream_type, 0x06);
+        assert_eq!(pmt.streams[2].pid, 0x0130);
+        assert_eq!(pmt.streams[2].component_tag, Some(0x30));
+        assert!(StreamComponent::Caption.matches(0x06, Some(0x30)));
+        assert!(!StreamComponent::Caption.matches(0x06, Some(0x38)));
+        assert!(StreamComponent::Superimpose.matches(0x06, Some(0x38)));
+
+        assert!(PmtSection::parse(&section[..10]).is_none());
+    }
+
+    #[test]
+    fn test_stream_component_from_str() {
+        assert_eq!("video".parse(), Ok(StreamComponent::Video));
+        assert_eq!("data".parse(), Ok(StreamComponent::Data));
+        assert!("unknown".parse::<StreamComponent>().is_err());
+    }
+}
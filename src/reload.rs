@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{self, Config, SharedConfig};
+use crate::eit_feeder;
+use crate::epg;
+use crate::error::Error;
+use crate::filter_pipeline;
+use crate::job;
+use crate::tuner;
+
+// Reloads the config file, and applies the new config to components.
+//
+// The whole config is validated before applying it.  The new config is
+// applied to all components or none of them.
+//
+// Settings in `server` are not applied until restart.
+pub async fn reload(shared: &SharedConfig) -> Result<(), Error> {
+    log::info!("Reloading {}...", shared.path());
+    let config = config::try_load(shared.path())?;
+    if config.server != shared.get().server {
+        log::warn!("Changes in `server` take effect after restart");
+    }
+    if let Err(err) = apply(config.clone()).await {
+        // Some of components may have been updated.  Restore the current
+        // config so that all components use the same config.
+        log::error!("Failed to apply the new config, restore the current one");
+        if let Err(err) = apply(shared.get()).await {
+            log::error!("Failed to restore the current config: {}", err);
+        }
+        return Err(err);
+    }
+    shared.set(config);
+    log::info!("Reloaded {}", shared.path());
+    Ok(())
+}
+
+async fn apply(config: Arc<Config>) -> Result<(), Error> {
+    tuner::reload(config.clone()).await?;
+    filter_pipeline::reload(config.clone()).await?;
+    eit_feeder::reload(config.clone()).await?;
+    job::reload(config.clone()).await?;
+    epg::reload(config).await?;
+    Ok(())
+}
+
+// Reloads the config file whenever SIGHUP is received.  Never returns unless
+// failing to install the signal handler.
+pub async fn reload_on_sighup(shared: SharedConfig) -> Result<(), Error> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    while sighup.recv().await.is_some() {
+        log::info!("SIGHUP received");
+        if let Err(err) = reload(&shared).await {
+            log::error!("Failed to reload {}: {}", shared.path(), err);
+        }
+    }
+    Ok(())
+}
@@ -1,16 +1,24 @@
+use std::time::Duration;
+
 use log;
 use serde_json;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::stream::StreamExt;
 
 use crate::command_util;
+use crate::config::ProcessConfig;
 use crate::epg::*;
 use crate::error::Error;
 use crate::models::*;
+use crate::mpeg_ts_stream::MpegTsStream;
+use crate::nit::{NitCollector, NitTable};
 use crate::tuner;
 
 pub struct ServiceScanner {
     command: String,
+    process: ProcessConfig,
     channels: Vec<EpgChannel>,
+    follow_relocations: bool,
 }
 
 // TODO: The following implementation has code clones similar to
@@ -19,31 +27,79 @@ pub struct ServiceScanner {
 impl ServiceScanner {
     const LABEL: &'static str = "service-scanner";
 
+    // The NIT is transmitted at least once every 10 seconds.
+    const NIT_TIMEOUT: Duration = Duration::from_secs(15);
+
     pub fn new(
         command: String,
-        channels: Vec<EpgChannel>
+        process: ProcessConfig,
+        channels: Vec<EpgChannel>,
+        follow_relocations: bool,
     ) -> Self {
-        ServiceScanner { command, channels }
+        ServiceScanner { command, process, channels, follow_relocations }
     }
 
-    pub async fn scan_services(self) -> Result<Vec<EpgService>, Error> {
+    // Returns services found and services which have moved to other
+    // transponders or slots.
+    //
+    // Services which have moved are included in the services found if
+    // `follow_relocations` is true.
+    pub async fn scan_services(
+        self,
+    ) -> Result<(Vec<EpgService>, Vec<ServiceRelocation>), Error> {
         log::debug!("Scanning services...");
 
-        let mut services = Vec::new();
+        // Services found in the last scan.
+        let known_services = query_services().await.unwrap_or_default();
+
+        let mut scanned = Vec::new();
+        let mut nit = NitTable::default();
+        let mut nit_collected = Vec::new();
         for channel in self.channels.iter() {
-            services.append(&mut Self::scan_services_in_channel(
-                &channel, &self.command).await?);
+            let nit_required = is_relocatable(channel.channel_type) &&
+                !nit_collected.contains(&channel.channel_type);
+            let (services, table, complete) = Self::scan_services_in_channel(
+                &channel, &self.command, &self.process, nit_required).await?;
+            nit.merge(table);
+            if complete {
+                nit_collected.push(channel.channel_type);
+            }
+            scanned.push((channel, services));
+        }
+
+        let relocations = find_relocations(&scanned, &known_services, &nit);
+        for relocation in relocations.iter() {
+            log::warn!("{}", relocation);
+        }
+
+        let mut services: Vec<EpgService> = scanned
+            .into_iter()
+            .flat_map(|(_, services)| services)
+            .collect();
+
+        if self.follow_relocations {
+            for channel in relocated_channels(&self.channels, &relocations) {
+                match Self::scan_services_in_channel(
+                    &channel, &self.command, &self.process, false).await {
+                    Ok((mut found, _, _)) => services.append(&mut found),
+                    Err(err) => log::error!(
+                        "Failed to scan services in {} {}: {}",
+                        channel.channel_type, channel.channel, err),
+                }
+            }
         }
 
         log::debug!("Found {} services", services.len());
 
-        Ok(services)
+        Ok((services, relocations))
     }
 
     async fn scan_services_in_channel(
         channel: &EpgChannel,
         command: &str,
-    ) -> Result<Vec<EpgService>, Error> {
+        process: &ProcessConfig,
+        nit_required: bool,
+    ) -> Result<(Vec<EpgService>, NitTable, bool), Error> {
         log::debug!("Scanning services in {}...", channel.name);
 
         let user = TunerUser {
@@ -52,7 +108,8 @@ impl ServiceScanner {
         };
 
         let stream = tuner::start_streaming(
-            channel.channel_type, channel.channel.clone(), user).await?;
+            channel.channel_type, channel.channel.clone(), user, None,
+            None).await?;
 
         let template = mustache::compile_str(command)?;
         let data = mustache::MapBuilder::new()
@@ -62,9 +119,9 @@ impl ServiceScanner {
         let cmd = template.render_data_to_string(&data)?;
 
         let (input, mut output) = command_util::spawn_pipeline(
-            vec![cmd], stream.id())?;
+            vec![cmd], stream.id(), process)?;
 
-        let handle = tokio::spawn(stream.pipe(input));
+        let handle = tokio::spawn(Self::pipe(stream, input, nit_required));
 
         let mut buf = Vec::new();
         output.read_to_end(&mut buf).await?;
@@ -75,14 +132,275 @@ impl ServiceScanner {
 
         // Wait for the task so that the tuner is released before a request for
         // streaming in the next iteration.
-        let _ = handle.await;
+        let (table, complete) = handle.await.unwrap_or_default();
 
         let services: Vec<TsService> = serde_json::from_slice(&buf)?;
         log::debug!("Found {} services in {}", services.len(), channel.name);
 
-        Ok(services
-           .into_iter()
-           .map(|sv| EpgService::from((channel, &sv)))
-           .collect())
+        let services = services
+            .into_iter()
+            .map(|sv| EpgService::from((channel, &sv)))
+            .collect();
+
+        Ok((services, table, complete))
+    }
+
+    // Pipes TS packets to the pipeline while collecting NIT sections.
+    //
+    // When the NIT is required, TS packets are read after the pipeline exits
+    // until all NIT sections are collected or it times out.  Returns the NIT
+    // table and whether all NIT sections have been collected.
+    async fn pipe<W>(
+        mut stream: MpegTsStream,
+        mut input: W,
+        nit_required: bool,
+    ) -> (NitTable, bool)
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut collector = NitCollector::default();
+        let mut table = NitTable::default();
+
+        while let Some(Ok(chunk)) = stream.next().await {
+            if nit_required && !collector.is_complete() {
+                collector.feed(&chunk, &mut table);
+            }
+            if input.write_all(&chunk).await.is_err() {
+                break;
+            }
+        }
+
+        if nit_required && !collector.is_complete() {
+            log::debug!("{}: Waiting for NIT sections...", stream.id());
+            let _ = tokio::time::timeout(Self::NIT_TIMEOUT, async {
+                while let Some(Ok(chunk)) = stream.next().await {
+                    collector.feed(&chunk, &mut table);
+                    if collector.is_complete() {
+                        break;
+                    }
+                }
+            }).await;
+        }
+
+        let complete = collector.is_complete();
+        (table, complete)
+    }
+}
+
+fn is_relocatable(channel_type: ChannelType) -> bool {
+    [ChannelType::BS, ChannelType::CS].contains(&channel_type)
+}
+
+// A service in the last scan has moved if it's not found in its channel but
+// the NIT shows it's carried on another transponder or slot.
+fn find_relocations(
+    scanned: &[(&EpgChannel, Vec<EpgService>)],
+    known_services: &[EpgService],
+    nit: &NitTable,
+) -> Vec<ServiceRelocation> {
+    let is_found = |sv: &EpgService| scanned
+        .iter()
+        .flat_map(|(_, services)| services.iter())
+        .any(|found| found.nid == sv.nid && found.sid == sv.sid);
+
+    let mut relocations = Vec::new();
+    for (channel, _) in scanned.iter() {
+        if !is_relocatable(channel.channel_type) {
+            continue;
+        }
+        let missing = known_services
+            .iter()
+            .filter(|sv| sv.channel.channel_type == channel.channel_type)
+            .filter(|sv| sv.channel.name == channel.name)
+            .filter(|sv| !is_found(sv));
+        for sv in missing {
+            let tsid = match nit.find(sv.nid, sv.sid) {
+                Some(tsid) => tsid,
+                None => continue,
+            };
+            let to = nit.channel_for_tsid(
+                channel.channel_type, sv.nid, tsid);
+            let to = match to {
+                Some(to) => to,
+                None => continue,
+            };
+            if to == channel.channel {
+                continue;
+            }
+            relocations.push(ServiceRelocation {
+                nid: sv.nid,
+                sid: sv.sid,
+                name: sv.name.clone(),
+                channel_type: channel.channel_type,
+                channel_name: channel.name.clone(),
+                from: channel.channel.clone(),
+                to,
+                tsid,
+            });
+        }
+    }
+    relocations
+}
+
+// Channels to be scanned for services which have moved.  Only the services
+// which have moved are scanned so that services on the transponder are not
+// duplicated when another channel in the config points to it.
+fn relocated_channels(
+    channels: &[EpgChannel],
+    relocations: &[ServiceRelocation],
+) -> Vec<EpgChannel> {
+    let mut relocated: Vec<EpgChannel> = Vec::new();
+    for relocation in relocations.iter() {
+        if let Some(channel) = relocated.iter_mut().find(|channel| {
+            channel.name == relocation.channel_name &&
+                channel.channel == relocation.to
+        }) {
+            channel.services.push(relocation.sid);
+            continue;
+        }
+        let channel = match channels.iter().find(|channel| {
+            channel.channel_type == relocation.channel_type &&
+                channel.name == relocation.channel_name
+        }) {
+            Some(channel) => channel,
+            None => continue,
+        };
+        relocated.push(EpgChannel {
+            channel: relocation.to.clone(),
+            services: vec![relocation.sid],
+            excluded_services: vec![],
+            ..channel.clone()
+        });
+    }
+    relocated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_relocations() {
+        let bs = channel("BS", ChannelType::BS, "BS15_0");
+        let gr = channel("GR", ChannelType::GR, "27");
+        let known = vec![
+            service(&bs, 4, 0x40F0, 101),
+            service(&bs, 4, 0x40F0, 102),
+            service(&bs, 4, 0x40F0, 103),
+            service(&gr, 0x7FE0, 0x7FE0, 1024),
+        ];
+        let scanned = vec![
+            (&bs, vec![service(&bs, 4, 0x40F0, 102)]),
+            (&gr, vec![]),
+        ];
+        let mut nit = NitTable::default();
+        nit.locations.insert((4.into(), 101.into()), 0x40F1.into());
+        nit.locations.insert((4.into(), 102.into()), 0x40F0.into());
+        nit.transport_streams.insert((4.into(), 0x40F0.into()));
+        nit.transport_streams.insert((4.into(), 0x40F1.into()));
+
+        let relocations = find_relocations(&scanned, &known, &nit);
+        assert_eq!(relocations, vec![ServiceRelocation {
+            nid: 4.into(),
+            sid: 101.into(),
+            name: "101".to_string(),
+            channel_type: ChannelType::BS,
+            channel_name: "BS".to_string(),
+            from: "BS15_0".to_string(),
+            to: "BS15_1".to_string(),
+            tsid: 0x40F1.into(),
+        }]);
+
+        // Found in another channel.
+        let bs2 = channel("BS2", ChannelType::BS, "BS15_1");
+        let scanned = vec![
+            (&bs, vec![service(&bs, 4, 0x40F0, 102)]),
+            (&bs2, vec![service(&bs2, 4, 0x40F1, 101)]),
+        ];
+        assert!(find_relocations(&scanned, &known, &nit).is_empty());
+    }
+
+    #[test]
+    fn test_relocated_channels() {
+        let channels = vec![
+            channel("BS", ChannelType::BS, "BS15_0"),
+        ];
+        let relocations = vec![
+            relocation("BS", 101, "BS15_1"),
+            relocation("BS", 102, "BS15_1"),
+            relocation("BS", 103, "BS15_2"),
+            relocation("unknown", 104, "BS15_2"),
+        ];
+        let relocated = relocated_channels(&channels, &relocations);
+        assert_eq!(relocated.len(), 2);
+        assert_eq!(relocated[0].name, "BS");
+        assert_eq!(relocated[0].channel, "BS15_1");
+        assert_eq!(relocated[0].services, [101.into(), 102.into()]);
+        assert_eq!(relocated[1].channel, "BS15_2");
+        assert_eq!(relocated[1].services, [103.into()]);
+    }
+
+    #[tokio::test]
+    async fn test_pipe() {
+        use crate::nit::test_helper::create_nit_packet;
+
+        let (mut sender, receiver) = tokio::sync::mpsc::channel(4);
+        let stream = MpegTsStream::new(Default::default(), receiver);
+        sender.send(create_nit_packet(4, 0, 0, 0, &[(0x40F1, &[101])]).into())
+            .await.unwrap();
+        drop(sender);
+
+        let mut buf = Vec::new();
+        let (table, complete) = ServiceScanner::pipe(stream, &mut buf, true)
+            .await;
+        assert_eq!(buf.len(), 188);
+        assert!(complete);
+        assert_eq!(table.find(4.into(), 101.into()), Some(0x40F1.into()));
+    }
+
+    fn channel(
+        name: &str,
+        channel_type: ChannelType,
+        channel: &str,
+    ) -> EpgChannel {
+        EpgChannel {
+            name: name.to_string(),
+            channel_type,
+            channel: channel.to_string(),
+            services: vec![],
+            excluded_services: vec![],
+        }
+    }
+
+    fn service(
+        channel: &EpgChannel,
+        nid: u16,
+        tsid: u16,
+        sid: u16,
+    ) -> EpgService {
+        EpgService {
+            nid: nid.into(),
+            tsid: tsid.into(),
+            sid: sid.into(),
+            service_type: 1,
+            logo_id: 0,
+            remote_control_key_id: 0,
+            name: sid.to_string(),
+            channel: channel.clone(),
+            has_logo_data: false,
+        }
+    }
+
+    fn relocation(channel_name: &str, sid: u16, to: &str) -> ServiceRelocation {
+        ServiceRelocation {
+            nid: 4.into(),
+            sid: sid.into(),
+            name: sid.to_string(),
+            channel_type: ChannelType::BS,
+            channel_name: channel_name.to_string(),
+            from: "BS15_0".to_string(),
+            to: to.to_string(),
+            tsid: 0.into(),
+        }
     }
 }
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use actix_web::dev::Server;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Instant};
+
+use crate::config::Config;
+use crate::epg;
+use crate::error::Error;
+use crate::job;
+use crate::tuner;
+
+// Waits for SIGINT or SIGTERM.
+pub async fn wait_for_signal() -> Result<(), Error> {
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigint.recv() => log::info!("SIGINT received"),
+        _ = sigterm.recv() => log::info!("SIGTERM received"),
+    }
+    Ok(())
+}
+
+// Stops components in the following order:
+//
+//   1. Stop accepting new connections
+//   2. Wait for running jobs until the deadline
+//   3. Deactivate tuners, which closes subscribers and cancels jobs still
+//      running
+//   4. Save EPG data
+//   5. Stop the server after in-flight requests finish or the deadline
+//
+// The deadline is specified by `server.shutdown-timeout`.
+pub async fn shutdown(config: &Config, server: Server) {
+    log::info!("Shutting down...");
+    let timeout = Duration::from_secs(config.server.shutdown_timeout);
+    let deadline = Instant::now() + timeout;
+
+    server.pause().await;
+
+    match time::timeout_at(deadline, job::shutdown()).await {
+        Ok(Ok(_)) => log::info!("All jobs finished"),
+        Ok(Err(err)) => log::error!("Failed to stop jobs: {}", err),
+        Err(_) => log::warn!("Jobs not finished before the deadline, cancel"),
+    }
+
+    if let Err(err) = tuner::shutdown().await {
+        log::error!("Failed to deactivate tuners: {}", err);
+    }
+
+    if let Err(err) = epg::save().await {
+        log::error!("Failed to save EPG data: {}", err);
+    }
+
+    if time::timeout_at(deadline, server.stop(true)).await.is_err() {
+        log::warn!("Requests not finished before the deadline, abort");
+    }
+
+    log::info!("Shut down");
+}
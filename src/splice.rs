@@ -0,0 +1,380 @@
+use std::collections::VecDeque;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes, BytesMut};
+use mio::unix::UnixReady;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+
+use crate::command_util::CommandPipelineInput;
+use crate::mpeg_ts_packet::TS_PACKET_SIZE;
+use crate::mpeg_ts_stream::MpegTsStream;
+use crate::tokio_snippet::ChildIo;
+use crate::tuner::TunerSubscriptionId as SubscriberId;
+
+// A fast path which moves TS packets from the stdout of a tuner process to the
+// stdin of a filter pipeline by using splice(2).
+//
+// Normally, TS packets are read into a buffer in the user space, broadcasted
+// to subscribers, and then written to the stdin of a filter pipeline.  When a
+// broadcaster has only one subscriber which goes straight into a filter
+// pipeline, the broadcaster can hand over the stdin of the pipeline to its
+// source.  The source moves data between the pipes in the kernel while the
+// sink is set, and no chunk is broadcasted.
+//
+// Bytes of a partial TS packet buffered in the broadcaster are handed over to
+// the filter pipeline before splicing.  The broadcaster releases the sink when
+// another subscriber comes in, and the source hands back the target at the end
+// of the current TS packet.  The filter pipeline of the spliced subscriber is
+// fed in the normal way after that.
+
+#[derive(Clone, Default)]
+pub struct SpliceSink(Arc<Mutex<Option<SpliceTarget>>>);
+
+impl SpliceSink {
+    pub fn set(&self, target: SpliceTarget) {
+        *self.0.lock().unwrap() = Some(target);
+    }
+
+    pub fn take(&self) -> Option<SpliceTarget> {
+        self.0.lock().unwrap().take()
+    }
+
+    // Starts splicing to the target if it has not been started.
+    //
+    // Bytes in `buf` are moved to the target.  They're the beginning of a TS
+    // packet if `aligned` is true.  Returns true if splicing has been started.
+    pub fn hand_over(&self, buf: &mut BytesMut, aligned: bool) -> bool {
+        let mut target = self.0.lock().unwrap();
+        let target = match *target {
+            Some(ref mut target) if !target.started => target,
+            _ => return false,
+        };
+        target.offset = if aligned {
+            target.offset.map(|offset| (offset + buf.len()) % TS_PACKET_SIZE)
+        } else {
+            None
+        };
+        if !buf.is_empty() {
+            target.pending.push_back(buf.split().freeze());
+        }
+        target.started = true;
+        true
+    }
+
+    // Asks the source to stop splicing at the end of the current TS packet.
+    //
+    // The target is sent to the returned receiver when stopped.
+    pub fn release(&self) -> Option<oneshot::Receiver<SpliceTarget>> {
+        let mut guard = self.0.lock().unwrap();
+        let started = guard.as_ref()?.started;
+        let (sender, receiver) = oneshot::channel();
+        if started {
+            guard.as_mut().unwrap().releaser = Some(sender);
+        } else {
+            let _ = sender.send(guard.take().unwrap());
+        }
+        Some(receiver)
+    }
+}
+
+pub struct SpliceTarget {
+    id: SubscriberId,
+    input: CommandPipelineInput,
+    // Chunks which have been queued before starting splicing.  These are
+    // written before splicing in order to keep the order of data.
+    pending: VecDeque<Bytes>,
+    // The position in a TS packet at the end of data which has been queued
+    // or spliced.  `None` if unknown.
+    offset: Option<usize>,
+    started: bool,
+    releaser: Option<oneshot::Sender<SpliceTarget>>,
+}
+
+impl SpliceTarget {
+    // The maximum number of splice(2) calls in a single poll.  The task
+    // yields after that so that other tasks can run.
+    const MAX_SPLICES_PER_POLL: usize = 16;
+    const SPLICE_SIZE: usize = 64 * 1024;
+
+    pub fn new(
+        id: SubscriberId,
+        input: CommandPipelineInput,
+        pending: Vec<Bytes>,
+    ) -> Self {
+        SpliceTarget {
+            id,
+            input,
+            pending: pending.into(),
+            // Queued chunks contain only whole TS packets.
+            offset: Some(0),
+            started: false,
+            releaser: None,
+        }
+    }
+
+    // Writes the remaining chunks, then copies chunks from `stream` to the
+    // filter pipeline in the normal way.
+    pub async fn fallback(self, stream: MpegTsStream) {
+        let SpliceTarget { id, mut input, pending, .. } = self;
+        for chunk in pending.into_iter() {
+            if let Err(err) = input.write_all(&chunk).await {
+                log::debug!("{}: Failed to write pending chunks: {}", id, err);
+                return;
+            }
+        }
+        stream.pipe(input).await
+    }
+
+    fn hand_back(mut self) {
+        if let Some(releaser) = self.releaser.take() {
+            let _ = releaser.send(self);
+        }
+    }
+
+    // Returns `Poll::Ready(Ok(()))` when EOF is reached on the source, or
+    // when the data is aligned with TS packets after the release.
+    fn poll_splice<T>(
+        &mut self,
+        source: &ChildIo<T>,
+        cx: &mut Context,
+    ) -> Poll<io::Result<()>>
+    where
+        T: AsRawFd,
+    {
+        while let Some(chunk) = self.pending.front_mut() {
+            let n = match Pin::new(&mut self.input).poll_write(cx, chunk) {
+                Poll::Ready(Ok(n)) => n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            };
+            chunk.advance(n);
+            if chunk.is_empty() {
+                self.pending.pop_front();
+            }
+        }
+
+        let src = source.get_ref().as_raw_fd();
+        let dst = self.input.as_raw_fd();
+        let readable = mio::Ready::readable() | UnixReady::hup();
+        for _ in 0..Self::MAX_SPLICES_PER_POLL {
+            let len = match (self.releaser.is_some(), self.offset) {
+                (false, _) => Self::SPLICE_SIZE,
+                (true, Some(offset)) if offset > 0 => TS_PACKET_SIZE - offset,
+                (true, _) => return Poll::Ready(Ok(())),
+            };
+            match source.poll_read_ready(cx, readable) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            match self.input.poll_write_ready(cx) {
+                Poll::Ready(Ok(_)) => (),
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+            match splice(src, dst, len) {
+                Ok(0) => return Poll::Ready(Ok(())),
+                Ok(n) => {
+                    self.offset = self.offset
+                        .map(|offset| (offset + n) % TS_PACKET_SIZE);
+                }
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    // We don't know which pipe is not ready.  Clearing the
+                    // readiness of a pipe which is actually ready causes a
+                    // hang-up because no readiness event will come.
+                    let (readable, writable) = poll_fds(src, dst)?;
+                    if !readable {
+                        source.clear_read_ready(cx, mio::Ready::readable())?;
+                    }
+                    if !writable {
+                        self.input.clear_write_ready(cx)?;
+                    }
+                }
+                Err(err) => return Poll::Ready(Err(err)),
+            }
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+// A reader which splices data to the sink while the sink is set.
+pub struct SpliceReader<T>
+where
+    T: AsRawFd,
+{
+    inner: ChildIo<T>,
+    sink: SpliceSink,
+}
+
+impl<T> SpliceReader<T>
+where
+    T: AsRawFd,
+{
+    pub fn new(inner: ChildIo<T>, sink: SpliceSink) -> Self {
+        SpliceReader { inner, sink }
+    }
+}
+
+impl<T> AsyncRead for SpliceReader<T>
+where
+    T: AsRawFd + io::Read + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut target = this.sink.0.lock().unwrap();
+        let result = match *target {
+            Some(ref mut spliced) if spliced.started =>
+                spliced.poll_splice(&this.inner, cx),
+            _ => return Pin::new(&mut this.inner).poll_read(cx, buf),
+        };
+        match result {
+            Poll::Ready(Ok(_)) => {
+                let released = target
+                    .as_ref()
+                    .is_some_and(|spliced| spliced.releaser.is_some());
+                if !released {
+                    return Poll::Ready(Ok(0));  // EOF
+                }
+                if let Some(spliced) = target.take() {
+                    spliced.hand_back();
+                }
+                drop(target);
+                Pin::new(&mut this.inner).poll_read(cx, buf)
+            }
+            Poll::Ready(Err(err)) => {
+                // The filter pipeline has probably been closed.  Read data in
+                // the normal way after that.
+                if let Some(spliced) = target.take() {
+                    log::debug!("{}: Stop splicing: {}", spliced.id, err);
+                }
+                drop(target);
+                Pin::new(&mut this.inner).poll_read(cx, buf)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(src: RawFd, dst: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(src, std::ptr::null_mut(), dst, std::ptr::null_mut(),
+                     len, libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(n as usize)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn splice(_src: RawFd, _dst: RawFd, _len: usize) -> io::Result<usize> {
+    Err(io::Error::new(io::ErrorKind::Other, "splice(2) is not supported"))
+}
+
+pub fn is_supported() -> bool {
+    cfg!(target_os = "linux")
+}
+
+// Returns whether `src` is readable and whether `dst` is writable.
+fn poll_fds(src: RawFd, dst: RawFd) -> io::Result<(bool, bool)> {
+    let mut fds = [
+        libc::pollfd { fd: src, events: libc::POLLIN, revents: 0 },
+        libc::pollfd { fd: dst, events: libc::POLLOUT, revents: 0 },
+    ];
+    if unsafe { libc::poll(fds.as_mut_ptr(), 2, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let readable = fds[0].revents & (libc::POLLIN | libc::POLLHUP) != 0;
+    let writable = fds[1].revents & (libc::POLLOUT | libc::POLLERR) != 0;
+    Ok((readable, writable))
+}
+
+#[cfg(test)]
+#[cfg(target_os = "linux")]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+    use tokio::io::AsyncReadExt;
+    use crate::command_util;
+    use crate::tokio_snippet;
+
+    #[tokio::test]
+    async fn test_splice_reader() {
+        let mut process = command_util::spawn_process(
+            "printf hello", Stdio::null(), &Default::default()).unwrap();
+        let stdout = tokio_snippet::stdio(process.stdout.take())
+            .unwrap().unwrap();
+
+        let (input, mut output) = command_util::spawn_pipeline(
+            vec!["cat".to_string()], Default::default(),
+            &Default::default()).unwrap();
+
+        let sink = SpliceSink::default();
+        sink.set(SpliceTarget::new(
+            Default::default(), input, vec![Bytes::from("0")]));
+        assert!(sink.hand_over(&mut BytesMut::new(), true));
+
+        let mut reader = SpliceReader::new(stdout, sink.clone());
+        let mut buf = Vec::new();
+        let n = reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(n, 0);
+        let _ = process.wait();
+
+        // Close the input of the pipeline.
+        drop(sink.take());
+
+        let mut buf = Vec::new();
+        output.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"0hello");
+    }
+
+    #[tokio::test]
+    async fn test_splice_reader_release() {
+        let mut process = command_util::spawn_process(
+            "head -c 400 /dev/zero", Stdio::null(),
+            &Default::default()).unwrap();
+        let stdout = tokio_snippet::stdio(process.stdout.take())
+            .unwrap().unwrap();
+        let _ = process.wait();
+
+        let (input, mut output) = command_util::spawn_pipeline(
+            vec!["cat".to_string()], Default::default(),
+            &Default::default()).unwrap();
+
+        let sink = SpliceSink::default();
+        sink.set(SpliceTarget::new(Default::default(), input, vec![]));
+        let mut partial = BytesMut::from(&[1u8; 100][..]);
+        assert!(sink.hand_over(&mut partial, true));
+        assert!(partial.is_empty());
+        assert!(!sink.hand_over(&mut partial, true));
+        let released = sink.release().unwrap();
+
+        // The rest of the TS packet is spliced, and then the remaining data
+        // is read in the normal way.
+        let mut reader = SpliceReader::new(stdout, sink.clone());
+        let mut buf = Vec::new();
+        let n = reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(n, 400 - (TS_PACKET_SIZE - 100));
+        assert!(sink.take().is_none());
+
+        // Close the input of the pipeline.
+        drop(released.await.unwrap());
+
+        let mut buf = Vec::new();
+        output.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf.len(), TS_PACKET_SIZE);
+        assert_eq!(&buf[..100], &[1u8; 100][..]);
+        assert!(buf[100..].iter().all(|&b| b == 0));
+    }
+}
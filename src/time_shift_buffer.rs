@@ -0,0 +1,439 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use bytes::Bytes;
+use chrono::{DateTime, Duration};
+use futures::future::{self, Future};
+use tokio::sync::oneshot;
+
+use crate::config::TimeShiftConfig;
+use crate::datetime_ext::Jst;
+
+// A rolling buffer which keeps chunks received in the last N minutes.
+//
+// Each chunk is identified by a sequence number which is incremented
+// monotonically.  A time-shifted subscriber has a cursor pointing to the next
+// chunk to be sent.
+pub struct TimeShiftBuffer {
+    duration: Duration,
+    storage: Storage,
+    entries: VecDeque<Entry>,
+    next_seq: u64,
+}
+
+struct Entry {
+    seq: u64,
+    time: DateTime<Jst>,
+    location: Location,
+}
+
+impl TimeShiftBuffer {
+    // `name` is used for making names of segment files.
+    pub fn new(config: &TimeShiftConfig, name: &str) -> Self {
+        let storage = match config.dir {
+            Some(ref dir) => Storage::File(FileStorage::new(dir, name)),
+            None => Storage::Memory,
+        };
+        TimeShiftBuffer {
+            duration: Duration::minutes(config.buffer_minutes as i64),
+            storage,
+            entries: VecDeque::new(),
+            next_seq: 0,
+        }
+    }
+
+    // Returns the sequence number of the oldest chunk in the buffer.
+    pub fn first_seq(&self) -> u64 {
+        self.entries.front().map_or(self.next_seq, |entry| entry.seq)
+    }
+
+    // Returns the sequence number of the next chunk to be pushed.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    // Returns the sequence number of the first chunk received at or after
+    // `time`.
+    pub fn seq_at(&self, time: DateTime<Jst>) -> u64 {
+        self.entries
+            .iter()
+            .find(|entry| entry.time >= time)
+            .map_or(self.next_seq, |entry| entry.seq)
+    }
+
+    pub fn push(&mut self, chunk: Bytes) -> io::Result<()> {
+        self.push_at(chunk, Jst::now())
+    }
+
+    fn push_at(&mut self, chunk: Bytes, time: DateTime<Jst>) -> io::Result<()> {
+        let location = self.storage.store(chunk)?;
+        self.entries.push_back(Entry { seq: self.next_seq, time, location });
+        self.next_seq += 1;
+
+        let deadline = time - self.duration;
+        while let Some(entry) = self.entries.front() {
+            if entry.time >= deadline {
+                break;
+            }
+            self.entries.pop_front();
+        }
+        if let Some(entry) = self.entries.front() {
+            self.storage.purge(&entry.location);
+        }
+        Ok(())
+    }
+
+    // Returns `None` if the chunk has already been evicted or hasn't been
+    // received yet.
+    pub fn get(&self, seq: u64) -> Option<Chunk> {
+        let first_seq = self.first_seq();
+        if seq < first_seq || seq >= self.next_seq {
+            return None;
+        }
+        let entry = &self.entries[(seq - first_seq) as usize];
+        Some(self.storage.load(&entry.location))
+    }
+}
+
+pub type LoadFuture = Pin<Box<dyn Future<Output = io::Result<Bytes>>>>;
+
+pub enum Chunk {
+    Ready(Bytes),
+    // Loaded from a segment file on the I/O thread.
+    Loading(LoadFuture),
+}
+
+enum Location {
+    Memory(Bytes),
+    File { segment: u64, offset: u64, len: usize },
+}
+
+enum Storage {
+    Memory,
+    File(FileStorage),
+}
+
+impl Storage {
+    fn store(&mut self, chunk: Bytes) -> io::Result<Location> {
+        match self {
+            Self::Memory => Ok(Location::Memory(chunk)),
+            Self::File(storage) => storage.store(&chunk),
+        }
+    }
+
+    fn load(&self, location: &Location) -> Chunk {
+        match (self, location) {
+            (_, Location::Memory(chunk)) => Chunk::Ready(chunk.clone()),
+            (Self::File(storage), Location::File { segment, offset, len }) =>
+                Chunk::Loading(storage.load(*segment, *offset, *len)),
+            _ => unreachable!(),
+        }
+    }
+
+    // Removes data older than `location`.
+    fn purge(&mut self, location: &Location) {
+        if let (Self::File(storage), Location::File { segment, .. }) =
+            (self, location) {
+            storage.purge(*segment);
+        }
+    }
+}
+
+// Chunks are appended to segment files.  A new segment file is created when
+// the size of the current one exceeds `SEGMENT_SIZE`, and old segment files
+// are removed when all chunks in them are evicted.
+//
+// Segment files are accessed only on a dedicated I/O thread so that the
+// Broadcaster is never blocked.  Requests are processed in order, so a chunk
+// is always written before it's loaded.
+struct FileStorage {
+    sender: mpsc::SyncSender<FileRequest>,
+    // The first error on the I/O thread.
+    error: Arc<Mutex<Option<io::Error>>>,
+    has_segment: bool,
+    segment_size: u64,
+    next_segment: u64,
+}
+
+impl FileStorage {
+    // 64 MiB, about 30 seconds of a typical terrestrial broadcast.
+    const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+    // The maximum number of requests queued for the I/O thread.  Chunks are
+    // dropped instead of piling up in memory when the disk is slower than the
+    // tuner.  256 chunks are about 8 MiB.
+    const QUEUE_SIZE: usize = 256;
+
+    fn new(dir: &str, name: &str) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(Self::QUEUE_SIZE);
+        let error = Arc::new(Mutex::new(None));
+        let mut worker = FileWorker {
+            dir: PathBuf::from(dir),
+            name: name.to_string(),
+            segments: VecDeque::new(),
+            error: error.clone(),
+        };
+        thread::spawn(move || worker.run(receiver));
+        FileStorage {
+            sender,
+            error,
+            has_segment: false,
+            segment_size: 0,
+            next_segment: 0,
+        }
+    }
+
+    fn store(&mut self, chunk: &Bytes) -> io::Result<Location> {
+        if let Some(err) = self.error.lock().unwrap().take() {
+            return Err(err);
+        }
+
+        if !self.has_segment || self.segment_size >= Self::SEGMENT_SIZE {
+            self.has_segment = true;
+            self.segment_size = 0;
+            self.next_segment += 1;
+        }
+
+        let segment = self.next_segment - 1;
+        let offset = self.segment_size;
+        // The chunk is dropped if the queue is full.  The error disables the
+        // buffer in the same way as I/O errors.
+        let chunk = chunk.clone();
+        let len = chunk.len();
+        self.send(FileRequest::Store { segment, offset, chunk })?;
+        self.segment_size += len as u64;
+
+        Ok(Location::File { segment, offset, len })
+    }
+
+    fn load(&self, segment: u64, offset: u64, len: usize) -> LoadFuture {
+        let (reply, receiver) = oneshot::channel();
+        let request = FileRequest::Load { segment, offset, len, reply };
+        if let Err(err) = self.send(request) {
+            return Box::pin(future::ready(Err(err)));
+        }
+        Box::pin(async move {
+            match receiver.await {
+                Ok(result) => result,
+                // The I/O thread has stopped.
+                Err(_) => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+            }
+        })
+    }
+
+    fn purge(&mut self, first_segment: u64) {
+        // Skipped segments are removed in the next purge.
+        let _ = self.send(FileRequest::Purge { first_segment });
+    }
+
+    fn send(&self, request: FileRequest) -> io::Result<()> {
+        match self.sender.try_send(request) {
+            Ok(_) => Ok(()),
+            Err(mpsc::TrySendError::Full(_)) => {
+                Err(io::Error::other("Too many requests for the disk"))
+            }
+            // The I/O thread has panicked.
+            Err(mpsc::TrySendError::Disconnected(_)) =>
+                Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        }
+    }
+}
+
+enum FileRequest {
+    Store { segment: u64, offset: u64, chunk: Bytes },
+    Load {
+        segment: u64,
+        offset: u64,
+        len: usize,
+        reply: oneshot::Sender<io::Result<Bytes>>,
+    },
+    Purge { first_segment: u64 },
+}
+
+// Runs on the I/O thread.
+struct FileWorker {
+    dir: PathBuf,
+    name: String,
+    // (segment, file) pairs in ascending order of the segment.
+    segments: VecDeque<(u64, File)>,
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl FileWorker {
+    // Returns when the FileStorage is dropped.
+    fn run(&mut self, receiver: mpsc::Receiver<FileRequest>) {
+        for request in receiver.iter() {
+            match request {
+                FileRequest::Store { segment, offset, chunk } => {
+                    if let Err(err) = self.store(segment, offset, &chunk) {
+                        self.error.lock().unwrap().get_or_insert(err);
+                    }
+                }
+                FileRequest::Load { segment, offset, len, reply } => {
+                    let _ = reply.send(self.load(segment, offset, len));
+                }
+                FileRequest::Purge { first_segment } =>
+                    self.purge(first_segment),
+            }
+        }
+        for (segment, _) in self.segments.iter() {
+            self.remove_file(*segment);
+        }
+    }
+
+    fn make_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{}.{}.ts", self.name, segment))
+    }
+
+    fn store(
+        &mut self,
+        segment: u64,
+        offset: u64,
+        chunk: &[u8],
+    ) -> io::Result<()> {
+        let is_new = self.segments
+            .back()
+            .map(|(seg, _)| *seg != segment)
+            .unwrap_or(true);
+        if is_new {
+            let path = self.make_path(segment);
+            let file = OpenOptions::new()
+                .read(true).write(true).create(true).truncate(true)
+                .open(&path)?;
+            log::debug!("Created a time-shift segment: {}", path.display());
+            self.segments.push_back((segment, file));
+        }
+
+        let (_, file) = self.segments.back_mut().unwrap();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(chunk)?;
+        Ok(())
+    }
+
+    fn load(&self, segment: u64, offset: u64, len: usize) -> io::Result<Bytes> {
+        let (_, file) = self.segments
+            .iter()
+            .find(|(seg, _)| *seg == segment)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let mut file = file;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; len];
+        file.read_exact(&mut buf)?;
+        Ok(Bytes::from(buf))
+    }
+
+    fn purge(&mut self, first_segment: u64) {
+        while let Some((segment, _)) = self.segments.front() {
+            if *segment >= first_segment {
+                break;
+            }
+            let segment = *segment;
+            self.segments.pop_front();
+            self.remove_file(segment);
+        }
+    }
+
+    fn remove_file(&self, segment: u64) {
+        let path = self.make_path(segment);
+        match fs::remove_file(&path) {
+            Ok(_) => log::debug!("Removed a time-shift segment: {}",
+                                 path.display()),
+            Err(err) => log::error!("Failed to remove {}: {}",
+                                    path.display(), err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_time_shift_buffer() {
+        let config = TimeShiftConfig { buffer_minutes: 1, dir: None };
+        let mut buffer = TimeShiftBuffer::new(&config, "test");
+        assert_eq!(buffer.first_seq(), 0);
+        assert_eq!(buffer.next_seq(), 0);
+        assert!(buffer.get(0).is_none());
+
+        let base = Jst.ymd(2020, 1, 1).and_hms(0, 0, 0);
+        buffer.push_at(Bytes::from("0"), base).unwrap();
+        buffer.push_at(Bytes::from("1"), base + Duration::seconds(30)).unwrap();
+        assert_eq!(buffer.first_seq(), 0);
+        assert_eq!(buffer.next_seq(), 2);
+        assert!(matches!(buffer.get(1),
+                         Some(Chunk::Ready(chunk)) if chunk == "1"));
+        assert_eq!(buffer.seq_at(base), 0);
+        assert_eq!(buffer.seq_at(base + Duration::seconds(1)), 1);
+        assert_eq!(buffer.seq_at(base + Duration::seconds(31)), 2);
+
+        // The first chunk is evicted.
+        buffer.push_at(Bytes::from("2"), base + Duration::seconds(61)).unwrap();
+        assert_eq!(buffer.first_seq(), 1);
+        assert_eq!(buffer.next_seq(), 3);
+        assert!(buffer.get(0).is_none());
+        assert!(matches!(buffer.get(2),
+                         Some(Chunk::Ready(chunk)) if chunk == "2"));
+        assert_eq!(buffer.seq_at(base), 1);
+    }
+
+    #[tokio::test]
+    async fn test_time_shift_buffer_file() {
+        let dir = std::env::temp_dir();
+        let name = format!("mirakc-test-time-shift-{}", std::process::id());
+        let config = TimeShiftConfig {
+            buffer_minutes: 1,
+            dir: Some(dir.to_str().unwrap().to_string()),
+        };
+        let path = dir.join(format!("{}.0.ts", name));
+
+        {
+            let mut buffer = TimeShiftBuffer::new(&config, &name);
+            buffer.push(Bytes::from("0")).unwrap();
+            buffer.push(Bytes::from("11")).unwrap();
+            assert_eq!(load(&buffer, 0).await, Bytes::from("0"));
+            assert_eq!(load(&buffer, 1).await, Bytes::from("11"));
+            assert!(buffer.get(2).is_none());
+            assert!(path.exists());
+        }
+
+        // Segment files are removed on the I/O thread when the buffer is
+        // dropped.
+        for _ in 0..100 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_file_storage_queue_full() {
+        // No I/O thread consumes requests.
+        let (sender, _receiver) = mpsc::sync_channel(1);
+        let mut storage = FileStorage {
+            sender,
+            error: Arc::new(Mutex::new(None)),
+            has_segment: false,
+            segment_size: 0,
+            next_segment: 0,
+        };
+        assert!(storage.store(&Bytes::from("0")).is_ok());
+        assert!(storage.store(&Bytes::from("1")).is_err());
+        assert_eq!(storage.segment_size, 1);
+    }
+
+    async fn load(buffer: &TimeShiftBuffer, seq: u64) -> Bytes {
+        match buffer.get(seq) {
+            Some(Chunk::Loading(future)) => future.await.unwrap(),
+            _ => panic!("Must be loaded from a file"),
+        }
+    }
+}
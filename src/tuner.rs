@@ -1,19 +1,28 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
-use std::process::{Child, Stdio};
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use actix::prelude::*;
 use cfg_if;
+use chrono::DateTime;
 use log;
 use mustache;
+use tokio::sync::broadcast;
 
 use crate::broadcaster::*;
-use crate::command_util;
-use crate::config::{Config, TunerConfig};
+use crate::command_util::{
+    self, CommandPipelineInput, MonitoredProcess, ProcessExit, StderrLog};
+use crate::config::{
+    Config, ProcessConfig, SlowSubscriberConfig, StreamingConfig, TunerConfig};
+use crate::datetime_ext::Jst;
 use crate::error::Error;
 use crate::models::*;
 use crate::mpeg_ts_stream::MpegTsStream;
+use crate::splice::{SpliceReader, SpliceSink};
 use crate::tokio_snippet;
 
 pub fn start(config: Arc<Config>) {
@@ -31,24 +40,181 @@ pub async fn query_tuners() -> Result<Vec<MirakurunTuner>, Error> {
     }
 }
 
+pub async fn query_tuner(index: usize) -> Result<MirakurunTuner, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            match index {
+                0 => Ok(Default::default()),
+                _ => Err(Error::TunerNotFound),
+            }
+        } else {
+            TunerManager::from_registry()
+                .send(QueryTunerMessage { index }).await?
+        }
+    }
+}
+
+// Kills the tuner process of the current session, and returns its PID.  The
+// tuner is deactivated, and streams on it end.
+pub async fn kill_tuner_process(
+    index: usize,
+) -> Result<MirakurunTunerProcess, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            match index {
+                0 => Ok(MirakurunTunerProcess { pid: None }),
+                _ => Err(Error::TunerNotFound),
+            }
+        } else {
+            TunerManager::from_registry()
+                .send(KillTunerProcessMessage { index }).await?
+        }
+    }
+}
+
+// Enables or disables the tuner at runtime.  A disabled tuner is deactivated
+// and never used until enabled again.  The state is kept over reloading the
+// config as long as the settings of the tuner are unchanged.
+pub async fn enable_tuner(index: usize, enabled: bool) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = enabled;
+            match index {
+                0 => Ok(()),
+                _ => Err(Error::TunerNotFound),
+            }
+        } else {
+            TunerManager::from_registry()
+                .send(EnableTunerMessage { index, enabled }).await?
+        }
+    }
+}
+
+// Returns the last lines of stderr output from the tuner command of the
+// current or last session.
+pub async fn query_tuner_stderr(index: usize) -> Result<Vec<String>, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            match index {
+                0 => Ok(vec!["stderr".to_string()]),
+                _ => Err(Error::TunerNotFound),
+            }
+        } else {
+            TunerManager::from_registry()
+                .send(QueryTunerStderrMessage { index }).await?
+        }
+    }
+}
+
+// `slow_subscriber` is used for overriding `streaming.slow-subscriber` in the
+// config.
+//
+// `start_time` is used for starting streaming from the time-shift buffer.
 pub async fn start_streaming(
     channel_type: ChannelType,
     channel: String,
-    user: TunerUser
+    user: TunerUser,
+    slow_subscriber: Option<SlowSubscriberConfig>,
+    start_time: Option<DateTime<Jst>>,
 )-> Result<MpegTsStream, Error> {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
-            let _ = (channel_type, channel, user);
+            let _ = (channel_type, channel, user, slow_subscriber, start_time);
             let (_, receiver) = tokio::sync::mpsc::channel(1);
             Ok(MpegTsStream::new(Default::default(), receiver))
         } else {
             TunerManager::from_registry().send(StartStreamingMessage {
-                channel_type, channel, user
+                channel_type, channel, user, slow_subscriber, start_time
             }).await?
         }
     }
 }
 
+// Tuners are added or removed.  Active sessions on tuners whose settings are
+// unchanged are kept.
+// Returns a receiver of preemptions which will occur.
+pub async fn subscribe_preemptions(
+) -> Result<broadcast::Receiver<MirakurunTunerPreemption>, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            // The receiver is closed immediately.
+            Ok(broadcast::channel(1).1)
+        } else {
+            Ok(TunerManager::from_registry()
+               .send(SubscribePreemptionsMessage).await?)
+        }
+    }
+}
+
+pub async fn query_streams() -> Result<Vec<MirakurunStream>, Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(Vec::new())
+        } else {
+            TunerManager::from_registry().send(QueryStreamsMessage).await?
+        }
+    }
+}
+
+// Ends the stream of the subscription on the server side.  The tuner is
+// released in the same way as when the client closes the stream.
+pub async fn kill_stream(id: TunerSubscriptionId) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            match id.serial_number {
+                1 => Ok(()),
+                _ => Err(Error::StreamNotFound),
+            }
+        } else {
+            TunerManager::from_registry()
+                .send(KillStreamMessage { id }).await?
+        }
+    }
+}
+
+// Records filters applied to the stream of the subscription.  Returns
+// a counter which has to be incremented with the number of bytes sent to the
+// client.
+pub fn set_stream_output(
+    id: TunerSubscriptionId,
+    filters: Vec<String>,
+) -> StreamByteCounter {
+    let counter = StreamByteCounter::default();
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = (id, filters);
+        } else {
+            TunerManager::from_registry().do_send(SetStreamOutputMessage {
+                id, filters, counter: counter.clone()
+            });
+        }
+    }
+    counter
+}
+
+pub async fn reload(config: Arc<Config>) -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            let _ = config;
+            Ok(())
+        } else {
+            Ok(TunerManager::from_registry()
+               .send(ReloadMessage { config }).await?)
+        }
+    }
+}
+
+// Deactivates all tuners, and rejects requests for streaming after that.
+pub async fn shutdown() -> Result<(), Error> {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            Ok(())
+        } else {
+            Ok(TunerManager::from_registry().send(ShutdownMessage).await?)
+        }
+    }
+}
+
 pub fn stop_streaming(id: TunerSubscriptionId) {
     cfg_if::cfg_if! {
         if #[cfg(test)] {
@@ -61,9 +227,41 @@ pub fn stop_streaming(id: TunerSubscriptionId) {
     }
 }
 
+// Feeds `input` with TS packets in `stream`.  The tuner output is directly
+// spliced to `input` if possible.
+pub fn splice_streaming(stream: MpegTsStream, input: CommandPipelineInput) {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            actix::spawn(stream.pipe(input));
+        } else {
+            TunerManager::from_registry().do_send(SpliceStreamingMessage {
+                stream, input
+            });
+        }
+    }
+}
+
+// Returns a function to be called on a monitoring thread when the tuner
+// process exits.
+//
+// The address of `TunerManager` has to be obtained on a thread of the actix
+// system.
+fn make_process_exited_fn(
+    id: TunerSessionId,
+) -> impl FnOnce(ProcessExit) + Send + 'static {
+    cfg_if::cfg_if! {
+        if #[cfg(test)] {
+            move |exit| { let _ = (id, exit); }
+        } else {
+            let manager = TunerManager::from_registry();
+            move |exit| manager.do_send(ProcessExitedMessage { id, exit })
+        }
+    }
+}
+
 // identifiers
 
-#[derive(Clone, Copy, Default, PartialEq)]
+#[derive(Clone, Copy, Default, Eq, Hash, PartialEq)]
 pub struct TunerSessionId {
     tuner_index: usize,
     tuner_pid: u32,
@@ -81,6 +279,12 @@ pub struct TunerSubscriptionId {
     serial_number: u32,
 }
 
+impl TunerSubscriptionId {
+    pub fn session_id(&self) -> TunerSessionId {
+        self.session_id
+    }
+}
+
 impl fmt::Display for TunerSubscriptionId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "tuner#{}.{}.{}",
@@ -89,11 +293,55 @@ impl fmt::Display for TunerSubscriptionId {
     }
 }
 
+// Parses a string in the format of `Display`.
+impl FromStr for TunerSubscriptionId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with("tuner#") {
+            return Err(Error::StreamNotFound);
+        }
+        let nums: Vec<&str> = s["tuner#".len()..].split('.').collect();
+        if nums.len() != 3 {
+            return Err(Error::StreamNotFound);
+        }
+        let tuner_index = nums[0].parse()
+            .map_err(|_| Error::StreamNotFound)?;
+        let tuner_pid = nums[1].parse()
+            .map_err(|_| Error::StreamNotFound)?;
+        let serial_number = nums[2].parse()
+            .map_err(|_| Error::StreamNotFound)?;
+        Ok(TunerSubscriptionId {
+            session_id: TunerSessionId { tuner_index, tuner_pid },
+            serial_number,
+        })
+    }
+}
+
+// counter
+
+// Counts bytes sent to the client of a stream.  Shared between the response
+// body of the stream and the tuner session.
+#[derive(Clone, Default)]
+pub struct StreamByteCounter(Arc<AtomicU64>);
+
+impl StreamByteCounter {
+    pub fn add(&self, n: usize) {
+        self.0.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 // tuner manager
 
 struct TunerManager {
     config: Arc<Config>,
     tuners: Vec<Tuner>,
+    shutting_down: bool,
+    preemption_sender: broadcast::Sender<MirakurunTunerPreemption>,
 }
 
 struct TunerSubscription {
@@ -102,21 +350,71 @@ struct TunerSubscription {
 }
 
 impl TunerManager {
+    // Preemptions are published to subscribers through a channel having this
+    // capacity.  Slow subscribers miss old preemptions.
+    const PREEMPTION_CHANNEL_CAPACITY: usize = 16;
+
     fn new(config: Arc<Config>) -> Self {
-        TunerManager { config, tuners: Vec::new() }
+        let (preemption_sender, _) =
+            broadcast::channel(Self::PREEMPTION_CHANNEL_CAPACITY);
+        TunerManager {
+            config,
+            tuners: Vec::new(),
+            shutting_down: false,
+            preemption_sender,
+        }
     }
 
-    fn load_tuners(&mut self) {
+    // Existing tuners are reused for unchanged settings even if their indexes
+    // change.  Other existing tuners are deactivated and removed.
+    fn load_tuners(&mut self, config: Arc<Config>) {
         log::info!("Loading tuners...");
-        let tuners: Vec<Tuner> = self.config
+        let mut old_tuners: Vec<Option<Tuner>> =
+            self.tuners.drain(..).map(Some).collect();
+        let tuners: Vec<Tuner> = config
             .tuners
             .iter()
-            .filter(|config| !config.disabled)
+            .filter(|tuner_config| !tuner_config.disabled)
             .enumerate()
-            .map(|(i, config)| Tuner::new(i, config))
+            .map(|(i, tuner_config)| {
+                let found = old_tuners
+                    .iter_mut()
+                    .find(|tuner| match tuner {
+                        Some(tuner) => tuner.is_configured_with(tuner_config),
+                        None => false,
+                    })
+                    .and_then(Option::take);
+                match found {
+                    Some(mut tuner) => {
+                        if tuner.index != i {
+                            log::info!("tuner#{}: Moved to tuner#{}",
+                                       tuner.index, i);
+                        }
+                        tuner.index = i;
+                        tuner.streaming = config.streaming.clone();
+                        tuner
+                    }
+                    None => {
+                        Tuner::new(i, tuner_config, config.streaming.clone())
+                    }
+                }
+            })
             .collect();
+        for tuner in old_tuners.into_iter().flatten() {
+            // The active session is deactivated when dropped.
+            log::info!("tuner#{}: Removed", tuner.index);
+        }
         log::info!("Loaded {} tuners", tuners.len());
         self.tuners = tuners;
+        self.config = config;
+    }
+
+    // Indexes of tuners may change when reloaded.  So, a tuner is looked up
+    // with the ID of its active session.
+    fn find_tuner_mut(&mut self, id: TunerSessionId) -> Option<&mut Tuner> {
+        self.tuners
+            .iter_mut()
+            .find(|tuner| tuner.activity.session_id() == Some(id))
     }
 
     fn activate_tuner(
@@ -125,14 +423,19 @@ impl TunerManager {
         channel: String,
         user: TunerUser,
     ) -> Result<TunerSubscription, Error> {
+        if self.shutting_down {
+            return Err(Error::ShuttingDown);
+        }
+
         if let TunerUserInfo::Tracker { stream_id } = user.info {
-            let tuner = &mut self.tuners[stream_id.session_id.tuner_index];
-            if tuner.is_active() {
-                return Ok(tuner.subscribe(user));
-            }
-            return Err(Error::TunerUnavailable);
+            return match self.find_tuner_mut(stream_id.session_id) {
+                Some(tuner) => Ok(tuner.subscribe(user)),
+                None => Err(Error::TunerUnavailable),
+            };
         }
 
+        self.check_quota(&user)?;
+
         let found = self.tuners
             .iter_mut()
             .find(|tuner| tuner.is_reuseable(channel_type, &channel));
@@ -156,14 +459,21 @@ impl TunerManager {
         // a tuner used by a low priority user.
         let found = self.tuners
             .iter_mut()
+            .filter(|tuner| tuner.is_enabled())
             .filter(|tuner| tuner.is_supported_type(channel_type))
             .find(|tuner| tuner.can_grab(user.priority));
         if let Some(tuner) = found {
             log::info!("tuner#{}: Grab tuner, rectivate with {} {}",
                        tuner.index, channel_type, channel);
+            let preemptions = tuner.preempt(&user);
             tuner.deactivate();
-            tuner.activate(channel_type, channel)?;
-            return Ok(tuner.subscribe(user));
+            let result = tuner.activate(channel_type, channel)
+                .map(|_| tuner.subscribe(user));
+            for preemption in preemptions.into_iter() {
+                // No one may subscribe preemptions.
+                let _ = self.preemption_sender.send(preemption);
+            }
+            return result;
         }
 
         log::warn!("No tuner available for {} {} {}",
@@ -171,14 +481,166 @@ impl TunerManager {
         Err(Error::TunerUnavailable)
     }
 
+    // Every stream has its own tuner subscription even when it shares a filter
+    // pipeline with other streams.  So, counting subscribers is enough.
+    fn check_quota(&self, user: &TunerUser) -> Result<(), Error> {
+        let name = match user.quota() {
+            Some(name) => name,
+            None => return Ok(()),
+        };
+        let max_streams = self.config.quotas
+            .iter()
+            .find(|quota| quota.name == name)
+            .and_then(|quota| quota.max_streams);
+        let max_streams = match max_streams {
+            Some(max_streams) => max_streams,
+            None => return Ok(()),
+        };
+        let num_streams: usize = self.tuners
+            .iter()
+            .map(|tuner| tuner.activity.count_users(
+                |user| user.quota() == Some(name)))
+            .sum();
+        if num_streams >= max_streams {
+            log::warn!("{}: Quota exceeded: {} streams are in use",
+                       name, num_streams);
+            return Err(Error::QuotaExceeded(name.to_string()));
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.shutting_down = true;
+        // Subscribers are notified of the end of the stream by broadcasters
+        // when tuner processes are killed.
+        for tuner in self.tuners.iter_mut().filter(|tuner| tuner.is_active()) {
+            log::info!("tuner#{}: Deactivate", tuner.index);
+            tuner.deactivate();
+        }
+    }
+
+    fn kill_tuner_process(
+        &mut self,
+        index: usize,
+    ) -> Result<MirakurunTunerProcess, Error> {
+        let tuner = self.tuners.get_mut(index).ok_or(Error::TunerNotFound)?;
+        let (_, pid, _) = tuner.activity.get_models();
+        if let Some(pid) = pid {
+            log::info!("tuner#{}: Kill the tuner process {}", index, pid);
+            tuner.deactivate();
+        }
+        Ok(MirakurunTunerProcess { pid })
+    }
+
+    fn enable_tuner(
+        &mut self,
+        index: usize,
+        enabled: bool,
+    ) -> Result<(), Error> {
+        let tuner = self.tuners.get_mut(index).ok_or(Error::TunerNotFound)?;
+        if enabled {
+            log::info!("tuner#{}: Enabled", index);
+        } else {
+            log::info!("tuner#{}: Disabled", index);
+            tuner.deactivate();
+        }
+        tuner.enabled = enabled;
+        Ok(())
+    }
+
     fn deactivate_tuner(&mut self, id: TunerSubscriptionId) {
-        log::info!("tuner#{}: Deactivate", id.session_id.tuner_index);
-        self.tuners[id.session_id.tuner_index].deactivate();
+        if let Some(tuner) = self.find_tuner_mut(id.session_id) {
+            log::info!("tuner#{}: Deactivate", tuner.index);
+            tuner.deactivate();
+        }
     }
 
     fn stop_streaming(&mut self, id: TunerSubscriptionId) {
         log::info!("{}: Stop streaming", id);
-        let _ = self.tuners[id.session_id.tuner_index].stop_streaming(id);
+        match self.find_tuner_mut(id.session_id) {
+            Some(tuner) => {
+                let _ = tuner.stop_streaming(id);
+            }
+            None => {
+                let subscription_id = id.to_string();
+                let preemption = self.tuners
+                    .iter()
+                    .flat_map(|tuner| tuner.preemption_history.iter())
+                    .find(|preemption| {
+                        preemption.subscription_id == subscription_id
+                    });
+                match preemption {
+                    Some(preemption) =>
+                        log::info!("{}: Stream ended: Preempted by a user \
+                                    having priority {}",
+                                   id, preemption.preempted_by.priority),
+                    None =>
+                        log::warn!("Session ID unmatched, {} was probably \
+                                    deactivated", id.session_id),
+                }
+            }
+        }
+    }
+
+    fn kill_stream(&mut self, id: TunerSubscriptionId) -> Result<(), Error> {
+        let found = self.find_tuner_mut(id.session_id)
+            .map(|tuner| tuner.activity.has_subscriber(id))
+            .unwrap_or(false);
+        if !found {
+            return Err(Error::StreamNotFound);
+        }
+        log::info!("{}: Kill the stream", id);
+        // The broadcaster closes the stream of the subscriber.
+        self.stop_streaming(id);
+        Ok(())
+    }
+
+    fn set_stream_output(
+        &mut self,
+        id: TunerSubscriptionId,
+        filters: Vec<String>,
+        counter: StreamByteCounter,
+    ) {
+        if let Some(tuner) = self.find_tuner_mut(id.session_id) {
+            tuner.activity.set_stream_output(id, filters, counter);
+        }
+    }
+
+    fn splice_streaming(
+        &mut self,
+        stream: MpegTsStream,
+        input: CommandPipelineInput,
+    ) {
+        let id = stream.id().session_id;
+        let broadcaster = self.find_tuner_mut(id)
+            .and_then(|tuner| tuner.activity.broadcaster(id));
+        match broadcaster {
+            Some(broadcaster) =>
+                broadcaster.do_send(SpliceMessage { stream, input }),
+            // The stream will be closed soon.
+            None => actix::spawn(stream.pipe(input)),
+        }
+    }
+
+    fn handle_process_exited(&mut self, id: TunerSessionId, exit: ProcessExit) {
+        if exit.is_expected() {
+            log::info!("{}: Tuner process exited with {}", id, exit.status);
+        } else {
+            log::error!("{}: Tuner process exited unexpectedly with {}",
+                        id, exit.status);
+        }
+        if let Some(tuner) = self.find_tuner_mut(id) {
+            tuner.add_exit(exit);
+            // Subscribers are notified of the end of the stream by the
+            // broadcaster.  The tuner becomes available for new sessions.
+            log::info!("{}: Deactivate", id);
+            tuner.deactivate();
+            return;
+        }
+        // The session has already been deactivated.  The tuner at
+        // `id.tuner_index` may be another one if tuners were reloaded in the
+        // meantime.  So, the exit is not recorded.
+        log::debug!("{}: No active session, the exit is not recorded", id);
     }
 }
 
@@ -187,54 +649,269 @@ impl Actor for TunerManager {
 
     fn started(&mut self, _: &mut Self::Context) {
         log::debug!("Started");
-        self.load_tuners();
+        self.load_tuners(self.config.clone());
     }
 
-    fn stopped(&mut self, _: &mut Self::Context) {
-        for tuner in self.tuners.iter_mut() {
-            tuner.deactivate();
-        }
-        log::debug!("Stopped");
+    fn stopped(&mut self, _: &mut Self::Context) {
+        for tuner in self.tuners.iter_mut() {
+            tuner.deactivate();
+        }
+        log::debug!("Stopped");
+    }
+}
+
+impl Supervised for TunerManager {}
+impl SystemService for TunerManager {}
+
+impl Default for TunerManager {
+    fn default() -> Self {
+        unreachable!();
+    }
+}
+
+// query tuners
+
+pub struct QueryTunersMessage;
+
+impl fmt::Display for QueryTunersMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryTuners")
+    }
+}
+
+impl Message for QueryTunersMessage {
+    type Result = Result<Vec<MirakurunTuner>, Error>;
+}
+
+impl Handler<QueryTunersMessage> for TunerManager {
+    type Result = Result<Vec<MirakurunTuner>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryTunersMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let tuners: Vec<MirakurunTuner> = self.tuners
+            .iter()
+            .map(|tuner| tuner.get_model())
+            .collect();
+        Ok(tuners)
+    }
+}
+
+// query streams
+
+pub struct QueryStreamsMessage;
+
+impl fmt::Display for QueryStreamsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryStreams")
+    }
+}
+
+impl Message for QueryStreamsMessage {
+    type Result = Result<Vec<MirakurunStream>, Error>;
+}
+
+impl Handler<QueryStreamsMessage> for TunerManager {
+    type Result = Result<Vec<MirakurunStream>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryStreamsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        let streams = self.tuners
+            .iter()
+            .flat_map(|tuner| tuner.get_stream_models())
+            .collect();
+        Ok(streams)
+    }
+}
+
+// subscribe preemptions
+
+pub struct SubscribePreemptionsMessage;
+
+impl fmt::Display for SubscribePreemptionsMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SubscribePreemptions")
+    }
+}
+
+impl Message for SubscribePreemptionsMessage {
+    type Result = broadcast::Receiver<MirakurunTunerPreemption>;
+}
+
+impl Handler<SubscribePreemptionsMessage> for TunerManager {
+    type Result = MessageResult<SubscribePreemptionsMessage>;
+
+    fn handle(
+        &mut self,
+        msg: SubscribePreemptionsMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        MessageResult(self.preemption_sender.subscribe())
+    }
+}
+
+// query tuner
+
+pub struct QueryTunerMessage {
+    pub index: usize,
+}
+
+impl fmt::Display for QueryTunerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryTuner tuner#{}", self.index)
+    }
+}
+
+impl Message for QueryTunerMessage {
+    type Result = Result<MirakurunTuner, Error>;
+}
+
+impl Handler<QueryTunerMessage> for TunerManager {
+    type Result = Result<MirakurunTuner, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryTunerMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.tuners
+            .get(msg.index)
+            .map(|tuner| tuner.get_model())
+            .ok_or(Error::TunerNotFound)
+    }
+}
+
+// kill tuner process
+
+pub struct KillTunerProcessMessage {
+    pub index: usize,
+}
+
+impl fmt::Display for KillTunerProcessMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KillTunerProcess tuner#{}", self.index)
+    }
+}
+
+impl Message for KillTunerProcessMessage {
+    type Result = Result<MirakurunTunerProcess, Error>;
+}
+
+impl Handler<KillTunerProcessMessage> for TunerManager {
+    type Result = Result<MirakurunTunerProcess, Error>;
+
+    fn handle(
+        &mut self,
+        msg: KillTunerProcessMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.kill_tuner_process(msg.index)
+    }
+}
+
+// enable tuner
+
+pub struct EnableTunerMessage {
+    pub index: usize,
+    pub enabled: bool,
+}
+
+impl fmt::Display for EnableTunerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.enabled {
+            write!(f, "EnableTuner tuner#{}", self.index)
+        } else {
+            write!(f, "DisableTuner tuner#{}", self.index)
+        }
+    }
+}
+
+impl Message for EnableTunerMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<EnableTunerMessage> for TunerManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: EnableTunerMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.enable_tuner(msg.index, msg.enabled)
+    }
+}
+
+// query tuner stderr
+
+pub struct QueryTunerStderrMessage {
+    pub index: usize,
+}
+
+impl fmt::Display for QueryTunerStderrMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QueryTunerStderr tuner#{}", self.index)
     }
 }
 
-impl Supervised for TunerManager {}
-impl SystemService for TunerManager {}
+impl Message for QueryTunerStderrMessage {
+    type Result = Result<Vec<String>, Error>;
+}
 
-impl Default for TunerManager {
-    fn default() -> Self {
-        unreachable!();
+impl Handler<QueryTunerStderrMessage> for TunerManager {
+    type Result = Result<Vec<String>, Error>;
+
+    fn handle(
+        &mut self,
+        msg: QueryTunerStderrMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.tuners
+            .get(msg.index)
+            .map(|tuner| tuner.stderr_log.lines())
+            .ok_or(Error::TunerNotFound)
     }
 }
 
-// query tuners
+// process exited
 
-pub struct QueryTunersMessage;
+pub struct ProcessExitedMessage {
+    pub id: TunerSessionId,
+    pub exit: ProcessExit,
+}
 
-impl fmt::Display for QueryTunersMessage {
+impl fmt::Display for ProcessExitedMessage {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "QueryTuners")
+        write!(f, "ProcessExited {} with {}", self.id, self.exit.status)
     }
 }
 
-impl Message for QueryTunersMessage {
-    type Result = Result<Vec<MirakurunTuner>, Error>;
+impl Message for ProcessExitedMessage {
+    type Result = ();
 }
 
-impl Handler<QueryTunersMessage> for TunerManager {
-    type Result = Result<Vec<MirakurunTuner>, Error>;
+impl Handler<ProcessExitedMessage> for TunerManager {
+    type Result = ();
 
     fn handle(
         &mut self,
-        msg: QueryTunersMessage,
+        msg: ProcessExitedMessage,
         _: &mut Self::Context,
     ) -> Self::Result {
         log::debug!("{}", msg);
-        let tuners: Vec<MirakurunTuner> = self.tuners
-            .iter()
-            .map(|tuner| tuner.get_model())
-            .collect();
-        Ok(tuners)
+        self.handle_process_exited(msg.id, msg.exit)
     }
 }
 
@@ -244,6 +921,8 @@ pub struct StartStreamingMessage {
     pub channel_type: ChannelType,
     pub channel: String,
     pub user: TunerUser,
+    pub slow_subscriber: Option<SlowSubscriberConfig>,
+    pub start_time: Option<DateTime<Jst>>,
 }
 
 impl fmt::Display for StartStreamingMessage {
@@ -267,6 +946,9 @@ impl Handler<StartStreamingMessage> for TunerManager {
     ) -> Self::Result {
         log::debug!("{}", msg);
 
+        let slow_subscriber = msg.slow_subscriber.unwrap_or_else(
+            || self.config.streaming.slow_subscriber.clone());
+
         let subscription = match self.activate_tuner(
             msg.channel_type, msg.channel, msg.user) {
             Ok(broadcaster) => broadcaster,
@@ -275,7 +957,9 @@ impl Handler<StartStreamingMessage> for TunerManager {
 
         let fut = actix::fut::wrap_future::<_, Self>(
             subscription.broadcaster.send(SubscribeMessage {
-                id: subscription.id
+                id: subscription.id,
+                config: slow_subscriber,
+                start_time: msg.start_time,
             }))
             .map(move |result, act, _| {
                 if result.is_ok() {
@@ -292,6 +976,62 @@ impl Handler<StartStreamingMessage> for TunerManager {
     }
 }
 
+// reload
+
+struct ReloadMessage {
+    config: Arc<Config>,
+}
+
+impl fmt::Display for ReloadMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reload")
+    }
+}
+
+impl Message for ReloadMessage {
+    type Result = ();
+}
+
+impl Handler<ReloadMessage> for TunerManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ReloadMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.load_tuners(msg.config)
+    }
+}
+
+// shutdown
+
+struct ShutdownMessage;
+
+impl fmt::Display for ShutdownMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Shutdown")
+    }
+}
+
+impl Message for ShutdownMessage {
+    type Result = ();
+}
+
+impl Handler<ShutdownMessage> for TunerManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: ShutdownMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.shutdown()
+    }
+}
+
 // stop streaming
 
 pub struct StopStreamingMessage {
@@ -321,6 +1061,97 @@ impl Handler<StopStreamingMessage> for TunerManager {
     }
 }
 
+// kill stream
+
+pub struct KillStreamMessage {
+    pub id: TunerSubscriptionId,
+}
+
+impl fmt::Display for KillStreamMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KillStream {}", self.id)
+    }
+}
+
+impl Message for KillStreamMessage {
+    type Result = Result<(), Error>;
+}
+
+impl Handler<KillStreamMessage> for TunerManager {
+    type Result = Result<(), Error>;
+
+    fn handle(
+        &mut self,
+        msg: KillStreamMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.kill_stream(msg.id)
+    }
+}
+
+// set stream output
+
+pub struct SetStreamOutputMessage {
+    pub id: TunerSubscriptionId,
+    pub filters: Vec<String>,
+    pub counter: StreamByteCounter,
+}
+
+impl fmt::Display for SetStreamOutputMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SetStreamOutput {} with {} filters",
+               self.id, self.filters.len())
+    }
+}
+
+impl Message for SetStreamOutputMessage {
+    type Result = ();
+}
+
+impl Handler<SetStreamOutputMessage> for TunerManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SetStreamOutputMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.set_stream_output(msg.id, msg.filters, msg.counter)
+    }
+}
+
+// splice streaming
+
+pub struct SpliceStreamingMessage {
+    pub stream: MpegTsStream,
+    pub input: CommandPipelineInput,
+}
+
+impl fmt::Display for SpliceStreamingMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SpliceStreaming {}", self.stream.id())
+    }
+}
+
+impl Message for SpliceStreamingMessage {
+    type Result = ();
+}
+
+impl Handler<SpliceStreamingMessage> for TunerManager {
+    type Result = ();
+
+    fn handle(
+        &mut self,
+        msg: SpliceStreamingMessage,
+        _: &mut Self::Context,
+    ) -> Self::Result {
+        log::debug!("{}", msg);
+        self.splice_streaming(msg.stream, msg.input)
+    }
+}
+
 // tuner
 
 struct Tuner {
@@ -328,23 +1159,53 @@ struct Tuner {
     name: String,
     channel_types: Vec<ChannelType>,
     command: String,
+    process: ProcessConfig,
+    streaming: StreamingConfig,
+    // Changed at runtime, unlike `TunerConfig::disabled`.
+    enabled: bool,
     activity: TunerActivity,
+    // Kept after the deactivation for diagnostics.
+    stderr_log: StderrLog,
+    exit_history: VecDeque<TunerExit>,
+    preemption_history: VecDeque<MirakurunTunerPreemption>,
+}
+
+struct TunerExit {
+    time: DateTime<Jst>,
+    exit: ProcessExit,
 }
 
 impl Tuner {
+    const MAX_EXIT_HISTORY: usize = 10;
+    const MAX_PREEMPTION_HISTORY: usize = 10;
+
     fn new(
         index: usize,
         config: &TunerConfig,
+        streaming: StreamingConfig,
     ) -> Self {
         Tuner {
             index,
             name: config.name.clone(),
             channel_types: config.channel_types.clone(),
             command: config.command.clone(),
+            process: config.process.clone(),
+            streaming,
+            enabled: true,
             activity: TunerActivity::Inactive,
+            stderr_log: Default::default(),
+            exit_history: VecDeque::new(),
+            preemption_history: VecDeque::new(),
         }
     }
 
+    fn is_configured_with(&self, config: &TunerConfig) -> bool {
+        self.name == config.name &&
+            self.channel_types == config.channel_types &&
+            self.command == config.command &&
+            self.process == config.process
+    }
+
     fn is_active(&self) -> bool {
         self.activity.is_active()
     }
@@ -357,8 +1218,13 @@ impl Tuner {
         self.channel_types.contains(&channel_type)
     }
 
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
     fn is_available_for(&self, channel_type: ChannelType) -> bool {
-        self.is_available() && self.is_supported_type(channel_type)
+        self.is_enabled() && self.is_available() &&
+            self.is_supported_type(channel_type)
     }
 
     fn is_reuseable(
@@ -376,8 +1242,12 @@ impl Tuner {
         channel: String,
     ) -> Result<(), Error> {
         let command = self.make_command(channel_type, &channel)?;
-        self.activity.activate(
-            self.index, channel_type, channel.clone(), command)
+        self.stderr_log = Default::default();
+        let session = TunerSession::new(
+            self.index, channel_type, channel, command, &self.process,
+            self.streaming.clone(), self.stderr_log.clone())?;
+        self.activity.activate(session);
+        Ok(())
     }
 
     fn deactivate(&mut self) {
@@ -399,8 +1269,47 @@ impl Tuner {
         Ok(())
     }
 
+    // Records subscriptions to be preempted by `user`.  Must be called before
+    // the tuner is deactivated.
+    fn preempt(&mut self, user: &TunerUser) -> Vec<MirakurunTunerPreemption> {
+        let preemptions = self.activity.preempt(self.index, user);
+        for preemption in preemptions.iter() {
+            if self.preemption_history.len() >= Self::MAX_PREEMPTION_HISTORY {
+                self.preemption_history.pop_front();
+            }
+            self.preemption_history.push_back(preemption.clone());
+        }
+        preemptions
+    }
+
+    fn add_exit(&mut self, exit: ProcessExit) {
+        if self.exit_history.len() >= Self::MAX_EXIT_HISTORY {
+            self.exit_history.pop_front();
+        }
+        self.exit_history.push_back(TunerExit { time: Jst::now(), exit });
+    }
+
+    // The tuner is regarded as faulty when the last tuner process exited
+    // unexpectedly, until it's activated again.
+    fn is_fault(&self) -> bool {
+        self.is_available() && self.exit_history
+            .back()
+            .is_some_and(|entry| !entry.exit.is_expected())
+    }
+
     fn get_model(&self) -> MirakurunTuner {
         let (command, pid, users) = self.activity.get_models();
+        let exit_history = self.exit_history
+            .iter()
+            .map(|entry| MirakurunTunerExit {
+                time: entry.time,
+                pid: entry.exit.pid,
+                command: entry.exit.command.clone(),
+                exit_code: entry.exit.status.code(),
+                signal: entry.exit.status.signal(),
+                expected: entry.exit.is_expected(),
+            })
+            .collect();
 
         MirakurunTuner {
             index: self.index,
@@ -409,14 +1318,23 @@ impl Tuner {
             command,
             pid,
             users,
-            is_available: true,
+            is_available: self.is_enabled(),
             is_remote: false,
             is_free: self.is_available(),
             is_using: !self.is_available(),
-            is_fault: false,
+            is_fault: self.is_fault(),
+            exit_history,
+            preemption_history: self.preemption_history
+                .iter()
+                .cloned()
+                .collect(),
         }
     }
 
+    fn get_stream_models(&self) -> Vec<MirakurunStream> {
+        self.activity.get_stream_models(self.index)
+    }
+
     fn make_command(
         &self,
         channel_type: ChannelType,
@@ -440,20 +1358,9 @@ enum TunerActivity {
 }
 
 impl TunerActivity {
-    fn activate(
-        &mut self,
-        tuner_index: usize,
-        channel_type: ChannelType,
-        channel: String,
-        command: String
-    ) -> Result<(), Error> {
+    fn activate(&mut self, session: TunerSession) {
         match self {
-            Self::Inactive => {
-                let session = TunerSession::new(
-                    tuner_index, channel_type, channel, command)?;
-                *self = Self::Active(session);
-                Ok(())
-            }
+            Self::Inactive => *self = Self::Active(session),
             Self::Active(_) => panic!("Must be deactivated before activating"),
         }
     }
@@ -498,6 +1405,14 @@ impl TunerActivity {
         }
     }
 
+    fn broadcaster(&self, id: TunerSessionId) -> Option<Addr<Broadcaster>> {
+        match self {
+            Self::Active(session) if session.id == id =>
+                Some(session.broadcaster.clone()),
+            _ => None,
+        }
+    }
+
     fn can_grab(&self, priority: TunerUserPriority) -> bool {
         match self {
             Self::Inactive => true,
@@ -505,6 +1420,63 @@ impl TunerActivity {
         }
     }
 
+    fn session_id(&self) -> Option<TunerSessionId> {
+        match self {
+            Self::Inactive => None,
+            Self::Active(session) => Some(session.id),
+        }
+    }
+
+    fn preempt(
+        &self,
+        tuner_index: usize,
+        user: &TunerUser,
+    ) -> Vec<MirakurunTunerPreemption> {
+        match self {
+            Self::Inactive => Vec::new(),
+            Self::Active(session) => session.preempt(tuner_index, user),
+        }
+    }
+
+    fn count_users<F>(&self, pred: F) -> usize
+    where
+        F: Fn(&TunerUser) -> bool,
+    {
+        match self {
+            Self::Inactive => 0,
+            Self::Active(session) => session.subscribers
+                .values()
+                .filter(|subscriber| pred(&subscriber.user))
+                .count(),
+        }
+    }
+
+    fn has_subscriber(&self, id: TunerSubscriptionId) -> bool {
+        match self {
+            Self::Active(session) if session.id == id.session_id =>
+                session.subscribers.contains_key(&id.serial_number),
+            _ => false,
+        }
+    }
+
+    fn set_stream_output(
+        &mut self,
+        id: TunerSubscriptionId,
+        filters: Vec<String>,
+        counter: StreamByteCounter,
+    ) {
+        if let Self::Active(session) = self {
+            session.set_stream_output(id, filters, counter);
+        }
+    }
+
+    fn get_stream_models(&self, tuner_index: usize) -> Vec<MirakurunStream> {
+        match self {
+            Self::Inactive => Vec::new(),
+            Self::Active(session) => session.get_stream_models(tuner_index),
+        }
+    }
+
     fn get_models(
         &self
     ) -> (Option<String>, Option<u32>, Vec<MirakurunTunerUser>) {
@@ -523,26 +1495,46 @@ struct TunerSession {
     channel: String,
     command: String,
     // Used for closing the tuner in order to take over the right to use it.
-    process: Child,
+    process: MonitoredProcess,
     broadcaster: Addr<Broadcaster>,
-    subscribers: HashMap<u32, TunerUser>,
+    subscribers: HashMap<u32, TunerSubscriber>,
     next_serial_number: u32,
 }
 
+struct TunerSubscriber {
+    user: TunerUser,
+    start_time: DateTime<Jst>,
+    // Set after the stream is sent to the client.  Empty for internal users
+    // like jobs.
+    filters: Vec<String>,
+    counter: StreamByteCounter,
+}
+
 impl TunerSession {
     fn new(
         tuner_index: usize,
         channel_type: ChannelType,
         channel: String,
-        command: String
+        command: String,
+        process: &ProcessConfig,
+        streaming: StreamingConfig,
+        stderr_log: StderrLog,
     ) -> Result<TunerSession, Error> {
-        let mut process = command_util::spawn_process(&command, Stdio::null())?;
+        let mut process =
+            command_util::spawn_process(&command, Stdio::null(), process)?;
         let id = TunerSessionId { tuner_index, tuner_pid: process.id() };
         log::debug!("{}: Spawned {}: `{}`", id, process.id(), command);
+        command_util::capture_stderr(
+            &mut process, id, &command, Some(stderr_log));
 
         let reader = tokio_snippet::stdio(process.stdout.take())?.unwrap();
+        let sink = SpliceSink::default();
+        let reader = SpliceReader::new(reader, sink.clone());
+        let process = MonitoredProcess::new(
+            process, id, &command, make_process_exited_fn(id));
         let broadcaster = Broadcaster::create(|ctx| {
-            Broadcaster::new(id.clone(), reader, ctx)
+            Broadcaster::with_splice_sink(
+                id.clone(), reader, sink, &streaming, ctx)
         });
 
         log::info!("{}: Activated with {} {}", id, channel_type, channel);
@@ -563,7 +1555,12 @@ impl TunerSession {
 
         let id = TunerSubscriptionId { session_id: self.id, serial_number };
         log::info!("{}: Subscribed: {}", id, user);
-        self.subscribers.insert(serial_number, user);
+        self.subscribers.insert(serial_number, TunerSubscriber {
+            user,
+            start_time: Jst::now(),
+            filters: Vec::new(),
+            counter: Default::default(),
+        });
 
         TunerSubscription { id, broadcaster: self.broadcaster.clone() }
     }
@@ -571,7 +1568,38 @@ impl TunerSession {
     fn can_grab(&self, priority: TunerUserPriority) -> bool {
         self.subscribers
             .values()
-            .all(|user| priority > user.priority)
+            .all(|subscriber| priority > subscriber.user.priority)
+    }
+
+    fn preempt(
+        &self,
+        tuner_index: usize,
+        user: &TunerUser,
+    ) -> Vec<MirakurunTunerPreemption> {
+        let time = Jst::now();
+        let mut serial_numbers: Vec<u32> =
+            self.subscribers.keys().cloned().collect();
+        serial_numbers.sort();
+        serial_numbers
+            .into_iter()
+            .map(|serial_number| {
+                let id = TunerSubscriptionId {
+                    session_id: self.id,
+                    serial_number,
+                };
+                let preempted = &self.subscribers[&serial_number].user;
+                log::warn!("{}: Preempted by {}", id, user);
+                MirakurunTunerPreemption {
+                    time,
+                    tuner_index,
+                    subscription_id: id.to_string(),
+                    channel_type: self.channel_type,
+                    channel: self.channel.clone(),
+                    user: preempted.get_model(),
+                    preempted_by: user.get_model(),
+                }
+            })
+            .collect()
     }
 
     fn stop_streaming(
@@ -584,7 +1612,8 @@ impl TunerSession {
             return Err(Error::SessionNotFound);
         }
         match self.subscribers.remove(&id.serial_number) {
-            Some(user) => log::info!("{}: Unsubscribed: {}", id, user),
+            Some(subscriber) =>
+                log::info!("{}: Unsubscribed: {}", id, subscriber.user),
             None => log::warn!("{}: Not subscribed", id),
         }
         self.broadcaster.do_send(UnsubscribeMessage { id });
@@ -597,18 +1626,60 @@ impl TunerSession {
         (
             Some(self.command.clone()),
             Some(self.process.id()),
-            self.subscribers.values().map(|user| user.get_model()).collect(),
+            self.subscribers
+                .values()
+                .map(|subscriber| subscriber.user.get_model())
+                .collect(),
         )
     }
+
+    fn set_stream_output(
+        &mut self,
+        id: TunerSubscriptionId,
+        filters: Vec<String>,
+        counter: StreamByteCounter,
+    ) {
+        if self.id != id.session_id {
+            return;
+        }
+        if let Some(subscriber) = self.subscribers.get_mut(&id.serial_number) {
+            subscriber.filters = filters;
+            subscriber.counter = counter;
+        }
+    }
+
+    fn get_stream_models(&self, tuner_index: usize) -> Vec<MirakurunStream> {
+        let mut serial_numbers: Vec<u32> =
+            self.subscribers.keys().cloned().collect();
+        serial_numbers.sort();
+        serial_numbers
+            .into_iter()
+            .map(|serial_number| {
+                let id = TunerSubscriptionId {
+                    session_id: self.id,
+                    serial_number,
+                };
+                let subscriber = &self.subscribers[&serial_number];
+                MirakurunStream {
+                    id: id.to_string(),
+                    tuner_index,
+                    channel_type: self.channel_type,
+                    channel: self.channel.clone(),
+                    user: subscriber.user.get_model(),
+                    filters: subscriber.filters.clone(),
+                    start_time: subscriber.start_time,
+                    num_bytes: subscriber.counter.get(),
+                }
+            })
+            .collect()
+    }
 }
 
 impl Drop for TunerSession {
     fn drop(&mut self) {
-        // Always kill the process and ignore the error.  Because there is no
-        // method to check whether the process is alive or dead.
-        let _ = self.process.kill();
-        let _ = self.process.wait();
-        log::debug!("{}: Killed {}: {}",
+        // The process is reaped by the monitoring thread.
+        self.process.kill();
+        log::debug!("{}: Kill {}: {}",
                     self.id, self.process.id(), self.command);
         log::info!("{}: Deactivated", self.id);
     }
@@ -618,11 +1689,12 @@ impl Drop for TunerSession {
 mod tests {
     use super::*;
     use matches::assert_matches;
+    use crate::config::QuotaConfig;
 
     #[actix_rt::test]
     async fn test_tuner_is_active() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, Default::default());
 
         assert!(!tuner.is_active());
 
@@ -636,14 +1708,14 @@ mod tests {
     async fn test_tuner_activate() {
         {
             let config = create_config("true".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, Default::default());
             let result = tuner.activate(ChannelType::GR, String::new());
             assert!(result.is_ok());
         }
 
         {
             let config = create_config("cmd '".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, Default::default());
             let result = tuner.activate(ChannelType::GR, String::new());
             assert_matches!(result, Err(Error::CommandFailed(
                 command_util::Error::UnableToParse(_))));
@@ -651,7 +1723,7 @@ mod tests {
 
         {
             let config = create_config("no-such-command".to_string());
-            let mut tuner = Tuner::new(0, &config);
+            let mut tuner = Tuner::new(0, &config, Default::default());
             let result = tuner.activate(ChannelType::GR, String::new());
             assert_matches!(result, Err(Error::CommandFailed(
                 command_util::Error::UnableToSpawn(..))));
@@ -661,14 +1733,18 @@ mod tests {
     #[actix_rt::test]
     async fn test_tuner_stop_streaming() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, Default::default());
         let result = tuner.stop_streaming(Default::default());
         assert_matches!(result, Err(Error::SessionNotFound));
 
         let result = tuner.activate(ChannelType::GR, String::new());
         assert!(result.is_ok());
         let subscription = tuner.subscribe(TunerUser {
-            info: TunerUserInfo::Web { remote: None, agent: None },
+            info: TunerUserInfo::Web {
+                remote: None,
+                agent: None,
+                quota: None,
+            },
             priority: 0.into(),
         });
 
@@ -679,10 +1755,33 @@ mod tests {
         assert_matches!(result, Ok(()));
     }
 
+    #[actix_rt::test]
+    async fn test_tuner_exit_history() {
+        let config = create_config("true".to_string());
+        let mut tuner = Tuner::new(0, &config, Default::default());
+        assert!(!tuner.is_fault());
+
+        tuner.add_exit(create_exit(0));
+        assert!(!tuner.is_fault());
+
+        tuner.add_exit(create_exit(1));
+        assert!(tuner.is_fault());
+        assert_eq!(tuner.get_model().exit_history.len(), 2);
+
+        tuner.activate(ChannelType::GR, "1".to_string()).unwrap();
+        assert!(!tuner.is_fault());
+
+        for _ in 0..Tuner::MAX_EXIT_HISTORY {
+            tuner.add_exit(create_exit(0));
+        }
+        assert_eq!(tuner.get_model().exit_history.len(),
+                   Tuner::MAX_EXIT_HISTORY);
+    }
+
     #[actix_rt::test]
     async fn test_tuner_can_grab() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, Default::default());
         assert!(tuner.can_grab(0.into()));
 
         tuner.activate(ChannelType::GR, "1".to_string()).unwrap();
@@ -711,7 +1810,7 @@ mod tests {
     #[actix_rt::test]
     async fn test_tuner_reactivate() {
         let config = create_config("true".to_string());
-        let mut tuner = Tuner::new(0, &config);
+        let mut tuner = Tuner::new(0, &config, Default::default());
         tuner.activate(ChannelType::GR, "1".to_string()).ok();
 
         tuner.deactivate();
@@ -719,12 +1818,251 @@ mod tests {
         assert!(result.is_ok());
     }
 
+    #[actix_rt::test]
+    async fn test_tuner_manager_load_tuners() {
+        let mut config = Config {
+            tuners: vec![
+                TunerConfig {
+                    name: "a".to_string(),
+                    ..create_config("true".to_string())
+                },
+                TunerConfig {
+                    name: "b".to_string(),
+                    ..create_config("true".to_string())
+                },
+            ],
+            ..Default::default()
+        };
+        let mut manager = TunerManager::new(Arc::new(config.clone()));
+        manager.load_tuners(Arc::new(config.clone()));
+        assert_eq!(manager.tuners.len(), 2);
+
+        manager.tuners[1].activate(ChannelType::GR, "1".to_string()).unwrap();
+        let session_id = manager.tuners[1].activity.session_id().unwrap();
+
+        // Remove the tuner "a".  The active session on "b" is kept.
+        config.tuners.remove(0);
+        manager.load_tuners(Arc::new(config.clone()));
+        assert_eq!(manager.tuners.len(), 1);
+        assert_eq!(manager.tuners[0].name, "b");
+        assert_eq!(manager.tuners[0].index, 0);
+        assert!(manager.find_tuner_mut(session_id).is_some());
+
+        // Change the command of "b".  The session is deactivated.
+        config.tuners[0].command = "false".to_string();
+        manager.load_tuners(Arc::new(config.clone()));
+        assert_eq!(manager.tuners.len(), 1);
+        assert!(!manager.tuners[0].is_active());
+        assert!(manager.find_tuner_mut(session_id).is_none());
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_check_quota() {
+        let config = Arc::new(Config {
+            tuners: vec![
+                TunerConfig {
+                    name: "a".to_string(),
+                    ..create_config("true".to_string())
+                },
+                TunerConfig {
+                    name: "b".to_string(),
+                    ..create_config("true".to_string())
+                },
+            ],
+            quotas: vec![
+                QuotaConfig {
+                    name: "limited".to_string(),
+                    max_streams: Some(2),
+                    ..Default::default()
+                },
+                QuotaConfig {
+                    name: "unlimited".to_string(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+        let mut manager = TunerManager::new(config.clone());
+        manager.load_tuners(config.clone());
+
+        let user = |quota: &str| TunerUser {
+            info: TunerUserInfo::Web {
+                remote: None,
+                agent: None,
+                quota: Some(quota.to_string()),
+            },
+            priority: 0.into(),
+        };
+
+        // Streams on different tuners are counted.
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), user("limited")).is_ok());
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "2".to_string(), user("limited")).is_ok());
+        assert_matches!(
+            manager.activate_tuner(
+                ChannelType::GR, "1".to_string(), user("limited")).err(),
+            Some(Error::QuotaExceeded(_)));
+
+        assert!(manager.check_quota(&user("unlimited")).is_ok());
+        assert!(manager.check_quota(&user("unknown")).is_ok());
+        assert!(manager.check_quota(&create_user(0.into())).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_preempt() {
+        let config = Arc::new(Config {
+            tuners: vec![create_config("true".to_string())],
+            ..Default::default()
+        });
+        let mut manager = TunerManager::new(config.clone());
+        manager.load_tuners(config.clone());
+        let mut receiver = manager.preemption_sender.subscribe();
+
+        let subscription = manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), create_user(0.into()))
+            .ok().unwrap();
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "2".to_string(), create_user(1.into())).is_ok());
+
+        let history = &manager.tuners[0].preemption_history;
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].subscription_id, subscription.id.to_string());
+        assert_eq!(history[0].channel, "1");
+        assert_eq!(history[0].user.priority, 0);
+        assert_eq!(history[0].preempted_by.priority, 1);
+
+        let preemption = receiver.try_recv().unwrap();
+        assert_eq!(preemption.subscription_id, subscription.id.to_string());
+        assert!(receiver.try_recv().is_err());
+
+        // Nothing is preempted when the tuner is reused.
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "2".to_string(), create_user(2.into())).is_ok());
+        assert_eq!(manager.tuners[0].preemption_history.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_enable_tuner() {
+        let config = Arc::new(Config {
+            tuners: vec![create_config("true".to_string())],
+            ..Default::default()
+        });
+        let mut manager = TunerManager::new(config.clone());
+        manager.load_tuners(config.clone());
+
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), create_user(0.into())).is_ok());
+        assert_matches!(manager.enable_tuner(1, false),
+                        Err(Error::TunerNotFound));
+        assert_matches!(manager.enable_tuner(0, false), Ok(()));
+        assert!(manager.tuners[0].is_available());
+        assert!(!manager.tuners[0].get_model().is_available);
+
+        // A disabled tuner is never used even by a grabbing user.
+        let result = manager.activate_tuner(
+            ChannelType::GR, "1".to_string(),
+            create_user(TunerUserPriority::GRAB));
+        assert_matches!(result.err(), Some(Error::TunerUnavailable));
+
+        // The state is kept over reloading.
+        manager.load_tuners(config.clone());
+        assert!(!manager.tuners[0].is_enabled());
+
+        assert_matches!(manager.enable_tuner(0, true), Ok(()));
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), create_user(0.into())).is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_kill_tuner_process() {
+        let config = Arc::new(Config {
+            tuners: vec![create_config("true".to_string())],
+            ..Default::default()
+        });
+        let mut manager = TunerManager::new(config.clone());
+        manager.load_tuners(config.clone());
+
+        let process = manager.kill_tuner_process(0).unwrap();
+        assert!(process.pid.is_none());
+        assert_matches!(manager.kill_tuner_process(1),
+                        Err(Error::TunerNotFound));
+
+        assert!(manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), create_user(0.into())).is_ok());
+        let pid = manager.tuners[0].get_model().pid;
+        assert!(pid.is_some());
+        let process = manager.kill_tuner_process(0).unwrap();
+        assert_eq!(process.pid, pid);
+        assert!(manager.tuners[0].is_available());
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_manager_streams() {
+        let config = Arc::new(Config {
+            tuners: vec![create_config("true".to_string())],
+            ..Default::default()
+        });
+        let mut manager = TunerManager::new(config.clone());
+        manager.load_tuners(config.clone());
+
+        let subscription = manager.activate_tuner(
+            ChannelType::GR, "1".to_string(), create_user(0.into()))
+            .ok().unwrap();
+        let counter = StreamByteCounter::default();
+        manager.set_stream_output(
+            subscription.id, vec!["filter".to_string()], counter.clone());
+        counter.add(10);
+
+        let streams = manager.tuners[0].get_stream_models();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].id, subscription.id.to_string());
+        assert_eq!(streams[0].tuner_index, 0);
+        assert_eq!(streams[0].channel, "1");
+        assert_eq!(streams[0].filters, vec!["filter".to_string()]);
+        assert_eq!(streams[0].num_bytes, 10);
+
+        assert_matches!(manager.kill_stream(Default::default()),
+                        Err(Error::StreamNotFound));
+        assert_matches!(manager.kill_stream(subscription.id), Ok(()));
+        // The tuner is released because no one uses it.
+        assert!(manager.tuners[0].is_available());
+        assert_matches!(manager.kill_stream(subscription.id),
+                        Err(Error::StreamNotFound));
+    }
+
+    #[test]
+    fn test_tuner_subscription_id_from_str() {
+        let id = TunerSubscriptionId {
+            session_id: TunerSessionId { tuner_index: 1, tuner_pid: 2 },
+            serial_number: 3,
+        };
+        let parsed: Option<TunerSubscriptionId> = id.to_string().parse().ok();
+        assert!(parsed == Some(id));
+        assert_matches!("tuner#1.2".parse::<TunerSubscriptionId>().err(),
+                        Some(Error::StreamNotFound));
+        assert_matches!("tuner#1.2.x".parse::<TunerSubscriptionId>().err(),
+                        Some(Error::StreamNotFound));
+        assert_matches!("1.2.3".parse::<TunerSubscriptionId>().err(),
+                        Some(Error::StreamNotFound));
+    }
+
+    fn create_exit(code: i32) -> ProcessExit {
+        ProcessExit {
+            pid: 1,
+            command: "true".to_string(),
+            status: std::process::ExitStatus::from_raw(code << 8),
+            killed: false,
+        }
+    }
+
     fn create_config(command: String) -> TunerConfig {
         TunerConfig {
             name: String::new(),
             channel_types: vec![ChannelType::GR],
             command,
             disabled: false,
+            process: Default::default(),
         }
     }
 
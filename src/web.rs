@@ -1,53 +1,237 @@
 use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
 use std::sync::Arc;
 
 use actix_files;
 use actix_web;
+use actix_web::dev::Server;
 use bytes::Bytes;
+use chrono::{DateTime, Duration, TimeZone};
 use futures;
 use serde::{Deserialize, Serialize};
 use tokio::stream::Stream;
 
 use crate::airtime_tracker;
+use crate::channel_scanner::{self, ChannelScanner};
 use crate::chunk_stream::ChunkStream;
 use crate::command_util;
-use crate::config::{Config, ServerAddr};
+use crate::config::{
+    ApiGroup, AuthConfig, Config, FilterChainConfig, FilterStage, QuotaConfig,
+    ServerAddr, ServerConfig, SharedConfig, SlowSubscriberConfig,
+    SlowSubscriberPolicy};
+use crate::datetime_ext::Jst;
 use crate::error::Error;
 use crate::epg;
+use crate::filter_pipeline;
+use crate::iptv;
+use crate::job::{self, ChannelScanStatus};
 use crate::epg::{EpgChannel, EpgProgram};
 use crate::models::*;
 use crate::mpeg_ts_stream::*;
+use crate::pid_filter::{PidFilter, PidFilterConfig, StreamComponent};
+use crate::reload;
 use crate::tuner;
 
-pub async fn serve(config: Arc<Config>) -> Result<(), Error> {
-    let server_config = config.server.clone();
+// Returns a handle of the server which has been started.
+//
+// Signals are handled in `shutdown::wait_for_signal()` instead of the server
+// so that other components can be stopped before the server.
+pub fn serve(config: SharedConfig) -> Result<Server, Error> {
+    let server_config = config.get().server.clone();
+    let authorizer = Arc::new(Authorizer::new(&server_config)?);
     let mut server = actix_web::HttpServer::new(
         move || {
+            let authorizer = authorizer.clone();
             actix_web::App::new()
                 .data(config.clone())
+                // Placed inside the logger so that rejected requests are
+                // logged.
+                .wrap_fn(move |req, srv| {
+                    use actix_web::dev::Service;
+                    match authorizer.authorize(&req) {
+                        Ok(_) => futures::future::Either::Left(srv.call(req)),
+                        Err(err) => futures::future::Either::Right(
+                            futures::future::err(err.into())),
+                    }
+                })
                 .wrap(actix_web::middleware::Logger::default())
                 .wrap(actix_web::middleware::DefaultHeaders::new()
                       .header("Server", server_name()))
                 .service(create_api_service())
         });
     for addr in server_config.addrs.iter() {
-        server = match addr {
-            ServerAddr::Http(addr) => server.bind(addr.as_str())?,
-            ServerAddr::Unix(path) => server.bind_uds(path.as_str())?,
+        server = match addr.addr {
+            ServerAddr::Http(ref addr) => server.bind(addr.as_str())?,
+            ServerAddr::Unix(ref path) => server.bind_uds(path.as_str())?,
         };
     }
-    server
+    let server = server
         .keep_alive(0)  // disable keep-alive
         .workers(server_config.workers)
-        .run()
-        .await?;
-    Ok(())
+        .shutdown_timeout(server_config.shutdown_timeout)
+        .disable_signals()
+        .run();
+    Ok(server)
 }
 
 fn server_name() -> String {
     format!("{}/{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
 }
 
+// authorization
+
+struct Authorizer {
+    auth: AuthConfig,
+    // Allowed clients for each socket address listened on.
+    listeners: Vec<(SocketAddr, Vec<String>)>,
+}
+
+impl Authorizer {
+    fn new(config: &ServerConfig) -> Result<Self, Error> {
+        let mut listeners = Vec::new();
+        for addr in config.addrs.iter() {
+            if let ServerAddr::Http(ref http) = addr.addr {
+                // Resolved in the same way as `HttpServer::bind()`.
+                for sock_addr in http.to_socket_addrs()? {
+                    listeners.push((sock_addr, addr.allowed_clients.clone()));
+                }
+            }
+        }
+        Ok(Authorizer { auth: config.auth.clone(), listeners })
+    }
+
+    fn authorize(
+        &self,
+        req: &actix_web::dev::ServiceRequest,
+    ) -> Result<(), Error> {
+        // The peer address is unavailable in UNIX domain sockets.
+        if let Some(peer_addr) = req.peer_addr() {
+            let local_addr = req.app_config().local_addr();
+            let allowed_clients = self.listeners
+                .iter()
+                .find(|(addr, _)| *addr == local_addr)
+                .map(|(_, allowed_clients)| allowed_clients.as_slice())
+                .unwrap_or_default();
+            let remote = peer_addr.to_string();
+            if !is_allowed_client(allowed_clients, Some(&remote)) {
+                log::warn!("{}: Not allowed to connect to {}",
+                           remote, local_addr);
+                return Err(Error::AccessDenied);
+            }
+        }
+
+        if !self.auth.is_enabled() {
+            return Ok(());
+        }
+
+        let (credential, groups) =
+            self.authenticate(req.headers(), req.query_string())
+            .ok_or(Error::Unauthorized)?;
+        // Use the path decoded in the same way as the router.  Otherwise,
+        // a percent-encoded path like `/api/%73treams` bypasses the check.
+        let path = req.match_info().path();
+        if !groups.contains(&api_group(req.method(), path)) {
+            return Err(Error::AccessDenied);
+        }
+        // Used for applying a quota.
+        actix_web::HttpMessage::extensions_mut(req).insert(credential);
+        Ok(())
+    }
+
+    // Returns the credential in the request and groups allowed for it.
+    fn authenticate(
+        &self,
+        headers: &actix_web::http::HeaderMap,
+        query: &str,
+    ) -> Option<(Credential, &[ApiGroup])> {
+        let authorization = headers
+            .get(actix_web::http::header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok());
+
+        if let Some(authorization) = authorization {
+            if let Some(token) = authorization.strip_prefix("Bearer ") {
+                return self.find_token(token.trim());
+            }
+            if let Some(basic) = authorization.strip_prefix("Basic ") {
+                let decoded = base64::decode(basic.trim()).ok()?;
+                let decoded = String::from_utf8(decoded).ok()?;
+                let pos = decoded.find(':')?;
+                return self.find_user(&decoded[..pos], &decoded[pos + 1..]);
+            }
+            return None;
+        }
+
+        // For players which cannot set the Authorization header.
+        let token = actix_web::web::Query::<TokenQuery>::from_query(query)
+            .ok()?
+            .into_inner()
+            .token?;
+        self.find_token(&token)
+    }
+
+    fn find_token(&self, token: &str) -> Option<(Credential, &[ApiGroup])> {
+        self.auth.tokens
+            .iter()
+            .find(|config| secure_eq(&config.token, token))
+            .map(|config| (Credential::Token(config.token.clone()),
+                           config.groups.as_slice()))
+    }
+
+    fn find_user(
+        &self,
+        name: &str,
+        password: &str,
+    ) -> Option<(Credential, &[ApiGroup])> {
+        self.auth.users
+            .iter()
+            .find(|config| {
+                config.name == name && secure_eq(&config.password, password)
+            })
+            .map(|config| (Credential::User(config.name.clone()),
+                           config.groups.as_slice()))
+    }
+}
+
+// A credential authenticated.
+#[derive(Clone, Debug, PartialEq)]
+enum Credential {
+    Token(String),
+    User(String),
+}
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+// Endpoints are classified by their methods and paths so that new endpoints
+// don't have to be marked one by one.
+fn api_group(method: &actix_web::http::Method, path: &str) -> ApiGroup {
+    if method != actix_web::http::Method::GET &&
+        method != actix_web::http::Method::HEAD {
+        return ApiGroup::Admin;
+    }
+    if path.ends_with("/stream") {
+        return ApiGroup::Streaming;
+    }
+    if path.starts_with("/api/tuners/") && path.ends_with("/stderr") {
+        return ApiGroup::Admin;
+    }
+    // The list contains information about other users.
+    if path == "/api/streams" {
+        return ApiGroup::Admin;
+    }
+    ApiGroup::Epg
+}
+
+// Compares strings in constant time in order to prevent timing attacks.
+fn secure_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.bytes().zip(b.bytes()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 // rest api
 
 const CHUNK_SIZE: usize = 4096 * 8;
@@ -64,6 +248,12 @@ struct ErrorBody {
 impl actix_web::ResponseError for Error {
     fn error_response(&self) -> actix_web::HttpResponse {
         match *self {
+            Error::TunerNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
             Error::TunerUnavailable =>
                 actix_web::HttpResponse::NotFound().json(ErrorBody {
                     code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
@@ -82,12 +272,80 @@ impl actix_web::ResponseError for Error {
                     reason: None,
                     errors: Vec::new(),
                 }),
+            // Mirakurun returns 503 when no logo data is available.
+            Error::LogoNotFound =>
+                actix_web::HttpResponse::ServiceUnavailable().json(ErrorBody {
+                    code: actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                        .as_u16(),
+                    reason: Some("Logo Data Unavailable"),
+                    errors: Vec::new(),
+                }),
             Error::ProgramNotFound =>
                 actix_web::HttpResponse::NotFound().json(ErrorBody {
                     code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
                     reason: None,
                     errors: Vec::new(),
                 }),
+            Error::StreamNotFound =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            // The tuner subscription feeds a shared filter pipeline.
+            Error::StreamInUse =>
+                actix_web::HttpResponse::Conflict().json(ErrorBody {
+                    code: actix_web::http::StatusCode::CONFLICT.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::FilterChainNotFound(_) =>
+                actix_web::HttpResponse::NotFound().json(ErrorBody {
+                    code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::FilterChainNotAllowed(_) =>
+                actix_web::HttpResponse::Forbidden().json(ErrorBody {
+                    code: actix_web::http::StatusCode::FORBIDDEN.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::Unauthorized =>
+                actix_web::HttpResponse::Unauthorized()
+                .header(actix_web::http::header::WWW_AUTHENTICATE,
+                        "Basic realm=\"mirakc\"")
+                .json(ErrorBody {
+                    code: actix_web::http::StatusCode::UNAUTHORIZED.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::AccessDenied =>
+                actix_web::HttpResponse::Forbidden().json(ErrorBody {
+                    code: actix_web::http::StatusCode::FORBIDDEN.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::QuotaExceeded(_) =>
+                actix_web::HttpResponse::TooManyRequests().json(ErrorBody {
+                    code: actix_web::http::StatusCode::TOO_MANY_REQUESTS
+                        .as_u16(),
+                    reason: Some("Quota exceeded"),
+                    errors: Vec::new(),
+                }),
+            Error::JobAlreadyRunning =>
+                actix_web::HttpResponse::Conflict().json(ErrorBody {
+                    code: actix_web::http::StatusCode::CONFLICT.as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
+            Error::ShuttingDown =>
+                actix_web::HttpResponse::ServiceUnavailable().json(ErrorBody {
+                    code: actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+                        .as_u16(),
+                    reason: None,
+                    errors: Vec::new(),
+                }),
             _ =>
                 actix_web::HttpResponse::InternalServerError().json(ErrorBody {
                     code: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
@@ -105,15 +363,32 @@ fn create_api_service() -> impl actix_web::dev::HttpServiceFactory {
         .service(get_status)
         .service(get_channels)
         .service(get_services)
+        // Must be registered before get_service.
+        .service(get_service_relocations)
         .service(get_service)
+        .service(get_service_logo)
         .service(get_programs)
         .service(get_program)
         .service(get_tuners)
+        .service(get_tuner)
+        .service(get_tuner_process)
+        .service(kill_tuner_process)
+        .service(enable_tuner)
+        .service(disable_tuner)
+        .service(get_tuner_stderr)
+        .service(get_streams)
+        .service(delete_stream)
         .service(get_channel_stream)
         .service(get_channel_service_stream)
         .service(get_service_stream)
         .service(get_program_stream)
         .service(get_docs)
+        .service(get_events)
+        .service(get_iptv_playlist)
+        .service(get_iptv_xmltv)
+        .service(reload_config)
+        .service(scan_channels)
+        .service(get_scanned_channels)
 }
 
 #[actix_web::get("/version")]
@@ -140,6 +415,12 @@ async fn get_services() -> ApiResult {
         .map(|services| actix_web::HttpResponse::Ok().json(services))
 }
 
+#[actix_web::get("/services/relocations")]
+async fn get_service_relocations() -> ApiResult {
+    epg::query_relocations().await
+        .map(|relocations| actix_web::HttpResponse::Ok().json(relocations))
+}
+
 #[actix_web::get("/services/{id}")]
 async fn get_service(path: actix_web::web::Path<ServicePath>) -> ApiResult {
     epg::query_service_by_nid_sid(path.id.nid(), path.id.sid()).await
@@ -147,6 +428,14 @@ async fn get_service(path: actix_web::web::Path<ServicePath>) -> ApiResult {
         .map(|service| actix_web::HttpResponse::Ok().json(service))
 }
 
+#[actix_web::get("/services/{id}/logo")]
+async fn get_service_logo(
+    path: actix_web::web::Path<ServicePath>,
+) -> Result<actix_files::NamedFile, Error> {
+    let logo_path = epg::query_logo_path(path.id.nid(), path.id.sid()).await?;
+    Ok(actix_files::NamedFile::open(logo_path)?)
+}
+
 #[actix_web::get("/programs")]
 async fn get_programs() -> ApiResult {
     epg::query_programs().await
@@ -169,119 +458,320 @@ async fn get_tuners() -> ApiResult {
         .map(|tuners| actix_web::HttpResponse::Ok().json(tuners))
 }
 
+#[actix_web::get("/tuners/{index}")]
+async fn get_tuner(path: actix_web::web::Path<TunerPath>) -> ApiResult {
+    tuner::query_tuner(path.index).await
+        .map(|tuner| actix_web::HttpResponse::Ok().json(tuner))
+}
+
+#[actix_web::get("/tuners/{index}/process")]
+async fn get_tuner_process(path: actix_web::web::Path<TunerPath>) -> ApiResult {
+    tuner::query_tuner(path.index).await
+        .map(|tuner| MirakurunTunerProcess { pid: tuner.pid })
+        .map(|process| actix_web::HttpResponse::Ok().json(process))
+}
+
+// Kills the tuner process in order to recover a stuck tuner.  Streams on the
+// tuner end.
+#[actix_web::delete("/tuners/{index}/process")]
+async fn kill_tuner_process(
+    path: actix_web::web::Path<TunerPath>,
+) -> ApiResult {
+    tuner::kill_tuner_process(path.index).await
+        .map(|process| actix_web::HttpResponse::Ok().json(process))
+}
+
+#[actix_web::post("/tuners/{index}/enable")]
+async fn enable_tuner(path: actix_web::web::Path<TunerPath>) -> ApiResult {
+    tuner::enable_tuner(path.index, true).await?;
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+// Streams on the tuner end.
+#[actix_web::post("/tuners/{index}/disable")]
+async fn disable_tuner(path: actix_web::web::Path<TunerPath>) -> ApiResult {
+    tuner::enable_tuner(path.index, false).await?;
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+#[actix_web::get("/tuners/{index}/stderr")]
+async fn get_tuner_stderr(path: actix_web::web::Path<TunerPath>) -> ApiResult {
+    tuner::query_tuner_stderr(path.index).await
+        .map(|lines| actix_web::HttpResponse::Ok().json(lines))
+}
+
+#[actix_web::get("/streams")]
+async fn get_streams() -> ApiResult {
+    tuner::query_streams().await
+        .map(|streams| actix_web::HttpResponse::Ok().json(streams))
+}
+
+// Ends the stream on the server side.  The tuner is released if no one uses
+// it.
+//
+// A stream sharing a filter pipeline is ended by the pipeline manager so that
+// other streams using the pipeline are not affected.
+#[actix_web::delete("/streams/{id}")]
+async fn delete_stream(path: actix_web::web::Path<StreamPath>) -> ApiResult {
+    let id = path.id.parse()?;
+    match filter_pipeline::kill_stream(id).await {
+        Err(Error::StreamNotFound) => tuner::kill_stream(id).await?,
+        result => result?,
+    }
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
 #[actix_web::get("/channels/{channel_type}/{channel}/stream")]
 async fn get_channel_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: actix_web::web::Data<SharedConfig>,
     path: actix_web::web::Path<ChannelPath>,
     query: actix_web::web::Query<StreamQuery>,
-    user: TunerUser
+    user: TunerUser,
+    req: actix_web::HttpRequest,
 ) -> ApiResult {
+    let config = config.get();
     let channel = epg::query_channel(
         path.channel_type, path.channel.clone()).await?;
 
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let chains = query.filter_chains(&config, peer.as_deref())?;
     let filters = make_filters(
-        &config, &channel, None, None, "".to_string(),
-        query.pre_filter_required(), query.post_filter_required())?;
+        &config, &channel, None, None, "".to_string(), &query, &chains)?;
 
     let stream = tuner::start_streaming(
-        path.channel_type, path.channel.clone(), user).await?;
+        path.channel_type, path.channel.clone(), user,
+        Some(query.slow_subscriber(&config)), query.start_time()).await?;
+
+    if query.is_shareable(&config) {
+        return shared_streaming(
+            stream, filters, query.slow_subscriber(&config)).await;
+    }
 
-    streaming(stream, filters, None)
+    streaming(&config, stream, query.pid_filter(), filters, None)
 }
 
 #[actix_web::get("/channels/{channel_type}/{channel}/services/{sid}/stream")]
 async fn get_channel_service_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: actix_web::web::Data<SharedConfig>,
     path: actix_web::web::Path<ChannelServicePath>,
     query: actix_web::web::Query<StreamQuery>,
-    user: TunerUser
+    user: TunerUser,
+    req: actix_web::HttpRequest,
 ) -> ApiResult {
+    let config = config.get();
     let channel = epg::query_channel(
         path.channel_type, path.channel.clone()).await?;
 
-    do_get_service_stream(config, &channel, path.sid, query, user).await
+    do_get_service_stream(
+        config, &channel, path.sid, query, user, &req).await
 }
 
 #[actix_web::get("/services/{id}/stream")]
 async fn get_service_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: actix_web::web::Data<SharedConfig>,
     path: actix_web::web::Path<ServicePath>,
     query: actix_web::web::Query<StreamQuery>,
-    user: TunerUser
+    user: TunerUser,
+    req: actix_web::HttpRequest,
 ) -> ApiResult {
+    let config = config.get();
     let service = epg::query_service_by_nid_sid(
         path.id.nid(), path.id.sid()).await?;
     do_get_service_stream(
-        config, &service.channel, service.sid, query, user).await
+        config, &service.channel, service.sid, query, user, &req).await
 }
 
 #[actix_web::get("/programs/{id}/stream")]
 async fn get_program_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: actix_web::web::Data<SharedConfig>,
     path: actix_web::web::Path<ProgramPath>,
     query: actix_web::web::Query<StreamQuery>,
-    user: TunerUser
+    user: TunerUser,
+    req: actix_web::HttpRequest,
 ) -> ApiResult {
+    let config = config.get();
     let program = epg::query_program_by_nid_sid_eid(
         path.id.nid(), path.id.sid(), path.id.eid()).await?;
     let service = epg::query_service_by_nid_sid(
         path.id.nid(), path.id.sid()).await?;
     let clock = epg::query_clock(service.triple()).await?;
 
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let chains = query.filter_chains(&config, peer.as_deref())?;
     let filters = make_program_filters(
-        &config, &service.channel, &program, &clock,
-        query.pre_filter_required(), query.post_filter_required())?;
+        &config, &service.channel, &program, &clock, &query, &chains)?;
 
     let stream = tuner::start_streaming(
         service.channel.channel_type, service.channel.channel.clone(),
-        user.clone()).await?;
+        user.clone(), Some(query.slow_subscriber(&config)),
+        query.start_time()).await?;
 
     let stop_trigger = airtime_tracker::track_airtime(
         &config.recorder.track_airtime_command, &service.channel, &program,
         stream.id()).await?;
 
-    streaming(stream, filters, stop_trigger)
+    streaming(&config, stream, query.pid_filter(), filters, stop_trigger)
+}
+
+// Publishes events in the server-sent events format.  The following events
+// are published:
+//
+//   tuner.preempted: A stream was preempted by a user having a higher priority
+//
+#[actix_web::get("/events")]
+async fn get_events() -> ApiResult {
+    use futures::stream::StreamExt;
+
+    let receiver = tuner::subscribe_preemptions().await?;
+    let events = receiver
+        .filter_map(|result| futures::future::ready(match result {
+            Ok(preemption) => Some(preemption),
+            Err(tokio::sync::broadcast::RecvError::Lagged(n)) => {
+                log::warn!("Missed {} preemptions", n);
+                None
+            }
+            Err(tokio::sync::broadcast::RecvError::Closed) => None,
+        }))
+        .map(|preemption| {
+            let data = serde_json::to_string(&preemption)?;
+            Ok::<_, Error>(Bytes::from(
+                format!("event: tuner.preempted\ndata: {}\n\n", data)))
+        });
+    Ok(actix_web::HttpResponse::Ok()
+       .set_header("cache-control", "no-store")
+       .set_header("content-type", "text/event-stream")
+       .streaming(events))
+}
+
+// For IPTV players which don't support the Mirakurun API.  Query parameters
+// are added to URLs in the playlist so that `token` and `decode` are applied
+// to streams.
+#[actix_web::get("/iptv/playlist")]
+async fn get_iptv_playlist(req: actix_web::HttpRequest) -> ApiResult {
+    let services = epg::query_services().await?;
+    let playlist = iptv::render_playlist(
+        &base_url(&req), req.query_string(), &services);
+    Ok(actix_web::HttpResponse::Ok()
+       .content_type("audio/x-mpegurl; charset=utf-8")
+       .body(playlist))
+}
+
+#[actix_web::get("/iptv/xmltv")]
+async fn get_iptv_xmltv(req: actix_web::HttpRequest) -> ApiResult {
+    let services = epg::query_services().await?;
+    let programs = epg::query_programs().await?;
+    let xmltv = iptv::render_xmltv(
+        &base_url(&req), req.query_string(), &services, &programs);
+    Ok(actix_web::HttpResponse::Ok()
+       .content_type("application/xml; charset=utf-8")
+       .body(xmltv))
+}
+
+fn base_url(req: &actix_web::HttpRequest) -> String {
+    let conn = req.connection_info();
+    format!("{}://{}", conn.scheme(), conn.host())
 }
 
 #[actix_web::get("/docs")]
 async fn get_docs(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: actix_web::web::Data<SharedConfig>,
 ) -> io::Result<actix_files::NamedFile> {
+    let config = config.get();
     // Mirakurun client requires this API since Mirakurun/2.14.0.
     //
     // mirakc simply returns a JSON data obtained from Mirakurun.
     Ok(actix_files::NamedFile::open(&config.mirakurun.openapi_json)?)
 }
 
+// Reloads the config file.  Active streams on unchanged tuners are kept.
+#[actix_web::post("/config/reload")]
+async fn reload_config(
+    config: actix_web::web::Data<SharedConfig>,
+) -> ApiResult {
+    reload::reload(&config).await?;
+    Ok(actix_web::HttpResponse::NoContent().finish())
+}
+
+// Starts scanning channels with the enabled tuners in background.  This may
+// take several minutes.  Only one scan can run at a time.
+#[actix_web::post("/channels/scan")]
+async fn scan_channels(
+    config: actix_web::web::Data<SharedConfig>,
+    query: actix_web::web::Query<ScanChannelsQuery>,
+) -> ApiResult {
+    let config = config.get();
+    let channel_types = match query.types {
+        Some(ref types) => types.clone(),
+        None => channel_scanner::supported_channel_types(&config),
+    };
+    job::invoke_scan_channels(
+        channel_types, std::time::Duration::from_secs(query.timeout)).await?;
+    Ok(actix_web::HttpResponse::Accepted().finish())
+}
+
+// Returns a `channels` section in the config YAML, which is the result of the
+// last channel scan.
+#[actix_web::get("/channels/scan")]
+async fn get_scanned_channels() -> ApiResult {
+    match job::query_channel_scan().await? {
+        Some(ChannelScanStatus::Running) =>
+            Ok(actix_web::HttpResponse::Accepted().finish()),
+        Some(ChannelScanStatus::Done(yaml)) =>
+            Ok(actix_web::HttpResponse::Ok()
+               .content_type("text/yaml; charset=utf-8")
+               .body(yaml)),
+        Some(ChannelScanStatus::Failed) =>
+            Ok(actix_web::HttpResponse::InternalServerError().json(ErrorBody {
+                code: actix_web::http::StatusCode::INTERNAL_SERVER_ERROR
+                    .as_u16(),
+                reason: Some("Channel scan failed"),
+                errors: Vec::new(),
+            })),
+        None =>
+            Ok(actix_web::HttpResponse::NotFound().json(ErrorBody {
+                code: actix_web::http::StatusCode::NOT_FOUND.as_u16(),
+                reason: None,
+                errors: Vec::new(),
+            })),
+    }
+}
+
 async fn do_get_service_stream(
-    config: actix_web::web::Data<Arc<Config>>,
+    config: Arc<Config>,
     channel: &EpgChannel,
     sid: ServiceId,
     query: actix_web::web::Query<StreamQuery>,
-    user: TunerUser
+    user: TunerUser,
+    req: &actix_web::HttpRequest,
 ) -> ApiResult {
+    let peer = req.peer_addr().map(|addr| addr.to_string());
+    let chains = query.filter_chains(&config, peer.as_deref())?;
     let filters = make_service_filters(
-        &config, channel, sid,
-        query.pre_filter_required(), query.post_filter_required())?;
+        &config, channel, sid, &query, &chains)?;
 
     let stream = tuner::start_streaming(
-        channel.channel_type, channel.channel.clone(), user).await?;
+        channel.channel_type, channel.channel.clone(), user,
+        Some(query.slow_subscriber(&config)), query.start_time()).await?;
 
-    streaming(stream, filters, None)
+    if query.is_shareable(&config) {
+        return shared_streaming(
+            stream, filters, query.slow_subscriber(&config)).await;
+    }
+
+    streaming(&config, stream, query.pid_filter(), filters, None)
 }
 
 fn make_service_filters(
     config: &Config,
     channel: &EpgChannel,
     sid: ServiceId,
-    pre_filter_required: bool,
-    post_filter_required: bool,
+    query: &StreamQuery,
+    chains: &[&FilterChainConfig],
 ) -> Result<Vec<String>, Error> {
     let filter = make_service_filter_command(
         &config.filters.service_filter, sid)?;
     make_filters(
-        config, channel, Some(sid), None,
-        filter, pre_filter_required, post_filter_required)
+        config, channel, Some(sid), None, filter, query, chains)
 }
 
 fn make_program_filters(
@@ -289,15 +779,15 @@ fn make_program_filters(
     channel: &EpgChannel,
     program: &EpgProgram,
     clock: &Clock,
-    pre_filter_required: bool,
-    post_filter_required: bool,
+    query: &StreamQuery,
+    chains: &[&FilterChainConfig],
 ) -> Result<Vec<String>, Error> {
     let filter = make_program_filter_command(
         &config.filters.program_filter, program.quad.sid(), program.quad.eid(),
         clock)?;
     make_filters(
         config, channel, Some(program.quad.sid()), Some(program.quad.eid()),
-        filter, pre_filter_required, post_filter_required)
+        filter, query, chains)
 }
 
 fn make_filters(
@@ -306,12 +796,12 @@ fn make_filters(
     sid: Option<ServiceId>,
     eid: Option<EventId>,
     filter: String,
-    pre_filter_required: bool,
-    post_filter_required: bool,
+    query: &StreamQuery,
+    chains: &[&FilterChainConfig],
 ) -> Result<Vec<String>, Error> {
     let mut filters = Vec::new();
 
-    if pre_filter_required {
+    if query.pre_filter_required() {
         if config.filters.pre_filter.is_empty() {
             log::warn!("Pre-filter is required, but not defined");
         } else {
@@ -321,13 +811,25 @@ fn make_filters(
         }
     }
 
+    for chain in chains.iter().filter(|c| c.stage == FilterStage::Pre) {
+        for command in chain.commands.iter() {
+            filters.push(make_filter_command(command, channel, sid, eid)?);
+        }
+    }
+
     if filter.is_empty() {
         log::warn!("Filter not defined");
     } else {
         filters.push(filter);
     }
 
-    if post_filter_required {
+    for chain in chains.iter().filter(|c| c.stage == FilterStage::Post) {
+        for command in chain.commands.iter() {
+            filters.push(make_filter_command(command, channel, sid, eid)?);
+        }
+    }
+
+    if query.post_filter_required() {
         if config.filters.post_filter.is_empty() {
             log::warn!("Post-filter is required, but not defined");
         } else {
@@ -340,7 +842,7 @@ fn make_filters(
     Ok(filters)
 }
 
-fn make_filter_command(
+pub fn make_filter_command(
     command: &str,
     channel:  &EpgChannel,
     sid: Option<ServiceId>,
@@ -361,36 +863,94 @@ fn make_filter_command(
 }
 
 fn streaming(
+    config: &Config,
     mut stream: MpegTsStream,
+    pid_filter: Option<PidFilterConfig>,
     filters: Vec<String>,
     stop_trigger: Option<MpegTsStreamStopTrigger>,
 ) -> ApiResult {
+    // PID filtering is done in the user space.
+    let spliceable = config.streaming.splice && pid_filter.is_none();
+    if let Some(config) = pid_filter {
+        log::debug!("{}: PID filter: {}", stream.id(), config);
+        stream.set_pid_filter(PidFilter::new(config));
+    }
+
+    let id = stream.id();
+    let counter = tuner::set_stream_output(id, filters.clone());
     if filters.is_empty() {
-        do_streaming(stream)
+        do_streaming(id, counter, stream)
     } else {
         let stop_trigger2 = stream.take_stop_trigger();
         let (input, output) = command_util::spawn_pipeline(
-            filters, stream.id())?;
-        actix::spawn(stream.pipe(input));
-        do_streaming(MpegTsStreamTerminator::new(
+            filters, id, &config.filters.process)?;
+        if spliceable {
+            tuner::splice_streaming(stream, input);
+        } else {
+            actix::spawn(stream.pipe(input));
+        }
+        do_streaming(id, counter, MpegTsStreamTerminator::new(
             ChunkStream::new(output, CHUNK_SIZE),
             [stop_trigger, stop_trigger2]))
     }
 }
 
-fn do_streaming<S>(stream: S) -> ApiResult
+// Shares a filter pipeline with other streams which have the same filters on
+// the same tuner session.
+async fn shared_streaming(
+    stream: MpegTsStream,
+    filters: Vec<String>,
+    slow_subscriber: SlowSubscriberConfig,
+) -> ApiResult {
+    let id = stream.id();
+    let counter = tuner::set_stream_output(id, filters.clone());
+    if filters.is_empty() {
+        return do_streaming(id, counter, stream);
+    }
+    let stream = filter_pipeline::start_streaming(
+        stream, filters, slow_subscriber).await?;
+    do_streaming(id, counter, stream)
+}
+
+// The ID of the subscription is returned in the X-Mirakurun-Tuner-User-ID
+// header so that clients can find a preemption of the stream.
+//
+// Bytes sent to the client are counted with `counter`.
+fn do_streaming<S>(
+    id: MpegTsStreamId,
+    counter: tuner::StreamByteCounter,
+    stream: S,
+) -> ApiResult
 where
     S: Stream<Item = io::Result<Bytes>> + Unpin + 'static,
 {
+    use futures::stream::StreamExt;
+
+    let stream = stream.inspect(move |result| {
+        if let Ok(chunk) = result {
+            counter.add(chunk.len());
+        }
+    });
     Ok(actix_web::HttpResponse::Ok()
        .force_close()
        .set_header("cache-control", "no-store")
        .set_header("content-type", "video/MP2T")
+       .set_header("x-mirakurun-tuner-user-id", id.to_string())
        .streaming(stream))
 }
 
 // extractors
 
+#[derive(Deserialize)]
+struct TunerPath {
+    index: usize,
+}
+
+#[derive(Deserialize)]
+struct StreamPath {
+    id: String,
+}
+
 #[derive(Deserialize)]
 struct ChannelPath {
     channel_type: ChannelType,
@@ -414,6 +974,21 @@ struct ProgramPath {
     id: MirakurunProgramId,
 }
 
+#[derive(Deserialize)]
+struct ScanChannelsQuery {
+    // Comma-separated list.  All types supported by the tuners by default.
+    #[serde(default, deserialize_with = "deserialize_channel_types")]
+    types: Option<Vec<ChannelType>>,
+    // Timeout in seconds for each channel.
+    #[serde(default = "ScanChannelsQuery::default_timeout",
+            deserialize_with = "deserialize_scan_timeout")]
+    timeout: u64,
+}
+
+impl ScanChannelsQuery {
+    fn default_timeout() -> u64 { 10 }
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 struct StreamQuery {
@@ -426,6 +1001,36 @@ struct StreamQuery {
     // The post-filter parameter can override the decode parameter.
     #[serde(default)]
     decode: u8,  // default: 0
+
+    // Override `streaming.slow-subscriber` in the config.
+    #[serde(default)]
+    slow_subscriber_policy: Option<SlowSubscriberPolicy>,
+    #[serde(default)]
+    queue_size: Option<usize>,
+    #[serde(default)]
+    max_drops: Option<usize>,
+    #[serde(default)]
+    max_buffer_size: Option<usize>,
+
+    // Start streaming from the time-shift buffer.
+    // `time-shift-start` (Unix time in milliseconds) takes precedence over
+    // `time-shift-offset` (seconds before now).  Out-of-range values are
+    // rejected.
+    #[serde(default, deserialize_with = "deserialize_time_shift_start")]
+    time_shift_start: Option<i64>,
+    #[serde(default, deserialize_with = "deserialize_time_shift_offset")]
+    time_shift_offset: Option<u64>,
+
+    // Select elementary streams to be sent.  Comma-separated lists.
+    #[serde(default, deserialize_with = "deserialize_components")]
+    components: Option<Vec<StreamComponent>>,
+    #[serde(default, deserialize_with = "deserialize_pids")]
+    pids: Option<Vec<u16>>,
+
+    // Names of filter chains defined in `filters.chains`.  Comma-separated
+    // list.
+    #[serde(default, deserialize_with = "deserialize_filter_chains")]
+    filters: Option<Vec<String>>,
 }
 
 impl StreamQuery {
@@ -442,6 +1047,242 @@ impl StreamQuery {
             (None, decode) => decode != 0,  // for compatibility with Mirakurun
         }
     }
+
+    // `queue-size` and `max-buffer-size` are capped by the values in the
+    // config so that a client cannot allocate unlimited memory.
+    fn slow_subscriber(&self, config: &Config) -> SlowSubscriberConfig {
+        let default = &config.streaming.slow_subscriber;
+        SlowSubscriberConfig {
+            policy: self.slow_subscriber_policy.unwrap_or(default.policy),
+            queue_size: self.queue_size
+                .unwrap_or(default.queue_size)
+                .min(default.queue_size),
+            max_drops: self.max_drops.unwrap_or(default.max_drops),
+            max_buffer_size: self.max_buffer_size
+                .unwrap_or(default.max_buffer_size)
+                .min(default.max_buffer_size),
+        }
+    }
+
+    fn pid_filter(&self) -> Option<PidFilterConfig> {
+        if self.components.is_none() && self.pids.is_none() {
+            return None;
+        }
+        Some(PidFilterConfig {
+            components: self.components.clone().unwrap_or_default(),
+            pids: self.pids.clone().unwrap_or_default(),
+        })
+    }
+
+    // The peer address is used instead of the remote address in
+    // `TunerUserInfo::Web` which may come from the X-Forwarded-For header
+    // specified by the client.
+    fn filter_chains<'a>(
+        &self,
+        config: &'a Config,
+        peer: Option<&str>,
+    ) -> Result<Vec<&'a FilterChainConfig>, Error> {
+        let names = match self.filters {
+            Some(ref names) => names,
+            None => return Ok(Vec::new()),
+        };
+        names
+            .iter()
+            .map(|name| {
+                let chain = config.filters.chains.get(name).ok_or_else(
+                    || Error::FilterChainNotFound(name.clone()))?;
+                if !is_allowed_client(&chain.allowed_clients, peer) {
+                    return Err(Error::FilterChainNotAllowed(name.clone()));
+                }
+                Ok(chain)
+            })
+            .collect()
+    }
+
+    // A stream is shareable if it contains the same TS packets as other
+    // streams of the same tuner session.  Streams using filter chains are not
+    // shared because the output of a chain may not be TS packets.
+    fn is_shareable(&self, config: &Config) -> bool {
+        config.streaming.share_filter_pipelines &&
+            self.filters.is_none() &&
+            self.pid_filter().is_none() &&
+            self.start_time().is_none()
+    }
+
+    fn start_time(&self) -> Option<DateTime<Jst>> {
+        match (self.time_shift_start, self.time_shift_offset) {
+            (Some(start), _) => time_shift_start_to_time(start),
+            (None, Some(offset)) => time_shift_offset_to_time(offset),
+            (None, None) => None,
+        }
+    }
+}
+
+fn time_shift_start_to_time(start: i64) -> Option<DateTime<Jst>> {
+    Jst.timestamp_millis_opt(start).single()
+}
+
+fn time_shift_offset_to_time(offset: u64) -> Option<DateTime<Jst>> {
+    let offset = std::time::Duration::from_secs(offset);
+    Duration::from_std(offset)
+        .ok()
+        .and_then(|offset| Jst::now().checked_sub_signed(offset))
+}
+
+fn deserialize_time_shift_start<'de, D>(
+    deserializer: D
+) -> Result<Option<i64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let start = i64::deserialize(deserializer)?;
+    match time_shift_start_to_time(start) {
+        Some(_) => Ok(Some(start)),
+        None => Err(serde::de::Error::custom(
+            format!("time-shift-start out of range: {}", start))),
+    }
+}
+
+fn deserialize_time_shift_offset<'de, D>(
+    deserializer: D
+) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let offset = u64::deserialize(deserializer)?;
+    match time_shift_offset_to_time(offset) {
+        Some(_) => Ok(Some(offset)),
+        None => Err(serde::de::Error::custom(
+            format!("time-shift-offset out of range: {}", offset))),
+    }
+}
+
+fn deserialize_components<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<StreamComponent>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split(',')
+        .map(|component| component.trim().parse())
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_pids<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<u16>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    s.split(',')
+        .map(|pid| {
+            let pid = pid.trim();
+            let result = match pid.strip_prefix("0x") {
+                Some(hex) => u16::from_str_radix(hex, 16),
+                None => pid.parse(),
+            };
+            match result {
+                Ok(pid) if pid < 0x2000 => Ok(pid),
+                _ => Err(format!("Invalid PID: {}", pid)),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_filter_chains<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let names: Vec<String> = s
+        .split(',')
+        .map(|name| name.trim().to_string())
+        .collect();
+    if names.iter().any(|name| name.is_empty()) {
+        return Err(serde::de::Error::custom("Empty filter chain name"));
+    }
+    Ok(Some(names))
+}
+
+fn deserialize_channel_types<'de, D>(
+    deserializer: D
+) -> Result<Option<Vec<ChannelType>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    let types: Result<Vec<ChannelType>, _> = s
+        .split(',')
+        .map(|channel_type| match channel_type.trim() {
+            "GR" => Ok(ChannelType::GR),
+            "BS" => Ok(ChannelType::BS),
+            "CS" => Ok(ChannelType::CS),
+            channel_type => Err(serde::de::Error::custom(
+                format!("Unsupported channel type: {}", channel_type))),
+        })
+        .collect();
+    Ok(Some(types?))
+}
+
+fn deserialize_scan_timeout<'de, D>(deserializer: D) -> Result<u64, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let timeout = u64::deserialize(deserializer)?;
+    if timeout == 0 || timeout > ChannelScanner::MAX_TIMEOUT_SECS {
+        return Err(serde::de::Error::custom(format!(
+            "timeout must be in 1..={}", ChannelScanner::MAX_TIMEOUT_SECS)));
+    }
+    Ok(timeout)
+}
+
+// `remote` is a string obtained from `ConnectionInfo::remote()`, which
+// contains an IP address optionally followed by a port number.
+fn is_allowed_client(allowed_clients: &[String], remote: Option<&str>) -> bool {
+    if allowed_clients.is_empty() {
+        return true;
+    }
+
+    let addr = match remote.and_then(parse_remote_addr) {
+        Some(addr) => addr,
+        None => return false,
+    };
+
+    allowed_clients.iter().any(|pattern| {
+        let (network, prefix_len) = match pattern.find('/') {
+            Some(pos) => (&pattern[..pos], pattern[pos + 1..].parse().ok()),
+            None => (pattern.as_str(), None),
+        };
+        match (network.parse::<IpAddr>(), addr) {
+            (Ok(IpAddr::V4(network)), IpAddr::V4(addr)) => {
+                let len = prefix_len.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (Ok(IpAddr::V6(network)), IpAddr::V6(addr)) => {
+                let len = prefix_len.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    })
+}
+
+fn parse_remote_addr(remote: &str) -> Option<IpAddr> {
+    remote
+        .parse::<SocketAddr>()
+        .map(|addr| addr.ip())
+        .or_else(|_| remote.parse::<IpAddr>())
+        .ok()
 }
 
 impl actix_web::FromRequest for TunerUser {
@@ -466,21 +1307,82 @@ impl actix_web::FromRequest for TunerUser {
                 value.to_str().ok().map_or(String::new(), |s| s.to_string())
             });
 
-        let info = TunerUserInfo::Web { remote, agent };
-
         let priority = req.headers().get_all("x-mirakurun-priority")
             .filter_map(|value| value.to_str().ok())
             .filter_map(|value| value.parse::<i32>().ok())
             .max()
-            .map(|value| value.max(0))
-            .map(TunerUserPriority::from)
-            .unwrap_or_default();
+            .map(|value| value.max(0));
+
+        // The peer address is used instead of `remote` which may come from
+        // the X-Forwarded-For header specified by the client.
+        let peer = req.peer_addr().map(|addr| addr.to_string());
+        let quota = req
+            .app_data::<actix_web::web::Data<SharedConfig>>()
+            .map(|config| config.get())
+            .and_then(|config| {
+                find_quota(&config.quotas, peer.as_deref(),
+                           req.extensions().get::<Credential>(),
+                           agent.as_deref())
+                    .cloned()
+            });
+
+        let priority = match quota {
+            Some(ref quota) => {
+                let priority = priority.unwrap_or(quota.default_priority);
+                match quota.max_priority {
+                    Some(max_priority) => priority.min(max_priority),
+                    None => priority,
+                }
+            }
+            None => priority.unwrap_or_default(),
+        };
 
-        futures::future::ok(TunerUser { info, priority })
+        let info = TunerUserInfo::Web {
+            remote,
+            agent,
+            quota: quota.map(|quota| quota.name),
+        };
+
+        futures::future::ok(TunerUser { info, priority: priority.into() })
     }
 }
 
-fn make_service_filter_command(
+// Returns the first quota matching with the client.
+fn find_quota<'a>(
+    quotas: &'a [QuotaConfig],
+    peer: Option<&str>,
+    credential: Option<&Credential>,
+    agent: Option<&str>,
+) -> Option<&'a QuotaConfig> {
+    quotas.iter().find(|quota| {
+        if !quota.clients.is_empty() &&
+            !is_allowed_client(&quota.clients, peer) {
+            return false;
+        }
+        if !quota.tokens.is_empty() || !quota.users.is_empty() {
+            let matched = match credential {
+                Some(Credential::Token(token)) => quota.tokens.contains(token),
+                Some(Credential::User(name)) => quota.users.contains(name),
+                None => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        if quota.user_agent.is_some() {
+            let matched = match (&quota.user_agent_regex, agent) {
+                (Some(regex), Some(agent)) => regex.is_match(agent),
+                _ => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+        true
+    })
+}
+
+pub fn make_service_filter_command(
     command: &str,
     sid: ServiceId
 ) -> Result<String, Error> {
@@ -512,6 +1414,8 @@ fn make_program_filter_command(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use matches::*;
+    use crate::config::CompiledRegex;
 
     // TODO
     // ----
@@ -542,9 +1446,11 @@ mod tests {
         // "/dev/null" is enough to test
         config.mirakurun.openapi_json = "/dev/null".to_string();
 
+        let config = SharedConfig::new(&config_path(), Arc::new(config));
+
         let mut app = actix_web::test::init_service(
             actix_web::App::new()
-                .data(Arc::new(config))
+                .data(config)
                 .service(create_api_service())).await;
         let req = actix_web::test::TestRequest::with_uri(uri)
             .method(method).to_request();
@@ -559,7 +1465,15 @@ mod tests {
         }
     }
 
+    fn config_path() -> String {
+        let path = std::env::temp_dir().join(
+            format!("mirakc-test-web-config-{}.yml", std::process::id()));
+        path.to_str().unwrap().to_string()
+    }
+
     impl_method!(get, GET);
+    impl_method!(post, POST);
+    impl_method!(delete, DELETE);
 
     #[actix_rt::test]
     async fn test_get_unknown() {
@@ -585,6 +1499,19 @@ mod tests {
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
+    #[actix_rt::test]
+    async fn test_get_service_logo() {
+        let res = get("/api/services/2/logo").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/services/1/logo").await;
+        assert!(
+            res.status() == actix_web::http::StatusCode::SERVICE_UNAVAILABLE);
+
+        let res = get("/api/services/0/logo").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
     #[actix_rt::test]
     async fn test_get_programs() {
         let res = get("/api/programs").await;
@@ -606,6 +1533,54 @@ mod tests {
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
 
+    #[actix_rt::test]
+    async fn test_get_tuner() {
+        let res = get("/api/tuners/0").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/tuners/1").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_tuner_process() {
+        let res = get("/api/tuners/0/process").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/tuners/1/process").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_kill_tuner_process() {
+        let res = delete("/api/tuners/0/process").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = delete("/api/tuners/1/process").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_enable_tuner() {
+        let res = post("/api/tuners/0/enable").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
+
+        let res = post("/api/tuners/0/disable").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
+
+        let res = post("/api/tuners/1/disable").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_tuner_stderr() {
+        let res = get("/api/tuners/0/stderr").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+
+        let res = get("/api/tuners/1/stderr").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
     #[actix_rt::test]
     async fn test_get_channel_stream() {
         let res = get("/api/channels/GR/ch/stream").await;
@@ -632,6 +1607,9 @@ mod tests {
                                   decode).as_str()).await;
             assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
         }
+
+        let res = get("/api/channels/GR/ch/stream?filters=unknown").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
     }
 
     #[actix_rt::test]
@@ -764,6 +1742,417 @@ mod tests {
         let query = actix_web::web::Query::<StreamQuery>::from_query(
             "post-filter=false&decode=1").unwrap().into_inner();
         assert_eq!(query.post_filter_required(), false);
+
+        let config = Config::default();
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "").unwrap().into_inner();
+        assert_eq!(query.slow_subscriber(&config),
+                   config.streaming.slow_subscriber);
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "slow-subscriber-policy=disconnect&max-drops=10")
+            .unwrap().into_inner();
+        assert_eq!(query.slow_subscriber(&config), SlowSubscriberConfig {
+            policy: SlowSubscriberPolicy::Disconnect,
+            max_drops: 10,
+            ..config.streaming.slow_subscriber.clone()
+        });
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "slow-subscriber-policy=block&queue-size=100\
+             &max-buffer-size=1024").unwrap().into_inner();
+        assert_eq!(query.slow_subscriber(&config), SlowSubscriberConfig {
+            policy: SlowSubscriberPolicy::Block,
+            queue_size: 100,
+            max_buffer_size: 1024,
+            ..config.streaming.slow_subscriber.clone()
+        });
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "queue-size=18446744073709551615\
+             &max-buffer-size=18446744073709551615").unwrap().into_inner();
+        assert_eq!(query.slow_subscriber(&config),
+                   config.streaming.slow_subscriber);
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "slow-subscriber-policy=wait");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "").unwrap().into_inner();
+        assert_eq!(query.start_time(), None);
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-start=1577804400000&time-shift-offset=60")
+            .unwrap().into_inner();
+        assert_eq!(query.start_time(),
+                   Some(Jst.ymd(2020, 1, 1).and_hms(0, 0, 0)));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-offset=60").unwrap().into_inner();
+        let start_time = query.start_time().unwrap();
+        assert!(start_time <= Jst::now() - Duration::seconds(60));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-start=9223372036854775807");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-offset=18446744073709551615");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-offset=9223372036854775");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "").unwrap().into_inner();
+        assert_eq!(query.pid_filter(), None);
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "components=video,audio&pids=0x0130,320").unwrap().into_inner();
+        assert_eq!(query.pid_filter(), Some(PidFilterConfig {
+            components: vec![StreamComponent::Video, StreamComponent::Audio],
+            pids: vec![0x0130, 0x0140],
+        }));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "components=audio").unwrap().into_inner();
+        assert_eq!(query.pid_filter(), Some(PidFilterConfig {
+            components: vec![StreamComponent::Audio],
+            pids: vec![],
+        }));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "components=video,unknown");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "pids=0x2000");
+        assert!(query.is_err());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "pids=");
+        assert!(query.is_err());
+
+        let config = Config::default();
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "decode=1").unwrap().into_inner();
+        assert!(query.is_shareable(&config));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "components=video").unwrap().into_inner();
+        assert!(!query.is_shareable(&config));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "time-shift-offset=60").unwrap().into_inner();
+        assert!(!query.is_shareable(&config));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "filters=a").unwrap().into_inner();
+        assert!(!query.is_shareable(&config));
+
+        let mut config = Config::default();
+        config.streaming.share_filter_pipelines = false;
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "decode=1").unwrap().into_inner();
+        assert!(!query.is_shareable(&config));
+
+        let mut config = Config::default();
+        config.filters.chains.insert("a".to_string(), FilterChainConfig {
+            commands: vec!["a".to_string()],
+            stage: FilterStage::Post,
+            allowed_clients: vec![],
+        });
+        config.filters.chains.insert("b".to_string(), FilterChainConfig {
+            commands: vec!["b".to_string()],
+            stage: FilterStage::Pre,
+            allowed_clients: vec!["127.0.0.1".to_string()],
+        });
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "").unwrap().into_inner();
+        assert!(query.filter_chains(&config, Some("127.0.0.1"))
+                .unwrap().is_empty());
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "filters=b,a").unwrap().into_inner();
+        let chains = query.filter_chains(&config, Some("127.0.0.1:1234"))
+            .unwrap();
+        assert_eq!(chains.len(), 2);
+        assert_eq!(chains[0].commands, vec!["b".to_string()]);
+        assert_eq!(chains[1].commands, vec!["a".to_string()]);
+        assert_matches!(
+            query.filter_chains(&config, Some("192.168.0.1")),
+            Err(Error::FilterChainNotAllowed(_)));
+        assert_matches!(
+            query.filter_chains(&config, None),
+            Err(Error::FilterChainNotAllowed(_)));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "filters=a,c").unwrap().into_inner();
+        assert_matches!(
+            query.filter_chains(&config, Some("127.0.0.1")),
+            Err(Error::FilterChainNotFound(_)));
+
+        let query = actix_web::web::Query::<StreamQuery>::from_query(
+            "filters=a,");
+        assert!(query.is_err());
+    }
+
+    #[test]
+    fn test_is_allowed_client() {
+        assert!(is_allowed_client(&[], None));
+
+        let allowed = vec![
+            "192.168.0.0/16".to_string(),
+            "10.0.0.1".to_string(),
+            "fd00::/8".to_string(),
+        ];
+        assert!(is_allowed_client(&allowed, Some("192.168.1.2")));
+        assert!(is_allowed_client(&allowed, Some("192.168.1.2:40772")));
+        assert!(is_allowed_client(&allowed, Some("10.0.0.1")));
+        assert!(is_allowed_client(&allowed, Some("[fd00::1]:40772")));
+        assert!(!is_allowed_client(&allowed, Some("10.0.0.2")));
+        assert!(!is_allowed_client(&allowed, Some("fe80::1")));
+        assert!(!is_allowed_client(&allowed, Some("unknown")));
+        assert!(!is_allowed_client(&allowed, None));
+    }
+
+    #[test]
+    fn test_authorizer() {
+        let config: ServerConfig = serde_yaml::from_str(r#"
+            addrs:
+              - http: '127.0.0.1:8080'
+                allowed-clients: [192.168.0.0/16]
+            auth:
+              tokens:
+                - token: secret
+                  groups: [epg, streaming]
+                - token: epg
+                  groups: [epg]
+              users:
+                - name: admin
+                  password: pass
+                  groups: [admin]
+        "#).unwrap();
+        let authorizer = Authorizer::new(&config).unwrap();
+
+        let authorize = |uri: &str, peer: &str, authorization: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::with_uri(uri)
+                .peer_addr(peer.parse().unwrap());
+            if let Some(authorization) = authorization {
+                req = req.header(
+                    actix_web::http::header::AUTHORIZATION, authorization);
+            }
+            authorizer.authorize(&req.to_srv_request())
+        };
+
+        const PEER: &str = "192.168.1.2:12345";
+        // admin:pass
+        const ADMIN: Option<&str> = Some("Basic YWRtaW46cGFzcw==");
+        assert_matches!(
+            authorize("/api/version", "10.0.0.1:12345", Some("Bearer secret")),
+            Err(Error::AccessDenied));
+        assert_matches!(
+            authorize("/api/version", PEER, None),
+            Err(Error::Unauthorized));
+        assert_matches!(
+            authorize("/api/version", PEER, Some("Bearer wrong")),
+            Err(Error::Unauthorized));
+        assert_matches!(
+            authorize("/api/version", PEER, Some("Bearer secret")),
+            Ok(()));
+        assert_matches!(
+            authorize("/api/services/1/stream?token=secret", PEER, None),
+            Ok(()));
+        assert_matches!(
+            authorize("/api/tuners/0/stderr?token=secret", PEER, None),
+            Err(Error::AccessDenied));
+        assert_matches!(
+            authorize("/api/tuners/0/stderr", PEER, ADMIN),
+            Ok(()));
+        assert_matches!(
+            authorize("/api/version", PEER, ADMIN),
+            Err(Error::AccessDenied));
+        // Percent-encoded paths.
+        assert_matches!(
+            authorize("/api/%73treams?token=secret", PEER, None),
+            Err(Error::AccessDenied));
+        assert_matches!(
+            authorize("/api/tuners/0/std%65rr?token=secret", PEER, None),
+            Err(Error::AccessDenied));
+        assert_matches!(
+            authorize("/api/services/1/strea%6D?token=epg", PEER, None),
+            Err(Error::AccessDenied));
+        assert_matches!(
+            authorize("/api/services/1/strea%6D?token=secret", PEER, None),
+            Ok(()));
+        // "admin:wrong"
+        assert_matches!(
+            authorize("/api/version", PEER, Some("Basic YWRtaW46d3Jvbmc=")),
+            Err(Error::Unauthorized));
+
+        // Authentication is disabled.
+        let authorizer = Authorizer::new(&Default::default()).unwrap();
+        let req = actix_web::test::TestRequest::with_uri("/api/version")
+            .peer_addr("10.0.0.1:12345".parse().unwrap())
+            .to_srv_request();
+        assert_matches!(authorizer.authorize(&req), Ok(()));
+    }
+
+    #[test]
+    fn test_find_quota() {
+        let quotas = vec![
+            QuotaConfig {
+                name: "token".to_string(),
+                tokens: vec!["secret".to_string()],
+                users: vec!["user".to_string()],
+                ..Default::default()
+            },
+            QuotaConfig {
+                name: "epgstation".to_string(),
+                clients: vec!["192.168.0.0/16".to_string()],
+                user_agent: Some("^EPGStation/".to_string()),
+                user_agent_regex: CompiledRegex::new("^EPGStation/").ok(),
+                ..Default::default()
+            },
+            QuotaConfig {
+                name: "lan".to_string(),
+                clients: vec!["192.168.0.0/16".to_string()],
+                ..Default::default()
+            },
+        ];
+
+        let find = |peer, credential, agent| {
+            find_quota(&quotas, peer, credential, agent)
+                .map(|quota| quota.name.as_str())
+        };
+
+        const LAN: Option<&str> = Some("192.168.1.2:12345");
+        const WAN: Option<&str> = Some("10.0.0.1:12345");
+        let token = Credential::Token("secret".to_string());
+        let user = Credential::User("user".to_string());
+        let other = Credential::User("other".to_string());
+
+        assert_eq!(find(WAN, Some(&token), None), Some("token"));
+        assert_eq!(find(WAN, Some(&user), None), Some("token"));
+        assert_eq!(find(WAN, Some(&other), None), None);
+        assert_eq!(find(LAN, None, Some("EPGStation/1.0")), Some("epgstation"));
+        assert_eq!(find(LAN, None, Some("curl/7.0")), Some("lan"));
+        assert_eq!(find(LAN, None, None), Some("lan"));
+        assert_eq!(find(WAN, None, Some("EPGStation/1.0")), None);
+        assert_eq!(find(None, None, None), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_tuner_user_with_quota() {
+        use actix_web::FromRequest;
+
+        let config = Config {
+            quotas: vec![
+                QuotaConfig {
+                    name: "lan".to_string(),
+                    clients: vec!["192.168.0.0/16".to_string()],
+                    max_priority: Some(1),
+                    default_priority: -1,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let config = SharedConfig::new(&config_path(), Arc::new(config));
+
+        let extract = |peer: &str, priority: Option<&str>| {
+            let mut req = actix_web::test::TestRequest::default()
+                .data(config.clone())
+                .peer_addr(peer.parse().unwrap());
+            if let Some(priority) = priority {
+                req = req.header("x-mirakurun-priority", priority);
+            }
+            TunerUser::extract(&req.to_http_request())
+        };
+
+        let user = extract("192.168.1.2:12345", None).await.unwrap();
+        assert_eq!(user.quota(), Some("lan"));
+        assert_eq!(user.priority, (-1).into());
+
+        let user = extract("192.168.1.2:12345", Some("128")).await.unwrap();
+        assert_eq!(user.priority, 1.into());
+
+        let user = extract("10.0.0.1:12345", Some("128")).await.unwrap();
+        assert_eq!(user.quota(), None);
+        assert_eq!(user.priority, TunerUserPriority::GRAB);
+    }
+
+    #[test]
+    fn test_api_group() {
+        use actix_web::http::Method;
+        assert_eq!(api_group(&Method::GET, "/api/version"), ApiGroup::Epg);
+        assert_eq!(api_group(&Method::GET, "/api/programs"), ApiGroup::Epg);
+        assert_eq!(api_group(&Method::GET, "/api/services/1/stream"),
+                   ApiGroup::Streaming);
+        assert_eq!(api_group(&Method::GET, "/api/tuners/0/stderr"),
+                   ApiGroup::Admin);
+        assert_eq!(api_group(&Method::POST, "/api/config/reload"),
+                   ApiGroup::Admin);
+        assert_eq!(api_group(&Method::DELETE, "/api/tuners/0/process"),
+                   ApiGroup::Admin);
+        assert_eq!(api_group(&Method::POST, "/api/tuners/0/disable"),
+                   ApiGroup::Admin);
+        assert_eq!(api_group(&Method::GET, "/api/streams"), ApiGroup::Admin);
+        assert_eq!(api_group(&Method::DELETE, "/api/streams/tuner#0.1.1"),
+                   ApiGroup::Admin);
+    }
+
+    #[test]
+    fn test_secure_eq() {
+        assert!(secure_eq("secret", "secret"));
+        assert!(!secure_eq("secret", "secreT"));
+        assert!(!secure_eq("secret", "secrets"));
+        assert!(secure_eq("", ""));
+    }
+
+    #[actix_rt::test]
+    async fn test_get_streams() {
+        let res = get("/api/streams").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_delete_stream() {
+        let res = delete("/api/streams/tuner%230.1.1").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
+
+        let res = delete("/api/streams/tuner%230.1.2").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+
+        let res = delete("/api/streams/invalid").await;
+        assert!(res.status() == actix_web::http::StatusCode::NOT_FOUND);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_events() {
+        let res = get("/api/events").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "text/event-stream");
+    }
+
+    #[actix_rt::test]
+    async fn test_get_iptv_playlist() {
+        let res = get("/api/iptv/playlist").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "audio/x-mpegurl; charset=utf-8");
+    }
+
+    #[actix_rt::test]
+    async fn test_get_iptv_xmltv() {
+        let res = get("/api/iptv/xmltv").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+        assert_eq!(res.headers().get("content-type").unwrap(),
+                   "application/xml; charset=utf-8");
     }
 
     #[actix_rt::test]
@@ -771,4 +2160,50 @@ mod tests {
         let res = get("/api/docs").await;
         assert!(res.status() == actix_web::http::StatusCode::OK);
     }
+
+    #[actix_rt::test]
+    async fn test_reload_config() {
+        std::fs::write(config_path(), "channels: []").unwrap();
+        let res = post("/api/config/reload").await;
+        assert!(res.status() == actix_web::http::StatusCode::NO_CONTENT);
+
+        std::fs::remove_file(config_path()).unwrap();
+        let res = post("/api/config/reload").await;
+        assert!(res.status() ==
+                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_rt::test]
+    async fn test_scan_channels() {
+        let res = post("/api/channels/scan").await;
+        assert!(res.status() == actix_web::http::StatusCode::ACCEPTED);
+
+        let res = post("/api/channels/scan?types=GR,BS&timeout=1").await;
+        assert!(res.status() == actix_web::http::StatusCode::ACCEPTED);
+
+        let res = post("/api/channels/scan?types=CS").await;
+        assert!(res.status() == actix_web::http::StatusCode::CONFLICT);
+
+        let res = post("/api/channels/scan?types=SKY").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res = post("/api/channels/scan?timeout=0").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+
+        let res =
+            post("/api/channels/scan?timeout=18446744073709551615").await;
+        assert!(res.status() == actix_web::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_scanned_channels() {
+        let res = get("/api/channels/scan").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_get_service_relocations() {
+        let res = get("/api/services/relocations").await;
+        assert!(res.status() == actix_web::http::StatusCode::OK);
+    }
 }